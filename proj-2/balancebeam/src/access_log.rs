@@ -0,0 +1,423 @@
+use crate::{json, request};
+use std::fs::OpenOptions;
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+/// The names accepted by `--access-log-format`.
+pub const FORMAT_NAMES: &[&str] = &["json", "clf"];
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// One JSON object per line
+    Json,
+    /// Apache's Common Log Format: `host ident authuser [date] "request line" status bytes`
+    Clf,
+}
+
+/// When a request started arriving, for the access log.
+#[derive(Clone, Copy)]
+pub struct Started {
+    at: SystemTime,
+    instant: Instant,
+}
+
+impl Started {
+    pub fn now() -> Started {
+        Started {
+            at: SystemTime::now(),
+            instant: Instant::now(),
+        }
+    }
+}
+
+/// A time in UTC, broken down into its calendar date and time of day.
+#[derive(Debug, PartialEq)]
+struct DateTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    millis: u32,
+}
+
+impl DateTime {
+    fn utc(time: SystemTime) -> DateTime {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        let secs_of_day = (secs % 86400) as u32;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day / 60 % 60,
+            second: secs_of_day % 60,
+            millis: since_epoch.subsec_millis(),
+        }
+    }
+
+    /// Formats the time as RFC 3339, e.g. 2020-05-04T13:05:09.123Z.
+    fn rfc3339(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis
+        )
+    }
+
+    /// Formats the time as the Common Log Format does, e.g. 04/May/2020:13:05:09 +0000.
+    fn clf(&self) -> String {
+        format!(
+            "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+}
+
+/// Converts a number of days since 1970-01-01 to a (year, month, day) date in the proleptic
+/// Gregorian calendar. This is Howard Hinnant's civil_from_days algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months are counted from March, so that the leap day comes last
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day as u32)
+}
+
+/// Where access log lines are written.
+type Output = BufWriter<Box<dyn AsyncWrite + Unpin + Send>>;
+
+/// Opens the access log file at `path` for appending.
+fn open(path: &str) -> Result<Output, String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| format!("Could not open access log {}: {}", path, err))?;
+    Ok(BufWriter::new(Box::new(tokio::fs::File::from_std(file))))
+}
+
+/// Writes a line for every response sent to a client, if turned on with `--access-log-format`. This
+/// is separate from the human-readable log, so that it can be fed to other programs.
+///
+/// Lines are handed to a task that does the writing, so that requests never wait for the disk. It
+/// flushes whenever it has written every line it has been given. A file is reopened on SIGUSR1, so
+/// that it can be rotated: once it has been renamed, new lines go to a new file at the old path.
+pub struct AccessLog {
+    /// What the lines look like, or None if there is no access log
+    format: Option<Format>,
+    /// Sends lines to the writing task
+    lines: Option<mpsc::UnboundedSender<String>>,
+}
+
+impl AccessLog {
+    /// An access log that writes nothing.
+    pub fn disabled() -> AccessLog {
+        AccessLog {
+            format: None,
+            lines: None,
+        }
+    }
+
+    /// Creates the access log in the format called `format_name`, appending to `path` if given or
+    /// writing to stderr otherwise, and starts the task that writes it.
+    pub fn new(format_name: &str, path: Option<&str>) -> Result<AccessLog, String> {
+        let format = match format_name {
+            "json" => Format::Json,
+            "clf" => Format::Clf,
+            _ => {
+                return Err(format!(
+                    "Unknown --access-log-format {:?}: expected one of {}",
+                    format_name,
+                    FORMAT_NAMES.join(", ")
+                ))
+            }
+        };
+        let out = match path {
+            Some(path) => open(path)?,
+            None => BufWriter::new(Box::new(tokio::io::stderr()) as Box<_>),
+        };
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let path = path.map(str::to_string);
+        tokio::spawn(async move {
+            write_lines(receiver, out, path).await;
+        });
+        Ok(AccessLog {
+            format: Some(format),
+            lines: Some(sender),
+        })
+    }
+
+    /// Records that a response with `status` and `bytes` of body has been sent to `client`.
+    /// `request` is None if the client's request couldn't be read, `upstream` is None if
+    /// balancebeam made the response itself, and `group` is None if the request wasn't routed to
+    /// a group of upstreams.
+    pub fn log(
+        &self,
+        started: Started,
+        client: IpAddr,
+        request: Option<&http::Request<Vec<u8>>>,
+        (upstream, group): (Option<&str>, Option<&str>),
+        (status, bytes): (http::StatusCode, usize),
+    ) {
+        let (format, lines) = match (self.format, &self.lines) {
+            (Some(format), Some(lines)) => (format, lines),
+            _ => return,
+        };
+        let line = match format {
+            Format::Json => json_line(started, client, request, (upstream, group), (status, bytes)),
+            Format::Clf => clf_line(started, client, request, (status, bytes)),
+        };
+        // The writing task only stops if it can't write at all, and has said so already
+        let _ = lines.send(line);
+    }
+}
+
+/// Writes the lines sent over `lines` to `out` until every sender has gone, reopening the file at
+/// `path` (if there is one) on SIGUSR1.
+async fn write_lines(
+    mut lines: mpsc::UnboundedReceiver<String>,
+    mut out: Output,
+    path: Option<String>,
+) {
+    let mut reopen = match &path {
+        Some(_) => match signal(SignalKind::user_defined1()) {
+            Ok(reopen) => Some(reopen),
+            Err(err) => {
+                log::warn!("Can't reopen the access log on SIGUSR1: {}", err);
+                None
+            }
+        },
+        None => None,
+    };
+    loop {
+        let reopen_requested = async {
+            match &mut reopen {
+                Some(reopen) => reopen.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            line = lines.recv() => {
+                let mut line = match line {
+                    Some(line) => line,
+                    None => break,
+                };
+                // Write everything that is waiting before flushing
+                loop {
+                    if let Err(err) = out.write_all(line.as_bytes()).await {
+                        log::error!("Failed to write to the access log: {}", err);
+                    }
+                    line = match lines.try_recv() {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                }
+                if let Err(err) = out.flush().await {
+                    log::error!("Failed to write to the access log: {}", err);
+                }
+            }
+            _ = reopen_requested => {
+                // reopen is only set up when there is a path
+                let path = path.as_ref().unwrap();
+                let _ = out.flush().await;
+                match open(path) {
+                    Ok(reopened) => {
+                        log::info!("Reopened access log {}", path);
+                        out = reopened;
+                    }
+                    Err(err) => log::error!("{}: still writing to the old file", err),
+                }
+            }
+        }
+    }
+    let _ = out.flush().await;
+}
+
+fn clf_line(
+    started: Started,
+    client: IpAddr,
+    request: Option<&http::Request<Vec<u8>>>,
+    (status, bytes): (http::StatusCode, usize),
+) -> String {
+    // A quote in the request line would end it early
+    let request_line = match request {
+        Some(request) => request::format_request_line(request)
+            .escape_default()
+            .to_string(),
+        None => "-".to_string(),
+    };
+    let bytes = match bytes {
+        0 => "-".to_string(),
+        len => len.to_string(),
+    };
+    format!(
+        "{} - - [{}] \"{}\" {} {}\n",
+        client,
+        DateTime::utc(started.at).clf(),
+        request_line,
+        status.as_u16(),
+        bytes
+    )
+}
+
+fn json_line(
+    started: Started,
+    client: IpAddr,
+    request: Option<&http::Request<Vec<u8>>>,
+    (upstream, group): (Option<&str>, Option<&str>),
+    (status, bytes): (http::StatusCode, usize),
+) -> String {
+    let quote_or_null = |value: Option<&str>| value.map_or("null".to_string(), json::quote);
+    format!(
+        "{{\"time\":\"{}\",\"request_id\":{},\"client\":\"{}\",\"method\":{},\"path\":{},\"upstream\":{},\
+         \"group\":{},\"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\"origin\":\"{}\"}}\n",
+        DateTime::utc(started.at).rfc3339(),
+        quote_or_null(request.and_then(|request| request::request_id(request.headers()))),
+        client,
+        quote_or_null(request.map(|request| request.method().as_str())),
+        quote_or_null(request.map(|request| {
+            request
+                .uri()
+                .path_and_query()
+                .map_or("/", |path| path.as_str())
+        })),
+        quote_or_null(upstream),
+        quote_or_null(group),
+        status.as_u16(),
+        bytes,
+        started.instant.elapsed().as_secs_f64() * 1000.0,
+        if upstream.is_some() {
+            "upstream"
+        } else {
+            "proxy"
+        }
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_utc() {
+        assert_eq!(
+            DateTime::utc(UNIX_EPOCH).rfc3339(),
+            "1970-01-01T00:00:00.000Z"
+        );
+        let time = UNIX_EPOCH + Duration::from_millis(1_588_597_509_123);
+        assert_eq!(DateTime::utc(time).rfc3339(), "2020-05-04T13:05:09.123Z");
+        // A leap day, and the last moment of a year
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(
+            DateTime::utc(leap_day).rfc3339(),
+            "2000-02-29T00:00:00.000Z"
+        );
+        let new_years_eve = UNIX_EPOCH + Duration::from_secs(1_609_459_199);
+        assert_eq!(
+            DateTime::utc(new_years_eve).rfc3339(),
+            "2020-12-31T23:59:59.000Z"
+        );
+    }
+
+    #[test]
+    fn test_clf_date() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_588_597_509_123);
+        assert_eq!(DateTime::utc(time).clf(), "04/May/2020:13:05:09 +0000");
+    }
+
+    #[test]
+    fn test_clf_line() {
+        let started = Started {
+            at: UNIX_EPOCH + Duration::from_secs(1_588_597_509),
+            instant: Instant::now(),
+        };
+        let client = "10.0.0.1".parse().unwrap();
+        let request = http::Request::builder()
+            .method("GET")
+            .uri("/items?id=1")
+            .body(Vec::new())
+            .unwrap();
+        assert_eq!(
+            clf_line(started, client, Some(&request), (http::StatusCode::OK, 5)),
+            "10.0.0.1 - - [04/May/2020:13:05:09 +0000] \"GET /items?id=1 HTTP/1.1\" 200 5\n"
+        );
+        // Without a request or a body
+        assert_eq!(
+            clf_line(
+                started,
+                client,
+                None,
+                (http::StatusCode::REQUEST_TIMEOUT, 0)
+            ),
+            "10.0.0.1 - - [04/May/2020:13:05:09 +0000] \"-\" 408 -\n"
+        );
+    }
+
+    #[test]
+    fn test_json_line() {
+        let started = Started {
+            at: UNIX_EPOCH + Duration::from_secs(1_588_597_509),
+            instant: Instant::now(),
+        };
+        let client = "10.0.0.1".parse().unwrap();
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/items?id=1")
+            .header("x-request-id", "abc-123")
+            .body(Vec::new())
+            .unwrap();
+        let line = json_line(
+            started,
+            client,
+            Some(&request),
+            (Some("127.0.0.1:8000"), Some("default")),
+            (http::StatusCode::CREATED, 7),
+        );
+        assert!(line.starts_with(
+            "{\"time\":\"2020-05-04T13:05:09.000Z\",\"request_id\":\"abc-123\",\"client\":\"10.0.0.1\",\"method\":\"POST\",\
+             \"path\":\"/items?id=1\",\"upstream\":\"127.0.0.1:8000\",\"group\":\"default\",\
+             \"status\":201,\
+             \"bytes\":7,\"duration_ms\":"
+        ));
+        assert!(line.ends_with(",\"origin\":\"upstream\"}\n"));
+
+        let line = json_line(
+            started,
+            client,
+            None,
+            (None, None),
+            (http::StatusCode::BAD_GATEWAY, 15),
+        );
+        assert!(line.contains(
+            "\"method\":null,\"path\":null,\"upstream\":null,\"group\":null,\"status\":502,"
+        ));
+        assert!(line.ends_with(",\"origin\":\"proxy\"}\n"));
+    }
+}
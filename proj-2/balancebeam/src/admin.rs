@@ -0,0 +1,421 @@
+use crate::canary::{self, Canary};
+use crate::metrics::Gauges;
+use crate::metrics::Metrics;
+use crate::strategy::UpstreamInfo;
+use crate::{check_server, json, parse_upstream, request, response, ProxyState};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::stream::StreamExt;
+
+/// What a listener other than the one clients use serves. None of its requests are ever forwarded
+/// to upstreams.
+#[derive(Clone, Copy)]
+pub enum Api {
+    /// The admin API, for looking at and changing the upstreams (see route_admin)
+    Admin,
+    /// `GET /metrics`, in the Prometheus text format
+    Metrics,
+}
+
+/// Serves `api` on `listener`.
+pub async fn serve(mut listener: TcpListener, state: Arc<ProxyState>, api: Api) {
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    handle_connection(stream, &state, api).await;
+                });
+            }
+            Err(err) => log::warn!("Failed to accept an admin connection: {}", err),
+        }
+    }
+}
+
+async fn handle_connection(mut conn: TcpStream, state: &ProxyState, api: Api) {
+    loop {
+        let read = request::read_from_stream(
+            &mut conn,
+            state.client_idle_timeout,
+            state.header_limits,
+            state.max_request_body_size,
+        );
+        let request = match read.await {
+            Ok(request) => request,
+            Err(request::Error::IncompleteRequest(0))
+            | Err(request::Error::TimedOut(0))
+            | Err(request::Error::ConnectionError(_)) => return,
+            Err(error) => {
+                log::debug!("Error parsing admin request: {}", error);
+                let response = response::make_http_error(match error {
+                    request::Error::HeadersTooLarge => {
+                        http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                    }
+                    _ => http::StatusCode::BAD_REQUEST,
+                });
+                let _ = response::write_to_stream(&response, &mut conn).await;
+                return;
+            }
+        };
+        log::info!("Admin request: {}", request::format_request_line(&request));
+        let response = match api {
+            Api::Admin => route_admin(state, &request).await,
+            Api::Metrics => route_metrics(state, &request).await,
+        };
+        if let Err(error) = response::write_to_stream(&response, &mut conn).await {
+            log::warn!("Failed to send admin response: {}", error);
+            return;
+        }
+    }
+}
+
+/// The admin API:
+///
+/// * `GET /status` shows how busy balancebeam is, and how each upstream is doing.
+/// * `GET /upstreams` lists the upstreams and how they are doing.
+/// * `POST /upstreams` adds the upstream in the request body, written as for `--upstream`.
+/// * `DELETE /upstreams/{address}` removes an upstream. Requests it is handling are finished.
+/// * `POST /upstreams/{address}/drain` stops new requests going to an upstream, while letting the
+///   ones it is handling finish.
+/// * `GET /canary` shows the share of requests going to the --canary-group, and how many have gone
+///   to it and to the stable group.
+/// * `PUT /canary` changes the percentage of requests going to the --canary-group to the one in the
+///   request body (such as 5 or 0.5).
+/// * `GET /maintenance` shows whether balancebeam is in maintenance mode, `POST /maintenance` turns
+///   it on, and `DELETE /maintenance` turns it off.
+/// * `GET /cache` shows how many responses are cached, and how much memory they take up.
+///   `DELETE /cache` throws them all away.
+async fn route_admin(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let method = request.method();
+    let path = request.uri().path();
+    if path == "/status" {
+        return match *method {
+            http::Method::GET => status(state).await,
+            _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    if path == "/upstreams" {
+        return match *method {
+            http::Method::GET => list_upstreams(state).await,
+            http::Method::POST => add_upstream(state, request.body()).await,
+            _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    if path == "/maintenance" {
+        match *method {
+            http::Method::GET => {}
+            http::Method::POST => state.maintenance.set(true),
+            http::Method::DELETE => state.maintenance.set(false),
+            _ => return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        }
+        let body = format!("{{\"maintenance\":{}}}", state.maintenance.is_on());
+        return json_response(http::StatusCode::OK, body);
+    }
+    if path == "/cache" {
+        let cache = match &state.cache {
+            Some(cache) => cache,
+            None => {
+                let message =
+                    "There is no cache: start balancebeam with --cache-max-bytes to have one";
+                return error_response(http::StatusCode::NOT_FOUND, message);
+            }
+        };
+        let (entries, bytes) = match *method {
+            http::Method::GET => cache.usage(),
+            http::Method::DELETE => {
+                let (entries, bytes) = cache.purge();
+                log::info!("Purged {} cached responses ({} bytes)", entries, bytes);
+                (0, 0)
+            }
+            _ => return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        };
+        let body = format!("{{\"entries\":{},\"bytes\":{}}}", entries, bytes);
+        return json_response(http::StatusCode::OK, body);
+    }
+    if path == "/canary" {
+        return match *method {
+            http::Method::GET => show_canary(state),
+            http::Method::PUT => set_canary(state, request.body()),
+            _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    if let Some(address) = path.strip_prefix("/upstreams/") {
+        return match address.strip_suffix("/drain") {
+            Some(address) if method == http::Method::POST => drain_upstream(state, address).await,
+            None if method == http::Method::DELETE => remove_upstream(state, address).await,
+            _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    response::make_http_error(http::StatusCode::NOT_FOUND)
+}
+
+async fn route_metrics(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    if request.uri().path() != "/metrics" {
+        return response::make_http_error(http::StatusCode::NOT_FOUND);
+    }
+    if request.method() != http::Method::GET {
+        return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let upstreams = state.upstreams.lock().await;
+    let gauges = Gauges {
+        open_connections: state.open_connections.load(Ordering::SeqCst),
+        upstreams: upstreams.len(),
+        healthy_upstreams: upstreams.iter().filter(|upstream| upstream.healthy).count(),
+        rate_limited_requests: state.rate_limited_requests.load(Ordering::Relaxed),
+    };
+    drop(upstreams);
+    let body = state.metrics.render(&gauges).into_bytes();
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+async fn status(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let upstreams = state.upstreams.lock().await;
+    let list: Vec<String> = upstreams.iter().map(upstream_json).collect();
+    let healthy = upstreams.iter().filter(|upstream| upstream.healthy).count();
+    drop(upstreams);
+    let body = format!(
+        "{{\"maintenance\":{},\"open_connections\":{},\"requests\":{},\"errors\":{},\"rate_limited_requests\":{},\
+         \"rate_limited_clients\":{},\"healthy_upstreams\":{},\"upstreams\":[{}]}}",
+        state.maintenance.is_on(),
+        state.open_connections.load(Ordering::SeqCst),
+        state.requests_received.load(Ordering::Relaxed),
+        state.error_responses.load(Ordering::Relaxed),
+        state.rate_limited_requests.load(Ordering::Relaxed),
+        state.rate_limiter.limited_clients(),
+        healthy,
+        list.join(",")
+    );
+    json_response(http::StatusCode::OK, body)
+}
+
+async fn list_upstreams(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let upstreams = state.upstreams.lock().await;
+    let list: Vec<String> = upstreams.iter().map(upstream_json).collect();
+    json_response(http::StatusCode::OK, format!("[{}]", list.join(",")))
+}
+
+/// Adds an upstream, which is health checked first so that it isn't sent requests if it's down.
+async fn add_upstream(state: &ProxyState, body: &[u8]) -> http::Response<Vec<u8>> {
+    let spec = match std::str::from_utf8(body)
+        .map_err(|_| "The upstream must be UTF-8".to_string())
+        .and_then(|body| parse_upstream(body.trim()))
+    {
+        Ok(spec) => spec,
+        Err(err) => return error_response(http::StatusCode::BAD_REQUEST, &err),
+    };
+    // Each group's load balancing strategy and backoff are set up at startup
+    if !state.groups.contains_key(&spec.group) {
+        let message = format!("There is no upstream group {:?}", spec.group);
+        return error_response(http::StatusCode::BAD_REQUEST, &message);
+    }
+    let path = spec
+        .health_check_path
+        .as_ref()
+        .unwrap_or(&state.active_health_check_path);
+    let passed = check_server(
+        (&spec.address, &spec.address),
+        path,
+        &state.health_check_expect,
+        state.health_check_connector(),
+        state.upstream_response_timeout,
+        state.header_limits,
+        &state.unix_socket_host,
+    )
+    .await;
+
+    let mut upstreams = state.upstreams.lock().await;
+    if upstreams
+        .iter()
+        .any(|upstream| upstream.address == spec.address)
+    {
+        let message = format!("Upstream {} already exists", spec.address);
+        return error_response(http::StatusCode::CONFLICT, &message);
+    }
+    let mut upstream = state.upstream_settings.new_upstream(spec);
+    upstream.set_healthy(passed);
+    log::info!(
+        "Added upstream {} ({})",
+        upstream.address,
+        if passed { "healthy" } else { "dead" }
+    );
+    let body = upstream_json(&upstream);
+    upstreams.push(upstream);
+    json_response(http::StatusCode::CREATED, body)
+}
+
+async fn remove_upstream(state: &ProxyState, address: &str) -> http::Response<Vec<u8>> {
+    let mut upstreams = state.upstreams.lock().await;
+    match upstreams
+        .iter()
+        .position(|upstream| upstream.address == address)
+    {
+        Some(idx) => {
+            let upstream = upstreams.remove(idx);
+            log::info!("Removed upstream {}", address);
+            json_response(http::StatusCode::OK, upstream_json(&upstream))
+        }
+        None => not_found(address),
+    }
+}
+
+async fn drain_upstream(state: &ProxyState, address: &str) -> http::Response<Vec<u8>> {
+    let mut upstreams = state.upstreams.lock().await;
+    match upstreams
+        .iter_mut()
+        .find(|upstream| upstream.address == address)
+    {
+        Some(upstream) => {
+            if !upstream.draining {
+                log::info!(
+                    "Draining upstream {} ({} requests in flight)",
+                    address,
+                    upstream.in_flight
+                );
+                upstream.draining = true;
+            }
+            json_response(http::StatusCode::OK, upstream_json(upstream))
+        }
+        None => not_found(address),
+    }
+}
+
+fn show_canary(state: &ProxyState) -> http::Response<Vec<u8>> {
+    match &state.canary {
+        Some(canary) => json_response(http::StatusCode::OK, canary_json(canary, &state.metrics)),
+        None => no_canary(),
+    }
+}
+
+fn set_canary(state: &ProxyState, body: &[u8]) -> http::Response<Vec<u8>> {
+    let canary = match &state.canary {
+        Some(canary) => canary,
+        None => return no_canary(),
+    };
+    let share = match std::str::from_utf8(body)
+        .map_err(|_| "The percentage must be UTF-8".to_string())
+        .and_then(canary::parse_percent)
+    {
+        Ok(share) => share,
+        Err(err) => return error_response(http::StatusCode::BAD_REQUEST, &err),
+    };
+    log::info!(
+        "Sending {}% of group {}'s requests to group {} (was {}%)",
+        canary::format_percent(share),
+        canary.stable_group,
+        canary.group,
+        canary::format_percent(canary.share())
+    );
+    canary.set_share(share);
+    json_response(http::StatusCode::OK, canary_json(canary, &state.metrics))
+}
+
+fn no_canary() -> http::Response<Vec<u8>> {
+    let message = "There is no canary: start balancebeam with --canary-group to have one";
+    error_response(http::StatusCode::NOT_FOUND, message)
+}
+
+/// Describes the canary split, with how many requests have actually gone each way.
+fn canary_json(canary: &Canary, metrics: &Metrics) -> String {
+    format!(
+        "{{\"group\":{},\"stable_group\":{},\"percent\":{},\"sticky\":{},\
+         \"canary_requests\":{},\"stable_requests\":{}}}",
+        json::quote(&canary.group),
+        json::quote(&canary.stable_group),
+        canary::format_percent(canary.share()),
+        canary.sticky,
+        metrics.group_requests(&canary.group),
+        metrics.group_requests(&canary.stable_group)
+    )
+}
+
+fn not_found(address: &str) -> http::Response<Vec<u8>> {
+    let message = format!("There is no upstream {}", address);
+    error_response(http::StatusCode::NOT_FOUND, &message)
+}
+
+fn upstream_json(upstream: &UpstreamInfo) -> String {
+    let latency_ms = match upstream.latency {
+        Some(latency) => format!("{:.3}", latency.as_secs_f64() * 1000.0),
+        None => "null".to_string(),
+    };
+    format!(
+        "{{\"address\":{},\"group\":{},\"weight\":{},\"healthy\":{},\"draining\":{},\"in_flight\":{},\
+         \"requests\":{},\"server_errors\":{},\"connect_failures\":{},\"latency_ms\":{}}}",
+        json::quote(&upstream.address),
+        json::quote(&upstream.group),
+        upstream.weight,
+        upstream.healthy,
+        upstream.draining,
+        upstream.in_flight,
+        upstream.counters.requests.load(Ordering::Relaxed),
+        upstream.counters.server_errors.load(Ordering::Relaxed),
+        upstream.counters.connect_failures.load(Ordering::Relaxed),
+        latency_ms
+    )
+}
+
+fn error_response(status: http::StatusCode, message: &str) -> http::Response<Vec<u8>> {
+    json_response(status, format!("{{\"error\":{}}}", json::quote(message)))
+}
+
+fn json_response(status: http::StatusCode, body: String) -> http::Response<Vec<u8>> {
+    let body = body.into_bytes();
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_canary_json() {
+        let canary = Canary::new("canary".to_string(), "default".to_string(), 250, true);
+        let metrics = Metrics::new();
+        metrics.record_group_request("canary");
+        assert_eq!(
+            canary_json(&canary, &metrics),
+            "{\"group\":\"canary\",\"stable_group\":\"default\",\"percent\":2.5,\"sticky\":true,\
+             \"canary_requests\":1,\"stable_requests\":0}"
+        );
+    }
+
+    #[test]
+    fn test_upstream_json() {
+        let mut upstream = UpstreamInfo::new("127.0.0.1:8000".to_string(), 2);
+        upstream.in_flight = 3;
+        upstream.counters.record_response(http::StatusCode::OK);
+        upstream
+            .counters
+            .record_response(http::StatusCode::BAD_GATEWAY);
+        upstream.counters.record_connect_failure();
+        assert_eq!(
+            upstream_json(&upstream),
+            "{\"address\":\"127.0.0.1:8000\",\"group\":\"default\",\"weight\":2,\"healthy\":true,\"draining\":false,\
+             \"in_flight\":3,\"requests\":2,\"server_errors\":1,\"connect_failures\":1,\
+             \"latency_ms\":null}"
+        );
+        upstream.latency = Some(Duration::from_micros(1500));
+        assert!(upstream_json(&upstream).ends_with("\"connect_failures\":1,\"latency_ms\":1.500}"));
+    }
+}
@@ -0,0 +1,103 @@
+use std::time::{Duration, Instant};
+
+/// How long to wait after the first failure.
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest wait, unless `--max-upstream-backoff` says otherwise.
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Keeps track of something that keeps failing, so that it is tried less and less often: the wait
+/// after each failure is twice as long as the one before, up to a maximum.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backoff {
+    max: Duration,
+    /// How many failures there have been in a row
+    failures: u32,
+    /// When the wait after the last failure runs out
+    until: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(max: Duration) -> Backoff {
+        Backoff {
+            max,
+            failures: 0,
+            until: None,
+        }
+    }
+
+    /// Records a failure at `now`, returning how long to wait before trying again.
+    pub fn fail_at(&mut self, now: Instant) -> Duration {
+        let factor = 1u32.checked_shl(self.failures).unwrap_or(u32::MAX);
+        let delay = INITIAL_BACKOFF
+            .checked_mul(factor)
+            .unwrap_or(self.max)
+            .min(self.max);
+        self.failures = self.failures.saturating_add(1);
+        self.until = Some(now + delay);
+        delay
+    }
+
+    /// Whether the wait after the last failure is still going on at `now`.
+    pub fn is_waiting_at(&self, now: Instant) -> bool {
+        self.until.is_some_and(|until| now < until)
+    }
+
+    /// Forgets about any failures, returning whether there were some.
+    pub fn reset(&mut self) -> bool {
+        let failed = self.failures > 0;
+        self.failures = 0;
+        self.until = None;
+        failed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(5));
+        let now = Instant::now();
+        let delays: Vec<u64> = (0..5).map(|_| backoff.fail_at(now).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        // However many failures there are
+        for _ in 0..100 {
+            backoff.fail_at(now);
+        }
+        assert_eq!(backoff.fail_at(now), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_waiting() {
+        let mut backoff = Backoff::new(Duration::from_secs(30));
+        let now = Instant::now();
+        assert!(!backoff.is_waiting_at(now));
+        backoff.fail_at(now);
+        backoff.fail_at(now);
+        assert!(backoff.is_waiting_at(now + Duration::from_millis(1999)));
+        assert!(!backoff.is_waiting_at(now + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut backoff = Backoff::new(Duration::from_secs(30));
+        let now = Instant::now();
+        assert!(!backoff.reset());
+        backoff.fail_at(now);
+        backoff.fail_at(now);
+        assert!(backoff.reset());
+        assert!(!backoff.is_waiting_at(now));
+        // Starts from the beginning again
+        assert_eq!(backoff.fail_at(now), INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn test_no_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(0));
+        let now = Instant::now();
+        assert_eq!(backoff.fail_at(now), Duration::from_secs(0));
+        assert!(!backoff.is_waiting_at(now));
+    }
+}
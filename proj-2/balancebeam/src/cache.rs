@@ -0,0 +1,465 @@
+use crate::response::Body;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// The response header that says whether a response came from the cache (HIT) or from an upstream
+/// that was asked because it wasn't cached (MISS).
+pub const HEADER: &str = "x-cache";
+
+/// What a response is cached under. Upstreams may encode a response differently depending on the
+/// request's Accept-Encoding, so that is part of the key too, whether or not the response says it
+/// varies by it. Responses that vary by any other header aren't cached at all.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    /// The group of upstreams the request was routed to, so that (say) a canary's responses are
+    /// kept apart from the stable group's
+    group: String,
+    method: http::Method,
+    host: String,
+    /// The path, with the query if there is one
+    path: String,
+    accept_encoding: String,
+}
+
+impl Key {
+    /// Roughly how much memory the key takes up.
+    fn size(&self) -> usize {
+        self.group.len() + self.host.len() + self.path.len() + self.accept_encoding.len()
+    }
+}
+
+/// The key a request routed to `group` is cached under, or None if it can't be answered from the
+/// cache (or its response cached): only GETs without a body are, and not those with Authorization
+/// (whose responses are meant for one user), or those asking (with Cache-Control or Pragma) not to
+/// be answered from a cache.
+pub fn key(request: &http::Request<Vec<u8>>, group: &str, unread_body: usize) -> Option<Key> {
+    if request.method() != http::Method::GET
+        || !request.body().is_empty()
+        || unread_body > 0
+        || request.headers().contains_key(http::header::AUTHORIZATION)
+    {
+        return None;
+    }
+    let cache_control = directives(request.headers(), http::header::CACHE_CONTROL);
+    let pragma = directives(request.headers(), http::header::PRAGMA);
+    if cache_control
+        .iter()
+        .chain(&pragma)
+        .any(|(name, _)| name == "no-cache" || name == "no-store")
+    {
+        return None;
+    }
+    let host = match request.uri().host() {
+        Some(host) => host,
+        None => request
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(""),
+    };
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    let accept_encoding = request
+        .headers()
+        .get_all(http::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    Some(Key {
+        group: group.to_string(),
+        method: request.method().clone(),
+        host: host.to_ascii_lowercase(),
+        path: path.to_string(),
+        accept_encoding,
+    })
+}
+
+/// The directives in a Cache-Control (or similar) header, by lowercase name, with their values (if
+/// any) unquoted.
+fn directives(
+    headers: &http::HeaderMap,
+    name: http::header::HeaderName,
+) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap().trim().to_ascii_lowercase();
+            let value = parts
+                .next()
+                .map(|value| value.trim().trim_matches('"').to_string());
+            (name, value)
+        })
+        .collect()
+}
+
+/// How long a response stays fresh once it has been received, or None if it mustn't be cached. Only
+/// 200s are cached, and only if Cache-Control gives them a lifetime (with s-maxage, or else
+/// max-age) that is longer than the Age they arrived with, and doesn't say no-store, private or
+/// no-cache (which would have them checked with the upstream each time). Responses that set cookies
+/// or vary by anything but Accept-Encoding aren't cached either.
+pub fn lifetime(response: &http::Response<Body>) -> Option<Duration> {
+    let headers = response.headers();
+    if response.status() != http::StatusCode::OK || headers.contains_key(http::header::SET_COOKIE) {
+        return None;
+    }
+    let varies_otherwise = headers
+        .get_all(http::header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|name| !name.is_empty() && !name.eq_ignore_ascii_case("accept-encoding"));
+    if varies_otherwise {
+        return None;
+    }
+    let directives = directives(headers, http::header::CACHE_CONTROL);
+    if directives
+        .iter()
+        .any(|(name, _)| name == "no-store" || name == "private" || name == "no-cache")
+    {
+        return None;
+    }
+    let seconds = |wanted: &str| {
+        directives
+            .iter()
+            .find(|(name, _)| name == wanted)
+            .and_then(|(_, value)| value.as_ref()?.parse::<u64>().ok())
+    };
+    let max_age = seconds("s-maxage").or_else(|| seconds("max-age"))?;
+    let fresh_for = max_age.checked_sub(age(headers))?;
+    if fresh_for == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(fresh_for))
+}
+
+/// How old (in seconds) the upstream says a response is already, from its Age header.
+fn age(headers: &http::HeaderMap) -> u64 {
+    headers
+        .get(http::header::AGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// A cached response.
+struct Entry {
+    status: http::StatusCode,
+    headers: http::HeaderMap,
+    body: Vec<u8>,
+    /// How old the response was when it was received, in seconds
+    age: u64,
+    received: Instant,
+    expires: Instant,
+    /// Roughly how much memory the entry (and its key) takes up
+    size: usize,
+    /// When the entry was last used, as Entries::clock counted then
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<Key, Entry>,
+    /// The keys of the entries, least recently used first
+    by_use: BTreeMap<u64, Key>,
+    /// The total size of the entries
+    bytes: usize,
+    /// Counts uses of entries, to order them by
+    clock: u64,
+}
+
+impl Entries {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.by_use.remove(&entry.used);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+}
+
+/// Keeps upstreams' responses to GETs in memory, so that the same requests can be answered without
+/// asking an upstream again until the responses are no longer fresh. Once the cache holds
+/// `max_bytes` of responses, the least recently used ones are thrown away to make room for more.
+pub struct Cache {
+    max_bytes: usize,
+    /// The biggest a single response's body can be to be cached
+    max_entry_size: usize,
+    entries: Mutex<Entries>,
+}
+
+impl Cache {
+    pub fn new(max_bytes: usize, max_entry_size: usize) -> Cache {
+        Cache {
+            max_bytes,
+            max_entry_size: max_entry_size.min(max_bytes),
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    pub fn max_entry_size(&self) -> usize {
+        self.max_entry_size
+    }
+
+    /// How many responses are cached, and how much memory they take up.
+    pub fn usage(&self) -> (usize, usize) {
+        let entries = self.entries.lock();
+        (entries.entries.len(), entries.bytes)
+    }
+
+    /// The response cached under `key`, if there is one that is still fresh at `now`, with an Age
+    /// header saying how old it is and HEADER saying it came from the cache.
+    pub fn get_at(&self, key: &Key, now: Instant) -> Option<http::Response<Vec<u8>>> {
+        let mut entries = self.entries.lock();
+        let entry = entries.entries.get(key)?;
+        if now >= entry.expires {
+            entries.remove(key);
+            return None;
+        }
+        let mut response = http::Response::builder()
+            .status(entry.status)
+            .version(http::Version::HTTP_11)
+            .body(entry.body.clone())
+            .unwrap();
+        *response.headers_mut() = entry.headers.clone();
+        let age = entry.age + now.duration_since(entry.received).as_secs();
+        let headers = response.headers_mut();
+        headers.insert(http::header::AGE, http::HeaderValue::from(age));
+        headers.insert(HEADER, http::HeaderValue::from_static("HIT"));
+
+        let used = entry.used;
+        let now_used = entries.tick();
+        entries.by_use.remove(&used);
+        entries.by_use.insert(now_used, key.clone());
+        entries.entries.get_mut(key).unwrap().used = now_used;
+        Some(response)
+    }
+
+    /// Caches `response` under `key`, received at `now`, if it can be (see lifetime) and its body
+    /// has been read in full and isn't bigger than max_entry_size. Room is made for it by throwing
+    /// away the least recently used responses.
+    pub fn insert_at(&self, key: Key, response: &http::Response<Body>, now: Instant) {
+        let body = match response.body() {
+            Body::Full(body) if body.len() <= self.max_entry_size => body,
+            _ => return,
+        };
+        let lifetime = match lifetime(response) {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+        let headers_size: usize = response
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        let size = key.size() + headers_size + body.len();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        entries.remove(&key);
+        while entries.bytes + size > self.max_bytes {
+            let oldest = entries.by_use.values().next().unwrap().clone();
+            entries.remove(&oldest);
+        }
+        let used = entries.tick();
+        entries.by_use.insert(used, key.clone());
+        entries.bytes += size;
+        let entry = Entry {
+            status: response.status(),
+            headers: response.headers().clone(),
+            body: body.clone(),
+            age: age(response.headers()),
+            received: now,
+            expires: now + lifetime,
+            size,
+            used,
+        };
+        entries.entries.insert(key, entry);
+    }
+
+    /// Throws away every cached response, returning how many there were and how much memory they
+    /// took up.
+    pub fn purge(&self) -> (usize, usize) {
+        let mut entries = self.entries.lock();
+        let purged = (entries.entries.len(), entries.bytes);
+        *entries = Entries::default();
+        purged
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn get(path: &str) -> http::Request<Vec<u8>> {
+        http::Request::builder()
+            .uri(path)
+            .header("host", "Example.com")
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    fn ok(cache_control: &str, body: &[u8]) -> http::Response<Body> {
+        http::Response::builder()
+            .header("cache-control", cache_control)
+            .header("content-length", body.len().to_string())
+            .body(Body::Full(body.to_vec()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_key() {
+        let request = get("/items?page=2");
+        let key = key(&request, "default", 0).unwrap();
+        assert_eq!(key.host, "example.com");
+        assert_eq!(key.path, "/items?page=2");
+        assert_ne!(
+            Some(&key),
+            super::key(&get("/items"), "default", 0).as_ref()
+        );
+        assert_ne!(Some(&key), super::key(&request, "canary", 0).as_ref());
+
+        let mut gzip = get("/items?page=2");
+        gzip.headers_mut()
+            .insert("accept-encoding", http::HeaderValue::from_static("gzip"));
+        assert_ne!(Some(&key), super::key(&gzip, "default", 0).as_ref());
+
+        // A body still to be read rules it out, as does the request's own say
+        assert!(super::key(&request, "default", 10).is_none());
+        let mut post = get("/items");
+        *post.method_mut() = http::Method::POST;
+        assert!(super::key(&post, "default", 0).is_none());
+        for (name, value) in [
+            ("authorization", "Bearer abc"),
+            ("cache-control", "max-age=0, no-cache"),
+            ("pragma", "no-cache"),
+        ] {
+            let mut request = get("/items");
+            request
+                .headers_mut()
+                .insert(name, http::HeaderValue::from_static(value));
+            assert!(super::key(&request, "default", 0).is_none(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_lifetime() {
+        assert_eq!(
+            lifetime(&ok("public, max-age=60", b"")),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            lifetime(&ok("max-age=60, s-maxage=\"10\"", b"")),
+            Some(Duration::from_secs(10))
+        );
+        for uncacheable in [
+            "no-store, max-age=60",
+            "private, max-age=60",
+            "no-cache, max-age=60",
+            "max-age=0",
+            "max-age=soon",
+            "public",
+        ] {
+            assert_eq!(lifetime(&ok(uncacheable, b"")), None, "{}", uncacheable);
+        }
+
+        let mut aged = ok("max-age=60", b"");
+        aged.headers_mut()
+            .insert("age", http::HeaderValue::from_static("45"));
+        assert_eq!(lifetime(&aged), Some(Duration::from_secs(15)));
+        aged.headers_mut()
+            .insert("age", http::HeaderValue::from_static("60"));
+        assert_eq!(lifetime(&aged), None);
+
+        let mut varies = ok("max-age=60", b"");
+        varies
+            .headers_mut()
+            .insert("vary", http::HeaderValue::from_static("Accept-Encoding"));
+        assert!(lifetime(&varies).is_some());
+        varies.headers_mut().insert(
+            "vary",
+            http::HeaderValue::from_static("Accept-Encoding, Cookie"),
+        );
+        assert!(lifetime(&varies).is_none());
+
+        let mut not_found = ok("max-age=60", b"");
+        *not_found.status_mut() = http::StatusCode::NOT_FOUND;
+        assert!(lifetime(&not_found).is_none());
+        let mut cookie = ok("max-age=60", b"");
+        cookie
+            .headers_mut()
+            .insert("set-cookie", http::HeaderValue::from_static("session=1"));
+        assert!(lifetime(&cookie).is_none());
+    }
+
+    #[test]
+    fn test_get_and_insert() {
+        let cache = Cache::new(1_000_000, 1_000);
+        let key = key(&get("/items"), "default", 0).unwrap();
+        let start = Instant::now();
+        assert!(cache.get_at(&key, start).is_none());
+        cache.insert_at(key.clone(), &ok("max-age=60", b"items"), start);
+
+        let hit = cache.get_at(&key, start + Duration::from_secs(5)).unwrap();
+        assert_eq!(hit.body(), b"items");
+        assert_eq!(hit.headers()["age"], "5");
+        assert_eq!(hit.headers()[HEADER], "HIT");
+        assert_eq!(hit.headers()["content-length"], "5");
+        // Until it is no longer fresh
+        assert!(cache
+            .get_at(&key, start + Duration::from_secs(60))
+            .is_none());
+        assert_eq!(cache.usage(), (0, 0));
+
+        // Bodies that are too big, or weren't read in full, aren't kept
+        cache.insert_at(key.clone(), &ok("max-age=60", &[0; 1001]), start);
+        let mut streamed = ok("max-age=60", b"");
+        *streamed.body_mut() = Body::Streamed(Vec::new(), crate::response::Framing::Length(5));
+        cache.insert_at(key.clone(), &streamed, start);
+        assert!(cache.get_at(&key, start).is_none());
+
+        cache.insert_at(key.clone(), &ok("max-age=60", b"items"), start);
+        assert_eq!(cache.purge().0, 1);
+        assert!(cache.get_at(&key, start).is_none());
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let keys: Vec<Key> = ["/a", "/b", "/c"]
+            .iter()
+            .map(|path| key(&get(path), "default", 0).unwrap())
+            .collect();
+        let response = ok("max-age=60", &[0; 100]);
+        let start = Instant::now();
+        // Room for two of the responses, but not three
+        let probe = Cache::new(1_000_000, 1_000);
+        probe.insert_at(keys[0].clone(), &response, start);
+        let size = probe.usage().1;
+        let cache = Cache::new(size * 2 + size / 2, 1_000);
+
+        cache.insert_at(keys[0].clone(), &response, start);
+        cache.insert_at(keys[1].clone(), &response, start);
+        // Using /a makes /b the least recently used, so it is the one thrown away for /c
+        assert!(cache.get_at(&keys[0], start).is_some());
+        cache.insert_at(keys[2].clone(), &response, start);
+        assert_eq!(cache.usage(), (2, size * 2));
+        assert!(cache.get_at(&keys[0], start).is_some());
+        assert!(cache.get_at(&keys[1], start).is_none());
+        assert!(cache.get_at(&keys[2], start).is_some());
+    }
+}
@@ -0,0 +1,181 @@
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The request header that chooses for itself: `always` sends the request to the canary, and
+/// `never` keeps it on the stable group.
+pub const HEADER: &str = "x-canary";
+
+/// How finely requests are split: the share of them that goes to the canary is in hundredths of a
+/// percent.
+const BUCKETS: u32 = 10_000;
+
+/// Sends a share of the requests for one group of upstreams (the stable group) to another (the
+/// canary), so that a new version can be tried out on some of the traffic before all of it. The
+/// share can be changed while balancebeam is running, through the admin API.
+pub struct Canary {
+    /// The group some of the stable group's requests go to instead
+    pub group: String,
+    pub stable_group: String,
+    /// The share of the stable group's requests that go to the canary, in hundredths of a percent
+    share: AtomicU32,
+    /// Whether each client sticks to one group, rather than its requests being split one by one.
+    /// A client on the canary stays on it as the share grows.
+    pub sticky: bool,
+    rng: Mutex<StdRng>,
+}
+
+impl Canary {
+    pub fn new(group: String, stable_group: String, share: u32, sticky: bool) -> Canary {
+        Canary {
+            group,
+            stable_group,
+            share: AtomicU32::new(share),
+            sticky,
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    pub fn share(&self) -> u32 {
+        self.share.load(Ordering::Relaxed)
+    }
+
+    pub fn set_share(&self, share: u32) {
+        self.share.store(share, Ordering::Relaxed);
+    }
+
+    /// The group a request from `client` with `headers` goes to, given it was routed to `routed`:
+    /// the canary for its share of the stable group's requests (or if the request asks for it),
+    /// and `routed` otherwise.
+    pub fn group_for<'a>(
+        &'a self,
+        routed: &'a str,
+        client: IpAddr,
+        headers: &http::HeaderMap,
+    ) -> &'a str {
+        if routed != self.stable_group {
+            return routed;
+        }
+        let forced = headers.get(HEADER).and_then(|value| value.to_str().ok());
+        let canary = match forced {
+            Some(value) if value.eq_ignore_ascii_case("always") => true,
+            Some(value) if value.eq_ignore_ascii_case("never") => false,
+            _ => {
+                let bucket = if self.sticky {
+                    bucket(client)
+                } else {
+                    self.rng.lock().gen_range(0, BUCKETS)
+                };
+                bucket < self.share()
+            }
+        };
+        if canary {
+            &self.group
+        } else {
+            routed
+        }
+    }
+}
+
+/// Where a client falls among the buckets, which stays the same for as long as balancebeam runs.
+fn bucket(client: IpAddr) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    client.hash(&mut hasher);
+    (hasher.finish() % BUCKETS as u64) as u32
+}
+
+/// Parses a percentage (such as 5 or 0.25) of requests to send to the canary, as a share in
+/// hundredths of a percent.
+pub fn parse_percent(percent: &str) -> Result<u32, String> {
+    let invalid = || {
+        format!(
+            "Invalid canary percentage {:?}: expected a number from 0 to 100",
+            percent
+        )
+    };
+    let value: f64 = percent.trim().parse().map_err(|_| invalid())?;
+    if !(0.0..=100.0).contains(&value) {
+        return Err(invalid());
+    }
+    Ok((value * 100.0).round() as u32)
+}
+
+/// Writes a share as the percentage it is (so that 500 is 5, and 25 is 0.25).
+pub fn format_percent(share: u32) -> String {
+    (share as f64 / 100.0).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    fn canary(percent: &str, sticky: bool) -> Canary {
+        let share = parse_percent(percent).unwrap();
+        Canary::new("canary".to_string(), "stable".to_string(), share, sticky)
+    }
+
+    #[test]
+    fn test_parse_percent() {
+        assert_eq!(parse_percent("5"), Ok(500));
+        assert_eq!(parse_percent("0.25"), Ok(25));
+        assert_eq!(parse_percent("100"), Ok(10_000));
+        assert_eq!(parse_percent(" 0 "), Ok(0));
+        for invalid in ["", "-1", "100.5", "five", "NaN", "5%"] {
+            assert!(parse_percent(invalid).is_err(), "{:?}", invalid);
+        }
+        assert_eq!(format_percent(500), "5");
+        assert_eq!(format_percent(25), "0.25");
+    }
+
+    #[test]
+    fn test_group_for() {
+        let headers = http::HeaderMap::new();
+        let none = canary("0", false);
+        let all = canary("100", false);
+        assert_eq!(none.group_for("stable", CLIENT, &headers), "stable");
+        assert_eq!(all.group_for("stable", CLIENT, &headers), "canary");
+        // Only the stable group's requests are split
+        assert_eq!(all.group_for("other", CLIENT, &headers), "other");
+
+        let mut always = http::HeaderMap::new();
+        always.insert(HEADER, http::HeaderValue::from_static("always"));
+        let mut never = http::HeaderMap::new();
+        never.insert(HEADER, http::HeaderValue::from_static("Never"));
+        assert_eq!(none.group_for("stable", CLIENT, &always), "canary");
+        assert_eq!(all.group_for("stable", CLIENT, &never), "stable");
+        assert_eq!(all.group_for("other", CLIENT, &always), "other");
+    }
+
+    #[test]
+    fn test_split() {
+        let headers = http::HeaderMap::new();
+        let split = canary("25", false);
+        let to_canary = (0..4000)
+            .filter(|_| split.group_for("stable", CLIENT, &headers) == "canary")
+            .count();
+        assert!((800..1200).contains(&to_canary), "{}", to_canary);
+
+        let sticky = canary("25", true);
+        let clients: Vec<IpAddr> = (0..4000_u32)
+            .map(|n| IpAddr::V4(std::net::Ipv4Addr::from(0x0a00_0000 + n)))
+            .collect();
+        let on_canary: Vec<bool> = clients
+            .iter()
+            .map(|&client| sticky.group_for("stable", client, &headers) == "canary")
+            .collect();
+        let to_canary = on_canary.iter().filter(|&&canary| canary).count();
+        assert!((800..1200).contains(&to_canary), "{}", to_canary);
+        // Each client keeps going to the same group, and stays on the canary as its share grows
+        sticky.set_share(parse_percent("50").unwrap());
+        for (&client, &was_canary) in clients.iter().zip(&on_canary) {
+            let is_canary = sticky.group_for("stable", client, &headers) == "canary";
+            assert!(is_canary || !was_canary, "{}", client);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::convert::TryFrom;
+
+/// The longest chunk-size line (with any chunk extensions) or trailer line that is read. Anything
+/// longer is taken as an attempt to use up memory.
+const MAX_LINE_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The body isn't validly chunked. httparse::Error says roughly how: Token for a bad chunk
+    /// size, NewLine for a missing line break.
+    Malformed(httparse::Error),
+    /// The decoded body would be bigger than the limit
+    TooLarge,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    /// Waiting for a chunk-size line
+    Size,
+    /// Waiting for this many more bytes of the current chunk's data
+    Data(usize),
+    /// Waiting for the line break after a chunk's data
+    DataEnd,
+    /// Waiting for the trailer section's lines (which are skipped) and the empty line that ends it
+    Trailers,
+    Done,
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked` (RFC 7230 section 4.1), however the
+/// pieces it is read in happen to be split.
+pub struct Decoder {
+    state: State,
+    /// Bytes that have been read but can't be decoded until more arrive
+    pending: Vec<u8>,
+    body: Vec<u8>,
+    /// How many bytes the chunks so far hold, whether or not they have been taken
+    decoded: usize,
+    max_size: usize,
+}
+
+impl Decoder {
+    /// Makes a decoder for a body of no more than `max_size` bytes, once decoded.
+    pub fn new(max_size: usize) -> Decoder {
+        Decoder {
+            state: State::Size,
+            pending: Vec::new(),
+            body: Vec::new(),
+            decoded: 0,
+            max_size,
+        }
+    }
+
+    /// Decodes the next piece of the body, returning whether the body is complete. Anything after
+    /// the end of the body is ignored.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<bool, Error> {
+        self.pending.extend_from_slice(bytes);
+        let mut used = 0;
+        let result = self.decode(&mut used);
+        self.pending.drain(..used);
+        result
+    }
+
+    /// Decodes as much of `pending` as possible, from `used` on, advancing `used` past whatever
+    /// has been decoded.
+    fn decode(&mut self, used: &mut usize) -> Result<bool, Error> {
+        loop {
+            let pending = &self.pending[*used..];
+            match self.state {
+                State::Size => match httparse::parse_chunk_size(pending) {
+                    Ok(httparse::Status::Complete((line_len, size))) => {
+                        *used += line_len;
+                        if size == 0 {
+                            self.state = State::Trailers;
+                            continue;
+                        }
+                        let size = usize::try_from(size).map_err(|_| Error::TooLarge)?;
+                        if size > self.max_size - self.decoded {
+                            return Err(Error::TooLarge);
+                        }
+                        self.decoded += size;
+                        self.state = State::Data(size);
+                    }
+                    Ok(httparse::Status::Partial) if pending.len() < MAX_LINE_SIZE => {
+                        return Ok(false)
+                    }
+                    _ => return Err(Error::Malformed(httparse::Error::Token)),
+                },
+                State::Data(remaining) => {
+                    let available = remaining.min(pending.len());
+                    self.body.extend_from_slice(&pending[..available]);
+                    *used += available;
+                    if available < remaining {
+                        self.state = State::Data(remaining - available);
+                        return Ok(false);
+                    }
+                    self.state = State::DataEnd;
+                }
+                State::DataEnd => {
+                    if pending.len() < 2 {
+                        return Ok(false);
+                    }
+                    if &pending[..2] != b"\r\n" {
+                        return Err(Error::Malformed(httparse::Error::NewLine));
+                    }
+                    *used += 2;
+                    self.state = State::Size;
+                }
+                State::Trailers => match pending.windows(2).position(|end| end == b"\r\n") {
+                    Some(0) => {
+                        *used += 2;
+                        self.state = State::Done;
+                    }
+                    Some(line_len) => *used += line_len + 2,
+                    None if pending.len() < MAX_LINE_SIZE => return Ok(false),
+                    None => return Err(Error::Malformed(httparse::Error::NewLine)),
+                },
+                State::Done => return Ok(true),
+            }
+        }
+    }
+
+    /// Takes what has been decoded so far, so that it can be passed on before the rest arrives.
+    pub fn take_body(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.body)
+    }
+
+    /// Returns the decoded body.
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BODY: &[u8] =
+        b"4\r\nWiki\r\n6;name=value\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\n\r\n";
+
+    /// Decodes `encoded` in pieces of `piece_size` bytes.
+    fn decode_in_pieces(encoded: &[u8], piece_size: usize) -> Result<Vec<u8>, Error> {
+        let mut decoder = Decoder::new(100);
+        let mut done = false;
+        for piece in encoded.chunks(piece_size) {
+            assert!(!done);
+            done = decoder.push(piece)?;
+        }
+        assert!(done);
+        Ok(decoder.into_body())
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            decode_in_pieces(BODY, BODY.len()).unwrap(),
+            b"Wikipedia in \r\n\r\nchunks."
+        );
+        assert_eq!(decode_in_pieces(b"0\r\n\r\n", 5).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_split_reads() {
+        // Every boundary falls inside a size line, some data or a line break at some piece size
+        for piece_size in 1..BODY.len() {
+            assert_eq!(
+                decode_in_pieces(BODY, piece_size).unwrap(),
+                b"Wikipedia in \r\n\r\nchunks.",
+                "{}",
+                piece_size
+            );
+        }
+    }
+
+    #[test]
+    fn test_trailers() {
+        let encoded = b"3\r\nabc\r\n0\r\nExpires: never\r\nX-Checksum: 1\r\n\r\n";
+        for piece_size in 1..encoded.len() {
+            assert_eq!(decode_in_pieces(encoded, piece_size).unwrap(), b"abc");
+        }
+    }
+
+    #[test]
+    fn test_rest_ignored() {
+        let mut decoder = Decoder::new(100);
+        assert!(decoder.push(b"1\r\na\r\n0\r\n\r\nGET / HTTP/1.1").unwrap());
+        assert_eq!(decoder.into_body(), b"a");
+    }
+
+    #[test]
+    fn test_malformed() {
+        for encoded in [
+            &b"x\r\nabc\r\n0\r\n\r\n"[..],
+            b"-3\r\nabc\r\n0\r\n\r\n",
+            b"3\r\nabcd\r\n0\r\n\r\n",
+            b"ffffffffffffffffff\r\n",
+        ] {
+            assert!(
+                matches!(decode_in_pieces(encoded, 1), Err(Error::Malformed(_))),
+                "{:?}",
+                String::from_utf8_lossy(encoded)
+            );
+        }
+        let endless_line = vec![b'1'; MAX_LINE_SIZE];
+        assert!(matches!(
+            Decoder::new(100).push(&endless_line),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_take_body() {
+        let mut decoder = Decoder::new(5);
+        assert!(!decoder.push(b"3\r\nabc\r\n2\r\nd").unwrap());
+        assert_eq!(decoder.take_body(), b"abcd");
+        assert!(decoder.push(b"e\r\n0\r\n\r\n").unwrap());
+        assert_eq!(decoder.take_body(), b"e");
+        // Taken bytes still count towards the limit
+        let mut decoder = Decoder::new(5);
+        decoder.push(b"3\r\nabc\r\n").unwrap();
+        decoder.take_body();
+        assert!(matches!(decoder.push(b"3\r\n"), Err(Error::TooLarge)));
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut decoder = Decoder::new(5);
+        assert!(!decoder.push(b"3\r\nabc\r\n").unwrap());
+        assert!(matches!(decoder.push(b"3\r\n"), Err(Error::TooLarge)));
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq)]
+enum State {
+    /// Requests go through as usual. Holds when the failures within the window happened.
+    Closed(VecDeque<Instant>),
+    /// No requests go through until the given time.
+    Open(Instant),
+    /// One trial request, started at the given time, is deciding whether to close again.
+    HalfOpen(Instant),
+}
+
+/// Stops sending requests to an upstream that keeps failing, then lets one request through now and
+/// then to find out whether it has recovered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CircuitBreaker {
+    /// How many failures within `window` open the breaker, or 0 if it never opens
+    max_failures: usize,
+    window: Duration,
+    /// How long the breaker stays open before a trial request is let through
+    cooldown: Duration,
+    state: State,
+}
+
+impl CircuitBreaker {
+    pub fn new(max_failures: usize, window: Duration, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            max_failures,
+            window,
+            cooldown,
+            state: State::Closed(VecDeque::new()),
+        }
+    }
+
+    /// A breaker that never opens.
+    pub fn disabled() -> CircuitBreaker {
+        CircuitBreaker::new(0, Duration::from_secs(0), Duration::from_secs(0))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_failures > 0
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed(_))
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    /// Whether a request can be sent at `now`: always while closed, once the cool-down is over
+    /// while open, and never while a trial request is in flight (unless the trial has been going
+    /// on for a whole cool-down, in case its result was never recorded).
+    pub fn allows_request_at(&self, now: Instant) -> bool {
+        match self.state {
+            State::Closed(_) => true,
+            State::Open(until) => now >= until,
+            State::HalfOpen(started) => now >= started + self.cooldown,
+        }
+    }
+
+    /// Records that a request is being sent at `now`, returning true if it is a trial request.
+    pub fn start_request_at(&mut self, now: Instant) -> bool {
+        if self.is_closed() || !self.allows_request_at(now) {
+            return false;
+        }
+        self.state = State::HalfOpen(now);
+        true
+    }
+
+    /// Records a failed request at `now`, returning true if that opened the breaker.
+    pub fn record_failure_at(&mut self, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let window = self.window;
+        match &mut self.state {
+            State::Closed(failures) => {
+                while failures
+                    .front()
+                    .is_some_and(|&failure| now.duration_since(failure) >= window)
+                {
+                    failures.pop_front();
+                }
+                failures.push_back(now);
+                if failures.len() < self.max_failures {
+                    return false;
+                }
+            }
+            // Requests that started before the breaker opened can still fail afterwards
+            State::Open(_) => return false,
+            State::HalfOpen(_) => {}
+        }
+        self.state = State::Open(now + self.cooldown);
+        true
+    }
+
+    /// Records a successful request, returning true if that closed the breaker.
+    pub fn record_success(&mut self) -> bool {
+        match self.state {
+            State::HalfOpen(_) => self.close(),
+            _ => false,
+        }
+    }
+
+    /// Closes the breaker, forgetting about any failures, and returns true if it wasn't closed
+    /// already.
+    pub fn close(&mut self) -> bool {
+        let was_closed = self.is_closed();
+        self.state = State::Closed(VecDeque::new());
+        !was_closed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(3, Duration::from_secs(10), Duration::from_secs(30))
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_opens_after_failures() {
+        let mut breaker = breaker();
+        let now = Instant::now();
+        assert!(!breaker.record_failure_at(now));
+        assert!(!breaker.record_failure_at(now + secs(1)));
+        assert!(breaker.allows_request_at(now + secs(1)));
+        assert!(breaker.record_failure_at(now + secs(2)));
+        assert!(!breaker.is_closed());
+        assert!(!breaker.allows_request_at(now + secs(31)));
+        // Requests already in flight can't open it again
+        assert!(!breaker.record_failure_at(now + secs(3)));
+    }
+
+    #[test]
+    fn test_failures_outside_window() {
+        let mut breaker = breaker();
+        let now = Instant::now();
+        breaker.record_failure_at(now);
+        breaker.record_failure_at(now + secs(5));
+        assert!(!breaker.record_failure_at(now + secs(10)));
+        assert!(breaker.is_closed());
+        assert!(breaker.record_failure_at(now + secs(11)));
+    }
+
+    #[test]
+    fn test_half_open_success() {
+        let mut breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure_at(now);
+        }
+        assert!(!breaker.start_request_at(now + secs(29)));
+        assert!(breaker.allows_request_at(now + secs(30)));
+        assert!(breaker.start_request_at(now + secs(30)));
+        // Only one trial request at a time
+        assert!(!breaker.allows_request_at(now + secs(31)));
+        assert!(breaker.record_success());
+        assert!(breaker.is_closed());
+        assert!(breaker.allows_request_at(now + secs(31)));
+        // Failures from before it opened are forgotten
+        assert!(!breaker.record_failure_at(now + secs(32)));
+    }
+
+    #[test]
+    fn test_half_open_failure() {
+        let mut breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure_at(now);
+        }
+        breaker.start_request_at(now + secs(30));
+        assert!(breaker.record_failure_at(now + secs(31)));
+        assert!(!breaker.allows_request_at(now + secs(60)));
+        assert!(breaker.allows_request_at(now + secs(61)));
+    }
+
+    #[test]
+    fn test_lost_trial() {
+        let mut breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure_at(now);
+        }
+        breaker.start_request_at(now + secs(30));
+        assert!(!breaker.allows_request_at(now + secs(59)));
+        assert!(breaker.start_request_at(now + secs(60)));
+    }
+
+    #[test]
+    fn test_close() {
+        let mut breaker = breaker();
+        let now = Instant::now();
+        assert!(!breaker.close());
+        for _ in 0..3 {
+            breaker.record_failure_at(now);
+        }
+        assert!(breaker.close());
+        assert!(breaker.allows_request_at(now));
+        // Successes while closed change nothing
+        assert!(!breaker.record_success());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut breaker = CircuitBreaker::disabled();
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(!breaker.record_failure_at(now));
+        }
+        assert!(breaker.allows_request_at(now));
+        assert!(!breaker.start_request_at(now));
+    }
+}
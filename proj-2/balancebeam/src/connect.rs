@@ -0,0 +1,106 @@
+/// A destination that clients may ask balancebeam to CONNECT them to, written as host:port. The
+/// host can be `*` for any host, or start with `*.` for any subdomain of the rest, and the port can
+/// be `*` for any port.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectPattern {
+    /// In lowercase, with IPv6 addresses still in brackets
+    host: String,
+    /// None for any port
+    port: Option<u16>,
+}
+
+impl ConnectPattern {
+    pub fn parse(spec: &str) -> Result<ConnectPattern, String> {
+        let spec = spec.trim();
+        let invalid = || format!("Invalid CONNECT destination {:?}: expected host:port", spec);
+        let (host, port) = spec.rsplit_once(':').ok_or_else(invalid)?;
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid());
+        }
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().map_err(|_| invalid())?),
+        };
+        Ok(ConnectPattern {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+
+    /// Whether `authority` (the host:port a CONNECT request asks for) is one of the destinations
+    /// this pattern allows.
+    pub fn matches(&self, authority: &str) -> bool {
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host.to_ascii_lowercase(), port),
+            None => return false,
+        };
+        let port_matches = match (self.port, port.parse::<u16>()) {
+            (_, Err(_)) => false,
+            (None, Ok(_)) => true,
+            (Some(allowed), Ok(port)) => allowed == port,
+        };
+        let host_matches = match self.host.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) if suffix.starts_with('.') => host.ends_with(suffix),
+            _ => host == self.host,
+        };
+        port_matches && host_matches
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            ConnectPattern::parse(" DB.internal:5432 "),
+            Ok(ConnectPattern {
+                host: "db.internal".to_string(),
+                port: Some(5432),
+            })
+        );
+        assert_eq!(
+            ConnectPattern::parse("[::1]:*"),
+            Ok(ConnectPattern {
+                host: "[::1]".to_string(),
+                port: None,
+            })
+        );
+        for spec in [
+            "db.internal",
+            ":443",
+            "db.internal:https",
+            "db.internal:70000",
+            "a/b:1",
+        ] {
+            assert!(ConnectPattern::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let exact = ConnectPattern::parse("db.internal:5432").unwrap();
+        assert!(exact.matches("db.internal:5432"));
+        assert!(exact.matches("DB.Internal:5432"));
+        assert!(!exact.matches("db.internal:5433"));
+        assert!(!exact.matches("db.internal"));
+        assert!(!exact.matches("evil.db.internal:5432"));
+
+        let subdomains = ConnectPattern::parse("*.internal:443").unwrap();
+        assert!(subdomains.matches("api.internal:443"));
+        assert!(subdomains.matches("a.b.internal:443"));
+        assert!(!subdomains.matches("internal:443"));
+        assert!(!subdomains.matches("notinternal:443"));
+
+        let any_port = ConnectPattern::parse("[::1]:*").unwrap();
+        assert!(any_port.matches("[::1]:22"));
+        assert!(!any_port.matches("[::2]:22"));
+        assert!(!any_port.matches("[::1]:x"));
+
+        assert!(ConnectPattern::parse("*:443")
+            .unwrap()
+            .matches("anything.example:443"));
+    }
+}
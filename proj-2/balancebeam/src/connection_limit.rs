@@ -0,0 +1,118 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Limits how many connections each client can have open at once.
+pub struct ConnectionLimiter {
+    /// Connections allowed per client, or 0 for no limit
+    max_per_ip: usize,
+    /// How many connections each client has open. Clients with none aren't in the map.
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Counts as one of a client's open connections until it is dropped, however the connection ends.
+pub struct ConnectionGuard<'a> {
+    limiter: &'a ConnectionLimiter,
+    client: IpAddr,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: usize) -> ConnectionLimiter {
+        ConnectionLimiter {
+            max_per_ip,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a new connection from `client`, or returns None if it already has as many open as
+    /// it is allowed.
+    pub fn try_acquire(&self, client: IpAddr) -> Option<ConnectionGuard<'_>> {
+        let mut open = self.open.lock();
+        let count = open.entry(client).or_insert(0);
+        if self.max_per_ip > 0 && *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard {
+            limiter: self,
+            client,
+        })
+    }
+
+    /// How many connections `client` has open.
+    #[cfg(test)]
+    fn open_for(&self, client: IpAddr) -> Option<usize> {
+        self.open.lock().get(&client).copied()
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock();
+        if let Some(count) = open.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn test_limit() {
+        let limiter = ConnectionLimiter::new(2);
+        let client = ip("10.0.0.1");
+        let first = limiter.try_acquire(client).unwrap();
+        let _second = limiter.try_acquire(client).unwrap();
+        assert!(limiter.try_acquire(client).is_none());
+        // Each client has its own count
+        assert!(limiter.try_acquire(ip("10.0.0.2")).is_some());
+        drop(first);
+        assert_eq!(limiter.open_for(client), Some(1));
+        assert!(limiter.try_acquire(client).is_some());
+    }
+
+    #[test]
+    fn test_forgets_clients() {
+        let limiter = ConnectionLimiter::new(1);
+        let client = ip("10.0.0.1");
+        drop(limiter.try_acquire(client));
+        assert_eq!(limiter.open_for(client), None);
+        // A refused connection isn't counted
+        let guard = limiter.try_acquire(client);
+        assert!(limiter.try_acquire(client).is_none());
+        drop(guard);
+        assert_eq!(limiter.open_for(client), None);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = ConnectionLimiter::new(0);
+        let guards: Vec<_> = (0..100)
+            .map(|_| limiter.try_acquire(ip("10.0.0.1")).unwrap())
+            .collect();
+        assert_eq!(limiter.open_for(ip("10.0.0.1")), Some(100));
+        drop(guards);
+        assert_eq!(limiter.open_for(ip("10.0.0.1")), None);
+    }
+
+    #[test]
+    fn test_released_on_panic() {
+        let limiter = ConnectionLimiter::new(1);
+        let client = ip("10.0.0.1");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = limiter.try_acquire(client).unwrap();
+            panic!("connection handler panicked");
+        }));
+        assert!(result.is_err());
+        assert!(limiter.try_acquire(client).is_some());
+    }
+}
@@ -0,0 +1,60 @@
+use crate::stream;
+use std::net::IpAddr;
+
+/// Whether an upstream address (as given to --upstream) names its host, rather than giving its IP
+/// address (or the path of a Unix socket), so that it has to be resolved.
+pub fn is_hostname(address: &str) -> bool {
+    if stream::unix_path(address).is_some() {
+        return false;
+    }
+    let (host_port, _) = stream::split_scheme(address);
+    stream::host(host_port).parse::<IpAddr>().is_err()
+}
+
+/// Looks up every address a hostname upstream resolves to, written the same way (with the same
+/// port, and with https:// if it has it), in order and without duplicates.
+pub async fn resolve(address: &str) -> std::io::Result<Vec<String>> {
+    let (host_port, https) = stream::split_scheme(address);
+    let mut addresses: Vec<String> = tokio::net::lookup_host(host_port)
+        .await?
+        .map(|resolved| {
+            if https {
+                format!("https://{}", resolved)
+            } else {
+                resolved.to_string()
+            }
+        })
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_hostname() {
+        assert!(is_hostname("backend.internal:8080"));
+        assert!(is_hostname("https://backend.internal:443"));
+        assert!(is_hostname("localhost:8080"));
+        assert!(!is_hostname("10.0.0.5:8080"));
+        assert!(!is_hostname("https://10.0.0.5:443"));
+        assert!(!is_hostname("[::1]:8080"));
+        assert!(!is_hostname("unix:/run/app.sock"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        assert_eq!(
+            resolve("127.0.0.1:8080").await.unwrap(),
+            vec!["127.0.0.1:8080"]
+        );
+        assert_eq!(
+            resolve("https://[::1]:8443").await.unwrap(),
+            vec!["https://[::1]:8443"]
+        );
+        assert!(resolve("backend.invalid:8080").await.is_err());
+    }
+}
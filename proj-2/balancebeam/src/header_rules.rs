@@ -0,0 +1,145 @@
+use crate::request::HOP_BY_HOP_HEADERS;
+use http::header::{HeaderName, HeaderValue};
+
+/// Headers that rules can't touch, besides the hop-by-hop ones: balancebeam frames each message's
+/// body itself, so changing how long a message says its body is would break it.
+const FRAMING_HEADERS: &[&str] = &["content-length"];
+
+/// Changes made to the headers of every request forwarded to an upstream, or every response passed
+/// on to a client, as given on the command line: headers are removed, then set (replacing any
+/// values they had), then appended to (keeping any values they had).
+#[derive(Debug)]
+pub struct HeaderRules {
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, HeaderValue)>,
+    append: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl HeaderRules {
+    /// Parses the `Name:Value` headers to set and append, and the names of those to remove,
+    /// refusing any that aren't valid headers or that balancebeam manages itself.
+    pub fn parse(
+        set: &[String],
+        append: &[String],
+        remove: &[String],
+    ) -> Result<HeaderRules, String> {
+        Ok(HeaderRules {
+            remove: remove
+                .iter()
+                .map(|name| parse_name(name))
+                .collect::<Result<_, _>>()?,
+            set: set
+                .iter()
+                .map(|header| parse_header(header))
+                .collect::<Result<_, _>>()?,
+            append: append
+                .iter()
+                .map(|header| parse_header(header))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn apply(&self, headers: &mut http::HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name.clone(), value.clone());
+        }
+        for (name, value) in &self.append {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+}
+
+fn parse_name(name: &str) -> Result<HeaderName, String> {
+    let parsed = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("Invalid header name {:?}", name))?;
+    if HOP_BY_HOP_HEADERS.contains(&parsed.as_str()) || FRAMING_HEADERS.contains(&parsed.as_str()) {
+        return Err(format!(
+            "Header {} is managed by balancebeam, so it can't be changed",
+            parsed
+        ));
+    }
+    Ok(parsed)
+}
+
+/// Parses a header written as `Name:Value`.
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid header {:?}: expected Name:Value", header))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("Invalid value for header {:?}", header))?;
+    Ok((parse_name(name)?, value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(strings: &[&str]) -> Vec<String> {
+        strings.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let rules = HeaderRules::parse(
+            &strings(&["X-Env: staging"]),
+            &strings(&["Via:balancebeam-test"]),
+            &strings(&["Server", " x-powered-by "]),
+        )
+        .unwrap();
+        assert_eq!(
+            rules.set,
+            vec![(
+                HeaderName::from_static("x-env"),
+                HeaderValue::from_static("staging")
+            )]
+        );
+        assert_eq!(rules.append[0].1, "balancebeam-test");
+        assert_eq!(rules.remove[1], "x-powered-by");
+
+        for invalid in [
+            "X-Env",
+            "X Env:staging",
+            "X-Env:bad\nvalue",
+            "Content-Length:0",
+        ] {
+            let set = strings(&[invalid]);
+            assert!(HeaderRules::parse(&set, &[], &[]).is_err(), "{}", invalid);
+        }
+        for invalid in ["", "Serv er", "Connection", "Transfer-Encoding"] {
+            let remove = strings(&[invalid]);
+            assert!(
+                HeaderRules::parse(&[], &[], &remove).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let rules = HeaderRules::parse(
+            &strings(&["x-env:staging"]),
+            &strings(&["cache-control:no-transform"]),
+            &strings(&["server"]),
+        )
+        .unwrap();
+        let mut headers = http::HeaderMap::new();
+        headers.insert("server", HeaderValue::from_static("nginx"));
+        headers.insert("x-env", HeaderValue::from_static("production"));
+        headers.insert("cache-control", HeaderValue::from_static("max-age=60"));
+        rules.apply(&mut headers);
+        assert!(!headers.contains_key("server"));
+        assert_eq!(
+            headers.get_all("x-env").iter().collect::<Vec<_>>(),
+            ["staging"]
+        );
+        assert_eq!(
+            headers.get_all("cache-control").iter().collect::<Vec<_>>(),
+            ["max-age=60", "no-transform"]
+        );
+    }
+}
@@ -0,0 +1,32 @@
+/// Quotes `s` as a JSON string.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("127.0.0.1:80"), "\"127.0.0.1:80\"");
+        assert_eq!(
+            quote("a \"quoted\"\\path\n\u{1}"),
+            "\"a \\\"quoted\\\"\\\\path\\n\\u0001\""
+        );
+    }
+}
@@ -1,13 +1,57 @@
+mod access_log;
+mod admin;
+mod backoff;
+mod cache;
+mod canary;
+mod chunked;
+mod circuit_breaker;
+mod connect;
+mod connection_limit;
+mod dns;
+mod header_rules;
+mod json;
+mod maintenance;
+mod metrics;
+mod mirror;
+mod pool;
+mod proxy_protocol;
+mod rate_limit;
 mod request;
 mod response;
+mod route;
+mod strategy;
+mod stream;
 
+use access_log::{AccessLog, Started};
+use backoff::Backoff;
+use cache::Cache;
+use canary::Canary;
+use circuit_breaker::CircuitBreaker;
 use clap::Clap;
+use connect::ConnectPattern;
+use connection_limit::ConnectionLimiter;
+use header_rules::HeaderRules;
+use maintenance::Maintenance;
+use metrics::{Metrics, NO_UPSTREAM};
+use mirror::Mirror;
+use pool::Pool;
+use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
+use rate_limit::{Cidr, RateLimiter};
+use route::{Routes, VirtualHosts};
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
+use strategy::{LoadBalancingStrategy, UpstreamCounters, UpstreamInfo};
+use stream::UpstreamStream;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::stream::StreamExt;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, watch, Mutex, Semaphore};
 use tokio::time::{delay_for, Duration};
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
@@ -18,52 +62,662 @@ struct CmdOptions {
     #[clap(
         short,
         long,
-        about = "IP/port to bind to",
+        about = "IP/port to bind to (repeat to accept connections on more than one)",
         default_value = "0.0.0.0:1100"
     )]
-    bind: String,
-    #[clap(short, long, about = "Upstream host to forward requests to")]
+    bind: Vec<String>,
+    #[clap(
+        long,
+        about = "Start as long as at least one --bind address can be bound, rather than exiting if \
+                 any of them can't"
+    )]
+    bind_lenient: bool,
+    #[clap(
+        long,
+        about = "Expect every client connection to start with a PROXY protocol (v1) header, as sent \
+                 by a load balancer in front of balancebeam, and take the client's address from \
+                 it (connections without one are closed)"
+    )]
+    accept_proxy_protocol: bool,
+    #[clap(
+        long,
+        about = "With --accept-proxy-protocol, take connections without a PROXY protocol header to \
+                 be straight from the client, rather than closing them"
+    )]
+    accept_proxy_protocol_lenient: bool,
+    #[clap(
+        long,
+        about = "Start every connection to an upstream with a PROXY protocol (v1) header giving the \
+                 client's address (connections can't be pooled, since each is for one client)"
+    )]
+    send_proxy_protocol: bool,
+    #[clap(
+        long,
+        about = "Accept HTTPS rather than plain HTTP, with the certificate (chain) in this PEM \
+                 file; needs --tls-key"
+    )]
+    tls_cert: Option<String>,
+    #[clap(long, about = "The private key for --tls-cert, as a PKCS #8 PEM file")]
+    tls_key: Option<String>,
+    #[clap(
+        long,
+        about = "IP/port to serve the admin API on, for listing, adding, removing and draining \
+                 upstreams while running (off unless given; never bind it to a public address)"
+    )]
+    admin_bind: Option<String>,
+    #[clap(
+        long,
+        about = "IP/port to serve Prometheus metrics on, at /metrics (off unless given)"
+    )]
+    metrics_bind: Option<String>,
+    #[clap(
+        long,
+        about = "Write a line about every response sent to a client in this format (json or clf), \
+                 as well as the usual log (off unless given)"
+    )]
+    access_log_format: Option<String>,
+    #[clap(
+        long,
+        about = "Append the access log to this file, rather than writing it to stderr (reopened on \
+                 SIGUSR1, for log rotation)"
+    )]
+    access_log_file: Option<String>,
+    #[clap(
+        short,
+        long,
+        about = "Upstream host to forward requests to, as host:port or host:port=weight (weight 0 = \
+                 backup, only used when every other upstream is dead), optionally followed by \
+                 ;health=/path to override --active-health-check-path for this upstream and \
+                 ;group=name to put it in a group for --route; write \
+                 https://host:port for an upstream that only speaks HTTPS, or unix:/path for one \
+                 listening on a Unix domain socket"
+    )]
     upstream: Vec<String>,
+    #[clap(
+        long,
+        about = "Host header to send unix:/path upstreams with health checks, and with requests \
+                 that don't have one of their own",
+        default_value = "localhost"
+    )]
+    unix_socket_host: String,
+    #[clap(
+        long,
+        about = "Send requests for paths starting with a prefix to a group of upstreams, as \
+                 prefix=group (e.g. /api=api, for upstreams given with ;group=api); the longest \
+                 matching prefix applies, and other requests go to upstreams without a group"
+    )]
+    route: Vec<String>,
+    #[clap(
+        long,
+        about = "When every upstream in a --route's group is dead, send its requests to the \
+                 upstreams without a group, rather than failing them"
+    )]
+    route_fallback: bool,
+    #[clap(
+        long,
+        about = "Send requests for a host to a group of upstreams, as host=group (e.g. \
+                 example.com=web or *.example.com=web); other requests are routed by --route"
+    )]
+    vhost: Vec<String>,
+    #[clap(
+        long,
+        about = "Answer requests for a host that no --vhost matches with 421 Misdirected Request, \
+                 rather than routing them by --route"
+    )]
+    reject_unknown_hosts: bool,
+    #[clap(
+        long,
+        about = "Send a share of the requests for the --canary-stable-group to this group of \
+                 upstreams instead (see --canary-percent)"
+    )]
+    canary_group: Option<String>,
+    #[clap(
+        long,
+        about = "The group of upstreams whose requests are shared with the --canary-group",
+        default_value = "default"
+    )]
+    canary_stable_group: String,
+    #[clap(
+        long,
+        about = "The percentage of the stable group's requests to send to the --canary-group \
+                 (0 to 100, e.g. 5 or 0.5), which the admin API can change [default: 0]"
+    )]
+    canary_percent: Option<String>,
+    #[clap(
+        long,
+        about = "Keep each client's requests on the same side of the canary split, by its IP \
+                 address, rather than splitting them request by request"
+    )]
+    canary_sticky: bool,
+    #[clap(
+        long,
+        about = "Send copies of requests to this shadow upstream as well (host:port, \
+                 https://host:port or unix:/path), throwing away its responses, to try it out \
+                 under real traffic"
+    )]
+    mirror_upstream: Option<String>,
+    #[clap(
+        long,
+        about = "The percentage of requests to copy to the --mirror-upstream (0 to 100)",
+        default_value = "100"
+    )]
+    mirror_percentage: f64,
+    #[clap(
+        long,
+        about = "The biggest request body to copy to the --mirror-upstream, in bytes (bodies \
+                 streamed to the upstream, those over 64 KiB, are never copied)",
+        default_value = "65536"
+    )]
+    mirror_max_body_size: usize,
+    #[clap(
+        long,
+        about = "Start in maintenance mode, answering every request with a 503 until it is turned \
+                 off (with SIGUSR2 or the admin API)"
+    )]
+    maintenance: bool,
+    #[clap(
+        long,
+        about = "IP address blocks (e.g. 10.0.0.0/8,fd00::/8) whose clients are let through in \
+                 maintenance mode"
+    )]
+    maintenance_allow: Vec<String>,
+    #[clap(
+        long,
+        about = "An HTML file to answer requests with in maintenance mode, rather than a plain \
+                 message"
+    )]
+    maintenance_page: Option<String>,
+    #[clap(
+        long,
+        about = "The Retry-After to send with 503s in maintenance mode (in seconds; 0 = none)",
+        default_value = "60"
+    )]
+    maintenance_retry_after: u64,
+    #[clap(
+        long,
+        about = "Cache responses to GETs that Cache-Control says can be (with max-age or s-maxage), \
+                 in up to this many bytes of memory"
+    )]
+    cache_max_bytes: Option<usize>,
+    #[clap(
+        long,
+        about = "The biggest response body to cache, in bytes",
+        default_value = "1048576"
+    )]
+    cache_max_entry_size: usize,
+    #[clap(
+        long,
+        about = "Trust the CA certificate(s) in this PEM file, as well as the system's, to sign the \
+                 certificates of https:// upstreams"
+    )]
+    upstream_ca: Option<String>,
+    #[clap(
+        long,
+        about = "Don't check the certificates of https:// upstreams at all (only for testing: \
+                 anyone in the way can pretend to be the upstream)"
+    )]
+    upstream_insecure: bool,
+    #[clap(
+        long,
+        about = "How often to look up the addresses of upstreams given by hostname again, adding \
+                 and removing upstreams as they change (in seconds; 0 = only at startup)",
+        default_value = "30"
+    )]
+    dns_refresh_interval: u64,
     #[clap(
         long,
         about = "Perform active health checks on this interval (in seconds)",
         default_value = "10"
     )]
     active_health_check_interval: usize,
+    #[clap(
+        long,
+        about = "Randomly lengthen or shorten each active health check interval by up to this \
+                 percentage, so that upstreams aren't all probed in lockstep (0 = no jitter)",
+        default_value = "20"
+    )]
+    active_health_check_jitter: u32,
     #[clap(
         long,
         about = "Path to send request to for active health checks",
         default_value = "/"
     )]
     active_health_check_path: String,
+    #[clap(
+        long,
+        about = "HTTP status codes that pass an active health check, as a comma-separated list of \
+                 codes and ranges (e.g. 200-299,301)",
+        default_value = "200"
+    )]
+    health_check_expect: String,
+    #[clap(
+        long,
+        about = "Number of active health checks in a row a dead upstream must pass before it is \
+                 used again",
+        default_value = "1"
+    )]
+    health_check_recovery_threshold: u32,
     #[clap(
         long,
         about = "Maximum number of requests to accept per IP per minute (0 = unlimited)",
         default_value = "0"
     )]
     max_requests_per_minute: usize,
+    #[clap(
+        long,
+        about = "Maximum number of connections each IP can have open at once (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_connections_per_ip: usize,
+    #[clap(
+        long,
+        about = "Maximum number of connections to handle at once (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_concurrent_connections: usize,
+    #[clap(
+        long,
+        about = "How long to wait for a connection to an upstream before treating it as dead (in \
+                 milliseconds)",
+        default_value = "3000"
+    )]
+    upstream_connect_timeout_ms: u64,
+    #[clap(
+        long,
+        about = "How long to wait for an upstream to respond to a request before replying with a \
+                 504 (in seconds; 0 = forever)",
+        default_value = "30"
+    )]
+    upstream_response_timeout: u64,
+    #[clap(
+        long,
+        about = "How long to wait for a client to send the next request on a connection, including \
+                 all of its headers, before closing the connection (in seconds; 0 = forever)",
+        default_value = "60"
+    )]
+    client_idle_timeout: u64,
+    #[clap(
+        long,
+        about = "The most idle connections to keep open to each upstream once their clients are \
+                 done with them, for other clients to use (0 = close them instead)",
+        default_value = "10"
+    )]
+    max_idle_upstream_connections: usize,
+    #[clap(
+        long,
+        about = "How long to keep an idle connection to an upstream open for (in seconds)",
+        default_value = "30"
+    )]
+    upstream_idle_timeout: u64,
+    #[clap(
+        long,
+        about = "The most bytes a request's (or response's) first line and headers may take up",
+        default_value = "16384"
+    )]
+    max_header_bytes: usize,
+    #[clap(
+        long,
+        about = "The most headers a request (or response) may have",
+        default_value = "100"
+    )]
+    max_headers: usize,
+    #[clap(
+        long,
+        about = "The biggest request body to accept, in bytes (0 = no limit)",
+        default_value = "10000000"
+    )]
+    max_request_body_size: usize,
+    #[clap(
+        long,
+        about = "The biggest response body to pass on from an upstream, in bytes; bigger ones are \
+                 cut off and count as a failure of the upstream (0 = no limit)",
+        default_value = "0"
+    )]
+    max_response_body_size: usize,
+    #[clap(
+        long,
+        about = "How long to let open connections finish their requests after SIGTERM or SIGINT \
+                 before exiting anyway (in seconds; 0 = as long as they take)",
+        default_value = "30"
+    )]
+    shutdown_grace_period: u64,
+    #[clap(
+        long,
+        about = "How often to log a summary of each upstream's requests (in seconds; 0 = never)",
+        default_value = "60"
+    )]
+    upstream_stats_interval: u64,
+    #[clap(
+        long,
+        about = "How many other upstreams to try a request on if forwarding it fails, before \
+                 replying with a 502",
+        default_value = "2"
+    )]
+    upstream_retries: usize,
+    #[clap(
+        long,
+        about = "Longest to wait before trying an upstream again after it fails, or before trying \
+                 any of them again once they are all down (in seconds; 0 = no waiting)",
+        default_value = "30"
+    )]
+    max_upstream_backoff: u64,
+    #[clap(
+        long,
+        about = "Number of failed connections or requests within --circuit-breaker-window that \
+                 stop an upstream being sent requests for --circuit-breaker-cooldown (0 = no \
+                 circuit breaker; each failure marks the upstream dead until a health check passes)",
+        default_value = "0"
+    )]
+    circuit_breaker_failures: usize,
+    #[clap(
+        long,
+        about = "How far back failures count towards --circuit-breaker-failures (in seconds)",
+        default_value = "10"
+    )]
+    circuit_breaker_window: u64,
+    #[clap(
+        long,
+        about = "How long an upstream's circuit breaker stays open before a trial request is let \
+                 through to see if it has recovered (in seconds)",
+        default_value = "30"
+    )]
+    circuit_breaker_cooldown: u64,
+    #[clap(
+        long,
+        about = "How long an upstream that has come back from the dead takes to work up from 10% \
+                 to its full share of requests (in seconds; 0 = no slow start)",
+        default_value = "0"
+    )]
+    slow_start_seconds: u64,
+    #[clap(
+        long,
+        about = "Retry requests that aren't idempotent (such as POSTs) too, even though the failed \
+                 upstream may have acted on them already"
+    )]
+    retry_non_idempotent: bool,
+    #[clap(
+        long,
+        about = "Give every request a new X-Request-Id, even if the client sent one"
+    )]
+    request_id_override: bool,
+    #[clap(
+        long,
+        about = "Don't add X-Real-IP, X-Forwarded-Proto and X-Forwarded-Port headers to forwarded \
+                 requests, for when a proxy in front of balancebeam already sets them"
+    )]
+    no_forwarding_headers: bool,
+    #[clap(
+        long,
+        about = "Set this header (Name:Value) on every forwarded request, replacing any value it had"
+    )]
+    set_request_header: Vec<String>,
+    #[clap(
+        long,
+        about = "Add this header (Name:Value) to every forwarded request, keeping any value it had"
+    )]
+    append_request_header: Vec<String>,
+    #[clap(long, about = "Remove this header from every forwarded request")]
+    remove_request_header: Vec<String>,
+    #[clap(
+        long,
+        about = "Set this header (Name:Value) on every response from an upstream, replacing any \
+                 value it had"
+    )]
+    set_response_header: Vec<String>,
+    #[clap(
+        long,
+        about = "Add this header (Name:Value) to every response from an upstream, keeping any \
+                 value it had"
+    )]
+    append_response_header: Vec<String>,
+    #[clap(
+        long,
+        about = "Remove this header (such as Server) from every response from an upstream"
+    )]
+    remove_response_header: Vec<String>,
+    #[clap(
+        long,
+        about = "Which header tells upstreams, and balancebeam behind a trusted proxy, who each \
+                 client is: x-forwarded-for, or rfc7239 (the standard Forwarded header)",
+        default_value = "x-forwarded-for"
+    )]
+    forwarded_header: String,
+    #[clap(
+        long,
+        about = "The name balancebeam adds to the Via header of forwarded requests and responses, \
+                 and refuses requests that already have (as forwarding loops), so each \
+                 balancebeam in a chain needs its own",
+        default_value = "balancebeam"
+    )]
+    via_token: String,
+    #[clap(
+        long,
+        about = "Tunnel CONNECT requests to these destinations (e.g. db.internal:5432,*.internal:443; \
+                 the host can be * or start with *., and the port can be *) rather than passing \
+                 CONNECT requests on to an upstream, refusing those to anywhere else with a 403"
+    )]
+    allow_connect: Vec<String>,
+    #[clap(
+        long,
+        about = "What to do with new connections once --max-concurrent-connections are open: \
+                 wait (leave them queued until another connection closes) or reject (reply with \
+                 a 503)",
+        default_value = "wait"
+    )]
+    when_saturated: String,
+    #[clap(
+        long,
+        about = "How to apply --max-requests-per-minute: sliding-window (no more than the limit in \
+                 any 60 seconds) or token-bucket (each request takes a token from the client's \
+                 bucket, which refills at the limit's rate, allowing short bursts)",
+        default_value = "sliding-window"
+    )]
+    rate_limit_algorithm: String,
+    #[clap(
+        long,
+        about = "How many requests a client's token bucket holds, for --rate-limit-algorithm \
+                 token-bucket (0 = the per-minute limit)",
+        default_value = "0"
+    )]
+    rate_limit_burst: usize,
+    #[clap(
+        long,
+        about = "IP address blocks (e.g. 10.0.0.0/8,fd00::/8) whose clients are never rate limited"
+    )]
+    rate_limit_exempt: Vec<String>,
+    #[clap(
+        long,
+        about = "A different per-minute limit for paths starting with a prefix, as prefix=limit \
+                 (e.g. /api/login=10); the longest matching prefix applies"
+    )]
+    rate_limit_path: Vec<String>,
+    #[clap(
+        long,
+        about = "Add X-RateLimit-Limit and X-RateLimit-Remaining headers to every rate-limited \
+                 response, not just to 429s"
+    )]
+    rate_limit_headers: bool,
+    #[clap(
+        long,
+        about = "Identify clients connecting through these proxies (IP address blocks, e.g. \
+                 10.0.0.0/8) by the --forwarded-header rather than the connection, for rate \
+                 limiting; with no blocks, loopback and private addresses are trusted"
+    )]
+    trust_proxy: Option<Vec<String>>,
+    #[clap(
+        long,
+        about = "How to choose an upstream for each request: random, round-robin, ip-hash (the \
+                 same upstream for every request from a client), p2c (the less busy of two \
+                 random upstreams), or ewma (favoring upstreams that have been responding quickly)",
+        default_value = "random"
+    )]
+    strategy: String,
+    #[clap(
+        long,
+        about = "How much of an upstream's average response time is kept when a new response time \
+                 is recorded, for --strategy ewma (between 0 and 1; higher reacts more slowly)",
+        default_value = "0.8"
+    )]
+    ewma_decay: f64,
+}
+
+/// How often to log how many of the --max-concurrent-connections are in use.
+const CONNECTION_USAGE_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often to check whether the connections open at shutdown have finished.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait for an upstream to answer a request sent with `Expect: 100-continue` before
+/// sending the body anyway, in case the upstream doesn't know about the expectation.
+const EXPECT_CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The proxies trusted by `--trust-proxy` when it isn't given any address blocks.
+const DEFAULT_TRUSTED_PROXIES: &[&str] = &[
+    "127.0.0.0/8",
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::1",
+    "fc00::/7",
+];
+
+/// The header that lists the clients and proxies a request has come through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ForwardedHeader {
+    XForwardedFor,
+    Rfc7239,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
 /// to, what servers have failed, rate limiting counts, etc.)
-///
-/// You should add fields to this struct in later milestones.
 struct ProxyState {
-    /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
+    /// How frequently we check whether upstream servers are alive
     active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
+    /// Up to how many percent each health check interval is randomly lengthened or shortened by
+    active_health_check_jitter: u32,
+    /// Where we should send requests when doing active health checks
     active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Vec<String>,
-    /// Health Status of servers that we are proxying to_string
-    upstream_status: Mutex<Vec<bool>>,
-    /// Request counter per ip_addr
-    rate_limit_counter: Mutex<HashMap<String, usize>>,
+    /// How long to wait for a connection to an upstream, for requests and health checks alike
+    upstream_connect_timeout: Duration,
+    /// Completes the TLS handshake with https:// upstreams
+    upstream_tls: tokio_tls::TlsConnector,
+    /// The Host header for unix:/path upstreams, which have no host:port to put in it
+    unix_socket_host: String,
+    /// Whether client connections start with a PROXY protocol header
+    accept_proxy_protocol: proxy_protocol::Accept,
+    /// Whether to start upstream connections with a PROXY protocol header
+    send_proxy_protocol: bool,
+    /// How long to wait for an upstream's response once a request has been sent, if there is a
+    /// limit
+    upstream_response_timeout: Option<Duration>,
+    /// How long a client can take to send each request's headers, if there is a limit
+    client_idle_timeout: Option<Duration>,
+    /// Completes the TLS handshake with each client, unless clients speak plain HTTP
+    tls_acceptor: Option<tokio_tls::TlsAcceptor>,
+    /// Connections to upstreams that no client is using at the moment
+    upstream_pool: Pool<UpstreamConnection>,
+    /// How big requests' and responses' headers can be
+    header_limits: request::HeaderLimits,
+    /// How big a request's body can be, if there is a limit
+    max_request_body_size: Option<usize>,
+    /// How big the body of an upstream's response can be, if there is a limit
+    max_response_body_size: Option<usize>,
+    /// How many more upstreams a request can be sent to after forwarding it fails
+    upstream_retries: usize,
+    /// Whether requests that aren't idempotent are retried after they may have reached an upstream
+    retry_non_idempotent: bool,
+    /// Whether requests get a new X-Request-Id even if they already have one
+    request_id_override: bool,
+    /// Whether forwarded requests get X-Real-IP, X-Forwarded-Proto and X-Forwarded-Port headers
+    forwarding_headers: bool,
+    /// Changes made to the headers of every forwarded request
+    request_header_rules: HeaderRules,
+    /// Changes made to the headers of every response from an upstream
+    response_header_rules: HeaderRules,
+    /// Status codes that pass an active health check
+    health_check_expect: Vec<RangeInclusive<u16>>,
+    /// How many active health checks in a row a dead upstream must pass to be brought back
+    health_check_recovery_threshold: u32,
+    /// Servers that we are proxying to, whether they are healthy, and how busy they are. Upstreams
+    /// can be added and removed while running, so they are known by their addresses (which are
+    /// unique) rather than their positions.
+    upstreams: Mutex<Vec<UpstreamInfo>>,
+    /// How to set up upstreams added while running
+    upstream_settings: UpstreamSettings,
+    /// Upstreams given by hostname, which are replaced by the upstreams at each of the addresses
+    /// the hostname resolves to
+    hostname_upstreams: Vec<UpstreamSpec>,
+    /// The groups of upstreams that requests are routed to, by label
+    groups: HashMap<String, Group>,
+    /// Which group each request goes to by its host, ahead of its path
+    vhosts: VirtualHosts,
+    /// Whether requests for a host that isn't in `vhosts` are refused, rather than routed by path
+    reject_unknown_hosts: bool,
+    /// Which group each request goes to by its path
+    routes: Routes,
+    /// Whether requests for a group whose upstreams are all dead go to the default group instead
+    route_fallback: bool,
+    /// Where some of the requests routed to one group go instead, if anywhere
+    canary: Option<Canary>,
+    /// Where copies of requests go as well, if anywhere
+    mirror: Option<Mirror>,
+    /// Whether requests are being turned away with a 503
+    maintenance: Maintenance,
+    /// Where responses are kept to answer the same requests with, if anywhere
+    cache: Option<Cache>,
+    /// How much of the old average each upstream's latency keeps when a new response is timed
+    ewma_decay: f64,
+    /// Permits for the connections being handled, if there is a limit on how many can be at once
+    connection_permits: Option<Arc<Semaphore>>,
+    /// How many permits there are in total
+    max_concurrent_connections: usize,
+    /// Whether connections that come in when there are no permits left are refused, rather than
+    /// waiting for one
+    reject_when_saturated: bool,
+    /// Counts each client's open connections
+    connection_limiter: ConnectionLimiter,
+    /// Counts each client's requests against the per-minute limits
+    rate_limiter: RateLimiter,
+    /// Whether responses that weren't refused tell clients how much of their limit is left
+    rate_limit_headers: bool,
+    /// Proxies whose forwarded headers are believed
+    trusted_proxies: Vec<Cidr>,
+    forwarded_header: ForwardedHeader,
+    /// What balancebeam calls itself in Via headers
+    via_token: String,
+    /// Where CONNECT requests may be tunneled to, or nothing if they are passed on to upstreams
+    /// like any other request
+    connect_allowlist: Vec<ConnectPattern>,
+    /// Becomes true once balancebeam starts shutting down
+    shutdown: watch::Receiver<bool>,
+    /// How many connections are being handled
+    open_connections: AtomicUsize,
+    /// How many requests have been read from clients
+    requests_received: AtomicU64,
+    /// How many error responses balancebeam has made itself, rather than forwarding them from an
+    /// upstream (including those refusing requests over the rate limit)
+    error_responses: AtomicU64,
+    /// How many requests have been refused for being over the rate limit
+    rate_limited_requests: AtomicU64,
+    /// What is exported for Prometheus
+    metrics: Metrics,
+    /// Where each response sent to a client is recorded, if anywhere
+    access_log: AccessLog,
+}
+
+impl ProxyState {
+    /// How health checks connect to upstreams: the timeout, the TLS connector, and the PROXY
+    /// protocol header to start with, if any.
+    fn health_check_connector(&self) -> (Duration, &tokio_tls::TlsConnector, Option<&str>) {
+        let proxy_header = self
+            .send_proxy_protocol
+            .then_some(proxy_protocol::UNKNOWN_HEADER);
+        (
+            self.upstream_connect_timeout,
+            &self.upstream_tls,
+            proxy_header,
+        )
+    }
 }
 
 #[tokio::main]
@@ -71,225 +725,2539 @@ async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
+    if options.upstream.is_empty() {
         log::error!("At least one upstream server must be specified using the --upstream option.");
         std::process::exit(1);
     }
-
-    // Start listening for connections
-    let mut listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
+    let upstream_settings = UpstreamSettings {
+        max_backoff: Duration::from_secs(options.max_upstream_backoff),
+        circuit_breaker: CircuitBreaker::new(
+            options.circuit_breaker_failures,
+            Duration::from_secs(options.circuit_breaker_window),
+            Duration::from_secs(options.circuit_breaker_cooldown),
+        ),
+        slow_start: Duration::from_secs(options.slow_start_seconds),
+    };
+    let mut specs: Vec<UpstreamSpec> = Vec::new();
+    for upstream in &options.upstream {
+        match parse_upstream(upstream) {
+            Ok(spec) if specs.iter().any(|other| other.address == spec.address) => {
+                log::error!("Upstream {} is given more than once", spec.address);
+                std::process::exit(1);
+            }
+            Ok(spec) => specs.push(spec),
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    // Upstreams given by IP address are used as they are, while those given by hostname stand for
+    // an upstream at each address the hostname resolves to
+    let specs_groups: Vec<String> = specs.iter().map(|spec| spec.group.clone()).collect();
+    let (hostname_upstreams, address_upstreams): (Vec<_>, Vec<_>) = specs
+        .into_iter()
+        .partition(|spec| dns::is_hostname(&spec.address));
+    let mut upstreams: Vec<UpstreamInfo> = address_upstreams
+        .into_iter()
+        .map(|spec| upstream_settings.new_upstream(spec))
+        .collect();
+    for spec in &hostname_upstreams {
+        match dns::resolve(&spec.address).await {
+            Ok(addresses) => {
+                // They are all health checked before any requests are accepted
+                let addresses: Vec<(String, bool)> = addresses
+                    .into_iter()
+                    .map(|address| (address, true))
+                    .collect();
+                update_resolved(&mut upstreams, &upstream_settings, spec, &addresses);
+            }
+            Err(err) => log::warn!("Could not resolve upstream {}: {}", spec.address, err),
+        }
+    }
+    if strategy::from_name(&options.strategy).is_none() {
+        log::error!(
+            "Unknown strategy {:?}: expected one of {}",
+            options.strategy,
+            strategy::STRATEGY_NAMES.join(", ")
+        );
+        std::process::exit(1);
+    }
+    let routes = match Routes::parse(&options.route) {
+        Ok(routes) => routes,
         Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
+            log::error!("{}", err);
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
-
-    let upstream_num = options.upstream.len();
-    // Handle incoming connections
-    let state = ProxyState {
-        upstream_addresses: options.upstream,
-        active_health_check_interval: options.active_health_check_interval,
+    let vhosts = match VirtualHosts::parse(&options.vhost) {
+        Ok(vhosts) => vhosts,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if options.reject_unknown_hosts && vhosts.is_empty() {
+        log::error!("--reject-unknown-hosts needs --vhost");
+        std::process::exit(1);
+    }
+    // Each group gets a strategy of its own, so that (for instance) round-robin takes turns
+    // within each group
+    let mut group_names: Vec<&str> = specs_groups.iter().map(String::as_str).collect();
+    group_names.push(route::DEFAULT_GROUP);
+    let routed = routes
+        .iter()
+        .map(|route| ("--route", route))
+        .chain(vhosts.iter().map(|vhost| ("--vhost", vhost)));
+    for (option, (from, group)) in routed {
+        if !specs_groups.iter().any(|name| name == group) {
+            log::error!(
+                "{} {}={} goes to a group with no upstreams in it",
+                option,
+                from,
+                group
+            );
+            std::process::exit(1);
+        }
+    }
+    let canary = match &options.canary_group {
+        Some(group) => {
+            let share =
+                match canary::parse_percent(options.canary_percent.as_deref().unwrap_or("0")) {
+                    Ok(share) => share,
+                    Err(err) => {
+                        log::error!("{}", err);
+                        std::process::exit(1);
+                    }
+                };
+            let stable_group = &options.canary_stable_group;
+            if !specs_groups.contains(group) {
+                log::error!("--canary-group {} has no upstreams in it", group);
+                std::process::exit(1);
+            }
+            if stable_group == group || !group_names.contains(&stable_group.as_str()) {
+                log::error!(
+                    "--canary-stable-group {} has to be another group of upstreams",
+                    stable_group
+                );
+                std::process::exit(1);
+            }
+            Some(Canary::new(
+                group.clone(),
+                stable_group.clone(),
+                share,
+                options.canary_sticky,
+            ))
+        }
+        None if options.canary_percent.is_some() || options.canary_sticky => {
+            log::error!("--canary-percent and --canary-sticky need --canary-group");
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    if !(0.0..=100.0).contains(&options.mirror_percentage) {
+        log::error!("--mirror-percentage has to be from 0 to 100");
+        std::process::exit(1);
+    }
+    let mirror = match &options.mirror_upstream {
+        Some(address) => {
+            if let Err(err) = stream::parse_scheme(address) {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+            Some(Mirror::new(
+                address.clone(),
+                options.mirror_percentage,
+                options.mirror_max_body_size,
+            ))
+        }
+        None => None,
+    };
+    let groups: HashMap<String, Group> = group_names
+        .into_iter()
+        .map(|name| {
+            let group = Group {
+                strategy: strategy::from_name(&options.strategy).unwrap(),
+                backoff: parking_lot::Mutex::new(Backoff::new(Duration::from_secs(
+                    options.max_upstream_backoff,
+                ))),
+            };
+            (name.to_string(), group)
+        })
+        .collect();
+
+    let rate_limiter = match RateLimiter::from_name(
+        &options.rate_limit_algorithm,
+        options.max_requests_per_minute,
+        options.rate_limit_burst,
+    ) {
+        Some(rate_limiter) => rate_limiter,
+        None => {
+            log::error!(
+                "Unknown rate limit algorithm {:?}: expected one of {}",
+                options.rate_limit_algorithm,
+                rate_limit::ALGORITHM_NAMES.join(", ")
+            );
+            std::process::exit(1);
+        }
+    };
+    let rate_limit_exempt = match parse_cidrs(&options.rate_limit_exempt) {
+        Ok(cidrs) => cidrs,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut rate_limiter = rate_limiter.with_exempt(rate_limit_exempt);
+    for spec in &options.rate_limit_path {
+        match parse_path_limit(spec) {
+            Ok((prefix, limit)) => rate_limiter = rate_limiter.with_path_limit(prefix, limit),
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let maintenance_allow = match parse_cidrs(&options.maintenance_allow) {
+        Ok(cidrs) => cidrs,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let maintenance_page = match &options.maintenance_page {
+        Some(path) => match std::fs::read(path) {
+            Ok(page) => Some(page),
+            Err(err) => {
+                log::error!("Could not read maintenance page {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let maintenance = Maintenance::new(
+        options.maintenance,
+        maintenance_allow,
+        maintenance_page,
+        options.maintenance_retry_after,
+    );
+    let request_header_rules = HeaderRules::parse(
+        &options.set_request_header,
+        &options.append_request_header,
+        &options.remove_request_header,
+    );
+    let response_header_rules = HeaderRules::parse(
+        &options.set_response_header,
+        &options.append_response_header,
+        &options.remove_response_header,
+    );
+    let (request_header_rules, response_header_rules) =
+        match (request_header_rules, response_header_rules) {
+            (Ok(request_rules), Ok(response_rules)) => (request_rules, response_rules),
+            (Err(err), _) | (_, Err(err)) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        };
+    let trusted_proxies = match options.trust_proxy.as_deref() {
+        None => Vec::new(),
+        Some([]) => DEFAULT_TRUSTED_PROXIES
+            .iter()
+            .map(|cidr| Cidr::parse(cidr).unwrap())
+            .collect(),
+        Some(lists) => match parse_cidrs(lists) {
+            Ok(cidrs) => cidrs,
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        },
+    };
+    let forwarded_header = match options.forwarded_header.as_str() {
+        "x-forwarded-for" => ForwardedHeader::XForwardedFor,
+        "rfc7239" => ForwardedHeader::Rfc7239,
+        other => {
+            log::error!(
+                "Unknown --forwarded-header {:?}: expected x-forwarded-for or rfc7239",
+                other
+            );
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = request::check_via_token(&options.via_token) {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
+    let connect_allowlist = options
+        .allow_connect
+        .iter()
+        .flat_map(|list| list.split(','))
+        .map(ConnectPattern::parse)
+        .collect::<Result<Vec<_>, _>>();
+    let connect_allowlist = match connect_allowlist {
+        Ok(patterns) => patterns,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let reject_when_saturated = match options.when_saturated.as_str() {
+        "wait" => false,
+        "reject" => true,
+        other => {
+            log::error!(
+                "Unknown --when-saturated {:?}: expected wait or reject",
+                other
+            );
+            std::process::exit(1);
+        }
+    };
+    if reject_when_saturated && options.max_concurrent_connections == 0 {
+        log::error!("--when-saturated reject needs --max-concurrent-connections");
+        std::process::exit(1);
+    }
+    let connection_permits = match options.max_concurrent_connections {
+        0 => None,
+        max => Some(Arc::new(Semaphore::new(max))),
+    };
+    if options.rate_limit_burst > 0 && options.rate_limit_algorithm != "token-bucket" {
+        log::error!("--rate-limit-burst needs --rate-limit-algorithm token-bucket");
+        std::process::exit(1);
+    }
+
+    let tls_acceptor = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => match load_tls_acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            log::error!("--tls-cert and --tls-key must be given together");
+            std::process::exit(1);
+        }
+    };
+
+    let upstream_tls =
+        match load_tls_connector(options.upstream_ca.as_deref(), options.upstream_insecure) {
+            Ok(connector) => connector,
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        };
+
+    let access_log = match &options.access_log_format {
+        Some(format) => match AccessLog::new(format, options.access_log_file.as_deref()) {
+            Ok(access_log) => access_log,
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        None if options.access_log_file.is_some() => {
+            log::error!("--access-log-file needs --access-log-format");
+            std::process::exit(1);
+        }
+        None => AccessLog::disabled(),
+    };
+
+    let health_check_expect = match parse_status_codes(&options.health_check_expect) {
+        Ok(codes) => codes,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if options.active_health_check_jitter > 100 {
+        log::error!("--active-health-check-jitter must be a percentage from 0 to 100");
+        std::process::exit(1);
+    }
+    if options.max_header_bytes == 0 || options.max_headers == 0 {
+        log::error!("--max-header-bytes and --max-headers must be at least 1");
+        std::process::exit(1);
+    }
+    if options.upstream_connect_timeout_ms == 0 {
+        log::error!("--upstream-connect-timeout-ms must be at least 1");
+        std::process::exit(1);
+    }
+    let accept_proxy_protocol = match (
+        options.accept_proxy_protocol,
+        options.accept_proxy_protocol_lenient,
+    ) {
+        (false, false) => proxy_protocol::Accept::Off,
+        (true, false) => proxy_protocol::Accept::Required,
+        (true, true) => proxy_protocol::Accept::Optional,
+        (false, true) => {
+            log::error!("--accept-proxy-protocol-lenient needs --accept-proxy-protocol");
+            std::process::exit(1);
+        }
+    };
+    if options.max_idle_upstream_connections > 0 && options.upstream_idle_timeout == 0 {
+        log::error!("--upstream-idle-timeout must be at least 1");
+        std::process::exit(1);
+    }
+    if options.circuit_breaker_failures > 0 && options.circuit_breaker_window == 0 {
+        log::error!("--circuit-breaker-window must be at least 1");
+        std::process::exit(1);
+    }
+    if options.health_check_recovery_threshold == 0 {
+        log::error!("--health-check-recovery-threshold must be at least 1");
+        std::process::exit(1);
+    }
+    if !(0.0..1.0).contains(&options.ewma_decay) {
+        log::error!(
+            "Invalid --ewma-decay {}: expected a number from 0 up to (but not including) 1",
+            options.ewma_decay
+        );
+        std::process::exit(1);
+    }
+
+    // Shutting down gracefully needs to hear about SIGTERM rather than being killed by it
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            log::error!("Could not listen for SIGTERM: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let shutdown_grace_period = match options.shutdown_grace_period {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let (shutdown_sender, shutdown) = watch::channel(false);
+
+    // Start listening for connections
+    let serving = if tls_acceptor.is_some() {
+        "HTTPS"
+    } else {
+        "HTTP"
+    };
+    let mut listeners = Vec::new();
+    for address in &options.bind {
+        match TcpListener::bind(address).await {
+            Ok(listener) => {
+                log::info!("Listening for {} requests on {}", serving, address);
+                listeners.push((listener, address.clone()));
+            }
+            Err(err) if options.bind_lenient => {
+                log::warn!("Could not bind to {}: {}", address, err);
+            }
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", address, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if listeners.is_empty() {
+        log::error!("Could not bind to any of the --bind addresses");
+        std::process::exit(1);
+    }
+    let admin_listener = bind_api(&options.admin_bind, "admin requests").await;
+    let metrics_listener = bind_api(&options.metrics_bind, "metrics requests").await;
+
+    // Handle incoming connections
+    let state = ProxyState {
+        upstreams: Mutex::new(upstreams),
+        groups,
+        vhosts,
+        reject_unknown_hosts: options.reject_unknown_hosts,
+        routes,
+        route_fallback: options.route_fallback,
+        canary,
+        mirror,
+        maintenance,
+        cache: options
+            .cache_max_bytes
+            .map(|max_bytes| Cache::new(max_bytes, options.cache_max_entry_size)),
+        ewma_decay: options.ewma_decay,
+        active_health_check_interval: options.active_health_check_interval,
+        active_health_check_jitter: options.active_health_check_jitter,
         active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
-        upstream_status: Mutex::new(vec![true; upstream_num]),
-        rate_limit_counter: Mutex::new(HashMap::new()),
+        upstream_settings,
+        hostname_upstreams,
+        health_check_expect,
+        upstream_connect_timeout: Duration::from_millis(options.upstream_connect_timeout_ms),
+        upstream_tls,
+        unix_socket_host: options.unix_socket_host,
+        accept_proxy_protocol,
+        send_proxy_protocol: options.send_proxy_protocol,
+        client_idle_timeout: match options.client_idle_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        tls_acceptor,
+        // A connection that starts with one client's PROXY protocol header can't be used for
+        // anyone else's requests
+        upstream_pool: Pool::new(
+            if options.send_proxy_protocol {
+                0
+            } else {
+                options.max_idle_upstream_connections
+            },
+            Duration::from_secs(options.upstream_idle_timeout),
+        ),
+        header_limits: request::HeaderLimits {
+            max_bytes: options.max_header_bytes,
+            max_headers: options.max_headers,
+        },
+        max_request_body_size: match options.max_request_body_size {
+            0 => None,
+            size => Some(size),
+        },
+        max_response_body_size: match options.max_response_body_size {
+            0 => None,
+            size => Some(size),
+        },
+        upstream_response_timeout: match options.upstream_response_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        upstream_retries: options.upstream_retries,
+        retry_non_idempotent: options.retry_non_idempotent,
+        request_id_override: options.request_id_override,
+        forwarding_headers: !options.no_forwarding_headers,
+        request_header_rules,
+        response_header_rules,
+        health_check_recovery_threshold: options.health_check_recovery_threshold,
+        connection_permits,
+        max_concurrent_connections: options.max_concurrent_connections,
+        reject_when_saturated,
+        connection_limiter: ConnectionLimiter::new(options.max_connections_per_ip),
+        rate_limiter,
+        rate_limit_headers: options.rate_limit_headers,
+        trusted_proxies,
+        forwarded_header,
+        via_token: options.via_token,
+        connect_allowlist,
+        shutdown,
+        open_connections: AtomicUsize::new(0),
+        requests_received: AtomicU64::new(0),
+        error_responses: AtomicU64::new(0),
+        rate_limited_requests: AtomicU64::new(0),
+        metrics: Metrics::new(),
+        access_log,
     };
 
     let shared_state = Arc::new(state);
 
+    // Find out which upstreams are down before sending them any clients
+    check_all_upstreams(&shared_state).await;
+
     let shared_state_clone = shared_state.clone();
     tokio::spawn(async move {
         active_health_check(shared_state_clone).await;
     });
 
-    if shared_state.max_requests_per_minute > 0 {
+    for (listener, api) in [
+        (admin_listener, admin::Api::Admin),
+        (metrics_listener, admin::Api::Metrics),
+    ] {
+        if let Some(listener) = listener {
+            let shared_state_clone = shared_state.clone();
+            tokio::spawn(async move {
+                admin::serve(listener, shared_state_clone, api).await;
+            });
+        }
+    }
+
+    if !shared_state.hostname_upstreams.is_empty() && options.dns_refresh_interval > 0 {
+        let shared_state_clone = shared_state.clone();
+        let interval = Duration::from_secs(options.dns_refresh_interval);
+        tokio::spawn(async move {
+            refresh_dns(shared_state_clone, interval).await;
+        });
+    }
+    if shared_state.rate_limiter.is_enabled() {
+        let shared_state_clone = shared_state.clone();
+        tokio::spawn(async move {
+            remove_expired_rate_limits(shared_state_clone).await;
+        });
+    }
+    if shared_state.upstream_pool.is_enabled() {
         let shared_state_clone = shared_state.clone();
         tokio::spawn(async move {
-            refresh_rate_limit_counter(shared_state_clone).await;
+            remove_idle_upstream_connections(shared_state_clone).await;
         });
     }
+    let shared_state_clone = shared_state.clone();
+    tokio::spawn(async move {
+        maintenance::toggle_on_signal(shared_state_clone).await;
+    });
+    if options.upstream_stats_interval > 0 {
+        let shared_state_clone = shared_state.clone();
+        let interval = Duration::from_secs(options.upstream_stats_interval);
+        tokio::spawn(async move {
+            log_upstream_stats(shared_state_clone, interval).await;
+        });
+    }
+    if shared_state.connection_permits.is_some() {
+        let shared_state_clone = shared_state.clone();
+        tokio::spawn(async move {
+            log_connection_usage(shared_state_clone).await;
+        });
+    }
+    // Each listener is served by its own task. Each holds a sender for `stopped` until it stops
+    // accepting connections, so the channel closes once none of them are left.
+    let (stopped_sender, mut stopped) = mpsc::channel::<()>(1);
+    for (listener, address) in listeners {
+        let shared_state_clone = shared_state.clone();
+        let stopped_sender = stopped_sender.clone();
+        tokio::spawn(async move {
+            accept_connections(listener, address, shared_state_clone).await;
+            drop(stopped_sender);
+        });
+    }
+    drop(stopped_sender);
+    tokio::select! {
+        _ = shutdown_signal(&mut terminate) => {}
+        _ = stopped.recv() => log::error!("No listeners are accepting connections: shutting down"),
+    }
+
+    // Stop accepting connections on every listener, and tell the open connections to close once
+    // they have finished the requests they are handling
+    let _ = shutdown_sender.broadcast(true);
+    stopped.recv().await;
+    drain_connections(&shared_state, shutdown_grace_period).await;
+}
+
+/// Accepts connections on `listener` (bound to `address`) and spawns a task to handle each one,
+/// until balancebeam starts shutting down or accepting fails.
+async fn accept_connections(mut listener: TcpListener, address: String, state: Arc<ProxyState>) {
+    let mut shutdown = state.shutdown.clone();
     let mut incoming = listener.incoming();
-    while let Some(stream) = incoming.next().await {
+    loop {
+        let stream = tokio::select! {
+            stream = incoming.next() => match stream {
+                Some(stream) => stream,
+                None => break,
+            },
+            _ = shutdown_started(&mut shutdown) => break,
+        };
         match stream {
             Ok(stream) => {
+                // Hold a permit for as long as the connection is being handled, so that a spike
+                // in traffic can't spawn tasks without bound. Waiting for one stops new
+                // connections being accepted (they queue up in the listen backlog instead).
+                let permit = match &state.connection_permits {
+                    None => None,
+                    Some(permits) if state.reject_when_saturated => {
+                        match permits.clone().try_acquire_owned() {
+                            Ok(permit) => Some(permit),
+                            Err(_) => {
+                                let state = state.clone();
+                                tokio::spawn(async move {
+                                    accept_connection(stream, state, true).await;
+                                });
+                                continue;
+                            }
+                        }
+                    }
+                    Some(permits) => Some(permits.clone().acquire_owned().await),
+                };
                 // Handle connection
-                let shared_state_clone = shared_state.clone();
+                let open_connection = OpenConnection::new(state.clone());
+                let state = state.clone();
                 tokio::spawn(async move {
-                    handle_connection(stream, shared_state_clone).await;
+                    accept_connection(stream, state, false).await;
+                    drop(permit);
+                    drop(open_connection);
                 });
             }
-            Err(_) => {
-                break;
+            Err(err) => {
+                log::error!("Could not accept a connection on {}: {}", address, err);
+                break;
+            }
+        }
+    }
+
+    log::info!("Stopped accepting connections on {}", address);
+}
+
+/// Reads the certificate and private key for --tls-cert and --tls-key.
+fn load_tls_acceptor(cert: &str, key: &str) -> Result<tokio_tls::TlsAcceptor, String> {
+    let read =
+        |path: &str| std::fs::read(path).map_err(|err| format!("Could not read {}: {}", path, err));
+    let identity = native_tls::Identity::from_pkcs8(&read(cert)?, &read(key)?)
+        .map_err(|err| format!("Invalid --tls-cert or --tls-key: {}", err))?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)
+        .map_err(|err| format!("Could not set up TLS: {}", err))?;
+    Ok(acceptor.into())
+}
+
+/// Sets up the TLS handshakes with https:// upstreams, which trust the CA certificates in the `ca`
+/// file as well as the system's, or don't check certificates at all if `insecure`.
+fn load_tls_connector(ca: Option<&str>, insecure: bool) -> Result<tokio_tls::TlsConnector, String> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(ca) = ca {
+        let pem = std::fs::read(ca).map_err(|err| format!("Could not read {}: {}", ca, err))?;
+        let cert = native_tls::Certificate::from_pem(&pem)
+            .map_err(|err| format!("Invalid --upstream-ca: {}", err))?;
+        builder.add_root_certificate(cert);
+    }
+    if insecure {
+        log::warn!("Not checking the certificates of https:// upstreams (--upstream-insecure)");
+        builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    let connector = builder
+        .build()
+        .map_err(|err| format!("Could not set up TLS: {}", err))?;
+    Ok(connector.into())
+}
+
+/// Waits for SIGTERM or SIGINT (Ctrl-C).
+async fn shutdown_signal(terminate: &mut Signal) {
+    tokio::select! {
+        _ = terminate.recv() => log::info!("Received SIGTERM: shutting down"),
+        _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT: shutting down"),
+    }
+}
+
+/// Returns once balancebeam has started shutting down (straight away if it already has).
+async fn shutdown_started(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.recv().await.is_none() {
+            return;
+        }
+    }
+}
+
+/// Binds the listener for an API that is served apart from the proxy, if it has an address,
+/// exiting if that fails.
+async fn bind_api(address: &Option<String>, serving: &str) -> Option<TcpListener> {
+    let address = address.as_ref()?;
+    match TcpListener::bind(address).await {
+        Ok(listener) => {
+            log::info!("Listening for {} on {}", serving, address);
+            Some(listener)
+        }
+        Err(err) => {
+            log::error!("Could not bind to {}: {}", address, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Counts a connection in ProxyState::open_connections until it is dropped, however the
+/// connection's task ends.
+struct OpenConnection(Arc<ProxyState>);
+
+impl OpenConnection {
+    fn new(state: Arc<ProxyState>) -> OpenConnection {
+        state.open_connections.fetch_add(1, Ordering::SeqCst);
+        OpenConnection(state)
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Waits for the open connections to finish, or for `grace_period` to run out (if there is one),
+/// logging how many are left whenever that changes.
+async fn drain_connections(state: &ProxyState, grace_period: Option<Duration>) {
+    let deadline = grace_period.map(|grace_period| Instant::now() + grace_period);
+    let mut last_logged = 0;
+    loop {
+        let open = state.open_connections.load(Ordering::SeqCst);
+        if open == 0 {
+            log::info!("All connections drained");
+            return;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            log::warn!(
+                "Shutdown grace period is over: closing {} connections",
+                open
+            );
+            return;
+        }
+        if open != last_logged {
+            log::info!("Draining {} connections", open);
+            last_logged = open;
+        }
+        delay_for(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// The settings shared by every upstream.
+struct UpstreamSettings {
+    max_backoff: Duration,
+    circuit_breaker: CircuitBreaker,
+    slow_start: Duration,
+}
+
+impl UpstreamSettings {
+    fn new_upstream(&self, spec: UpstreamSpec) -> UpstreamInfo {
+        UpstreamInfo::new(spec.address, spec.weight)
+            .with_health_check_path(spec.health_check_path)
+            .with_max_backoff(self.max_backoff)
+            .with_circuit_breaker(self.circuit_breaker.clone())
+            .with_slow_start(self.slow_start)
+            .with_group(spec.group)
+    }
+}
+
+/// An upstream as given to --upstream.
+#[derive(Clone, Debug, PartialEq)]
+struct UpstreamSpec {
+    address: String,
+    weight: u32,
+    /// Where to send this upstream's active health checks, if not --active-health-check-path
+    health_check_path: Option<String>,
+    /// The group it is in (route::DEFAULT_GROUP unless it has a group label)
+    group: String,
+}
+
+/// The upstreams with the same group label, which requests are routed to as if they were a
+/// cluster of their own.
+struct Group {
+    /// Chooses which of the group's upstreams each request goes to
+    strategy: Box<dyn LoadBalancingStrategy + Send + Sync>,
+    /// Until when the group's requests fail straight away, without trying any upstreams, once they
+    /// have all been found dead
+    backoff: parking_lot::Mutex<Backoff>,
+}
+
+/// Brings the upstreams looked up for the hostname upstream `spec` up to date with the addresses
+/// it now resolves to, each with whether it is healthy. The upstreams at addresses that have gone
+/// are removed and ones at new addresses are added, while those at addresses it still resolves to
+/// are left as they are. An address that another upstream already has isn't added again.
+fn update_resolved(
+    upstreams: &mut Vec<UpstreamInfo>,
+    settings: &UpstreamSettings,
+    spec: &UpstreamSpec,
+    addresses: &[(String, bool)],
+) {
+    upstreams.retain(|upstream| {
+        let gone = upstream.resolved_from.as_ref() == Some(&spec.address)
+            && !addresses
+                .iter()
+                .any(|(address, _)| *address == upstream.address);
+        if gone {
+            log::info!(
+                "Removed upstream {}, which {} no longer resolves to",
+                upstream.address,
+                spec.address
+            );
+        }
+        !gone
+    });
+    for (address, healthy) in addresses {
+        if upstreams
+            .iter()
+            .any(|upstream| upstream.address == *address)
+        {
+            continue;
+        }
+        log::info!(
+            "Added upstream {}, which {} resolves to ({})",
+            address,
+            spec.address,
+            if *healthy { "healthy" } else { "dead" }
+        );
+        let resolved = UpstreamSpec {
+            address: address.clone(),
+            ..spec.clone()
+        };
+        let mut upstream = settings
+            .new_upstream(resolved)
+            .with_resolved_from(spec.address.clone());
+        upstream.set_healthy(*healthy);
+        upstreams.push(upstream);
+    }
+}
+
+/// Parses an --upstream value: `host:port` (or `https://host:port`, or `unix:/path`), then
+/// optionally `=weight` (which defaults to 1), then any number of `;key=value` settings:
+/// `health=/path` and `group=name`.
+fn parse_upstream(upstream: &str) -> Result<UpstreamSpec, String> {
+    let mut settings = upstream.split(';');
+    // split always yields at least one piece
+    let address = settings.next().unwrap();
+    let mut health_check_path = None;
+    let mut group = None;
+    for setting in settings {
+        match setting.split_once('=') {
+            Some(("group", label)) if group.is_none() => {
+                route::parse_group(label)?;
+                group = Some(label.to_string());
+            }
+            Some(("group", _)) => {
+                return Err(format!("Upstream {:?} has more than one group", upstream))
+            }
+            Some(("health", path)) if path.starts_with('/') && health_check_path.is_none() => {
+                health_check_path = Some(path.to_string())
+            }
+            Some(("health", path)) if !path.starts_with('/') => {
+                return Err(format!(
+                    "Invalid health check path {:?} for upstream {:?}: it must start with /",
+                    path, upstream
+                ))
+            }
+            Some(("health", _)) => {
+                return Err(format!(
+                    "Upstream {:?} has more than one health check path",
+                    upstream
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown setting {:?} for upstream {:?}: expected health=/path or group=name",
+                    setting, upstream
+                ))
+            }
+        }
+    }
+    let (address, weight) = match address.split_once('=') {
+        Some((address, weight)) => {
+            let weight = weight.parse::<u32>().map_err(|_| {
+                format!(
+                    "Invalid weight {:?} for upstream {:?}: expected host:port=weight, where weight \
+                     is a non-negative integer",
+                    weight, upstream
+                )
+            })?;
+            (address, weight)
+        }
+        None => (address, 1),
+    };
+    if address.is_empty() {
+        return Err(format!("Upstream {:?} is missing an address", upstream));
+    }
+    stream::parse_scheme(address)?;
+    Ok(UpstreamSpec {
+        address: address.to_string(),
+        weight,
+        health_check_path,
+        group: group.unwrap_or_else(|| route::DEFAULT_GROUP.to_string()),
+    })
+}
+
+/// Parses a --health-check-expect value: a comma-separated list of status codes (`301`) and
+/// inclusive ranges of them (`200-299`).
+fn parse_status_codes(spec: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+    spec.split(',')
+        .map(|item| {
+            let item = item.trim();
+            let (low, high) = item.split_once('-').unwrap_or((item, item));
+            let parse = |code: &str| {
+                code.trim()
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|code| (100..=599).contains(code))
+            };
+            match (parse(low), parse(high)) {
+                (Some(low), Some(high)) if low <= high => Ok(low..=high),
+                _ => Err(format!(
+                    "Invalid --health-check-expect {:?}: {:?} is not a status code (100-599) or a \
+                     range of them (such as 200-299)",
+                    spec, item
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Parses the address blocks given to a flag, each of which may be a comma-separated list.
+fn parse_cidrs(lists: &[String]) -> Result<Vec<Cidr>, String> {
+    lists
+        .iter()
+        .flat_map(|list| list.split(','))
+        .map(Cidr::parse)
+        .collect()
+}
+
+/// Works out which client sent `request` over a connection from `peer`. If the peer is a trusted
+/// proxy, that is the address it says the request came from in the `header`, or if that is
+/// a trusted proxy too, the one before it, and so on. The header is read from the right because
+/// each proxy appends the address it received the request from, while a client can put anything
+/// at the left. If every address is trusted, or the next one along isn't an IP address, the last
+/// trusted one is used.
+fn client_identity(
+    peer: IpAddr,
+    request: &http::Request<Vec<u8>>,
+    trusted: &[Cidr],
+    header: ForwardedHeader,
+) -> IpAddr {
+    let is_trusted = |address: IpAddr| trusted.iter().any(|cidr| cidr.contains(address));
+    let mut client = peer;
+    if !is_trusted(peer) {
+        return client;
+    }
+    let forwarded: Vec<Option<IpAddr>> = match header {
+        ForwardedHeader::XForwardedFor => request
+            .headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|hop| hop.trim().parse().ok())
+            .collect(),
+        ForwardedHeader::Rfc7239 => request::forwarded_for(request.headers())
+            .iter()
+            .map(|node| request::parse_forwarded_node(node))
+            .collect(),
+    };
+    for hop in forwarded.iter().rev() {
+        match hop {
+            Some(address) => client = *address,
+            None => break,
+        }
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Tells the upstream who `client` is, and how it reached balancebeam: over `proto` (http or
+/// https) to `port`. X-Real-IP is replaced rather than extended, since a client could have set it
+/// to anything.
+fn add_forwarding_headers(
+    request: &mut http::Request<Vec<u8>>,
+    client: IpAddr,
+    proto: &str,
+    port: u16,
+) {
+    request.headers_mut().insert(
+        "x-real-ip",
+        http::HeaderValue::from_str(&client.to_string()).unwrap(),
+    );
+    request::extend_header_value(request, "x-forwarded-proto", proto);
+    request::extend_header_value(request, "x-forwarded-port", &port.to_string());
+}
+
+/// Parses a `--rate-limit-path` setting, prefix=limit, into the prefix and the limit.
+fn parse_path_limit(spec: &str) -> Result<(String, usize), String> {
+    match spec.rsplit_once('=') {
+        Some((prefix, limit)) if prefix.starts_with('/') => match limit.parse() {
+            Ok(limit) => Ok((prefix.to_string(), limit)),
+            Err(_) => Err(format!(
+                "Invalid --rate-limit-path {:?}: {:?} is not a number of requests",
+                spec, limit
+            )),
+        },
+        _ => Err(format!(
+            "Invalid --rate-limit-path {:?}: expected a path prefix starting with / and a limit, \
+             such as /api/login=10",
+            spec
+        )),
+    }
+}
+
+/// Connects to the upstream in `group` that the group's load balancing strategy chooses for
+/// `client_ip`, other than the ones in `tried`, returning the connection. Upstreams that can't be
+/// connected to are taken out of use (see record_upstream_failure), and another one is tried.
+///
+/// Once every upstream in the group is dead, each is tried again as soon as it has been left alone
+/// for long enough, rather than only when an active health check brings it back. If none of them
+/// can be tried, the whole group is down, and requests fail straight away until the group's backoff
+/// runs out or a health check finds an upstream working. With --route-fallback, they go to the
+/// default group instead.
+async fn connect_to_upstream(
+    state: &ProxyState,
+    (client_ip, group): (IpAddr, &str),
+    tried: &[String],
+    proxy_header: Option<&str>,
+) -> Result<UpstreamConnection, std::io::Error> {
+    let mut group = group;
+    loop {
+        let (upstream_ip, name, counters) = {
+            let mut upstreams = state.upstreams.lock().await;
+            // The strategy is only shown the group's upstreams, with the ones already tried shown
+            // as dead, even if a circuit breaker has kept them in use
+            let members: Vec<usize> = (0..upstreams.len())
+                .filter(|&idx| upstreams[idx].group == group)
+                .collect();
+            let masked: Vec<UpstreamInfo>;
+            let choices: &[UpstreamInfo] = if tried.is_empty() && members.len() == upstreams.len() {
+                &upstreams
+            } else {
+                masked = members
+                    .iter()
+                    .map(|&idx| {
+                        let mut upstream = upstreams[idx].clone();
+                        upstream.healthy &= !tried.contains(&upstream.address);
+                        upstream
+                    })
+                    .collect();
+                &masked
+            };
+            let choice = match state.groups[group].strategy.pick(choices, client_ip) {
+                Some(choice) => Some(choice),
+                None => retry_dead_upstream(state, group, choices, tried),
+            };
+            let upstream_idx = match choice {
+                Some(choice) => members[choice],
+                None if state.route_fallback && group != route::DEFAULT_GROUP => {
+                    log::info!(
+                        "No upstream in group {} can be used: falling back to group {}",
+                        group,
+                        route::DEFAULT_GROUP
+                    );
+                    group = route::DEFAULT_GROUP;
+                    continue;
+                }
+                None => return Err(std::io::Error::other("All servers are dead")),
+            };
+            let upstream = &mut upstreams[upstream_idx];
+            if upstream.circuit_breaker.start_request_at(Instant::now()) {
+                log::info!(
+                    "Circuit breaker for upstream {} is half-open: sending it a trial request",
+                    upstream.address
+                );
+            }
+            let name = upstream.name().to_string();
+            (upstream.address.clone(), name, upstream.counters.clone())
+        };
+        // An idle connection to the upstream saves opening a new one, as long as the upstream
+        // hasn't closed it in the meantime
+        while let Some(mut connection) = state
+            .upstream_pool
+            .check_out_at(&upstream_ip, Instant::now())
+        {
+            if response::is_reusable(&mut connection.stream).await {
+                let reuse_rate = state.upstream_pool.record_use(true);
+                log::debug!(
+                    "Reusing an idle connection to upstream {} ({:.1}% of upstream connections \
+                     have been reused)",
+                    upstream_ip,
+                    reuse_rate
+                );
+                connection.counters = counters;
+                return Ok(connection);
+            }
+        }
+        let timeout = state.upstream_connect_timeout;
+        let connect = stream::connect(
+            &upstream_ip,
+            &name,
+            timeout,
+            &state.upstream_tls,
+            proxy_header,
+        );
+        match connect.await {
+            Ok(stream) => {
+                if state.upstream_pool.is_enabled() {
+                    let reuse_rate = state.upstream_pool.record_use(false);
+                    log::debug!(
+                        "Opened a new connection to upstream {} ({:.1}% of upstream connections \
+                         have been reused)",
+                        upstream_ip,
+                        reuse_rate
+                    );
+                }
+                return Ok(UpstreamConnection {
+                    stream,
+                    address: upstream_ip,
+                    name,
+                    group: group.to_string(),
+                    counters,
+                    responses: 0,
+                });
+            }
+            Err(error) => {
+                log::info!("Failed to connect to upstream {}: {}", upstream_ip, error);
+                state.metrics.record_connect_failure(&upstream_ip);
+                counters.record_connect_failure();
+                if let Some(upstream) =
+                    find_upstream(&mut state.upstreams.lock().await, &upstream_ip)
+                {
+                    record_upstream_failure(upstream);
+                }
+            }
+        }
+    }
+}
+
+/// Finds the upstream at `address`, unless it has been removed.
+fn find_upstream<'a>(
+    upstreams: &'a mut [UpstreamInfo],
+    address: &str,
+) -> Option<&'a mut UpstreamInfo> {
+    upstreams
+        .iter_mut()
+        .find(|upstream| upstream.address == address)
+}
+
+/// Takes an upstream that couldn't be connected to, or couldn't handle a request, out of use:
+/// until an active health check finds it working again, or with a circuit breaker, once it has
+/// failed often enough to open the breaker. Either way, if every upstream ends up out of use, it is
+/// left alone for a while before requests try it again.
+fn record_upstream_failure(upstream: &mut UpstreamInfo) {
+    let now = Instant::now();
+    if upstream.circuit_breaker.is_enabled() {
+        if upstream.circuit_breaker.record_failure_at(now) {
+            log::info!(
+                "Circuit breaker for upstream {} is open: not sending it requests for {:?}",
+                upstream.address,
+                upstream.circuit_breaker.cooldown()
+            );
+        }
+    } else {
+        if upstream.healthy {
+            log::info!("Upstream {} is dead", upstream.address);
+        }
+        upstream.set_healthy(false);
+    }
+    upstream.reconnect_backoff.fail_at(now);
+}
+
+/// Chooses a dead upstream in `group` (whose upstreams are `upstreams`) to try again when there are
+/// no healthy ones, other than the ones the request has been `tried` on already, or returns None
+/// (having started the group's backoff if it hasn't already) if they all need to be left alone.
+/// Once the request has been tried on every upstream, None is returned without starting the
+/// backoff: the ones it failed on have been taken out of use already.
+fn retry_dead_upstream(
+    state: &ProxyState,
+    group: &str,
+    upstreams: &[UpstreamInfo],
+    tried: &[String],
+) -> Option<usize> {
+    let now = Instant::now();
+    let mut cluster_backoff = state.groups[group].backoff.lock();
+    if cluster_backoff.is_waiting_at(now) {
+        return None;
+    }
+    let untried = |upstream: &UpstreamInfo| !tried.contains(&upstream.address);
+    let upstream_idx = upstreams.iter().position(|upstream| {
+        untried(upstream)
+            && !upstream.draining
+            && !upstream.reconnect_backoff.is_waiting_at(now)
+            && upstream.circuit_breaker.allows_request_at(now)
+    });
+    if upstream_idx.is_none() && upstreams.iter().any(untried) {
+        let delay = cluster_backoff.fail_at(now);
+        log::warn!(
+            "All upstreams in group {} are down: failing its requests without trying them for {:?}",
+            group,
+            delay
+        );
+    }
+    upstream_idx
+}
+
+/// Connects to `address`, giving up after `timeout`. A host that has gone away can leave a
+/// connection attempt hanging for minutes otherwise.
+async fn connect_with_timeout(address: &str, timeout: Duration) -> std::io::Result<TcpStream> {
+    match tokio::time::timeout(timeout, TcpStream::connect(address)).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "connection timed out",
+        )),
+    }
+}
+
+/// Sends an error response that balancebeam made itself, counting it in
+/// ProxyState::error_responses and writing it to the access log along with when the request
+/// started, who sent it, what it was (if it could be read), and the group it was routed to (if it
+/// got that far).
+async fn send_error_response(
+    client_conn: &mut (impl AsyncWrite + Unpin),
+    state: &ProxyState,
+    (started, client, request): (Started, IpAddr, Option<&http::Request<Vec<u8>>>),
+    group: Option<&str>,
+    response: &http::Response<Vec<u8>>,
+) {
+    state.error_responses.fetch_add(1, Ordering::Relaxed);
+    state
+        .metrics
+        .record_response(NO_UPSTREAM, response.status());
+    send_response(client_conn, client, response).await;
+    state
+        .access_log
+        .log(started, client, request, (None, group), sent(response));
+}
+
+/// Answers a request that balancebeam won't forward with `response`, which is given the request's
+/// ID. Returns whether the client connection has to be closed afterwards, which the response then
+/// says: it does if the client asked for that (as HTTP/1.0 clients do unless they ask to be kept
+/// alive), or if any of the request's body is left unread, since that can't be told apart from the
+/// next request.
+async fn refuse_request(
+    client_conn: &mut (impl AsyncWrite + Unpin),
+    state: &ProxyState,
+    (started, client, request): (Started, IpAddr, &http::Request<Vec<u8>>),
+    mut response: http::Response<Vec<u8>>,
+    (client_wants_close, unread_body): (bool, usize),
+) -> bool {
+    let close = client_wants_close || unread_body > 0;
+    let headers = response.headers_mut();
+    headers.insert(
+        request::REQUEST_ID_HEADER,
+        request.headers()[request::REQUEST_ID_HEADER].clone(),
+    );
+    if close {
+        headers.insert("connection", http::HeaderValue::from_static("close"));
+    } else if request.version() < http::Version::HTTP_11 {
+        headers.insert("connection", http::HeaderValue::from_static("keep-alive"));
+    }
+    let request_info = (started, client, Some(request));
+    send_error_response(client_conn, state, request_info, None, &response).await;
+    close
+}
+
+/// The status and body length of a response, as the access log records them.
+fn sent(response: &http::Response<Vec<u8>>) -> (http::StatusCode, usize) {
+    (response.status(), response.body().len())
+}
+
+async fn send_response(
+    client_conn: &mut (impl AsyncWrite + Unpin),
+    client: IpAddr,
+    response: &http::Response<Vec<u8>>,
+) {
+    log::info!(
+        "[{}] {} <- {}",
+        request::request_id(response.headers()).unwrap_or("-"),
+        client,
+        response::format_response_line(response)
+    );
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
+        log::warn!("Failed to send response to client: {}", error);
+    }
+}
+
+/// Handles a connection accepted from a client, first completing the TLS handshake if clients
+/// speak HTTPS, or refusing it with a 503 if there are already `--max-concurrent-connections` open
+/// (and balancebeam is to reject the rest). A client whose handshake fails or takes longer than
+/// `--client-idle-timeout` never gets as far as sending a request, so it isn't counted against
+/// any limits; the connection is just closed. The same goes for a connection that should start with
+/// a PROXY protocol header and doesn't. Otherwise, the addresses in the header stand in for the
+/// connection's own from then on.
+async fn accept_connection(mut stream: TcpStream, state: Arc<ProxyState>, saturated: bool) {
+    let mut addresses = match (stream.peer_addr(), stream.local_addr()) {
+        (Ok(peer), Ok(local)) => (peer, local),
+        _ => return,
+    };
+    if state.accept_proxy_protocol != proxy_protocol::Accept::Off {
+        let header = proxy_protocol::read_header(&mut stream);
+        let header = match state.client_idle_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, header).await,
+            None => Ok(header.await),
+        };
+        let conveyed = match header {
+            Ok(Ok(Some(proxy_protocol::Header::Tcp {
+                source,
+                destination,
+            }))) => Some((source, destination)),
+            Ok(Ok(Some(proxy_protocol::Header::Unknown))) => Some(addresses),
+            Ok(Ok(None)) if state.accept_proxy_protocol == proxy_protocol::Accept::Optional => {
+                Some(addresses)
+            }
+            Ok(Ok(None)) => {
+                log::info!("{} didn't send a PROXY protocol header", addresses.0);
+                None
+            }
+            Ok(Err(error)) => {
+                log::info!(
+                    "Failed to read a PROXY protocol header from {}: {}",
+                    addresses.0,
+                    error
+                );
+                None
+            }
+            Err(_) => {
+                log::debug!("PROXY protocol header from {} timed out", addresses.0);
+                None
+            }
+        };
+        addresses = match conveyed {
+            Some(addresses) => addresses,
+            None => return,
+        };
+    }
+    let acceptor = match &state.tls_acceptor {
+        Some(acceptor) => acceptor.clone(),
+        None if saturated => return refuse_connection(stream, addresses.0.ip(), &state).await,
+        None => return handle_connection(stream, addresses, state).await,
+    };
+    let handshake = acceptor.accept(stream);
+    let handshake = match state.client_idle_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, handshake).await,
+        None => Ok(handshake.await),
+    };
+    match handshake {
+        Ok(Ok(stream)) if saturated => refuse_connection(stream, addresses.0.ip(), &state).await,
+        Ok(Ok(stream)) => handle_connection(stream, addresses, state).await,
+        Ok(Err(error)) => log::debug!("TLS handshake with {} failed: {}", addresses.0, error),
+        Err(_) => log::debug!("TLS handshake with {} timed out", addresses.0),
+    }
+}
+
+/// Tells a client there is no room for its connection.
+async fn refuse_connection(
+    mut client_conn: impl AsyncWrite + Unpin,
+    client: IpAddr,
+    state: &ProxyState,
+) {
+    let response = response::make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+    send_error_response(
+        &mut client_conn,
+        state,
+        (Started::now(), client, None),
+        None,
+        &response,
+    )
+    .await;
+}
+
+/// The group of upstreams `request` goes to: the one a --vhost for its host names, or else the one
+/// its path is routed to, or None if its host is unknown and --reject-unknown-hosts is set. The
+/// host is taken from the request's URI if it has one there, as RFC 7230 says, or else from its
+/// Host header.
+fn route_request<'a>(state: &'a ProxyState, request: &http::Request<Vec<u8>>) -> Option<&'a str> {
+    if !state.vhosts.is_empty() {
+        let host = request.uri().host().or_else(|| {
+            let host = request.headers().get(http::header::HOST)?;
+            host.to_str().ok()
+        });
+        let vhost = host.and_then(|host| state.vhosts.group_for(&route::normalize_host(host)));
+        if vhost.is_some() || state.reject_unknown_hosts {
+            return vhost;
+        }
+    }
+    Some(state.routes.group_for(request.uri().path()))
+}
+
+/// Reads requests from a client's connection (from `peer` to `local`), forwarding each to an
+/// upstream and passing on its response, until the client or balancebeam closes the connection.
+async fn handle_connection(
+    client_conn: impl AsyncRead + AsyncWrite + Unpin,
+    (peer, local): (SocketAddr, SocketAddr),
+    state: Arc<ProxyState>,
+) {
+    // Buffered so that wait_for_request can tell when a request starts without taking any of it
+    // out of the stream
+    let mut client_conn = BufReader::new(client_conn);
+    let client_addr = peer.ip();
+    let client_ip = client_addr.to_string();
+    log::info!("Connection received from {} on {}", client_ip, local);
+
+    // Every open connection holds on to a file descriptor, so one client mustn't be able to use
+    // them all up, however slowly it sends requests. The guard counts this connection until the
+    // function returns.
+    let _connection_guard = match state.connection_limiter.try_acquire(client_addr) {
+        Some(guard) => guard,
+        None => {
+            log::info!("{} has too many connections open", client_ip);
+            let response = response::make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+            let request_info = (Started::now(), client_addr, None);
+            send_error_response(&mut client_conn, &state, request_info, None, &response).await;
+            return;
+        }
+    };
+
+    // The upstream is chosen when the first request comes in, and again whenever forwarding one
+    // fails. Once the client is done, the connection goes back in the pool, if it can be used
+    // again.
+    let mut upstream = HeldUpstream {
+        connection: None,
+        state: &state,
+    };
+    let mut shutdown = state.shutdown.clone();
+    // Sent at the start of every connection to an upstream made for this client
+    let proxy_header = state
+        .send_proxy_protocol
+        .then(|| proxy_protocol::header(peer, local));
+
+    // The client may now send us one or more requests. Keep trying to read requests until the
+    // client hangs up or we get an error.
+    loop {
+        // Wait for the client to start sending a request. A connection that isn't in the middle of
+        // a request is closed straight away when shutting down.
+        let idle_started = Instant::now();
+        if *shutdown.borrow()
+            || !wait_for_request(&mut client_conn, state.client_idle_timeout, &mut shutdown).await
+        {
+            log::debug!("Shutting down: closing connection from {}", client_ip);
+            return;
+        }
+        let started = Started::now();
+        let idle_timeout = state
+            .client_idle_timeout
+            .map(|timeout| timeout.saturating_sub(idle_started.elapsed()));
+
+        // Read a request from the client. A large body is left to be passed on to the upstream as
+        // it arrives.
+        let read = request::read_head_from_stream(
+            &mut client_conn,
+            idle_timeout,
+            state.header_limits,
+            state.max_request_body_size,
+        )
+        .await;
+        let (mut request, mut unread_body) = match read {
+            Ok(read) => {
+                state.requests_received.fetch_add(1, Ordering::Relaxed);
+                read
+            }
+            // Handle case where client closed connection and is no longer sending requests
+            Err(request::Error::IncompleteRequest(0)) => {
+                log::debug!("Client finished sending requests. Shutting down connection");
+                return;
+            }
+            // Handle case where client has gone quiet without closing the connection
+            Err(request::Error::TimedOut(0)) => {
+                log::debug!("Client stopped sending requests. Shutting down connection");
+                return;
+            }
+            // Handle case where client stalled partway through a request
+            Err(request::Error::TimedOut(_)) => {
+                log::debug!("Client took too long to send a request. Shutting down connection");
+                let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                let request_info = (started, client_addr, None);
+                send_error_response(&mut client_conn, &state, request_info, None, &response).await;
+                return;
+            }
+            // Handle I/O error in reading from the client
+            Err(request::Error::ConnectionError(io_err)) => {
+                log::info!("Error reading request from client stream: {}", io_err);
+                return;
+            }
+            // Handle a request whose headers go on for longer than they may. The rest of them are
+            // still to come, so nothing more can be read from the connection either
+            Err(request::Error::HeadersTooLarge) => {
+                log::info!("Refusing request with too many headers from {}", client_ip);
+                let status = http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+                let response = response::make_http_error(status);
+                let request_info = (started, client_addr, None);
+                send_error_response(&mut client_conn, &state, request_info, None, &response).await;
+                return;
+            }
+            // Handle a request whose body is too big. The rest of the body hasn't been read, so it
+            // can't be told apart from another request
+            Err(request::Error::RequestBodyTooLarge) => {
+                log::info!("Refusing request with too large a body from {}", client_ip);
+                let explanation = format!(
+                    "request bodies can be at most {} bytes",
+                    state.max_request_body_size.unwrap()
+                );
+                let response = response::make_http_error_explained(
+                    http::StatusCode::PAYLOAD_TOO_LARGE,
+                    &explanation,
+                );
+                let request_info = (started, client_addr, None);
+                send_error_response(&mut client_conn, &state, request_info, None, &response).await;
+                return;
+            }
+            // Handle a request whose body can't be told apart from whatever follows it, which
+            // means nothing more can be read from the connection either
+            Err(error @ request::Error::InvalidContentLength)
+            | Err(error @ request::Error::AmbiguousBodyLength) => {
+                log::info!(
+                    "Refusing request with an unclear body length from {}: {}",
+                    client_ip,
+                    error
+                );
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                let request_info = (started, client_addr, None);
+                send_error_response(&mut client_conn, &state, request_info, None, &response).await;
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing request: {}", error);
+                let response = response::make_http_error(match error {
+                    request::Error::IncompleteRequest(_)
+                    | request::Error::MalformedRequest(_)
+                    | request::Error::InvalidContentLength
+                    | request::Error::AmbiguousBodyLength
+                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
+                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                    request::Error::HeadersTooLarge => {
+                        http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                    }
+                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+                    request::Error::TimedOut(_) => http::StatusCode::REQUEST_TIMEOUT,
+                });
+                let request_info = (started, client_addr, None);
+                send_error_response(&mut client_conn, &state, request_info, None, &response).await;
+                continue;
+            }
+        };
+
+        // Connection and the other hop-by-hop headers are about the client's connection to
+        // balancebeam, not balancebeam's to the upstream, so they go no further. The exception is
+        // a request to switch protocols (such as to WebSocket), which the upstream has to agree to.
+        let client_version = request.version();
+        let client_wants_close = request::wants_close(client_version, request.headers());
+        let client_upgrade = request::upgrade(request.headers());
+        request::remove_hop_by_hop_headers(request.headers_mut());
+        if let Some(protocol) = client_upgrade.clone() {
+            request::set_upgrade(request.headers_mut(), protocol);
+        }
+
+        // Give the request an ID, which the upstream is sent and the client gets back, and which
+        // every log line about the request includes
+        if state.request_id_override || request::request_id(request.headers()).is_none() {
+            let request_id = http::HeaderValue::from_str(&request::new_request_id()).unwrap();
+            request
+                .headers_mut()
+                .insert(request::REQUEST_ID_HEADER, request_id);
+        }
+        let request_id = request.headers()[request::REQUEST_ID_HEADER].clone();
+
+        // A request that has been through balancebeam already would only keep coming back (say
+        // if an upstream is balancebeam itself), tying up more connections each time
+        if request::via_contains(request.headers(), &state.via_token) {
+            log::warn!(
+                "[{}] Refusing request that has already been through {}: {}",
+                request::request_id(request.headers()).unwrap(),
+                state.via_token,
+                request::format_request_line(&request)
+            );
+            let response = response::make_http_error(http::StatusCode::LOOP_DETECTED);
+            let request_info = (started, client_addr, &request);
+            let connection = (client_wants_close, unread_body);
+            if refuse_request(&mut client_conn, &state, request_info, response, connection).await {
+                return;
+            }
+            continue;
+        }
+
+        // The request has been read, so the client will see the error (rather than a reset
+        // connection) even if it hangs up straight afterwards. Behind a trusted proxy, the client
+        // can only be identified from the request's headers.
+        let client = client_identity(
+            client_addr,
+            &request,
+            &state.trusted_proxies,
+            state.forwarded_header,
+        );
+        if state.maintenance.turns_away(client) {
+            log::info!(
+                "[{}] Turning away {} for maintenance: {}",
+                request::request_id(request.headers()).unwrap(),
+                client,
+                request::format_request_line(&request)
+            );
+            let response = state.maintenance.response();
+            let request_info = (started, client, &request);
+            let connection = (client_wants_close, unread_body);
+            if refuse_request(&mut client_conn, &state, request_info, response, connection).await {
+                return;
+            }
+            continue;
+        }
+        let quota = state.rate_limiter.check(client, request.uri().path());
+        if let Some(quota) = quota.as_ref().filter(|quota| !quota.allowed) {
+            log::info!(
+                "[{}] {} is over the rate limit for {}",
+                request::request_id(request.headers()).unwrap(),
+                client,
+                request.uri().path()
+            );
+            let mut response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+            response.headers_mut().insert(
+                "retry-after",
+                http::HeaderValue::from(quota.retry_after_secs()),
+            );
+            quota.add_headers(&mut response);
+            state.rate_limited_requests.fetch_add(1, Ordering::Relaxed);
+            let request_info = (started, client, &request);
+            let connection = (client_wants_close, unread_body);
+            if refuse_request(&mut client_conn, &state, request_info, response, connection).await {
+                return;
+            }
+            continue;
+        }
+
+        // With --allow-connect, a CONNECT request names the destination it wants a tunnel to, which
+        // balancebeam connects to itself rather than to an upstream
+        if request.method() == http::Method::CONNECT && !state.connect_allowlist.is_empty() {
+            let authority = request
+                .uri()
+                .authority()
+                .map(|authority| authority.as_str());
+            let destination = authority.filter(|authority| {
+                state
+                    .connect_allowlist
+                    .iter()
+                    .any(|pattern| pattern.matches(authority))
+            });
+            if let Some(destination) = destination {
+                let destination = destination.to_string();
+                let request_info = (started, client, &request);
+                connect_tunnel(&mut client_conn, &state, request_info, &destination).await;
+                return;
+            }
+            log::info!(
+                "[{}] Refusing CONNECT to {} from {}",
+                request::request_id(request.headers()).unwrap(),
+                request.uri(),
+                client
+            );
+            let response = response::make_http_error(http::StatusCode::FORBIDDEN);
+            let request_info = (started, client, &request);
+            let connection = (client_wants_close, unread_body);
+            if refuse_request(&mut client_conn, &state, request_info, response, connection).await {
+                return;
+            }
+            continue;
+        }
+
+        // The group of upstreams the request goes to. A request for a host that no --vhost
+        // matches is refused with --reject-unknown-hosts, since another server must have been
+        // meant to get it.
+        let group = match route_request(&state, &request) {
+            Some(group) => group,
+            None => {
+                log::info!(
+                    "[{}] Refusing request for unknown host from {}: {}",
+                    request::request_id(request.headers()).unwrap(),
+                    client,
+                    request::format_request_line(&request)
+                );
+                let response = response::make_http_error(http::StatusCode::MISDIRECTED_REQUEST);
+                let request_info = (started, client, &request);
+                let connection = (client_wants_close, unread_body);
+                if refuse_request(&mut client_conn, &state, request_info, response, connection)
+                    .await
+                {
+                    return;
+                }
+                continue;
+            }
+        };
+        // With --canary-group, some of the stable group's requests go to the canary instead
+        let group = match &state.canary {
+            Some(canary) => canary.group_for(group, client, request.headers()),
+            None => group,
+        };
+        state.metrics.record_group_request(group);
+
+        // With --cache-max-bytes, a GET whose response is cached (and still fresh) is answered
+        // without going to an upstream at all
+        let cache_key = match &state.cache {
+            Some(_) => cache::key(&request, group, unread_body),
+            None => None,
+        };
+        let cached = match (&state.cache, &cache_key) {
+            (Some(cache), Some(key)) => cache.get_at(key, Instant::now()),
+            _ => None,
+        };
+        if let Some(mut response) = cached {
+            state.metrics.record_cache("hit");
+            let headers = response.headers_mut();
+            state.response_header_rules.apply(headers);
+            headers.insert(request::REQUEST_ID_HEADER, request_id);
+            request::add_via(headers, &state.via_token);
+            if client_wants_close || *shutdown.borrow() {
+                headers.insert("connection", http::HeaderValue::from_static("close"));
+            } else if client_version < http::Version::HTTP_11 {
+                headers.insert("connection", http::HeaderValue::from_static("keep-alive"));
+            }
+            if let Some(quota) = quota.filter(|_| state.rate_limit_headers) {
+                quota.add_headers(&mut response);
+            }
+            state
+                .metrics
+                .record_response(NO_UPSTREAM, response.status());
+            send_response(&mut client_conn, client, &response).await;
+            let upstream = (None, Some(group));
+            let request = Some(&request);
+            state
+                .access_log
+                .log(started, client, request, upstream, sent(&response));
+            if client_wants_close {
+                return;
+            }
+            continue;
+        }
+
+        let proto = if state.tls_acceptor.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        // Add an X-Forwarded-For or Forwarded header so that the upstream server knows the
+        // client's IP address. (We're the ones connecting directly to the upstream server, so
+        // without this header, the upstream server will only know our IP, not the client's.)
+        match state.forwarded_header {
+            ForwardedHeader::XForwardedFor => {
+                request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+            }
+            ForwardedHeader::Rfc7239 => {
+                let element = request::forwarded_element(Some(client_addr), proto, Some(local));
+                request::extend_header_value(&mut request, request::FORWARDED_HEADER, &element);
+            }
+        }
+        if state.forwarding_headers {
+            add_forwarding_headers(&mut request, client, proto, local.port());
+        }
+        // Along with any --set-request-header rules and the like
+        state.request_header_rules.apply(request.headers_mut());
+
+        // Forward the request and read the response, closing the client connection if no
+        // upstream could answer
+        request::add_via(request.headers_mut(), &state.via_token);
+        // With --mirror-upstream, a copy of the request is kept to send to the shadow upstream
+        // once the request has been forwarded (while its body can still be copied)
+        let mirrored = match &state.mirror {
+            Some(mirror) if client_upgrade.is_none() && mirror.wants(&request, unread_body) => {
+                Some(mirror::copy(&request))
+            }
+            _ => None,
+        };
+        let body = (&mut client_conn, &mut unread_body);
+        let response = forward_with_retries(
+            &state,
+            (client_addr, group),
+            &request,
+            body,
+            &mut upstream.connection,
+            proxy_header.as_deref(),
+        )
+        .await;
+        let mut response = match response {
+            Ok(response) => response,
+            Err(status) => {
+                let mut response = response::make_http_error(status);
+                response
+                    .headers_mut()
+                    .insert(request::REQUEST_ID_HEADER, request_id);
+                let request_info = (started, client, Some(&request));
+                let group = Some(group);
+                send_error_response(&mut client_conn, &state, request_info, group, &response).await;
+                return;
+            }
+        };
+        if let Some(copy) = mirrored {
+            mirror::spawn(&state, copy, proxy_header.clone());
+        }
+        // Likewise for the response. The upstream connection can't be used again if the upstream
+        // is closing it (which it does after a body that ran until it closed).
+        let upstream_wants_close = request::wants_close(response.version(), response.headers());
+        // If the upstream agrees to switch protocols, the client's connection and the upstream's
+        // are joined together once the client has been told
+        let upgrade = match client_upgrade {
+            Some(protocol) if response.status() == http::StatusCode::SWITCHING_PROTOCOLS => {
+                Some(request::upgrade(response.headers()).unwrap_or(protocol))
+            }
+            _ => None,
+        };
+        request::remove_hop_by_hop_headers(response.headers_mut());
+        // A response that can be cached is read in full before it is passed on (unless it turns out
+        // to be too big), so that it can be kept
+        if let (Some(cache), Some(key), None) = (&state.cache, cache_key, &upgrade) {
+            state.metrics.record_cache("miss");
+            if cache::lifetime(&response).is_some() {
+                let max_size = state
+                    .max_response_body_size
+                    .map_or(cache.max_entry_size(), |max| {
+                        max.min(cache.max_entry_size())
+                    });
+                let connection = upstream.connection.as_mut().unwrap();
+                response = match response::buffer(response, &mut connection.stream, max_size).await
+                {
+                    Ok(response) => response,
+                    Err(error) => {
+                        log::warn!(
+                            "[{}] Failed to read the response from {}: {}",
+                            request_id.to_str().unwrap_or("-"),
+                            connection.address,
+                            error
+                        );
+                        let mut response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+                        response
+                            .headers_mut()
+                            .insert(request::REQUEST_ID_HEADER, request_id);
+                        let request_info = (started, client, Some(&request));
+                        let group = Some(group);
+                        send_error_response(
+                            &mut client_conn,
+                            &state,
+                            request_info,
+                            group,
+                            &response,
+                        )
+                        .await;
+                        upstream.connection = None;
+                        return;
+                    }
+                };
+                cache.insert_at(key, &response, Instant::now());
+            }
+            response
+                .headers_mut()
+                .insert(cache::HEADER, http::HeaderValue::from_static("MISS"));
+        }
+        // Any --set-response-header rules and the like. A cached response is kept without these
+        // changes, since they are made again each time it is served.
+        state.response_header_rules.apply(response.headers_mut());
+        response
+            .headers_mut()
+            .insert(request::REQUEST_ID_HEADER, request_id);
+        request::add_via(response.headers_mut(), &state.via_token);
+        if let Some(quota) = quota.filter(|_| state.rate_limit_headers) {
+            quota.add_headers(&mut response);
+        }
+        // The connection is closed after this response if the client asked for that (as HTTP/1.0
+        // clients do unless they ask to be kept alive), or if balancebeam has started shutting
+        // down in the meantime, so let the client know not to send another request. Likewise if
+        // the upstream answered without wanting the body the client was waiting to send: whatever
+        // the client does with it, it can't be told apart from another request (and the upstream
+        // is still owed it). And an HTTP/1.0 client can't be sent a body of unknown length in
+        // chunks, so the connection closing has to end it instead.
+        let body_refused = unread_body > 0;
+        let close_client = client_wants_close
+            || body_refused
+            || (client_version < http::Version::HTTP_11 && !response::has_known_length(&response));
+        if let Some(protocol) = upgrade.clone() {
+            request::set_upgrade(response.headers_mut(), protocol);
+        } else if close_client || *shutdown.borrow() {
+            response
+                .headers_mut()
+                .insert("connection", http::HeaderValue::from_static("close"));
+        } else if client_version < http::Version::HTTP_11 {
+            response
+                .headers_mut()
+                .insert("connection", http::HeaderValue::from_static("keep-alive"));
+        }
+        // Forward the response to the client, passing on its body as it arrives. forward_with_retries
+        // leaves the connection to the upstream that sent it open.
+        let connection = upstream.connection.as_mut().unwrap();
+        log::info!(
+            "[{}] {} <- {}",
+            request::request_id(response.headers()).unwrap_or("-"),
+            client_ip,
+            response::format_response_line(&response)
+        );
+        let (bytes, relayed) = response::relay(
+            &response,
+            &mut connection.stream,
+            &mut client_conn,
+            client_version,
+            state.max_response_body_size,
+        )
+        .await;
+        state
+            .metrics
+            .record_response(&connection.address, response.status());
+        state.access_log.log(
+            started,
+            client,
+            Some(&request),
+            (Some(&connection.address), Some(group)),
+            (response.status(), bytes),
+        );
+        // Only part of the response may have reached the client, so nothing else can be sent
+        // over its connection, and nothing else can be read from the upstream's
+        if let Err(error) = relayed {
+            log::warn!(
+                "[{}] Failed to pass on the response from {}: {}",
+                request::request_id(response.headers()).unwrap_or("-"),
+                connection.address,
+                error
+            );
+            if let response::Error::ResponseBodyTooLarge = error {
+                if let Some(info) =
+                    find_upstream(&mut state.upstreams.lock().await, &connection.address)
+                {
+                    record_upstream_failure(info);
+                }
+            }
+            upstream.connection = None;
+            return;
+        }
+        log::debug!("Forwarded response to client");
+        // Neither connection speaks HTTP any more, so all balancebeam can do is pass on what each
+        // side sends until they are finished with each other
+        if let Some(protocol) = upgrade {
+            let mut connection = upstream.connection.take().unwrap();
+            let request_id = request::request_id(response.headers()).unwrap_or("-");
+            match tunnel(&mut client_conn, &mut connection.stream).await {
+                Ok((sent, received)) => log::debug!(
+                    "[{}] Closed {:?} connection after passing on {} bytes from {} and {} from \
+                     upstream {}",
+                    request_id,
+                    protocol,
+                    sent,
+                    client_ip,
+                    received,
+                    connection.address
+                ),
+                Err(error) => log::debug!(
+                    "[{}] {:?} connection between {} and upstream {} failed: {}",
+                    request_id,
+                    protocol,
+                    client_ip,
+                    connection.address,
+                    error
+                ),
+            }
+            return;
+        }
+        // An upstream that sends more than its Content-Length can't be trusted to frame its
+        // responses, so whatever it sends next isn't read as one
+        if response::sent_extra_bytes(&mut connection.stream).await {
+            log::warn!(
+                "[{}] Upstream {} sent more body than its Content-Length: closing its connection",
+                request::request_id(response.headers()).unwrap_or("-"),
+                connection.address
+            );
+            if let Some(info) =
+                find_upstream(&mut state.upstreams.lock().await, &connection.address)
+            {
+                record_upstream_failure(info);
             }
+            upstream.connection = None;
+        } else if upstream_wants_close || body_refused {
+            // (The upstream is still owed the rest of the body.)
+            upstream.connection = None;
+        }
+        if close_client {
+            return;
         }
     }
 }
 
-async fn choose_health_upstream_randomly(state: &Arc<ProxyState>) -> Option<usize> {
-    loop {
-        let mut rng = rand::rngs::StdRng::from_entropy();
-        let upstream_status = state.upstream_status.lock().await;
-        let upstream_idx = rng.gen_range(0, upstream_status.len());
-        if upstream_status[upstream_idx] {
-            return Some(upstream_idx);
+/// Handles a CONNECT request that --allow-connect allows, by connecting to `destination`, telling
+/// the client the tunnel is open, and then passing on whatever either side sends. If the
+/// destination can't be connected to, the client gets a 502 instead.
+async fn connect_tunnel(
+    client_conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    state: &ProxyState,
+    (started, client, request): (Started, IpAddr, &http::Request<Vec<u8>>),
+    destination: &str,
+) {
+    let request_id = request::request_id(request.headers()).unwrap_or("-");
+    let connect = connect_with_timeout(destination, state.upstream_connect_timeout);
+    let mut destination_conn = match connect.await {
+        Ok(stream) => stream,
+        Err(error) => {
+            log::info!(
+                "[{}] Failed to connect to CONNECT destination {}: {}",
+                request_id,
+                destination,
+                error
+            );
+            let mut response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+            response.headers_mut().insert(
+                request::REQUEST_ID_HEADER,
+                request.headers()[request::REQUEST_ID_HEADER].clone(),
+            );
+            let request_info = (started, client, Some(request));
+            send_error_response(client_conn, state, request_info, None, &response).await;
+            return;
         }
+    };
+    log::info!(
+        "[{}] {} <- HTTP/1.1 200 Connection Established ({})",
+        request_id,
+        client,
+        destination
+    );
+    if let Err(error) = response::write_connection_established(client_conn).await {
+        log::info!("Failed to tell the client its tunnel is open: {}", error);
+        return;
+    }
+    state.access_log.log(
+        started,
+        client,
+        Some(request),
+        (Some(destination), None),
+        (http::StatusCode::OK, 0),
+    );
+    // A client that didn't wait to hear the tunnel was open may have sent some of what it wants
+    // to go through it along with the request
+    if let Err(error) = destination_conn.write_all(request.body()).await {
+        log::debug!(
+            "[{}] Failed to write to {}: {}",
+            request_id,
+            destination,
+            error
+        );
+        return;
+    }
+    match tunnel(client_conn, &mut destination_conn).await {
+        Ok((sent, received)) => log::debug!(
+            "[{}] Closed tunnel after passing on {} bytes from {} and {} from {}",
+            request_id,
+            sent,
+            client,
+            received,
+            destination
+        ),
+        Err(error) => log::debug!(
+            "[{}] Tunnel between {} and {} failed: {}",
+            request_id,
+            client,
+            destination,
+            error
+        ),
     }
 }
 
-async fn connect_to_upstream(state: Arc<ProxyState>) -> Result<TcpStream, std::io::Error> {
-    // TODO: implement failover (milestone 3)
-    loop {
-        if let Some(upstream_idx) = choose_health_upstream_randomly(&state).await {
-            let upstream_ip = &state.upstream_addresses[upstream_idx];
-            match TcpStream::connect(upstream_ip).await {
-                Ok(upstream) => return Ok(upstream),
-                Err(_) => {
-                    log::info!(
-                        "Failed to connect to upstream {}: this server is dead",
-                        upstream_ip
-                    );
-                    let mut upstream_status = state.upstream_status.lock().await;
-                    upstream_status[upstream_idx] = false;
-                    continue;
-                }
+/// Passes on whatever the client and the other end (an upstream, or a CONNECT destination) send
+/// each other, once their connections have switched to another protocol. When one of them stops
+/// sending, the other is told so, and the tunnel lasts until both have (or either connection
+/// fails). Returns how many bytes were passed on from the client, and how many to it.
+async fn tunnel(
+    client_conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    upstream_conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> std::io::Result<(u64, u64)> {
+    let (mut client_read, mut client_write) = tokio::io::split(client_conn);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream_conn);
+    let sent = async {
+        let bytes = tokio::io::copy(&mut client_read, &mut upstream_write).await?;
+        upstream_write.shutdown().await?;
+        Ok(bytes)
+    };
+    let received = async {
+        let bytes = tokio::io::copy(&mut upstream_read, &mut client_write).await?;
+        client_write.shutdown().await?;
+        Ok(bytes)
+    };
+    tokio::try_join!(sent, received)
+}
+
+/// Waits until the client starts sending another request (or hangs up), `timeout` runs out, or
+/// balancebeam starts shutting down, returning false in the last case.
+async fn wait_for_request(
+    client_conn: &mut BufReader<impl AsyncRead + Unpin>,
+    timeout: Option<Duration>,
+    shutdown: &mut watch::Receiver<bool>,
+) -> bool {
+    // Whatever arrives is left in the buffer, for the request to be read from
+    let arrived = stream::peek(client_conn);
+    let ready = async {
+        match timeout {
+            Some(timeout) => {
+                let _ = tokio::time::timeout(timeout, arrived).await;
             }
-        } else {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "All servers are dead",
-            ));
-        };
+            None => {
+                let _ = arrived.await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = ready => true,
+        _ = shutdown_started(shutdown) => false,
     }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!(
-        "{} <- {}",
-        client_ip,
-        response::format_response_line(&response)
-    );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
-        log::warn!("Failed to send response to client: {}", error);
-        return;
-    }
+/// An open connection to an upstream, which a client's requests keep using until one fails (and
+/// which other clients' requests may use afterwards, from the pool).
+struct UpstreamConnection {
+    stream: UpstreamStream,
+    /// The upstream's address, as given to --upstream (or as its hostname resolved to)
+    address: String,
+    /// See UpstreamInfo::name
+    name: String,
+    /// The group the upstream was chosen from
+    group: String,
+    counters: Arc<UpstreamCounters>,
+    /// How many responses have been read from it
+    responses: usize,
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: Arc<ProxyState>) {
-    if !check_rate_limit_counter(&mut client_conn, &state).await {
-        return;
-    }
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
+/// Holds a client's connection to its upstream, putting it in ProxyState::upstream_pool when the
+/// client's connection is done with it, however that ends. Anything that leaves it unusable (such
+/// as a response that wasn't read in full) closes it first.
+struct HeldUpstream<'a> {
+    connection: Option<UpstreamConnection>,
+    state: &'a ProxyState,
+}
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+impl Drop for HeldUpstream<'_> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            let address = connection.address.clone();
+            let pool = &self.state.upstream_pool;
+            pool.check_in_at(&address, connection, Instant::now());
         }
-    };
-    let upstream_ip = client_conn.peer_addr().unwrap().ip().to_string();
+    }
+}
 
-    // The client may now send us one or more requests. Keep trying to read requests until the
-    // client hangs up or we get an error.
+/// Forwards `request` over `upstream`, first connecting to the upstream the load balancing
+/// strategy of `group` chooses if there is no connection (or since it was opened, the upstream has
+/// been removed or drained, or its circuit breaker has opened, or it was for another group).
+/// If forwarding fails, the upstream is taken out of use and the connection is closed (a late
+/// response would be taken as the answer to the next request), then the request is sent to another
+/// upstream, up to `--upstream-retries` times.
+///
+/// A request that isn't idempotent may have been acted on by the failed upstream, so it is only
+/// retried with `--retry-non-idempotent`. (Upstreams that can't be connected to never see any of
+/// the request, so connect_to_upstream moves on from them regardless.) Timeouts aren't retried
+/// either, since the client has already waited as long as it should. Nor are requests whose body
+/// is still being read from the client (`body` gives the client's connection and how many bytes of
+/// the body are unread), since the body can only be passed on once. The count is left as it is if
+/// the upstream answers without wanting the body (see forward_request).
+///
+/// A connection that has been used before may be closed by the upstream for being idle just as a
+/// request is sent over it, which isn't the upstream's fault. So if the upstream closes it without
+/// sending anything back, and the whole request is still at hand, the request is sent once more
+/// over a new connection to the same upstream before forwarding counts as having failed.
+///
+/// New connections start with `proxy_header`, if there is one (see --send-proxy-protocol).
+async fn forward_with_retries(
+    state: &ProxyState,
+    (client_addr, group): (IpAddr, &str),
+    request: &http::Request<Vec<u8>>,
+    body: (&mut (impl AsyncRead + AsyncWrite + Unpin), &mut usize),
+    upstream: &mut Option<UpstreamConnection>,
+    proxy_header: Option<&str>,
+) -> Result<http::Response<response::Body>, http::StatusCode> {
+    let (client_conn, unread_body) = body;
+    let client_ip = client_addr.to_string();
+    let retryable =
+        *unread_body == 0 && (request.method().is_idempotent() || state.retry_non_idempotent);
+    let request_id = request::request_id(request.headers()).unwrap_or("-");
+    let mut tried = Vec::new();
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
-            Ok(request) => request,
-            // Handle case where client closed connection and is no longer sending requests
-            Err(request::Error::IncompleteRequest(0)) => {
-                log::debug!("Client finished sending requests. Shutting down connection");
-                return;
+        if let Some(connection) = upstream.take() {
+            let still_usable = state.upstreams.lock().await.iter().any(|info| {
+                info.address == connection.address
+                    && !info.draining
+                    && info.circuit_breaker.is_closed()
+            });
+            if still_usable && connection.group == group {
+                *upstream = Some(connection);
+            } else if still_usable {
+                // Requests for its own group can still use it
+                let address = connection.address.clone();
+                let pool = &state.upstream_pool;
+                pool.check_in_at(&address, connection, Instant::now());
             }
-            // Handle I/O error in reading from the client
-            Err(request::Error::ConnectionError(io_err)) => {
-                log::info!("Error reading request from client stream: {}", io_err);
-                return;
+        }
+        if upstream.is_none() {
+            let client = (client_addr, group);
+            let connection = connect_to_upstream(state, client, &tried, proxy_header)
+                .await
+                .map_err(|_| http::StatusCode::BAD_GATEWAY)?;
+            *upstream = Some(connection);
+        }
+        let connection = upstream.as_mut().unwrap();
+        log::info!(
+            "[{}] {} -> {}: {}",
+            request_id,
+            client_ip,
+            connection.address,
+            request::format_request_line(request)
+        );
+
+        // The request counts as in flight until forward_request returns, whether or not it
+        // succeeds, but only successful responses are timed
+        if let Some(info) = find_upstream(&mut state.upstreams.lock().await, &connection.address) {
+            info.in_flight += 1;
+        }
+        let strategy = &state.groups[connection.group.as_str()].strategy;
+        strategy.on_request_start(&connection.address);
+        let started = Instant::now();
+        let replayable = *unread_body == 0;
+        let default_host =
+            stream::unix_path(&connection.address).map(|_| state.unix_socket_host.as_str());
+        let mut response = forward_request(
+            request,
+            (&mut *client_conn, &mut *unread_body),
+            connection,
+            default_host,
+            state.upstream_response_timeout,
+            state.header_limits,
+            state.max_response_body_size,
+        )
+        .await;
+        if let Err(ForwardError::Closed) = response {
+            if connection.responses > 0 && replayable {
+                log::info!(
+                    "[{}] Upstream {} closed a connection it had answered on before: resending \
+                     the request on a new one",
+                    request_id,
+                    connection.address
+                );
+                let timeout = state.upstream_connect_timeout;
+                let connect = stream::connect(
+                    &connection.address,
+                    &connection.name,
+                    timeout,
+                    &state.upstream_tls,
+                    proxy_header,
+                );
+                match connect.await {
+                    Ok(stream) => {
+                        connection.stream = stream;
+                        connection.responses = 0;
+                        response = forward_request(
+                            request,
+                            (&mut *client_conn, &mut *unread_body),
+                            connection,
+                            default_host,
+                            state.upstream_response_timeout,
+                            state.header_limits,
+                            state.max_response_body_size,
+                        )
+                        .await;
+                    }
+                    Err(error) => {
+                        log::info!(
+                            "Failed to connect to upstream {}: {}",
+                            connection.address,
+                            error
+                        );
+                    }
+                }
             }
-            Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
-                continue;
+        }
+        let response = response.map_err(|error| error.status());
+        let latency = started.elapsed();
+        strategy.on_request_end(&connection.address);
+        connection.counters.record_response(match &response {
+            Ok(response) => response.status(),
+            Err(status) => *status,
+        });
+        let mut upstreams = state.upstreams.lock().await;
+        // Nothing needs to be recorded about an upstream that has been removed
+        if let Some(info) = find_upstream(&mut upstreams, &connection.address) {
+            info.in_flight -= 1;
+            if response.is_ok() {
+                info.record_latency(latency, state.ewma_decay);
+                state.metrics.record_latency(latency);
+                info.reconnect_backoff.reset();
+                if info.circuit_breaker.record_success() {
+                    log::info!(
+                        "Upstream {} passed a trial request: closing its circuit breaker",
+                        info.address
+                    );
+                }
+                let group = &state.groups[connection.group.as_str()];
+                if group.backoff.lock().reset() {
+                    log::info!(
+                        "Upstream {} responded: no longer failing requests straight away",
+                        info.address
+                    );
+                }
+            } else {
+                // The upstream accepted the connection but couldn't handle the request
+                log::info!(
+                    "[{}] Failed to forward a request to upstream {}",
+                    request_id,
+                    info.address
+                );
+                record_upstream_failure(info);
             }
+        }
+        drop(upstreams);
+
+        let status = match response {
+            Ok(response) => {
+                connection.responses += 1;
+                return Ok(response);
+            }
+            Err(status) => status,
         };
+        tried.push(connection.address.clone());
+        *upstream = None;
+        if status != http::StatusCode::BAD_GATEWAY
+            || !retryable
+            || tried.len() > state.upstream_retries
+        {
+            return Err(status);
+        }
         log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
+            "[{}] Retrying {} on another upstream (retry {} of {})",
+            request_id,
+            request::format_request_line(request),
+            tried.len(),
+            state.upstream_retries
         );
+    }
+}
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
-
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
+/// Sends `request` to the upstream server, followed by the rest of its body from the client as
+/// `body` describes (setting the count of unread bytes to 0 once they have been passed on), and
+/// reads its response. If the client is waiting to be told to continue before sending the body,
+/// the upstream gets to answer that first, and its final response is returned without the body
+/// being read if it doesn't want it. If either fails, returns why (having logged it): see
+/// ForwardError. A request without a Host header is sent with `default_host`, if there is one.
+async fn forward_request(
+    request: &http::Request<Vec<u8>>,
+    body: (&mut (impl AsyncRead + AsyncWrite + Unpin), &mut usize),
+    upstream: &mut UpstreamConnection,
+    default_host: Option<&str>,
+    response_timeout: Option<Duration>,
+    header_limits: request::HeaderLimits,
+    max_body_size: Option<usize>,
+) -> Result<http::Response<response::Body>, ForwardError> {
+    let upstream_conn = &mut upstream.stream;
+    let upstream_ip = upstream.address.as_str();
+    if let Err(error) = request::write_to_stream(request, default_host, upstream_conn).await {
+        log::error!(
+            "Failed to send request to upstream {}: {}",
+            upstream_ip,
+            error
+        );
+        return Err(ForwardError::Closed);
+    }
+    let (client_conn, unread_body) = body;
+    if *unread_body > 0 {
+        if request::expects_continue(request) {
+            let answer = wait_for_continue(
+                upstream_conn,
+                request.method(),
+                header_limits,
+                max_body_size,
+            );
+            match answer.await {
+                Ok(Some(response)) => return Ok(response),
+                Ok(None) => {}
+                Err(error) => {
+                    log::error!("Error reading response from server: {}", error);
+                    return Err(ForwardError::Failed(http::StatusCode::BAD_GATEWAY));
+                }
+            }
+            if let Err(error) = response::write_continue(client_conn).await {
+                log::info!("Failed to tell the client to continue: {}", error);
+                return Err(ForwardError::Failed(http::StatusCode::BAD_GATEWAY));
+            }
+        }
+        if let Err(error) = request::copy_body(client_conn, upstream_conn, *unread_body).await {
             log::error!(
-                "Failed to send request to upstream {}: {}",
+                "Failed to pass on a request body to upstream {}: {}",
                 upstream_ip,
                 error
             );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+            return Err(ForwardError::Failed(http::StatusCode::BAD_GATEWAY));
         }
-        log::debug!("Forwarded request to server");
+        *unread_body = 0;
+    }
+    log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
-            }
-        };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+    // The request has been sent in full by now, so the timeout doesn't count time spent reading
+    // a large body from a slow client
+    let method = request.method();
+    let read = read_upstream_response(
+        upstream_conn,
+        method,
+        response_timeout,
+        header_limits,
+        max_body_size,
+    );
+    match read.await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(response::Error::IncompleteResponse(0))) => {
+            log::error!(
+                "Upstream {} closed the connection without responding",
+                upstream_ip
+            );
+            Err(ForwardError::Closed)
+        }
+        Ok(Err(error)) => {
+            log::error!("Error reading response from server: {}", error);
+            Err(ForwardError::Failed(http::StatusCode::BAD_GATEWAY))
+        }
+        Err(_) => {
+            log::error!(
+                "Upstream {} didn't respond within {:?}",
+                upstream_ip,
+                response_timeout.unwrap()
+            );
+            Err(ForwardError::Failed(http::StatusCode::GATEWAY_TIMEOUT))
+        }
+    }
+}
+
+/// Why forward_request didn't get a response.
+enum ForwardError {
+    /// The upstream closed the connection without sending anything back: the request couldn't be
+    /// sent, or nothing at all could be read after it
+    Closed,
+    /// Anything else, with the status to reply to the client with: 504 if the upstream didn't
+    /// respond in time, or 502 otherwise (including if the response's headers are bigger than
+    /// allowed, or its Content-Length is bigger than the maximum body size)
+    Failed(http::StatusCode),
+}
+
+impl ForwardError {
+    fn status(&self) -> http::StatusCode {
+        match self {
+            ForwardError::Closed => http::StatusCode::BAD_GATEWAY,
+            ForwardError::Failed(status) => *status,
+        }
+    }
+}
+
+/// Waits for an upstream's answer to a request head sent with `Expect: 100-continue`, as
+/// response::read_continue describes. An upstream that says nothing for EXPECT_CONTINUE_TIMEOUT is
+/// taken to be waiting for the body.
+async fn wait_for_continue(
+    upstream_conn: &mut UpstreamStream,
+    request_method: &http::Method,
+    header_limits: request::HeaderLimits,
+    max_body_size: Option<usize>,
+) -> Result<Option<http::Response<response::Body>>, response::Error> {
+    // Waiting for the answer to start, rather than for all of it, means none of it is lost if the
+    // time runs out
+    let peek = stream::peek(upstream_conn);
+    if tokio::time::timeout(EXPECT_CONTINUE_TIMEOUT, peek)
+        .await
+        .is_err()
+    {
+        return Ok(None);
+    }
+    response::read_continue(upstream_conn, request_method, header_limits, max_body_size).await
+}
+
+/// Reads a response from an upstream, giving up if it takes longer than `timeout` (if given).
+async fn read_upstream_response(
+    upstream_conn: &mut UpstreamStream,
+    request_method: &http::Method,
+    timeout: Option<Duration>,
+    header_limits: request::HeaderLimits,
+    max_body_size: Option<usize>,
+) -> Result<Result<http::Response<response::Body>, response::Error>, tokio::time::Elapsed> {
+    let read =
+        response::read_from_stream(upstream_conn, request_method, header_limits, max_body_size);
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read).await,
+        None => Ok(read.await),
     }
 }
 
-async fn check_server(upstream_idx: usize, state: &Arc<ProxyState>) -> bool {
-    let upstream_ip = &state.upstream_addresses[upstream_idx];
+/// Sends a health check to the upstream at `upstream_ip`, which is called `name` (see
+/// UpstreamInfo::name), returning whether it answered with one of `expected_statuses`. The check's
+/// Host header is the upstream's host:port, or `unix_host` for a Unix socket.
+async fn check_server(
+    (upstream_ip, name): (&str, &str),
+    health_check_path: &str,
+    expected_statuses: &[RangeInclusive<u16>],
+    connector: (Duration, &tokio_tls::TlsConnector, Option<&str>),
+    response_timeout: Option<Duration>,
+    header_limits: request::HeaderLimits,
+    unix_host: &str,
+) -> bool {
+    let host = match stream::unix_path(name) {
+        Some(_) => unix_host,
+        None => stream::split_scheme(name).0,
+    };
     let request = http::Request::builder()
         .method(http::Method::GET)
-        .uri(&state.active_health_check_path)
-        .header("Host", upstream_ip)
+        .uri(health_check_path)
+        .header("Host", host)
         .body(Vec::new())
         .unwrap();
 
-    let mut upstream_conn = match connect_to_specify_server(upstream_ip).await {
+    let mut upstream_conn = match connect_to_specify_server((upstream_ip, name), connector).await {
         Ok(stream) => stream,
         Err(_error) => {
             return false;
         }
     };
-    if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
+    if let Err(error) = request::write_to_stream(&request, None, &mut upstream_conn).await {
         log::debug!(
             "Failed to send request to upsteram {}: {}",
             upstream_ip,
@@ -297,65 +3265,561 @@ async fn check_server(upstream_idx: usize, state: &Arc<ProxyState>) -> bool {
         );
         return false;
     }
-    match response::read_from_stream(&mut upstream_conn, request.method()).await {
-        Ok(resp) if resp.status() == http::StatusCode::OK => return true,
-        Ok(_resp) => {
-            log::info!("Error the server is not healthy");
+    let status = match read_upstream_response(
+        &mut upstream_conn,
+        request.method(),
+        response_timeout,
+        header_limits,
+        None,
+    )
+    .await
+    {
+        Ok(Ok(resp)) => resp.status(),
+        Ok(Err(error)) => {
+            log::info!("Error reading response from server: {}", error);
             return false;
         }
-        Err(error) => {
-            log::info!("Error reading response from server: {:?}", error);
+        Err(_) => {
+            log::info!(
+                "Upstream {} didn't respond to its health check within {:?}",
+                upstream_ip,
+                response_timeout.unwrap()
+            );
             return false;
         }
+    };
+    if expected_statuses
+        .iter()
+        .any(|codes| codes.contains(&status.as_u16()))
+    {
+        true
+    } else {
+        log::info!(
+            "Upstream {} failed its health check with status {}",
+            upstream_ip,
+            status
+        );
+        false
     }
 }
 
+/// Returns `interval` seconds, randomly lengthened or shortened by up to `jitter_percent` percent.
+fn jittered_interval<R: Rng>(interval: u64, jitter_percent: u32, rng: &mut R) -> Duration {
+    let spread = jitter_percent as f64 / 100.0;
+    let factor = if spread > 0.0 {
+        rng.gen_range(1.0 - spread, 1.0 + spread)
+    } else {
+        1.0
+    };
+    Duration::from_secs(interval).mul_f64(factor)
+}
+
+/// Checks all the upstreams again every `active_health_check_interval` seconds (give or take the
+/// jitter). The first round is run by main, before any clients are accepted.
 async fn active_health_check(state: Arc<ProxyState>) {
     let internal = state.active_health_check_interval as u64;
+    let mut rng = StdRng::from_entropy();
     loop {
-        delay_for(Duration::from_secs(internal)).await;
-        let mut upstream_status = state.upstream_status.lock().await;
-        for upstream_idx in 0..upstream_status.len() {
-            if check_server(upstream_idx, &state).await {
-                upstream_status[upstream_idx] = true;
-            } else {
-                upstream_status[upstream_idx] = false;
-            }
+        delay_for(jittered_interval(
+            internal,
+            state.active_health_check_jitter,
+            &mut rng,
+        ))
+        .await;
+        check_all_upstreams(&state).await;
+    }
+}
+
+/// Checks all the upstreams at once, returning when every check has finished. The upstreams are
+/// only locked to copy their addresses and to record each result as it comes in, so requests keep
+/// being routed during the checks, and a slow upstream doesn't hold up the results for the others.
+async fn check_all_upstreams(state: &Arc<ProxyState>) {
+    let upstreams: Vec<(String, String, Option<String>)> = state
+        .upstreams
+        .lock()
+        .await
+        .iter()
+        .map(|upstream| {
+            let name = upstream.name().to_string();
+            (
+                upstream.address.clone(),
+                name,
+                upstream.health_check_path.clone(),
+            )
+        })
+        .collect();
+    let checks: Vec<_> = upstreams
+        .into_iter()
+        .map(|(address, name, health_check_path)| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let path = health_check_path
+                    .as_ref()
+                    .unwrap_or(&state.active_health_check_path);
+                let passed = check_server(
+                    (&address, &name),
+                    path,
+                    &state.health_check_expect,
+                    state.health_check_connector(),
+                    state.upstream_response_timeout,
+                    state.header_limits,
+                    &state.unix_socket_host,
+                )
+                .await;
+                let mut upstreams = state.upstreams.lock().await;
+                // The upstream may have been removed during the check
+                let upstream = match find_upstream(&mut upstreams, &address) {
+                    Some(upstream) => upstream,
+                    None => return,
+                };
+                let breaker_was_closed = upstream.circuit_breaker.is_closed();
+                let was_healthy = upstream.healthy;
+                upstream.record_health_check(passed, state.health_check_recovery_threshold);
+                if upstream.healthy != was_healthy {
+                    state
+                        .metrics
+                        .record_health_change(&address, upstream.healthy);
+                }
+                if !breaker_was_closed && upstream.circuit_breaker.is_closed() {
+                    log::info!(
+                        "Upstream {} passed a health check: closing its circuit breaker",
+                        address
+                    );
+                }
+            })
+        })
+        .collect();
+    for check in checks {
+        if let Err(err) = check.await {
+            log::error!("Health check task failed: {}", err);
         }
     }
 }
 
 // connect to the specified server
-async fn connect_to_specify_server(upstream_ip: &str) -> Result<TcpStream, std::io::Error> {
-    match TcpStream::connect(upstream_ip).await {
-        Ok(upstream) => return Ok(upstream),
+async fn connect_to_specify_server(
+    (upstream_ip, name): (&str, &str),
+    (timeout, tls, proxy_header): (Duration, &tokio_tls::TlsConnector, Option<&str>),
+) -> Result<UpstreamStream, std::io::Error> {
+    match stream::connect(upstream_ip, name, timeout, tls, proxy_header).await {
+        Ok(upstream) => Ok(upstream),
         Err(err) => {
             log::info!("Failed to connect to upstream {}: {}", upstream_ip, err);
-            return Err(err);
+            Err(err)
         }
     }
 }
 
-// Refreash rate limit counter
-async fn refresh_rate_limit_counter(state: Arc<ProxyState>) {
-    delay_for(Duration::from_secs(
-        state.active_health_check_interval as u64,
-    ))
-    .await;
-    let mut rate_limit_counter = state.rate_limit_counter.lock().await;
-    rate_limit_counter.clear();
-}
-
-async fn check_rate_limit_counter(client_conn: &mut TcpStream, state: &Arc<ProxyState>) -> bool {
-    let ip_addr = client_conn.peer_addr().unwrap().ip().to_string();
-    let mut rate_limit_counter = state.rate_limit_counter.lock().await;
-    let count = rate_limit_counter.entry(ip_addr.to_string()).or_insert(0);
-    *count += 1;
-    log::info!("{} requests from ip: {}", count, ip_addr);
-    if *count > state.max_requests_per_minute {
-        let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-        send_response(client_conn, &response).await;
-        return false;
+/// Logs a line for each upstream every `interval`, with how many of its requests succeeded and
+/// failed since balancebeam started.
+async fn log_upstream_stats(state: Arc<ProxyState>, interval: Duration) {
+    loop {
+        delay_for(interval).await;
+        let upstreams: Vec<(String, bool, Arc<UpstreamCounters>)> = state
+            .upstreams
+            .lock()
+            .await
+            .iter()
+            .map(|upstream| {
+                let healthy = upstream.healthy;
+                (upstream.address.clone(), healthy, upstream.counters.clone())
+            })
+            .collect();
+        for (address, healthy, counters) in upstreams {
+            log::info!(
+                "upstream {} ok={} 5xx={} connect_fail={} healthy={}",
+                address,
+                counters.ok(),
+                counters.server_errors.load(Ordering::Relaxed),
+                counters.connect_failures.load(Ordering::Relaxed),
+                healthy
+            );
+        }
+    }
+}
+
+/// Periodically logs how many of the --max-concurrent-connections are in use.
+async fn log_connection_usage(state: Arc<ProxyState>) {
+    let permits = match &state.connection_permits {
+        Some(permits) => permits,
+        None => return,
+    };
+    loop {
+        delay_for(CONNECTION_USAGE_LOG_INTERVAL).await;
+        log::info!(
+            "{} of {} concurrent connections in use",
+            state.max_concurrent_connections - permits.available_permits(),
+            state.max_concurrent_connections
+        );
+    }
+}
+
+/// Periodically closes the connections in the upstream pool that have been idle for too long.
+async fn remove_idle_upstream_connections(state: Arc<ProxyState>) {
+    loop {
+        delay_for(state.upstream_pool.idle_timeout()).await;
+        state.upstream_pool.remove_expired_at(Instant::now());
+    }
+}
+
+/// Looks up the addresses of the upstreams given by hostname again every `interval`, health
+/// checking the upstreams at any new addresses before they are added. If looking one up fails, the
+/// upstreams it resolved to last time are kept.
+async fn refresh_dns(state: Arc<ProxyState>, interval: Duration) {
+    loop {
+        delay_for(interval).await;
+        for spec in &state.hostname_upstreams {
+            let addresses = match dns::resolve(&spec.address).await {
+                Ok(addresses) if !addresses.is_empty() => addresses,
+                Ok(_) => {
+                    log::warn!(
+                        "Upstream {} resolved to no addresses: keeping the ones it had",
+                        spec.address
+                    );
+                    continue;
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Could not resolve upstream {}: {} (keeping the addresses it had)",
+                        spec.address,
+                        err
+                    );
+                    continue;
+                }
+            };
+            let known: Vec<String> = state
+                .upstreams
+                .lock()
+                .await
+                .iter()
+                .map(|upstream| upstream.address.clone())
+                .collect();
+            let path = spec
+                .health_check_path
+                .as_ref()
+                .unwrap_or(&state.active_health_check_path);
+            let mut checked = Vec::new();
+            for address in addresses {
+                // Upstreams that are already known keep their health
+                let healthy = known.contains(&address)
+                    || check_server(
+                        (&address, &spec.address),
+                        path,
+                        &state.health_check_expect,
+                        state.health_check_connector(),
+                        state.upstream_response_timeout,
+                        state.header_limits,
+                        &state.unix_socket_host,
+                    )
+                    .await;
+                checked.push((address, healthy));
+            }
+            let mut upstreams = state.upstreams.lock().await;
+            update_resolved(&mut upstreams, &state.upstream_settings, spec, &checked);
+        }
+    }
+}
+
+/// Periodically forgets clients that haven't made a request in the last window, or whose token
+/// buckets have refilled.
+async fn remove_expired_rate_limits(state: Arc<ProxyState>) {
+    loop {
+        delay_for(rate_limit::WINDOW).await;
+        state.rate_limiter.remove_expired();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spec(address: &str, weight: u32, health_check_path: Option<&str>) -> UpstreamSpec {
+        UpstreamSpec {
+            address: address.to_string(),
+            weight,
+            health_check_path: health_check_path.map(String::from),
+            group: route::DEFAULT_GROUP.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_update_resolved() {
+        let settings = UpstreamSettings {
+            max_backoff: Duration::from_secs(30),
+            circuit_breaker: CircuitBreaker::disabled(),
+            slow_start: Duration::from_secs(0),
+        };
+        let hostname = spec("backend.internal:8080", 2, Some("/healthz"));
+        let addresses = |list: &[(&str, bool)]| -> Vec<(String, bool)> {
+            list.iter()
+                .map(|(address, healthy)| (address.to_string(), *healthy))
+                .collect()
+        };
+        let mut upstreams = vec![UpstreamInfo::new("10.0.0.1:8080".to_string(), 1)];
+        update_resolved(
+            &mut upstreams,
+            &settings,
+            &hostname,
+            &addresses(&[("10.0.0.1:8080", true), ("10.0.0.2:8080", true)]),
+        );
+        // An address given by itself stays as it was
+        assert_eq!(upstreams.len(), 2);
+        assert_eq!(upstreams[0].resolved_from, None);
+        assert_eq!(upstreams[1].address, "10.0.0.2:8080");
+        assert_eq!(upstreams[1].name(), "backend.internal:8080");
+        assert_eq!(upstreams[1].weight, 2);
+        assert_eq!(upstreams[1].health_check_path.as_deref(), Some("/healthz"));
+
+        // Upstreams at addresses that are still there keep their state
+        upstreams[1].in_flight = 3;
+        update_resolved(
+            &mut upstreams,
+            &settings,
+            &hostname,
+            &addresses(&[("10.0.0.2:8080", true), ("10.0.0.3:8080", false)]),
+        );
+        let listed: Vec<(&str, bool, usize)> = upstreams
+            .iter()
+            .map(|upstream| {
+                (
+                    upstream.address.as_str(),
+                    upstream.healthy,
+                    upstream.in_flight,
+                )
+            })
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("10.0.0.1:8080", true, 0),
+                ("10.0.0.2:8080", true, 3),
+                ("10.0.0.3:8080", false, 0),
+            ]
+        );
+
+        update_resolved(&mut upstreams, &settings, &hostname, &addresses(&[]));
+        assert_eq!(upstreams.len(), 1);
+        assert_eq!(upstreams[0].address, "10.0.0.1:8080");
+    }
+
+    #[test]
+    fn test_parse_upstream() {
+        assert_eq!(
+            parse_upstream("127.0.0.1:8080"),
+            Ok(spec("127.0.0.1:8080", 1, None))
+        );
+        assert_eq!(
+            parse_upstream("127.0.0.1:8080=3"),
+            Ok(spec("127.0.0.1:8080", 3, None))
+        );
+        assert_eq!(
+            parse_upstream("127.0.0.1:8080=0"),
+            Ok(spec("127.0.0.1:8080", 0, None))
+        );
+        assert_eq!(
+            parse_upstream("10.0.0.5:8080;health=/healthz"),
+            Ok(spec("10.0.0.5:8080", 1, Some("/healthz")))
+        );
+        assert_eq!(
+            parse_upstream("10.0.0.5:8080=2;health=/status?full=1"),
+            Ok(spec("10.0.0.5:8080", 2, Some("/status?full=1")))
+        );
+        for invalid in &[
+            "127.0.0.1:8080=",
+            "127.0.0.1:8080=-1",
+            "127.0.0.1:8080=2=3",
+            "=2",
+            "127.0.0.1:8080;",
+            "127.0.0.1:8080;health=",
+            "127.0.0.1:8080;health=healthz",
+            "127.0.0.1:8080;health=/a;health=/b",
+            "127.0.0.1:8080;path=/healthz",
+            ";health=/healthz",
+        ] {
+            assert!(parse_upstream(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(
+            parse_upstream("https://api.internal:443=2"),
+            Ok(spec("https://api.internal:443", 2, None))
+        );
+        assert_eq!(
+            parse_upstream("unix:/run/app.sock=2;health=/healthz"),
+            Ok(spec("unix:/run/app.sock", 2, Some("/healthz")))
+        );
+        assert!(parse_upstream("unix:=2").is_err());
+        let mut grouped = spec("10.0.0.5:8080", 2, Some("/healthz"));
+        grouped.group = "api".to_string();
+        assert_eq!(
+            parse_upstream("10.0.0.5:8080=2;group=api;health=/healthz"),
+            Ok(grouped)
+        );
+        for invalid in &[
+            "10.0.0.5:8080;group=",
+            "10.0.0.5:8080;group=a b",
+            "10.0.0.5:8080;group=api;group=web",
+        ] {
+            assert!(parse_upstream(invalid).is_err(), "{}", invalid);
+        }
+        let err = parse_upstream("http://api.internal:80").unwrap_err();
+        assert!(err.contains("\"http\""), "{}", err);
+        let err = parse_upstream("127.0.0.1:8080=heavy").unwrap_err();
+        assert!(err.contains("\"heavy\""), "{}", err);
+    }
+
+    fn forwarded_request(forwarded_for: &[&str]) -> http::Request<Vec<u8>> {
+        let mut request = http::Request::builder();
+        for value in forwarded_for {
+            request = request.header("x-forwarded-for", *value);
+        }
+        request.body(Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_forwarding_headers() {
+        let mut request = forwarded_request(&["2.2.2.2"]);
+        request
+            .headers_mut()
+            .insert("x-real-ip", http::HeaderValue::from_static("6.6.6.6"));
+        request
+            .headers_mut()
+            .insert("x-forwarded-port", http::HeaderValue::from_static("443"));
+        add_forwarding_headers(&mut request, "2.2.2.2".parse().unwrap(), "https", 1100);
+        let headers = request.headers();
+        // A spoofed X-Real-IP is replaced, while the other headers are added to
+        assert_eq!(headers.get_all("x-real-ip").iter().count(), 1);
+        assert_eq!(headers["x-real-ip"], "2.2.2.2");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-port"], "443, 1100");
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let trusted = vec![
+            Cidr::parse("10.0.0.0/8").unwrap(),
+            Cidr::parse("fd00::/8").unwrap(),
+        ];
+        let ip = |address: &str| address.parse::<IpAddr>().unwrap();
+        let proxy = ip("10.0.0.1");
+        let identify = |peer, forwarded_for: &[&str]| {
+            let request = forwarded_request(forwarded_for);
+            client_identity(peer, &request, &trusted, ForwardedHeader::XForwardedFor)
+        };
+
+        // Headers from untrusted peers are ignored
+        assert_eq!(identify(ip("1.1.1.1"), &["2.2.2.2"]), ip("1.1.1.1"));
+        assert_eq!(identify(proxy, &[]), proxy);
+        assert_eq!(identify(proxy, &["2.2.2.2"]), ip("2.2.2.2"));
+        // The nearest untrusted address wins, not whatever the client put at the left
+        assert_eq!(
+            identify(proxy, &["6.6.6.6, 2.2.2.2, 10.0.0.2"]),
+            ip("2.2.2.2")
+        );
+        assert_eq!(
+            identify(proxy, &["6.6.6.6, 2.2.2.2", "fd00::5"]),
+            ip("2.2.2.2")
+        );
+        assert_eq!(identify(proxy, &["10.0.0.3, 10.0.0.2"]), ip("10.0.0.3"));
+        assert_eq!(identify(proxy, &["2001:db8::1"]), ip("2001:db8::1"));
+        // Stop at anything that isn't an address
+        assert_eq!(identify(proxy, &["2.2.2.2, unknown"]), proxy);
+        assert_eq!(
+            identify(proxy, &["2.2.2.2, garbage, 10.0.0.2"]),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn test_client_identity_rfc7239() {
+        let trusted = vec![Cidr::parse("10.0.0.0/8").unwrap()];
+        let ip = |address: &str| address.parse::<IpAddr>().unwrap();
+        let proxy = ip("10.0.0.1");
+        let identify = |forwarded: &str| {
+            let request = http::Request::builder()
+                .header("x-forwarded-for", "3.3.3.3")
+                .header("forwarded", forwarded)
+                .body(Vec::new())
+                .unwrap();
+            client_identity(proxy, &request, &trusted, ForwardedHeader::Rfc7239)
+        };
+
+        assert_eq!(
+            identify("for=6.6.6.6, for=2.2.2.2;proto=http, for=\"10.0.0.2:80\""),
+            ip("2.2.2.2")
+        );
+        assert_eq!(
+            identify("for=\"[2001:db8::1]\";by=10.0.0.2"),
+            ip("2001:db8::1")
+        );
+        // Obfuscated identifiers hide the client, so the nearest proxy stands in for it
+        assert_eq!(identify("for=_hidden, for=10.0.0.2"), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_default_trusted_proxies() {
+        for cidr in DEFAULT_TRUSTED_PROXIES {
+            assert!(Cidr::parse(cidr).is_ok(), "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn test_parse_path_limit() {
+        assert_eq!(
+            parse_path_limit("/api/login=10"),
+            Ok(("/api/login".to_string(), 10))
+        );
+        assert_eq!(parse_path_limit("/=0"), Ok(("/".to_string(), 0)));
+        // Only the last = separates the limit
+        assert_eq!(parse_path_limit("/a=b=3"), Ok(("/a=b".to_string(), 3)));
+        for spec in &[
+            "api/login=10",
+            "/api/login",
+            "/api/login=",
+            "/api/login=-1",
+            "=10",
+        ] {
+            assert!(parse_path_limit(spec).is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn test_jittered_interval() {
+        let mut rng = StdRng::seed_from_u64(110);
+        assert_eq!(jittered_interval(10, 0, &mut rng), Duration::from_secs(10));
+        let intervals: Vec<Duration> = (0..1000)
+            .map(|_| jittered_interval(10, 20, &mut rng))
+            .collect();
+        for interval in &intervals {
+            assert!(
+                (Duration::from_secs(8)..=Duration::from_secs(12)).contains(interval),
+                "{:?}",
+                interval
+            );
+        }
+        // Spread over the whole range
+        assert!(intervals.iter().any(|&i| i < Duration::from_millis(8500)));
+        assert!(intervals.iter().any(|&i| i > Duration::from_millis(11500)));
+    }
+
+    #[test]
+    fn test_parse_status_codes() {
+        assert_eq!(parse_status_codes("200"), Ok(vec![200..=200]));
+        assert_eq!(
+            parse_status_codes("200-299,301, 302"),
+            Ok(vec![200..=299, 301..=301, 302..=302])
+        );
+        for invalid in &[
+            "",
+            "200,",
+            "ok",
+            "200-",
+            "299-200",
+            "99",
+            "600",
+            "200-299-300",
+        ] {
+            assert!(parse_status_codes(invalid).is_err(), "{}", invalid);
+        }
+        let err = parse_status_codes("200,2xx").unwrap_err();
+        assert!(err.contains("\"2xx\""), "{}", err);
     }
-    return true;
 }
@@ -0,0 +1,122 @@
+use crate::rate_limit::Cidr;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Answers every client request with a 503 while it is on (say during a deploy), apart from those
+/// from clients in `allow`. Requests already being handled are finished, and health checks carry
+/// on, so that the upstreams' health is up to date when it is turned off again.
+pub struct Maintenance {
+    on: AtomicBool,
+    /// Clients that are let through anyway, such as testers checking the deploy
+    allow: Vec<Cidr>,
+    /// The body of the 503, in place of the usual one, if --maintenance-page was given
+    page: Option<Vec<u8>>,
+    /// What to say in Retry-After, in seconds (0 = nothing)
+    retry_after: u64,
+}
+
+impl Maintenance {
+    pub fn new(on: bool, allow: Vec<Cidr>, page: Option<Vec<u8>>, retry_after: u64) -> Maintenance {
+        Maintenance {
+            on: AtomicBool::new(on),
+            allow,
+            page,
+            retry_after,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    /// Turns maintenance mode on or off, logging if that changes anything.
+    pub fn set(&self, on: bool) {
+        if self.on.swap(on, Ordering::Relaxed) != on {
+            log::info!("Maintenance mode {}", if on { "on" } else { "off" });
+        }
+    }
+
+    /// Whether a request from `client` is answered with the maintenance response, rather than
+    /// forwarded.
+    pub fn turns_away(&self, client: IpAddr) -> bool {
+        self.is_on() && !self.allow.iter().any(|cidr| cidr.contains(client))
+    }
+
+    /// The 503 that clients are turned away with.
+    pub fn response(&self) -> http::Response<Vec<u8>> {
+        let status = http::StatusCode::SERVICE_UNAVAILABLE;
+        let mut response = match &self.page {
+            Some(page) => http::Response::builder()
+                .status(status)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .header("Content-Length", page.len().to_string())
+                .version(http::Version::HTTP_11)
+                .body(page.clone())
+                .unwrap(),
+            None => crate::response::make_http_error_explained(
+                status,
+                "down for maintenance, try again later",
+            ),
+        };
+        if self.retry_after > 0 {
+            response
+                .headers_mut()
+                .insert("retry-after", http::HeaderValue::from(self.retry_after));
+        }
+        response
+    }
+}
+
+/// Toggles maintenance mode whenever balancebeam gets SIGUSR2.
+pub async fn toggle_on_signal(state: Arc<crate::ProxyState>) {
+    let mut toggle = match signal(SignalKind::user_defined2()) {
+        Ok(toggle) => toggle,
+        Err(err) => {
+            log::warn!("Can't toggle maintenance mode on SIGUSR2: {}", err);
+            return;
+        }
+    };
+    while toggle.recv().await.is_some() {
+        state.maintenance.set(!state.maintenance.is_on());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_turns_away() {
+        let allow = vec![Cidr::parse("10.0.0.0/8").unwrap()];
+        let maintenance = Maintenance::new(false, allow, None, 60);
+        assert!(!maintenance.turns_away(ip("203.0.113.7")));
+        maintenance.set(true);
+        assert!(maintenance.turns_away(ip("203.0.113.7")));
+        assert!(!maintenance.turns_away(ip("10.1.2.3")));
+        maintenance.set(false);
+        assert!(!maintenance.turns_away(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn test_response() {
+        let response = Maintenance::new(true, Vec::new(), None, 60).response();
+        assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "60");
+
+        let page = b"<h1>Back soon</h1>".to_vec();
+        let response = Maintenance::new(true, Vec::new(), Some(page.clone()), 0).response();
+        assert_eq!(response.body(), &page);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.headers()["content-length"], "18");
+        assert!(!response.headers().contains_key("retry-after"));
+    }
+}
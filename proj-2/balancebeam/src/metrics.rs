@@ -0,0 +1,436 @@
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// The upper bounds of the upstream response time histogram's buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The upstream label of responses balancebeam made itself.
+pub const NO_UPSTREAM: &str = "";
+
+/// How many upstream responses took how long.
+struct Histogram {
+    /// How many responses fell in each of LATENCY_BUCKETS (but not an earlier one)
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+/// Counters exported in the Prometheus text format. The metric names are part of balancebeam's
+/// interface, so that dashboards built against them keep working: don't rename them.
+#[derive(Default)]
+pub struct Metrics {
+    /// Responses sent to clients, by upstream and status class
+    responses: Mutex<BTreeMap<(String, &'static str), u64>>,
+    /// Failed connection attempts, by upstream
+    connect_failures: Mutex<BTreeMap<String, u64>>,
+    /// Changes of health found by active health checks, by upstream and new health
+    health_changes: Mutex<BTreeMap<(String, bool), u64>>,
+    /// Requests routed to each group of upstreams
+    group_requests: Mutex<BTreeMap<String, u64>>,
+    /// Copies of requests sent to the --mirror-upstream, by how they went
+    mirrored_requests: Mutex<BTreeMap<&'static str, u64>>,
+    /// Requests that could be answered from the cache, by whether they were
+    cache_requests: Mutex<BTreeMap<&'static str, u64>>,
+    latency: Mutex<Histogram>,
+}
+
+/// Gauges, which are read from the proxy's state rather than counted.
+pub struct Gauges {
+    pub open_connections: usize,
+    pub upstreams: usize,
+    pub healthy_upstreams: usize,
+    pub rate_limited_requests: u64,
+}
+
+fn status_class(status: http::StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+/// Escapes a label value, as the text format requires.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Counts a response sent to a client by `upstream`, or NO_UPSTREAM.
+    pub fn record_response(&self, upstream: &str, status: http::StatusCode) {
+        let key = (upstream.to_string(), status_class(status));
+        *self.responses.lock().entry(key).or_insert(0) += 1;
+    }
+
+    /// Counts a response from an upstream that took `latency`.
+    pub fn record_latency(&self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let mut histogram = self.latency.lock();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    pub fn record_connect_failure(&self, upstream: &str) {
+        *self
+            .connect_failures
+            .lock()
+            .entry(upstream.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Counts an active health check finding that `upstream` has become healthy or dead.
+    pub fn record_group_request(&self, group: &str) {
+        *self
+            .group_requests
+            .lock()
+            .entry(group.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// How many requests have been routed to `group`.
+    pub fn group_requests(&self, group: &str) -> u64 {
+        self.group_requests.lock().get(group).copied().unwrap_or(0)
+    }
+
+    pub fn record_mirror(&self, result: &'static str) {
+        *self.mirrored_requests.lock().entry(result).or_insert(0) += 1;
+    }
+
+    /// Counts a request that was answered from the cache ("hit"), or that could have been but
+    /// wasn't cached ("miss").
+    pub fn record_cache(&self, result: &'static str) {
+        *self.cache_requests.lock().entry(result).or_insert(0) += 1;
+    }
+
+    pub fn record_health_change(&self, upstream: &str, healthy: bool) {
+        *self
+            .health_changes
+            .lock()
+            .entry((upstream.to_string(), healthy))
+            .or_insert(0) += 1;
+    }
+
+    /// Writes out every metric in the Prometheus text exposition format.
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "balancebeam_requests_total",
+            "counter",
+            "Responses sent to clients, by the upstream that sent them (empty if balancebeam made \
+             the response itself) and status class.",
+        );
+        for ((upstream, class), count) in self.responses.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_requests_total{{upstream=\"{}\",class=\"{}\"}} {}",
+                escape_label(upstream),
+                class,
+                count
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_upstream_response_seconds",
+            "histogram",
+            "How long upstreams took to respond to forwarded requests.",
+        );
+        let histogram = self.latency.lock();
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+            cumulative += count;
+            writeln!(
+                out,
+                "balancebeam_upstream_response_seconds_bucket{{le=\"{}\"}} {}",
+                le, cumulative
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "balancebeam_upstream_response_seconds_bucket{{le=\"+Inf\"}} {}",
+            histogram.count
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "balancebeam_upstream_response_seconds_sum {}",
+            histogram.sum
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "balancebeam_upstream_response_seconds_count {}",
+            histogram.count
+        )
+        .unwrap();
+        drop(histogram);
+
+        write_header(
+            &mut out,
+            "balancebeam_upstream_connect_failures_total",
+            "counter",
+            "Failed attempts to connect to upstreams for client requests.",
+        );
+        for (upstream, count) in self.connect_failures.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_upstream_connect_failures_total{{upstream=\"{}\"}} {}",
+                escape_label(upstream),
+                count
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_upstream_health_changes_total",
+            "counter",
+            "Times an active health check found an upstream had become healthy or dead.",
+        );
+        for ((upstream, healthy), count) in self.health_changes.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_upstream_health_changes_total{{upstream=\"{}\",healthy=\"{}\"}} {}",
+                escape_label(upstream),
+                healthy,
+                count
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_group_requests_total",
+            "counter",
+            "Requests routed to each group of upstreams (after any canary split).",
+        );
+        for (group, count) in self.group_requests.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_group_requests_total{{group=\"{}\"}} {}",
+                escape_label(group),
+                count
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_mirrored_requests_total",
+            "counter",
+            "Copies of requests for the shadow upstream, by whether it responded (sent), didn't \
+             (failed), or too many were in flight to send them (dropped).",
+        );
+        for (result, count) in self.mirrored_requests.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_mirrored_requests_total{{result=\"{}\"}} {}",
+                result, count
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_cache_requests_total",
+            "counter",
+            "Requests that could be answered from the cache, by whether they were (hit) or had to \
+             go to an upstream (miss).",
+        );
+        for (result, count) in self.cache_requests.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_cache_requests_total{{result=\"{}\"}} {}",
+                result, count
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_rate_limited_requests_total",
+            "counter",
+            "Requests refused for being over the rate limit.",
+        );
+        writeln!(
+            out,
+            "balancebeam_rate_limited_requests_total {}",
+            gauges.rate_limited_requests
+        )
+        .unwrap();
+
+        write_header(
+            &mut out,
+            "balancebeam_upstreams",
+            "gauge",
+            "Upstreams configured.",
+        );
+        writeln!(out, "balancebeam_upstreams {}", gauges.upstreams).unwrap();
+        write_header(
+            &mut out,
+            "balancebeam_healthy_upstreams",
+            "gauge",
+            "Upstreams that are currently healthy.",
+        );
+        writeln!(
+            out,
+            "balancebeam_healthy_upstreams {}",
+            gauges.healthy_upstreams
+        )
+        .unwrap();
+        write_header(
+            &mut out,
+            "balancebeam_open_connections",
+            "gauge",
+            "Client connections currently being handled.",
+        );
+        writeln!(
+            out,
+            "balancebeam_open_connections {}",
+            gauges.open_connections
+        )
+        .unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gauges() -> Gauges {
+        Gauges {
+            open_connections: 2,
+            upstreams: 3,
+            healthy_upstreams: 1,
+            rate_limited_requests: 4,
+        }
+    }
+
+    #[test]
+    fn test_responses() {
+        let metrics = Metrics::new();
+        metrics.record_response("127.0.0.1:8000", http::StatusCode::OK);
+        metrics.record_response("127.0.0.1:8000", http::StatusCode::NO_CONTENT);
+        metrics.record_response("127.0.0.1:8000", http::StatusCode::NOT_FOUND);
+        metrics.record_response(NO_UPSTREAM, http::StatusCode::BAD_GATEWAY);
+        let text = metrics.render(&gauges());
+        assert!(text.contains("balancebeam_requests_total{upstream=\"\",class=\"5xx\"} 1\n"));
+        assert!(text
+            .contains("balancebeam_requests_total{upstream=\"127.0.0.1:8000\",class=\"2xx\"} 2\n"));
+        assert!(text
+            .contains("balancebeam_requests_total{upstream=\"127.0.0.1:8000\",class=\"4xx\"} 1\n"));
+        assert!(text.contains("# TYPE balancebeam_requests_total counter\n"));
+    }
+
+    #[test]
+    fn test_group_requests() {
+        let metrics = Metrics::new();
+        metrics.record_group_request("default");
+        metrics.record_group_request("default");
+        metrics.record_group_request("canary");
+        assert_eq!(metrics.group_requests("default"), 2);
+        assert_eq!(metrics.group_requests("api"), 0);
+        let text = metrics.render(&gauges());
+        assert!(text.contains("balancebeam_group_requests_total{group=\"canary\"} 1\n"));
+        assert!(text.contains("balancebeam_group_requests_total{group=\"default\"} 2\n"));
+    }
+
+    #[test]
+    fn test_mirrored_requests() {
+        let metrics = Metrics::new();
+        metrics.record_mirror("sent");
+        metrics.record_mirror("sent");
+        metrics.record_mirror("dropped");
+        let text = metrics.render(&gauges());
+        assert!(text.contains("balancebeam_mirrored_requests_total{result=\"dropped\"} 1\n"));
+        assert!(text.contains("balancebeam_mirrored_requests_total{result=\"sent\"} 2\n"));
+    }
+
+    #[test]
+    fn test_cache_requests() {
+        let metrics = Metrics::new();
+        metrics.record_cache("miss");
+        metrics.record_cache("hit");
+        metrics.record_cache("hit");
+        let text = metrics.render(&gauges());
+        assert!(text.contains("balancebeam_cache_requests_total{result=\"hit\"} 2\n"));
+        assert!(text.contains("balancebeam_cache_requests_total{result=\"miss\"} 1\n"));
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let metrics = Metrics::new();
+        metrics.record_latency(Duration::from_millis(3));
+        metrics.record_latency(Duration::from_millis(30));
+        metrics.record_latency(Duration::from_secs(60));
+        let text = metrics.render(&gauges());
+        // Buckets count every response up to their bound
+        assert!(text.contains("balancebeam_upstream_response_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("balancebeam_upstream_response_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(text.contains("balancebeam_upstream_response_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(text.contains("balancebeam_upstream_response_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(text.contains("balancebeam_upstream_response_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("balancebeam_upstream_response_seconds_sum 60.033\n"));
+        assert!(text.contains("balancebeam_upstream_response_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn test_gauges() {
+        let metrics = Metrics::new();
+        metrics.record_connect_failure("127.0.0.1:8000");
+        metrics.record_health_change("127.0.0.1:8000", false);
+        let text = metrics.render(&gauges());
+        assert!(text.contains(
+            "balancebeam_upstream_connect_failures_total{upstream=\"127.0.0.1:8000\"} 1\n"
+        ));
+        assert!(text.contains(
+            "balancebeam_upstream_health_changes_total{upstream=\"127.0.0.1:8000\",\
+             healthy=\"false\"} 1\n"
+        ));
+        assert!(text.contains("balancebeam_rate_limited_requests_total 4\n"));
+        assert!(text.contains("balancebeam_upstreams 3\n"));
+        assert!(text.contains("balancebeam_healthy_upstreams 1\n"));
+        assert!(text.contains("balancebeam_open_connections 2\n"));
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
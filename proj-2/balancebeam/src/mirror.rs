@@ -0,0 +1,261 @@
+use crate::{read_upstream_response, request, response, stream, ProxyState};
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// The header that tells the shadow upstream a request is a copy, whose response nobody will see.
+pub const HEADER: &str = "x-shadow";
+
+/// The most copies to have on their way to the shadow upstream at once. Any more are dropped, so
+/// that a slow shadow can't build up tasks and connections without bound.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// How long a copy can take altogether, from connecting to the end of the response's body, when
+/// there's no --upstream-response-timeout to go by. Without a limit, a shadow that stalls partway
+/// through its responses would keep every place among the copies in flight, and nothing more would
+/// be mirrored.
+const DEADLINE: Duration = Duration::from_secs(60);
+
+/// How a mirrored request went, as the metrics count it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// The shadow upstream responded
+    Sent,
+    /// It couldn't be connected to, or didn't respond in time (or properly)
+    Failed,
+    /// Too many copies were in flight already
+    Dropped,
+}
+
+impl Outcome {
+    pub fn label(self) -> &'static str {
+        match self {
+            Outcome::Sent => "sent",
+            Outcome::Failed => "failed",
+            Outcome::Dropped => "dropped",
+        }
+    }
+}
+
+/// Sends copies of some of the requests forwarded to upstreams to a shadow upstream too, so that it
+/// can be tried out under real traffic. Its responses are read and thrown away, and nothing about
+/// it (not even how long it takes) affects what clients get back.
+pub struct Mirror {
+    /// Where the copies go, written as for --upstream
+    pub address: String,
+    /// The fraction of requests to copy, from 0 to 1
+    fraction: f64,
+    /// The biggest body a request can have to be copied. Only bodies read in full before they are
+    /// forwarded can be copied, so a streamed body never is, whatever this says.
+    max_body_size: usize,
+    in_flight: Arc<Semaphore>,
+    rng: Mutex<StdRng>,
+}
+
+impl Mirror {
+    pub fn new(address: String, percentage: f64, max_body_size: usize) -> Mirror {
+        Mirror {
+            address,
+            fraction: percentage / 100.0,
+            max_body_size,
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    /// Whether to copy `request`, which has `unread_body` bytes of its body still to be read from
+    /// the client: it has to have been read in full, and be picked for the percentage.
+    pub fn wants(&self, request: &http::Request<Vec<u8>>, unread_body: usize) -> bool {
+        unread_body == 0
+            && request.body().len() <= self.max_body_size
+            && self.rng.lock().gen_bool(self.fraction)
+    }
+
+    /// A place among the copies in flight, or None if there are too many already.
+    pub fn permit(&self) -> Option<OwnedSemaphorePermit> {
+        self.in_flight.clone().try_acquire_owned().ok()
+    }
+}
+
+/// Copies a request (as it is being forwarded, body and all) to be sent to the shadow upstream,
+/// marked with HEADER. The shadow upstream is asked to close the connection after responding, since
+/// it is never used again.
+pub fn copy(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut copy = http::Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .version(request.version())
+        .body(request.body().clone())
+        .unwrap();
+    *copy.headers_mut() = request.headers().clone();
+    let headers = copy.headers_mut();
+    headers.insert(HEADER, http::HeaderValue::from_static("true"));
+    headers.insert("connection", http::HeaderValue::from_static("close"));
+    copy
+}
+
+/// Sends a copy of a request to the shadow upstream in the background (see send), unless too many
+/// copies are on their way already, counting how it went in the metrics. The copy fails if it
+/// isn't done within the upstream response timeout (or DEADLINE, without one).
+pub fn spawn(
+    state: &Arc<ProxyState>,
+    request: http::Request<Vec<u8>>,
+    proxy_header: Option<String>,
+) {
+    let mirror = match &state.mirror {
+        Some(mirror) => mirror,
+        None => return,
+    };
+    let permit = match mirror.permit() {
+        Some(permit) => permit,
+        None => {
+            state.metrics.record_mirror(Outcome::Dropped.label());
+            return;
+        }
+    };
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Some(mirror) = &state.mirror {
+            let deadline = state.upstream_response_timeout.unwrap_or(DEADLINE);
+            let send = send(&state, mirror, &request, proxy_header.as_deref());
+            let outcome = match tokio::time::timeout(deadline, send).await {
+                Ok(outcome) => outcome,
+                Err(_) => {
+                    log::debug!(
+                        "Shadow upstream {} didn't finish responding in time",
+                        mirror.address
+                    );
+                    Outcome::Failed
+                }
+            };
+            state.metrics.record_mirror(outcome.label());
+        }
+        drop(permit);
+    });
+}
+
+/// Sends `request` (a copy) to the shadow upstream, starting with `proxy_header` if there is one,
+/// and reads its response to the end.
+async fn send(
+    state: &ProxyState,
+    mirror: &Mirror,
+    request: &http::Request<Vec<u8>>,
+    proxy_header: Option<&str>,
+) -> Outcome {
+    let address = mirror.address.as_str();
+    let connect = stream::connect(
+        address,
+        address,
+        state.upstream_connect_timeout,
+        &state.upstream_tls,
+        proxy_header,
+    );
+    let mut conn = match connect.await {
+        Ok(conn) => conn,
+        Err(error) => {
+            log::debug!(
+                "Failed to connect to shadow upstream {}: {}",
+                address,
+                error
+            );
+            return Outcome::Failed;
+        }
+    };
+    let default_host = stream::unix_path(address).map(|_| state.unix_socket_host.as_str());
+    if let Err(error) = request::write_to_stream(request, default_host, &mut conn).await {
+        log::debug!(
+            "Failed to send request to shadow upstream {}: {}",
+            address,
+            error
+        );
+        return Outcome::Failed;
+    }
+    let read = read_upstream_response(
+        &mut conn,
+        request.method(),
+        state.upstream_response_timeout,
+        state.header_limits,
+        state.max_response_body_size,
+    );
+    let response = match read.await {
+        Ok(Ok(response)) => response,
+        Ok(Err(error)) => {
+            log::debug!(
+                "Error reading response from shadow upstream {}: {}",
+                address,
+                error
+            );
+            return Outcome::Failed;
+        }
+        Err(_) => {
+            log::debug!("Shadow upstream {} didn't respond in time", address);
+            return Outcome::Failed;
+        }
+    };
+    let (_, relayed) = response::relay(
+        &response,
+        &mut conn,
+        &mut tokio::io::sink(),
+        http::Version::HTTP_11,
+        state.max_response_body_size,
+    )
+    .await;
+    match relayed {
+        Ok(()) => {
+            log::debug!(
+                "Shadow upstream {} answered {}",
+                address,
+                response::format_response_line(&response)
+            );
+            Outcome::Sent
+        }
+        Err(error) => {
+            log::debug!(
+                "Error reading response from shadow upstream {}: {}",
+                address,
+                error
+            );
+            Outcome::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_copy() {
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/items?id=1")
+            .header("host", "example.com")
+            .header("content-length", "5")
+            .body(b"hello".to_vec())
+            .unwrap();
+        let copied = copy(&request);
+        assert_eq!(copied.method(), request.method());
+        assert_eq!(copied.uri(), request.uri());
+        assert_eq!(copied.body(), request.body());
+        assert_eq!(copied.headers()["host"], "example.com");
+        assert_eq!(copied.headers()[HEADER], "true");
+        assert_eq!(copied.headers()["connection"], "close");
+        assert!(!request.headers().contains_key(HEADER));
+    }
+
+    #[test]
+    fn test_wants() {
+        let small = http::Request::new(vec![0; 10]);
+        let big = http::Request::new(vec![0; 11]);
+        let all = Mirror::new("127.0.0.1:8000".to_string(), 100.0, 10);
+        assert!(all.wants(&small, 0));
+        assert!(!all.wants(&big, 0));
+        // A body still being streamed can't be copied
+        assert!(!all.wants(&small, 100));
+        let none = Mirror::new("127.0.0.1:8000".to_string(), 0.0, 10);
+        assert!(!none.wants(&small, 0));
+    }
+}
@@ -0,0 +1,167 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Keeps connections to upstreams open once their clients are done with them, so that later
+/// clients can use them rather than opening new ones. Connections are kept by the address of the
+/// upstream they go to, for up to `idle_timeout`, and no more than `max_per_upstream` at a time for
+/// each upstream.
+pub struct Pool<T> {
+    /// Idle connections kept per upstream, or 0 to keep none
+    max_per_upstream: usize,
+    idle_timeout: Duration,
+    /// The idle connections to each upstream, oldest first, with when each was returned.
+    /// Upstreams with none aren't in the map.
+    idle: Mutex<HashMap<String, Vec<(T, Instant)>>>,
+    /// How many upstream connections have been used, and how many of those came from the pool
+    uses: AtomicU64,
+    reuses: AtomicU64,
+}
+
+impl<T> Pool<T> {
+    pub fn new(max_per_upstream: usize, idle_timeout: Duration) -> Pool<T> {
+        Pool {
+            max_per_upstream,
+            idle_timeout,
+            idle: Mutex::new(HashMap::new()),
+            uses: AtomicU64::new(0),
+            reuses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_per_upstream > 0
+    }
+
+    /// How long a connection is kept once it is returned.
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Takes the connection to `address` that was returned most recently out of the pool, if it
+    /// has one that hasn't been idle for too long at `now`.
+    pub fn check_out_at(&self, address: &str, now: Instant) -> Option<T> {
+        let mut idle = self.idle.lock();
+        let connections = idle.get_mut(address)?;
+        let connection = match connections.pop() {
+            // Any older ones have been idle for even longer
+            Some((_, returned)) if now.duration_since(returned) >= self.idle_timeout => None,
+            Some((connection, _)) => Some(connection),
+            None => None,
+        };
+        if connection.is_none() {
+            connections.clear();
+        }
+        if connections.is_empty() {
+            idle.remove(address);
+        }
+        connection
+    }
+
+    /// Puts a connection to `address` in the pool at `now`, closing the oldest idle connection to
+    /// the same upstream if there are already as many as can be kept (or closing this one, if none
+    /// can be).
+    pub fn check_in_at(&self, address: &str, connection: T, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut idle = self.idle.lock();
+        let connections = idle.entry(address.to_string()).or_default();
+        if connections.len() >= self.max_per_upstream {
+            connections.remove(0);
+        }
+        connections.push((connection, now));
+    }
+
+    /// Closes the connections that have been idle for too long at `now`.
+    pub fn remove_expired_at(&self, now: Instant) {
+        let idle_timeout = self.idle_timeout;
+        self.idle.lock().retain(|_, connections| {
+            connections.retain(|(_, returned)| now.duration_since(*returned) < idle_timeout);
+            !connections.is_empty()
+        });
+    }
+
+    /// Counts an upstream connection being used, which either came from the pool or was opened
+    /// for the purpose, returning the percentage of them so far that came from the pool.
+    pub fn record_use(&self, reused: bool) -> f64 {
+        let uses = self.uses.fetch_add(1, Ordering::Relaxed) + 1;
+        let reuses = self.reuses.fetch_add(reused as u64, Ordering::Relaxed) + reused as u64;
+        reuses as f64 * 100.0 / uses as f64
+    }
+
+    /// How many idle connections to `address` are in the pool.
+    #[cfg(test)]
+    fn idle_for(&self, address: &str) -> usize {
+        self.idle.lock().get(address).map_or(0, Vec::len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn test_reuses_latest() {
+        let pool = Pool::new(2, IDLE_TIMEOUT);
+        let now = Instant::now();
+        assert_eq!(pool.check_out_at("a:80", now), None);
+        pool.check_in_at("a:80", 1, now);
+        pool.check_in_at("a:80", 2, now + Duration::from_secs(1));
+        // Each upstream has its own connections
+        pool.check_in_at("b:80", 3, now);
+        let later = now + Duration::from_secs(2);
+        assert_eq!(pool.check_out_at("a:80", later), Some(2));
+        assert_eq!(pool.check_out_at("a:80", later), Some(1));
+        assert_eq!(pool.check_out_at("a:80", later), None);
+        assert_eq!(pool.idle_for("b:80"), 1);
+    }
+
+    #[test]
+    fn test_max_per_upstream() {
+        let pool = Pool::new(2, IDLE_TIMEOUT);
+        let now = Instant::now();
+        for connection in 1..=3 {
+            pool.check_in_at("a:80", connection, now);
+        }
+        // The oldest is closed to make room
+        assert_eq!(pool.idle_for("a:80"), 2);
+        assert_eq!(pool.check_out_at("a:80", now), Some(3));
+        assert_eq!(pool.check_out_at("a:80", now), Some(2));
+        assert_eq!(pool.check_out_at("a:80", now), None);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let pool = Pool::new(4, IDLE_TIMEOUT);
+        let now = Instant::now();
+        pool.check_in_at("a:80", 1, now);
+        pool.check_in_at("a:80", 2, now + Duration::from_secs(10));
+        pool.check_in_at("b:80", 3, now);
+        pool.remove_expired_at(now + IDLE_TIMEOUT);
+        assert_eq!(pool.idle_for("a:80"), 1);
+        assert_eq!(pool.idle_for("b:80"), 0);
+        assert_eq!(pool.check_out_at("a:80", now + IDLE_TIMEOUT * 2), None);
+        assert_eq!(pool.idle_for("a:80"), 0);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let pool = Pool::new(0, IDLE_TIMEOUT);
+        let now = Instant::now();
+        pool.check_in_at("a:80", 1, now);
+        assert_eq!(pool.check_out_at("a:80", now), None);
+    }
+
+    #[test]
+    fn test_reuse_rate() {
+        let pool = Pool::<()>::new(1, IDLE_TIMEOUT);
+        assert_eq!(pool.record_use(false), 0.0);
+        assert_eq!(pool.record_use(true), 50.0);
+        assert_eq!(pool.record_use(true), 200.0 / 3.0);
+        assert_eq!(pool.record_use(true), 75.0);
+    }
+}
@@ -0,0 +1,207 @@
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, Duration};
+
+/// What every PROXY protocol (version 1) header starts with.
+const SIGNATURE: &[u8] = b"PROXY ";
+
+/// The most a header can take up, including the CRLF at the end.
+const MAX_HEADER_LEN: usize = 107;
+
+/// The header balancebeam sends with connections it makes for itself (health checks), which have
+/// no client to tell the upstream about.
+pub const UNKNOWN_HEADER: &str = "PROXY UNKNOWN\r\n";
+
+/// Whether clients' connections have to start with a PROXY protocol header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Accept {
+    /// Headers aren't expected, and would be taken as (malformed) requests
+    Off,
+    /// Connections without a header are closed, as the spec says they should be
+    Required,
+    /// Connections without a header are taken to be straight from the client
+    Optional,
+}
+
+/// What a PROXY protocol header says about the connection it starts.
+#[derive(Debug, PartialEq)]
+pub enum Header {
+    /// The proxy's client connected from `source` to `destination`
+    Tcp {
+        source: SocketAddr,
+        destination: SocketAddr,
+    },
+    /// The proxy didn't say (as for its own health checks), so the connection is taken to be from
+    /// the proxy itself
+    Unknown,
+}
+
+/// Parses a header line, without its CRLF: `PROXY TCP4|TCP6 source destination source-port
+/// destination-port`, or `PROXY UNKNOWN` followed by anything.
+pub fn parse(line: &[u8]) -> Result<Header, String> {
+    let line = std::str::from_utf8(line).map_err(|_| "the header isn't ASCII".to_string())?;
+    let invalid = || {
+        format!(
+            "expected PROXY TCP4|TCP6 source destination source-port destination-port, got {:?}",
+            line
+        )
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    let (family, addresses) = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => return Ok(Header::Unknown),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            (
+                family,
+                [(source, source_port), (destination, destination_port)],
+            )
+        }
+        _ => return Err(invalid()),
+    };
+    let mut parsed = Vec::new();
+    for (ip, port) in addresses.iter() {
+        let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+        if ip.is_ipv4() != (family == "TCP4") {
+            return Err(invalid());
+        }
+        let port: u16 = port.parse().map_err(|_| invalid())?;
+        parsed.push(SocketAddr::new(ip, port));
+    }
+    Ok(Header::Tcp {
+        source: parsed[0],
+        destination: parsed[1],
+    })
+}
+
+/// Reads the PROXY protocol header at the start of `stream`, returning None (and leaving what was
+/// sent to be read) if it doesn't start with one. A malformed header is an InvalidData error.
+pub async fn read_header(stream: &mut TcpStream) -> std::io::Result<Option<Header>> {
+    // The start of the connection is only looked at until it's clear whether it's a header, since
+    // it may be the start of a request instead
+    let mut start = [0_u8; SIGNATURE.len()];
+    loop {
+        let peeked = stream.peek(&mut start).await?;
+        if peeked == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if start[..peeked] != SIGNATURE[..peeked] {
+            return Ok(None);
+        }
+        if peeked == SIGNATURE.len() {
+            break;
+        }
+        // Peeking again straight away would just see the same bytes
+        delay_for(Duration::from_millis(10)).await;
+    }
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_HEADER_LEN {
+            return Err(invalid(format!(
+                "the header is longer than {} bytes",
+                MAX_HEADER_LEN
+            )));
+        }
+        let mut byte = [0_u8; 1];
+        if stream.read(&mut byte).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        line.push(byte[0]);
+    }
+    parse(&line[..line.len() - 2]).map(Some).map_err(invalid)
+}
+
+/// Makes the header that tells an upstream a connection is from `source` to `destination`. If only
+/// one of them is IPv6, the other is written as an IPv4-mapped IPv6 address, since both have to be
+/// the same family.
+pub fn header(source: SocketAddr, destination: SocketAddr) -> String {
+    let (family, source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            ("TCP4", IpAddr::V4(source), IpAddr::V4(destination))
+        }
+        (source, destination) => ("TCP6", to_ipv6(source), to_ipv6(destination)),
+    };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        source_ip,
+        destination_ip,
+        source.port(),
+        destination.port()
+    )
+}
+
+fn to_ipv6(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V6(ip.to_ipv6_mapped()),
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443"),
+            Ok(Header::Tcp {
+                source: "203.0.113.7:56324".parse().unwrap(),
+                destination: "10.0.0.1:443".parse().unwrap(),
+            })
+        );
+        assert_eq!(
+            parse(b"PROXY TCP6 2001:db8::7 ::1 56324 443"),
+            Ok(Header::Tcp {
+                source: "[2001:db8::7]:56324".parse().unwrap(),
+                destination: "[::1]:443".parse().unwrap(),
+            })
+        );
+        assert_eq!(parse(b"PROXY UNKNOWN"), Ok(Header::Unknown));
+        assert_eq!(
+            parse(b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535"),
+            Ok(Header::Unknown)
+        );
+        for invalid in [
+            &b"PROXY TCP4 203.0.113.7 10.0.0.1 56324"[..],
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443 extra",
+            b"PROXY TCP4 2001:db8::7 10.0.0.1 56324 443",
+            b"PROXY TCP6 203.0.113.7 ::1 56324 443",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 70000",
+            b"PROXY UDP4 203.0.113.7 10.0.0.1 56324 443",
+            b"PROXY  TCP4 203.0.113.7 10.0.0.1 56324 443",
+            b"GET / HTTP/1.1",
+            b"PROXY TCP4 \xff 10.0.0.1 56324 443",
+        ] {
+            assert!(parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let header = |source: &str, destination: &str| {
+            super::header(source.parse().unwrap(), destination.parse().unwrap())
+        };
+        assert_eq!(
+            header("203.0.113.7:56324", "10.0.0.1:443"),
+            "PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\n"
+        );
+        assert_eq!(
+            header("[2001:db8::7]:56324", "[::1]:443"),
+            "PROXY TCP6 2001:db8::7 ::1 56324 443\r\n"
+        );
+        assert_eq!(
+            header("203.0.113.7:56324", "[::1]:443"),
+            "PROXY TCP6 ::ffff:203.0.113.7 ::1 56324 443\r\n"
+        );
+        let sent = header("203.0.113.7:56324", "10.0.0.1:443");
+        assert_eq!(
+            parse(sent.trim_end().as_bytes()),
+            Ok(Header::Tcp {
+                source: "203.0.113.7:56324".parse().unwrap(),
+                destination: "10.0.0.1:443".parse().unwrap(),
+            })
+        );
+    }
+}
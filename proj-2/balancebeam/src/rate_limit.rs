@@ -0,0 +1,777 @@
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How far back a client's requests count against its limit.
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+/// The names accepted by `--rate-limit-algorithm`.
+pub const ALGORITHM_NAMES: &[&str] = &["sliding-window", "token-bucket"];
+
+/// A block of IP addresses, such as 10.0.0.0/8 or fd00::/8.
+#[derive(Debug, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    /// Parses a block written as address/prefix-length. A bare address is a block of just that
+    /// address.
+    pub fn parse(spec: &str) -> Result<Cidr, String> {
+        let spec = spec.trim();
+        let invalid = || format!("Invalid CIDR block {:?}", spec);
+        let (address, prefix_len) = match spec.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (spec, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+
+    /// Whether `ip` is in this block. IPv4 addresses are never in IPv6 blocks, or vice versa.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Where a client stands against the limit that applies to a request.
+#[derive(Debug, PartialEq)]
+pub struct Quota {
+    /// Whether the request is allowed
+    pub allowed: bool,
+    /// Requests allowed per minute
+    pub limit: usize,
+    /// How many more requests the client could make straight away
+    pub remaining: usize,
+    /// How long until the client gets another request back
+    pub reset: Duration,
+}
+
+impl Quota {
+    /// Whole seconds until the client can make another request, for a Retry-After header.
+    pub fn retry_after_secs(&self) -> u64 {
+        self.reset.as_secs_f64().ceil() as u64
+    }
+
+    /// Adds X-RateLimit-Limit and X-RateLimit-Remaining headers to `response`.
+    pub fn add_headers<B>(&self, response: &mut http::Response<B>) {
+        let headers = response.headers_mut();
+        headers.insert("x-ratelimit-limit", http::HeaderValue::from(self.limit));
+        headers.insert(
+            "x-ratelimit-remaining",
+            http::HeaderValue::from(self.remaining),
+        );
+    }
+}
+
+/// A client's token bucket, as of the last time it was topped up.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// What is tracked for each client, depending on the algorithm.
+enum Clients {
+    /// When each client's allowed requests in the last window were made, oldest first. Refused
+    /// requests aren't recorded, so each holds at most max_requests entries.
+    SlidingWindow(HashMap<IpAddr, VecDeque<Instant>>),
+    /// Each client's bucket, which holds up to `burst` tokens and refills at the per-minute limit
+    TokenBucket {
+        burst: f64,
+        buckets: HashMap<IpAddr, Bucket>,
+    },
+}
+
+/// Which algorithm each limit uses.
+#[derive(Clone, Copy)]
+enum Algorithm {
+    SlidingWindow,
+    /// Buckets hold up to `burst` tokens, or a minute's worth if it's 0
+    TokenBucket {
+        burst: usize,
+    },
+}
+
+/// One per-minute limit, and the requests each client has made against it.
+struct Limit {
+    /// Requests allowed per minute, or 0 for no limit
+    max_requests: usize,
+    clients: Mutex<Clients>,
+}
+
+/// Drops the times that are a full window or more before `now`.
+fn prune(times: &mut VecDeque<Instant>, now: Instant) {
+    while times
+        .front()
+        .is_some_and(|&time| now.duration_since(time) >= WINDOW)
+    {
+        times.pop_front();
+    }
+}
+
+impl Bucket {
+    /// Adds the tokens earned since the bucket was last updated, at `rate` per second.
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let earned = now.duration_since(self.updated).as_secs_f64() * rate;
+        self.tokens = (self.tokens + earned).min(burst);
+        self.updated = now;
+    }
+}
+
+impl Limit {
+    fn new(algorithm: Algorithm, max_requests: usize) -> Limit {
+        let clients = match algorithm {
+            Algorithm::SlidingWindow => Clients::SlidingWindow(HashMap::new()),
+            Algorithm::TokenBucket { burst } => Clients::TokenBucket {
+                burst: if burst == 0 { max_requests } else { burst } as f64,
+                buckets: HashMap::new(),
+            },
+        };
+        Limit {
+            max_requests,
+            clients: Mutex::new(clients),
+        }
+    }
+
+    /// Tokens added to each bucket per second.
+    fn refill_rate(&self) -> f64 {
+        self.max_requests as f64 / WINDOW.as_secs_f64()
+    }
+
+    /// Counts a request from `client`, or returns None if there is no limit.
+    fn check_at(&self, client: IpAddr, now: Instant) -> Option<Quota> {
+        if self.max_requests == 0 {
+            return None;
+        }
+        let quota = match &mut *self.clients.lock() {
+            Clients::SlidingWindow(recent) => {
+                let times = recent.entry(client).or_default();
+                prune(times, now);
+                let allowed = times.len() < self.max_requests;
+                if allowed {
+                    times.push_back(now);
+                    log::debug!(
+                        "{} requests from {} in the last minute",
+                        times.len(),
+                        client
+                    );
+                }
+                // A request comes back once the oldest one in the window leaves it
+                let oldest = *times.front().unwrap();
+                Quota {
+                    allowed,
+                    limit: self.max_requests,
+                    remaining: self.max_requests - times.len(),
+                    reset: WINDOW - now.duration_since(oldest),
+                }
+            }
+            Clients::TokenBucket { burst, buckets } => {
+                // A client's first bucket starts full
+                let bucket = buckets.entry(client).or_insert(Bucket {
+                    tokens: *burst,
+                    updated: now,
+                });
+                let rate = self.refill_rate();
+                bucket.refill(now, rate, *burst);
+                let allowed = bucket.tokens >= 1.0;
+                if allowed {
+                    bucket.tokens -= 1.0;
+                    log::debug!("{:.1} tokens left for {}", bucket.tokens, client);
+                }
+                Quota {
+                    allowed,
+                    limit: self.max_requests,
+                    remaining: bucket.tokens as usize,
+                    reset: Duration::from_secs_f64((1.0 - bucket.tokens.fract()) / rate),
+                }
+            }
+        };
+        Some(quota)
+    }
+
+    /// Adds the clients that would be refused if they made a request at `now` to `limited`.
+    fn add_limited_clients_at(&self, now: Instant, limited: &mut HashSet<IpAddr>) {
+        let rate = self.refill_rate();
+        match &mut *self.clients.lock() {
+            Clients::SlidingWindow(recent) => {
+                for (client, times) in recent.iter_mut() {
+                    prune(times, now);
+                    if times.len() >= self.max_requests {
+                        limited.insert(*client);
+                    }
+                }
+            }
+            Clients::TokenBucket { burst, buckets } => {
+                for (client, bucket) in buckets.iter_mut() {
+                    bucket.refill(now, rate, *burst);
+                    if bucket.tokens < 1.0 {
+                        limited.insert(*client);
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove_expired_at(&self, now: Instant) {
+        let rate = self.refill_rate();
+        match &mut *self.clients.lock() {
+            Clients::SlidingWindow(recent) => recent.retain(|_, times| {
+                prune(times, now);
+                !times.is_empty()
+            }),
+            Clients::TokenBucket { burst, buckets } => buckets.retain(|_, bucket| {
+                bucket.refill(now, rate, *burst);
+                bucket.tokens < *burst
+            }),
+        }
+    }
+}
+
+/// Limits how many requests each client can make per minute.
+///
+/// By default the minute slides: a request is allowed only if fewer than the limit were allowed in
+/// the 60 seconds before it, so no 60-second span ever admits more than the limit, even one
+/// straddling what a fixed window would treat as two separate minutes. With a token bucket, each
+/// request instead takes a token from the client's bucket, which refills steadily at the
+/// per-minute limit, so a client that has been quiet can send a burst of up to the bucket's
+/// capacity at once.
+///
+/// Paths can have limits of their own. A request counts only against the limit for the longest
+/// prefix of its path that has one, or the default limit if none does.
+pub struct RateLimiter {
+    algorithm: Algorithm,
+    /// Clients that are never limited
+    exempt: Vec<Cidr>,
+    default: Limit,
+    /// Path prefixes with their own limits
+    paths: Vec<(String, Limit)>,
+}
+
+impl RateLimiter {
+    fn with_algorithm(algorithm: Algorithm, max_requests_per_minute: usize) -> RateLimiter {
+        RateLimiter {
+            algorithm,
+            exempt: Vec::new(),
+            default: Limit::new(algorithm, max_requests_per_minute),
+            paths: Vec::new(),
+        }
+    }
+
+    /// Creates a sliding-window limiter.
+    pub fn new(max_requests_per_minute: usize) -> RateLimiter {
+        RateLimiter::with_algorithm(Algorithm::SlidingWindow, max_requests_per_minute)
+    }
+
+    /// Creates a token-bucket limiter, whose buckets hold up to `burst` requests, or a minute's
+    /// worth if `burst` is 0.
+    pub fn token_bucket(max_requests_per_minute: usize, burst: usize) -> RateLimiter {
+        RateLimiter::with_algorithm(Algorithm::TokenBucket { burst }, max_requests_per_minute)
+    }
+
+    /// Creates the limiter for the algorithm called `name`. `burst` is only used by the token
+    /// bucket.
+    pub fn from_name(
+        name: &str,
+        max_requests_per_minute: usize,
+        burst: usize,
+    ) -> Option<RateLimiter> {
+        match name {
+            "sliding-window" => Some(RateLimiter::new(max_requests_per_minute)),
+            "token-bucket" => Some(RateLimiter::token_bucket(max_requests_per_minute, burst)),
+            _ => None,
+        }
+    }
+
+    /// Exempts the clients in `cidrs` from the limit.
+    pub fn with_exempt(mut self, cidrs: Vec<Cidr>) -> RateLimiter {
+        self.exempt = cidrs;
+        self
+    }
+
+    /// Gives paths starting with `prefix` a limit of their own (0 for no limit).
+    pub fn with_path_limit(
+        mut self,
+        prefix: String,
+        max_requests_per_minute: usize,
+    ) -> RateLimiter {
+        let limit = Limit::new(self.algorithm, max_requests_per_minute);
+        self.paths.push((prefix, limit));
+        self
+    }
+
+    /// Whether any requests are limited at all.
+    pub fn is_enabled(&self) -> bool {
+        std::iter::once(&self.default)
+            .chain(self.paths.iter().map(|(_, limit)| limit))
+            .any(|limit| limit.max_requests > 0)
+    }
+
+    /// Counts a request from `client` for `path`, returning whether it is within the limit and
+    /// how much of the limit is left, or None if the request isn't limited at all. Requests from
+    /// exempt clients aren't counted.
+    pub fn check(&self, client: IpAddr, path: &str) -> Option<Quota> {
+        self.check_at(client, path, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, path: &str, now: Instant) -> Option<Quota> {
+        if self.exempt.iter().any(|cidr| cidr.contains(client)) {
+            return None;
+        }
+        self.limit_for(path).check_at(client, now)
+    }
+
+    #[cfg(test)]
+    fn allows_at(&self, client: IpAddr, path: &str, now: Instant) -> bool {
+        self.check_at(client, path, now)
+            .is_none_or(|quota| quota.allowed)
+    }
+
+    #[cfg(test)]
+    fn allows(&self, client: IpAddr, path: &str) -> bool {
+        self.allows_at(client, path, Instant::now())
+    }
+
+    /// The limit that requests for `path` count against.
+    fn limit_for(&self, path: &str) -> &Limit {
+        self.paths
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(&self.default, |(_, limit)| limit)
+    }
+
+    /// Forgets the clients that haven't made a request in the last window, or whose buckets have
+    /// refilled, so that the map doesn't keep growing with every client ever seen. Either way,
+    /// they are treated just as they would be if they had never been seen.
+    pub fn remove_expired(&self) {
+        self.remove_expired_at(Instant::now());
+    }
+
+    /// How many clients have used up one of their limits, so that their next request would be
+    /// refused (at least if it was for a path with that limit).
+    pub fn limited_clients(&self) -> usize {
+        self.limited_clients_at(Instant::now())
+    }
+
+    fn limited_clients_at(&self, now: Instant) -> usize {
+        let mut limited = HashSet::new();
+        for limit in std::iter::once(&self.default).chain(self.paths.iter().map(|(_, limit)| limit))
+        {
+            if limit.max_requests > 0 {
+                limit.add_limited_clients_at(now, &mut limited);
+            }
+        }
+        limited.len()
+    }
+
+    fn remove_expired_at(&self, now: Instant) {
+        self.default.remove_expired_at(now);
+        for (_, limit) in &self.paths {
+            limit.remove_expired_at(now);
+        }
+    }
+
+    /// The clients currently being tracked against the default limit.
+    #[cfg(test)]
+    fn tracked(&self) -> Vec<String> {
+        let mut clients: Vec<String> = match &*self.default.clients.lock() {
+            Clients::SlidingWindow(recent) => recent.keys().map(IpAddr::to_string).collect(),
+            Clients::TokenBucket { buckets, .. } => buckets.keys().map(IpAddr::to_string).collect(),
+        };
+        clients.sort();
+        clients
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn test_limit() {
+        let limiter = RateLimiter::new(3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.allows_at(ip("10.0.0.1"), "/", start));
+        }
+        assert!(!limiter.allows_at(ip("10.0.0.1"), "/", start + Duration::from_secs(30)));
+        // Each client has its own count
+        assert!(limiter.allows_at(ip("10.0.0.2"), "/", start + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_window_slides() {
+        let limiter = RateLimiter::new(2);
+        let start = Instant::now();
+        assert!(limiter.allows_at(ip("10.0.0.1"), "/", start));
+        assert!(limiter.allows_at(ip("10.0.0.1"), "/", start + Duration::from_secs(30)));
+        assert!(!limiter.allows_at(
+            ip("10.0.0.1"),
+            "/",
+            start + WINDOW - Duration::from_millis(1)
+        ));
+        // The first request has left the window, but the second hasn't
+        assert!(limiter.allows_at(ip("10.0.0.1"), "/", start + WINDOW));
+        assert!(!limiter.allows_at(
+            ip("10.0.0.1"),
+            "/",
+            start + WINDOW + Duration::from_secs(29)
+        ));
+        assert!(limiter.allows_at(
+            ip("10.0.0.1"),
+            "/",
+            start + WINDOW + Duration::from_secs(30)
+        ));
+    }
+
+    #[test]
+    fn test_boundary_burst() {
+        // A fixed window starting at `start` would allow 3 requests just before it ends and
+        // another 3 just after, 6 within a couple of seconds
+        let limiter = RateLimiter::new(3);
+        let start = Instant::now();
+        let before_boundary = start + WINDOW - Duration::from_secs(1);
+        let after_boundary = start + WINDOW + Duration::from_secs(1);
+        assert!(limiter.allows_at(ip("10.0.0.1"), "/", start));
+        for _ in 0..2 {
+            assert!(limiter.allows_at(ip("10.0.0.1"), "/", before_boundary));
+        }
+        assert!(!limiter.allows_at(ip("10.0.0.1"), "/", before_boundary));
+        // Only the request at `start` has left the window
+        assert!(limiter.allows_at(ip("10.0.0.1"), "/", after_boundary));
+        for _ in 0..3 {
+            assert!(!limiter.allows_at(ip("10.0.0.1"), "/", after_boundary));
+        }
+    }
+
+    #[test]
+    fn test_refused_requests_not_counted() {
+        let limiter = RateLimiter::new(1);
+        let start = Instant::now();
+        assert!(limiter.allows_at(ip("10.0.0.1"), "/", start));
+        for secs in 1..60 {
+            assert!(!limiter.allows_at(ip("10.0.0.1"), "/", start + Duration::from_secs(secs)));
+        }
+        assert!(limiter.allows_at(ip("10.0.0.1"), "/", start + WINDOW));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::new(0);
+        for _ in 0..100 {
+            assert!(limiter.allows(ip("10.0.0.1"), "/"));
+        }
+        assert!(limiter.tracked().is_empty());
+    }
+
+    #[test]
+    fn test_remove_expired() {
+        let limiter = RateLimiter::new(1);
+        let start = Instant::now();
+        limiter.allows_at(ip("10.0.0.1"), "/", start);
+        limiter.allows_at(ip("10.0.0.2"), "/", start + Duration::from_secs(30));
+        limiter.remove_expired_at(start + WINDOW);
+        assert_eq!(limiter.tracked(), vec!["10.0.0.2"]);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        // One token a second, up to 3
+        let limiter = RateLimiter::token_bucket(60, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.allows_at(ip("10.0.0.1"), "/", start));
+        }
+        assert!(!limiter.allows_at(ip("10.0.0.1"), "/", start));
+        assert!(!limiter.allows_at(ip("10.0.0.1"), "/", start + Duration::from_millis(900)));
+        assert!(limiter.allows_at(ip("10.0.0.1"), "/", start + Duration::from_secs(1)));
+        assert!(!limiter.allows_at(ip("10.0.0.1"), "/", start + Duration::from_secs(1)));
+        // A long wait refills the bucket, but only up to its capacity
+        let later = start + Duration::from_secs(100);
+        for _ in 0..3 {
+            assert!(limiter.allows_at(ip("10.0.0.1"), "/", later));
+        }
+        assert!(!limiter.allows_at(ip("10.0.0.1"), "/", later));
+        // Each client has its own bucket
+        assert!(limiter.allows_at(ip("10.0.0.2"), "/", later));
+    }
+
+    #[test]
+    fn test_token_bucket_steady_rate() {
+        // Once the burst is used up, requests are allowed at the per-minute rate
+        let limiter = RateLimiter::token_bucket(6, 2);
+        let start = Instant::now();
+        let allowed = (0..120)
+            .filter(|&secs| {
+                limiter.allows_at(ip("10.0.0.1"), "/", start + Duration::from_secs(secs))
+            })
+            .count();
+        assert_eq!(allowed, 2 + 11);
+    }
+
+    #[test]
+    fn test_token_bucket_remove_expired() {
+        let limiter = RateLimiter::token_bucket(60, 3);
+        let start = Instant::now();
+        limiter.allows_at(ip("10.0.0.1"), "/", start);
+        for _ in 0..3 {
+            limiter.allows_at(ip("10.0.0.2"), "/", start);
+        }
+        // 10.0.0.1's bucket has refilled, but 10.0.0.2's is one token short
+        limiter.remove_expired_at(start + Duration::from_secs(2));
+        assert_eq!(limiter.tracked(), vec!["10.0.0.2"]);
+        limiter.remove_expired_at(start + Duration::from_secs(3));
+        assert!(limiter.tracked().is_empty());
+    }
+
+    #[test]
+    fn test_from_name() {
+        for name in ALGORITHM_NAMES {
+            assert!(RateLimiter::from_name(name, 10, 0).is_some(), "{}", name);
+        }
+        assert!(RateLimiter::from_name("leaky-bucket", 10, 0).is_none());
+        // A burst of 0 means a minute's worth
+        let limiter = RateLimiter::from_name("token-bucket", 2, 0).unwrap();
+        let now = Instant::now();
+        assert!(limiter.allows_at(ip("10.0.0.1"), "/", now));
+        assert!(limiter.allows_at(ip("10.0.0.1"), "/", now));
+        assert!(!limiter.allows_at(ip("10.0.0.1"), "/", now));
+    }
+
+    #[test]
+    fn test_exempt() {
+        let limiter = RateLimiter::new(1).with_exempt(vec![
+            Cidr::parse("192.168.1.0/24").unwrap(),
+            Cidr::parse("fd00::/8").unwrap(),
+        ]);
+        for _ in 0..5 {
+            assert!(limiter.allows(ip("192.168.1.255"), "/"));
+            assert!(limiter.allows(ip("fd12::1"), "/"));
+        }
+        assert!(limiter.tracked().is_empty());
+        assert!(limiter.allows(ip("192.168.2.0"), "/"));
+        assert!(!limiter.allows(ip("192.168.2.0"), "/"));
+    }
+
+    #[test]
+    fn test_quota() {
+        let limiter = RateLimiter::new(2);
+        let client = ip("10.0.0.1");
+        let start = Instant::now();
+        let quota = |allowed, remaining, reset| {
+            Some(Quota {
+                allowed,
+                limit: 2,
+                remaining,
+                reset,
+            })
+        };
+        assert_eq!(limiter.check_at(client, "/", start), quota(true, 1, WINDOW));
+        let second = start + Duration::from_secs(30);
+        assert_eq!(
+            limiter.check_at(client, "/", second),
+            quota(true, 0, Duration::from_secs(30))
+        );
+        // Right at the edge of the first request's window
+        let edge = start + WINDOW - Duration::from_millis(1);
+        let refused = limiter.check_at(client, "/", edge).unwrap();
+        assert_eq!(refused, quota(false, 0, Duration::from_millis(1)).unwrap());
+        assert_eq!(refused.retry_after_secs(), 1);
+        // Once it leaves the window, there's a request to spare again, until the second one's
+        // window ends
+        assert_eq!(
+            limiter.check_at(client, "/", start + WINDOW),
+            quota(true, 0, Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn test_token_bucket_quota() {
+        // One token every 2 seconds, up to 2
+        let limiter = RateLimiter::token_bucket(30, 2);
+        let client = ip("10.0.0.1");
+        let start = Instant::now();
+        let first = limiter.check_at(client, "/", start).unwrap();
+        assert!(first.allowed);
+        assert_eq!((first.limit, first.remaining), (30, 1));
+        let second = limiter.check_at(client, "/", start).unwrap();
+        assert!(second.allowed);
+        assert_eq!(second.remaining, 0);
+        assert_eq!(second.reset, Duration::from_secs(2));
+        let refused = limiter
+            .check_at(client, "/", start + Duration::from_millis(500))
+            .unwrap();
+        assert!(!refused.allowed);
+        assert_eq!(refused.remaining, 0);
+        assert_eq!(refused.retry_after_secs(), 2);
+    }
+
+    #[test]
+    fn test_quota_headers() {
+        let quota = Quota {
+            allowed: true,
+            limit: 10,
+            remaining: 3,
+            reset: Duration::from_secs(5),
+        };
+        let mut response = http::Response::new(Vec::<u8>::new());
+        quota.add_headers(&mut response);
+        assert_eq!(response.headers()["x-ratelimit-limit"], "10");
+        assert_eq!(response.headers()["x-ratelimit-remaining"], "3");
+        assert_eq!(
+            Quota {
+                reset: Duration::from_millis(59_001),
+                ..quota
+            }
+            .retry_after_secs(),
+            60
+        );
+    }
+
+    #[test]
+    fn test_path_limits() {
+        let limiter = RateLimiter::new(3)
+            .with_path_limit("/api".to_string(), 2)
+            .with_path_limit("/api/login".to_string(), 1);
+        let client = ip("10.0.0.1");
+        let now = Instant::now();
+        // The longest matching prefix wins
+        assert!(limiter.allows_at(client, "/api/login?next=/", now));
+        assert!(!limiter.allows_at(client, "/api/login", now));
+        // Each limit counts separately
+        for _ in 0..2 {
+            assert!(limiter.allows_at(client, "/api/users", now));
+        }
+        assert!(!limiter.allows_at(client, "/api/users", now));
+        for _ in 0..3 {
+            assert!(limiter.allows_at(client, "/index.html", now));
+        }
+        assert!(!limiter.allows_at(client, "/", now));
+    }
+
+    #[test]
+    fn test_path_limits_without_default() {
+        let limiter = RateLimiter::new(0).with_path_limit("/api/login".to_string(), 1);
+        assert!(limiter.is_enabled());
+        let client = ip("10.0.0.1");
+        assert!(limiter.allows(client, "/api/login"));
+        assert!(!limiter.allows(client, "/api/login"));
+        for _ in 0..10 {
+            assert!(limiter.allows(client, "/"));
+        }
+        assert!(!RateLimiter::new(0).is_enabled());
+        assert!(!RateLimiter::new(0)
+            .with_path_limit("/api".to_string(), 0)
+            .is_enabled());
+    }
+
+    #[test]
+    fn test_path_limits_remove_expired() {
+        let limiter = RateLimiter::new(1).with_path_limit("/api".to_string(), 1);
+        let start = Instant::now();
+        limiter.allows_at(ip("10.0.0.1"), "/api", start);
+        limiter.remove_expired_at(start + WINDOW);
+        let clients = limiter.paths[0].1.clients.lock();
+        assert!(matches!(&*clients, Clients::SlidingWindow(recent) if recent.is_empty()));
+    }
+
+    #[test]
+    fn test_limited_clients() {
+        let limiter = RateLimiter::new(2).with_path_limit("/api".to_string(), 1);
+        let start = Instant::now();
+        assert_eq!(limiter.limited_clients_at(start), 0);
+        limiter.allows_at(ip("10.0.0.1"), "/", start);
+        limiter.allows_at(ip("10.0.0.1"), "/", start);
+        limiter.allows_at(ip("10.0.0.1"), "/api", start);
+        limiter.allows_at(ip("10.0.0.2"), "/", start);
+        limiter.allows_at(ip("10.0.0.3"), "/api", start);
+        // 10.0.0.1 is over both limits, but only counts once
+        assert_eq!(limiter.limited_clients_at(start), 2);
+        assert_eq!(limiter.limited_clients_at(start + WINDOW), 0);
+    }
+
+    #[test]
+    fn test_token_bucket_limited_clients() {
+        let limiter = RateLimiter::token_bucket(60, 1);
+        let start = Instant::now();
+        limiter.allows_at(ip("10.0.0.1"), "/", start);
+        assert_eq!(limiter.limited_clients_at(start), 1);
+        // A token comes back every second
+        assert_eq!(
+            limiter.limited_clients_at(start + Duration::from_secs(1)),
+            0
+        );
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let block = Cidr::parse("192.168.1.0/24").unwrap();
+        assert!(block.contains(ip("192.168.1.0")));
+        assert!(block.contains(ip("192.168.1.255")));
+        assert!(!block.contains(ip("192.168.0.255")));
+        assert!(!block.contains(ip("192.168.2.0")));
+        assert!(!block.contains(ip("::ffff:192.168.1.1")));
+
+        let block = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(block.contains(ip("2001:db8:ffff::1")));
+        assert!(!block.contains(ip("2001:db9::")));
+        assert!(!block.contains(ip("192.168.1.1")));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("::1")));
+        let host = Cidr::parse("10.1.2.3").unwrap();
+        assert!(host.contains(ip("10.1.2.3")));
+        assert!(!host.contains(ip("10.1.2.4")));
+    }
+
+    #[test]
+    fn test_cidr_parse() {
+        assert_eq!(
+            Cidr::parse(" 10.0.0.0/8 "),
+            Ok(Cidr {
+                network: ip("10.0.0.0"),
+                prefix_len: 8
+            })
+        );
+        assert!(Cidr::parse("::1/128").is_ok());
+        for spec in &[
+            "",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "example.com/8",
+        ] {
+            assert!(Cidr::parse(spec).is_err(), "{:?}", spec);
+        }
+    }
+}
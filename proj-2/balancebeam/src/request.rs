@@ -1,11 +1,18 @@
-use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::{chunked, response};
+use std::cmp::{max, min};
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{Duration, Instant};
 
-const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
-const MAX_NUM_HEADERS: usize = 32;
+/// How much room is made for a head at first. The buffer grows as needed, up to
+/// HeaderLimits::max_bytes, so that most heads don't take up the whole limit.
+const INITIAL_HEADERS_BUFFER_SIZE: usize = 4096;
+/// Bodies up to this size are read in full before their request is forwarded, so that the request
+/// can be retried on another upstream. Bigger ones are passed on as they arrive, this much at a
+/// time.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
@@ -13,36 +20,108 @@ pub enum Error {
     IncompleteRequest(usize),
     /// Client sent an invalid HTTP request. httparse::Error contains more details
     MalformedRequest(httparse::Error),
+    /// The request line and headers are bigger than HeaderLimits::max_bytes, or there are more
+    /// headers than HeaderLimits::max_headers
+    HeadersTooLarge,
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
+    /// The request gives more than one Content-Length, or Transfer-Encoding as well, or
+    /// a Transfer-Encoding other than chunked, so servers could disagree on where its body ends
+    AmbiguousBodyLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The request body is bigger than the limit balancebeam was given
     RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// Encountered an I/O error when reading/writing a stream
     ConnectionError(std::io::Error),
+    /// Client didn't send a complete set of headers in time. TimedOut contains the number of bytes
+    /// that were read before the time ran out
+    TimedOut(usize),
+}
+
+/// How big the head (the first line and headers) of a request, or of a response, may be. Without
+/// limits, a client could use up memory by sending headers that never end.
+#[derive(Clone, Copy, Debug)]
+pub struct HeaderLimits {
+    /// The most bytes the head may take up
+    pub max_bytes: usize,
+    /// The most headers the head may have
+    pub max_headers: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> HeaderLimits {
+        HeaderLimits {
+            max_bytes: 16 * 1024,
+            max_headers: 100,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::IncompleteRequest(read) => {
+                write!(
+                    f,
+                    "client hung up after sending {} bytes of a request",
+                    read
+                )
+            }
+            Error::MalformedRequest(error) => write!(f, "malformed request: {}", error),
+            Error::HeadersTooLarge => write!(f, "request headers too large"),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::AmbiguousBodyLength => {
+                write!(f, "conflicting Content-Length or Transfer-Encoding")
+            }
+            Error::ContentLengthMismatch => write!(f, "body doesn't match its Content-Length"),
+            Error::RequestBodyTooLarge => write!(f, "request body too large"),
+            Error::ConnectionError(error) => write!(f, "{}", error),
+            Error::TimedOut(read) => write!(f, "timed out after {} bytes of a request", read),
+        }
+    }
+}
+
+impl From<chunked::Error> for Error {
+    fn from(error: chunked::Error) -> Error {
+        match error {
+            chunked::Error::Malformed(error) => Error::MalformedRequest(error),
+            chunked::Error::TooLarge => Error::RequestBodyTooLarge,
+        }
+    }
 }
 
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
 ///
-/// You won't need to touch this function.
+/// A request whose lengths disagree, or that has a Transfer-Encoding too, is refused with
+/// AmbiguousBodyLength: the upstream might read its body differently, and take the rest of it for
+/// another request that balancebeam never saw (request smuggling).
 fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Error> {
-    // Look for content-length header
-    if let Some(header_value) = request.headers().get("content-length") {
-        // If it exists, parse it as a usize (or return InvalidContentLength if it can't be parsed as such)
-        Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
+    let mut content_length = None;
+    for header_value in request.headers().get_all("content-length") {
+        let header_value = header_value.to_str().or(Err(Error::InvalidContentLength))?;
+        // The same length can be given more than once (RFC 7230 section 3.3.2)
+        for length in header_value.split(',') {
+            // Only digits: parse() would take a sign too
+            let length = length.trim();
+            if length.is_empty() || !length.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(Error::InvalidContentLength);
+            }
+            let length = length
                 .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
-        ))
-    } else {
-        // If it doesn't exist, return None
-        Ok(None)
+                .or(Err(Error::InvalidContentLength))?;
+            if content_length.is_some_and(|other| other != length) {
+                return Err(Error::AmbiguousBodyLength);
+            }
+            content_length = Some(length);
+        }
     }
+    if content_length.is_some() && request.headers().contains_key("transfer-encoding") {
+        return Err(Error::AmbiguousBodyLength);
+    }
+    Ok(content_length)
 }
 
 /// This function appends to a header value (adding a new header if the header is not already
@@ -74,18 +153,23 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+#[allow(clippy::type_complexity)]
+fn parse_request(
+    buffer: &[u8],
+    max_headers: usize,
+) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(|error| match error {
+        httparse::Error::TooManyHeaders => Error::HeadersTooLarge,
+        error => Error::MalformedRequest(error),
+    })?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(parse_version(req.version));
         for header in req.headers {
             request = request.header(header.name, header.value);
         }
@@ -96,25 +180,50 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
     }
 }
 
+/// Makes room in `buffer`, which holds `filled` bytes of a head that is still being read, for
+/// more of the head. Returns false if the head has already reached `max_bytes`.
+pub fn grow_head_buffer(buffer: &mut Vec<u8>, filled: usize, max_bytes: usize) -> bool {
+    if filled < buffer.len() {
+        return true;
+    }
+    if filled >= max_bytes {
+        return false;
+    }
+    let size = max(buffer.len() * 2, INITIAL_HEADERS_BUFFER_SIZE);
+    buffer.resize(min(size, max_bytes), 0);
+    true
+}
+
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
 /// This function only reads the request line and headers; the read_body function can subsequently
 /// be called in order to read the request body (for a POST request).
 ///
-/// Returns Ok(http::Request) if a valid request is received, or Error if not.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+/// Returns Ok(http::Request) if a valid request is received, or Error if not. If `timeout` is given,
+/// the headers must all arrive within that time, and they must stay within `limits`.
+async fn read_headers(
+    stream: &mut (impl AsyncRead + Unpin),
+    timeout: Option<Duration>,
+    limits: HeaderLimits,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
+    let mut request_buffer = Vec::new();
     let mut bytes_read = 0;
     loop {
+        if !grow_head_buffer(&mut request_buffer, bytes_read, limits.max_bytes) {
+            return Err(Error::HeadersTooLarge);
+        }
         // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let read = stream.read(&mut request_buffer[bytes_read..]);
+        let new_bytes = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, read)
+                .await
+                .map_err(|_| Error::TimedOut(bytes_read))?,
+            None => read.await,
+        }
+        .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
@@ -122,7 +231,9 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
         bytes_read += new_bytes;
 
         // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
+        if let Some((mut request, headers_len)) =
+            parse_request(&request_buffer[..bytes_read], limits.max_headers)?
+        {
             // We've read a complete set of headers. However, if this was a POST request, a request
             // body might have been included as well, and we might have read part of the body out of
             // the stream into header_buffer. We need to add those bytes to the Request body so that
@@ -138,10 +249,8 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
 /// This function reads the body for a request from the stream. The client only sends a body if the
 /// Content-Length header is present; this function reads that number of bytes from the stream. It
 /// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
-///
-/// You will need to modify this function in Milestone 2.
 async fn read_body(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
 ) -> Result<(), Error> {
@@ -153,7 +262,7 @@ async fn read_body(
         let bytes_read = stream
             .read(&mut buffer)
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
@@ -180,45 +289,440 @@ async fn read_body(
     Ok(())
 }
 
+/// Reads a body sent with `Transfer-Encoding: chunked`, the only transfer coding balancebeam
+/// understands, and gives the request a Content-Length instead, so that it can be forwarded as it
+/// is. Any trailers are dropped.
+async fn read_chunked_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    request: &mut http::Request<Vec<u8>>,
+    max_body_size: Option<usize>,
+) -> Result<(), Error> {
+    let codings: Vec<&http::HeaderValue> = request
+        .headers()
+        .get_all("transfer-encoding")
+        .iter()
+        .collect();
+    if !matches!(codings[..], [coding] if coding.as_bytes().eq_ignore_ascii_case(b"chunked")) {
+        return Err(Error::AmbiguousBodyLength);
+    }
+
+    let mut decoder = chunked::Decoder::new(max_body_size.unwrap_or(usize::MAX));
+    // Some of the body may have been read along with the headers
+    let start = std::mem::take(request.body_mut());
+    let mut bytes_read = start.len();
+    let mut done = decoder.push(&start)?;
+    while !done {
+        let mut buffer = [0_u8; 512];
+        let new_bytes = stream
+            .read(&mut buffer)
+            .await
+            .map_err(Error::ConnectionError)?;
+        if new_bytes == 0 {
+            log::debug!("Client hung up partway through a chunked body");
+            return Err(Error::IncompleteRequest(bytes_read));
+        }
+        bytes_read += new_bytes;
+        done = decoder.push(&buffer[..new_bytes])?;
+    }
+
+    let body = decoder.into_body();
+    let headers = request.headers_mut();
+    headers.remove("transfer-encoding");
+    headers.insert("content-length", http::HeaderValue::from(body.len()));
+    *request.body_mut() = body;
+    Ok(())
+}
+
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// closes the connection prematurely or sends an invalid request. If `idle_timeout` is given, the
+/// client must send the request's headers within that time. (The body isn't covered, so that slow
+/// clients can still upload large bodies.) The headers must stay within `limits`, and the body
+/// must be no bigger than `max_body_size` (if given).
+pub async fn read_from_stream(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    idle_timeout: Option<Duration>,
+    limits: HeaderLimits,
+    max_body_size: Option<usize>,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    let (mut request, unread) =
+        read_head_from_stream(stream, idle_timeout, limits, max_body_size).await?;
+    if unread > 0 {
+        if expects_continue(&request) {
+            response::write_continue(stream)
+                .await
+                .map_err(Error::ConnectionError)?;
+        }
+        let content_length = request.body().len() + unread;
+        read_body(stream, &mut request, content_length).await?;
+    }
+    Ok(request)
+}
+
+/// Reads a request like read_from_stream, except that a body bigger than STREAM_BUFFER_SIZE is
+/// left to be passed on with copy_body, as is a body that the client won't send until it is told
+/// to continue (see expects_continue). Returns the request, with as much of its body as has been
+/// read, and how many bytes of the body are still to be read.
 ///
-/// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+/// A chunked body is always read here, so a client waiting to send one is told to continue
+/// straight away, and the expectation is taken off the request.
+pub async fn read_head_from_stream(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    idle_timeout: Option<Duration>,
+    limits: HeaderLimits,
+    max_body_size: Option<usize>,
+) -> Result<(http::Request<Vec<u8>>, usize), Error> {
     // Read headers
-    let mut request = read_headers(stream).await?;
+    let mut request = read_headers(stream, idle_timeout, limits).await?;
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
+    // or a Transfer-Encoding
     if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
+        if max_body_size.is_some_and(|max_body_size| content_length > max_body_size) {
             return Err(Error::RequestBodyTooLarge);
+        } else if content_length > STREAM_BUFFER_SIZE || expects_continue(&request) {
+            let unread = content_length
+                .checked_sub(request.body().len())
+                .ok_or(Error::ContentLengthMismatch)?;
+            return Ok((request, unread));
         } else {
             read_body(stream, &mut request, content_length).await?;
         }
+    } else if request.headers().contains_key("transfer-encoding") {
+        if expects_continue(&request) {
+            request.headers_mut().remove("expect");
+            response::write_continue(stream)
+                .await
+                .map_err(Error::ConnectionError)?;
+        }
+        read_chunked_body(stream, &mut request, max_body_size).await?;
     }
-    Ok(request)
+    Ok((request, 0))
+}
+
+/// Passes the `remaining` bytes of a request body still to be read from `client` on to `upstream`
+/// as they arrive, so that it never has to be held in memory all at once.
+pub async fn copy_body(
+    client: &mut (impl AsyncRead + Unpin),
+    upstream: &mut (impl AsyncWrite + Unpin),
+    mut remaining: usize,
+) -> Result<(), Error> {
+    let mut buffer = vec![0_u8; min(STREAM_BUFFER_SIZE, remaining)];
+    while remaining > 0 {
+        let wanted = min(buffer.len(), remaining);
+        let bytes_read = client
+            .read(&mut buffer[..wanted])
+            .await
+            .map_err(Error::ConnectionError)?;
+        if bytes_read == 0 {
+            log::debug!(
+                "Client hung up with {} bytes of its request body still to send",
+                remaining
+            );
+            return Err(Error::ContentLengthMismatch);
+        }
+        upstream
+            .write_all(&buffer[..bytes_read])
+            .await
+            .map_err(Error::ConnectionError)?;
+        remaining -= bytes_read;
+    }
+    Ok(())
+}
+
+/// Whether the client is waiting to be told `100 Continue` before it sends the request's body
+/// (RFC 7231 section 5.1.1).
+pub fn expects_continue<B>(request: &http::Request<B>) -> bool {
+    request
+        .headers()
+        .get("expect")
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"))
 }
 
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
-///
-/// You will need to modify this function in Milestone 2.
 pub async fn write_to_stream(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    default_host: Option<&str>,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
-    stream
-        .write(&format_request_line(request).into_bytes())
-        .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    write_head(request, default_host, stream).await?;
+    if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
+    }
+    Ok(())
+}
+
+/// Writes a request's line and headers, but not its body. balancebeam speaks HTTP/1.1 to
+/// upstreams, whichever version the client spoke, so a request without a Host header (which only an
+/// HTTP/1.0 client can send) is given `default_host` as its Host, if there is one.
+async fn write_head(
+    request: &http::Request<Vec<u8>>,
+    default_host: Option<&str>,
+    stream: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), std::io::Error> {
+    // The head is written all at once: sent a piece at a time over a connection that has been
+    // used before, the later pieces can wait for the upstream to acknowledge the first
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method(), request.uri()).into_bytes();
     for (header_name, header_value) in request.headers() {
-        stream
-            .write(&format!("{}: ", header_name).as_bytes())
-            .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        head.extend_from_slice(header_name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header_value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    if let Some(host) = default_host {
+        if !request.headers().contains_key(http::header::HOST) {
+            head.extend_from_slice(format!("host: {}\r\n", host).as_bytes());
+        }
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    head.extend_from_slice(b"\r\n");
+    stream.write_all(&head).await
+}
+
+/// The header that identifies a request, both in balancebeam's logs and to the upstream.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Makes a random (version 4) UUID to identify a request that didn't come with an ID.
+pub fn new_request_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Returns the ID in a request's or response's headers, if it has one.
+pub fn request_id(headers: &http::HeaderMap) -> Option<&str> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+}
+
+/// Headers that are about a single connection, rather than the request or response sent over it
+/// (RFC 7230 section 6.1), so a proxy mustn't pass them on. Proxy-Connection isn't standard, but
+/// old clients still send it.
+pub const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// The options in the Connection headers, in lowercase.
+fn connection_options(headers: &http::HeaderMap) -> Vec<String> {
+    headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .filter(|option| !option.is_empty())
+        .collect()
+}
+
+/// Whether the sender of a request or response with this version and these headers will close the
+/// connection after it. HTTP/1.1 connections stay open unless they are to close, and HTTP/1.0 ones
+/// close unless they are kept alive (RFC 7230 section 6.3).
+pub fn wants_close(version: http::Version, headers: &http::HeaderMap) -> bool {
+    let options = connection_options(headers);
+    if options.iter().any(|option| option == "close") {
+        return true;
+    }
+    version < http::Version::HTTP_11 && !options.iter().any(|option| option == "keep-alive")
+}
+
+/// The protocol a request asks to switch the connection to, or a 101 response says it is switching
+/// to: what the Upgrade header says, as long as the Connection header names it.
+pub fn upgrade(headers: &http::HeaderMap) -> Option<http::HeaderValue> {
+    if !connection_options(headers)
+        .iter()
+        .any(|option| option == "upgrade")
+    {
+        return None;
+    }
+    headers.get("upgrade").cloned()
+}
+
+/// Puts back the headers asking to switch to `protocol` (or saying that the connection is
+/// switching to it) once remove_hop_by_hop_headers has taken them out, since an upgrade can only
+/// happen if every connection along the way makes it.
+pub fn set_upgrade(headers: &mut http::HeaderMap, protocol: http::HeaderValue) {
+    headers.insert("upgrade", protocol);
+    headers.insert("connection", http::HeaderValue::from_static("upgrade"));
+}
+
+/// The version of a message httparse has parsed, given the minor version it found (HTTP/1.x).
+pub fn parse_version(minor_version: Option<u8>) -> http::Version {
+    match minor_version {
+        Some(0) => http::Version::HTTP_10,
+        _ => http::Version::HTTP_11,
+    }
+}
+
+/// Removes the hop-by-hop headers from a request or response, both the standard ones and any the
+/// Connection header names, so that it can be sent on over another connection.
+pub fn remove_hop_by_hop_headers(headers: &mut http::HeaderMap) {
+    for name in connection_options(headers) {
+        headers.remove(name.as_str());
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
+
+/// The header each proxy a request or response goes through adds itself to.
+pub const VIA_HEADER: &str = "via";
+
+/// The standard header (RFC 7239) for what X-Forwarded-For and friends say.
+pub const FORWARDED_HEADER: &str = "forwarded";
+
+/// Formats a node for a Forwarded header's for= or by= parameter. IPv6 addresses are bracketed,
+/// and anything that isn't a plain token (so any IPv6 address, or an address with a port) is
+/// quoted. Without an address, a random obfuscated identifier stands in for it, as RFC 7239
+/// section 6.3 suggests.
+pub fn forwarded_node(address: Option<IpAddr>, port: Option<u16>) -> String {
+    let node = match address {
+        Some(IpAddr::V4(address)) => address.to_string(),
+        Some(IpAddr::V6(address)) => format!("[{}]", address),
+        None => format!("_{:08x}", rand::random::<u32>()),
+    };
+    match port {
+        Some(port) => format!("\"{}:{}\"", node, port),
+        None if node.starts_with('[') => format!("\"{}\"", node),
+        None => node,
+    }
+}
+
+/// Formats the element a proxy adds to the Forwarded header: which client it received the request
+/// from, with which protocol, and on which of its own addresses.
+pub fn forwarded_element(client: Option<IpAddr>, proto: &str, by: Option<SocketAddr>) -> String {
+    let by = match by {
+        Some(by) => forwarded_node(Some(by.ip()), Some(by.port())),
+        None => forwarded_node(None, None),
+    };
+    format!(
+        "for={};proto={};by={}",
+        forwarded_node(client, None),
+        proto,
+        by
+    )
+}
+
+/// Splits `value` at every `separator` that isn't in a quoted string.
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (idx, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&value[start..idx]);
+            start = idx + 1;
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+/// Removes the quotes (and backslash escapes) from a quoted string, or returns a token as it is.
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(value) => {
+            let mut unquoted = String::new();
+            let mut chars = value.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Reads the for= node of every element of the Forwarded headers, from the first proxy's to the
+/// last. Elements without one give an empty string, so that every hop is accounted for.
+pub fn forwarded_for(headers: &http::HeaderMap) -> Vec<String> {
+    headers
+        .get_all(FORWARDED_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| split_unquoted(value, ','))
+        .map(|element| {
+            split_unquoted(element, ';')
+                .into_iter()
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .map(|(_, node)| unquote(node.trim()))
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Parses the IP address out of a Forwarded node, ignoring any port. Obfuscated identifiers and
+/// "unknown" have no address.
+pub fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    if let Some(node) = node.strip_prefix('[') {
+        return node.split(']').next()?.parse().ok();
+    }
+    let address = match node.split_once(':') {
+        Some((address, _port)) => address,
+        None => node,
+    };
+    address.parse().ok()
+}
+
+/// Adds balancebeam, under the pseudonym `token`, to the end of the Via header of a forwarded
+/// request or response.
+pub fn add_via(headers: &mut http::HeaderMap, token: &str) {
+    let element = format!("1.1 {}", token);
+    let value = match headers.get(VIA_HEADER) {
+        Some(existing) => [existing.as_bytes(), b", ", element.as_bytes()].concat(),
+        None => element.into_bytes(),
+    };
+    headers.insert(VIA_HEADER, http::HeaderValue::from_bytes(&value).unwrap());
+}
+
+/// Whether a proxy called `token` has already forwarded the request (or response) with these
+/// headers, going by the received-by part of each Via element (RFC 7230 section 5.7.1).
+pub fn via_contains(headers: &http::HeaderMap, token: &str) -> bool {
+    headers
+        .get_all(VIA_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| element.split_whitespace().nth(1))
+        .any(|received_by| received_by.eq_ignore_ascii_case(token))
+}
+
+/// Checks that `token` can be used as a Via pseudonym: one token, with no spaces or separators.
+pub fn check_via_token(token: &str) -> Result<(), String> {
+    let is_token_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if token.is_empty() || !token.chars().all(is_token_char) {
+        return Err(format!(
+            "Invalid --via-token {:?}: expected a single word, such as balancebeam",
+            token
+        ));
     }
     Ok(())
 }
@@ -231,3 +735,291 @@ pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
         request.version()
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> http::Request<Vec<u8>> {
+        let mut request = http::Request::builder().method("POST").uri("/");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_header_limits() {
+        let head = b"GET / HTTP/1.1\r\nHost: a\r\nX-One: 1\r\nX-Two: 2\r\n\r\n";
+        assert!(matches!(parse_request(head, 3), Ok(Some(_))));
+        assert!(matches!(
+            parse_request(head, 2),
+            Err(Error::HeadersTooLarge)
+        ));
+
+        let mut buffer = Vec::new();
+        assert!(grow_head_buffer(&mut buffer, 0, 10000));
+        assert_eq!(buffer.len(), INITIAL_HEADERS_BUFFER_SIZE);
+        assert!(grow_head_buffer(&mut buffer, 100, 10000));
+        assert_eq!(buffer.len(), INITIAL_HEADERS_BUFFER_SIZE);
+        assert!(grow_head_buffer(
+            &mut buffer,
+            INITIAL_HEADERS_BUFFER_SIZE,
+            10000
+        ));
+        assert_eq!(buffer.len(), 2 * INITIAL_HEADERS_BUFFER_SIZE);
+        assert!(grow_head_buffer(
+            &mut buffer,
+            2 * INITIAL_HEADERS_BUFFER_SIZE,
+            10000
+        ));
+        assert_eq!(buffer.len(), 10000);
+        assert!(!grow_head_buffer(&mut buffer, 10000, 10000));
+    }
+
+    #[test]
+    fn test_expects_continue() {
+        assert!(expects_continue(&request(&[("expect", "100-continue")])));
+        assert!(expects_continue(&request(&[("expect", "100-Continue")])));
+        assert!(!expects_continue(&request(&[("expect", "something-else")])));
+        assert!(!expects_continue(&request(&[])));
+    }
+
+    #[test]
+    fn test_content_length() {
+        let length = |headers: &[(&str, &str)]| get_content_length(&request(headers));
+        assert!(matches!(length(&[]), Ok(None)));
+        assert!(matches!(length(&[("content-length", "5")]), Ok(Some(5))));
+        assert!(matches!(
+            length(&[("content-length", "5"), ("content-length", "5, 5")]),
+            Ok(Some(5))
+        ));
+        for invalid in [
+            "+5",
+            "-5",
+            "5 5",
+            "0x5",
+            "5.0",
+            "",
+            "99999999999999999999999",
+        ] {
+            assert!(
+                matches!(
+                    length(&[("content-length", invalid)]),
+                    Err(Error::InvalidContentLength)
+                ),
+                "{:?}",
+                invalid
+            );
+        }
+        assert!(matches!(
+            length(&[("content-length", "5"), ("content-length", "6")]),
+            Err(Error::AmbiguousBodyLength)
+        ));
+        assert!(matches!(
+            length(&[("content-length", "5, 6")]),
+            Err(Error::AmbiguousBodyLength)
+        ));
+    }
+
+    /// The CL.TE and TE.CL probes from the request smuggling literature: a front end and back end
+    /// that go by different headers disagree on where the body ends. Either way round, both are
+    /// there, so balancebeam refuses the request.
+    #[test]
+    fn test_smuggling_probes() {
+        let cl_te = request(&[("content-length", "6"), ("transfer-encoding", "chunked")]);
+        assert!(matches!(
+            get_content_length(&cl_te),
+            Err(Error::AmbiguousBodyLength)
+        ));
+        let te_cl = request(&[("transfer-encoding", "chunked"), ("content-length", "3")]);
+        assert!(matches!(
+            get_content_length(&te_cl),
+            Err(Error::AmbiguousBodyLength)
+        ));
+        let obfuscated = request(&[("content-length", "4"), ("transfer-encoding", "xchunked")]);
+        assert!(matches!(
+            get_content_length(&obfuscated),
+            Err(Error::AmbiguousBodyLength)
+        ));
+    }
+
+    fn headers(forwarded: &[&str]) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        for value in forwarded {
+            headers.append(FORWARDED_HEADER, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_remove_hop_by_hop_headers() {
+        let mut headers = http::HeaderMap::new();
+        for (name, value) in [
+            ("connection", "Keep-Alive, X-Secret"),
+            ("connection", "close"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-authorization", "Basic YTpi"),
+            ("te", "trailers"),
+            ("trailer", "Expires"),
+            ("transfer-encoding", "gzip"),
+            ("upgrade", "websocket"),
+            ("x-secret", "hunter2"),
+            ("x-sent-by", "balancebeam-tests"),
+            ("content-length", "5"),
+        ] {
+            headers.append(name, value.parse().unwrap());
+        }
+        assert!(wants_close(http::Version::HTTP_11, &headers));
+        remove_hop_by_hop_headers(&mut headers);
+        let names: Vec<&str> = headers.keys().map(|name| name.as_str()).collect();
+        assert_eq!(names, vec!["x-sent-by", "content-length"]);
+        assert!(!wants_close(http::Version::HTTP_11, &headers));
+    }
+
+    #[test]
+    fn test_wants_close() {
+        let mut headers = http::HeaderMap::new();
+        assert!(!wants_close(http::Version::HTTP_11, &headers));
+        assert!(wants_close(http::Version::HTTP_10, &headers));
+        headers.insert("connection", "Keep-Alive".parse().unwrap());
+        assert!(!wants_close(http::Version::HTTP_11, &headers));
+        assert!(!wants_close(http::Version::HTTP_10, &headers));
+        headers.insert("connection", "Upgrade, CLOSE".parse().unwrap());
+        assert!(wants_close(http::Version::HTTP_11, &headers));
+        assert!(wants_close(http::Version::HTTP_10, &headers));
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("upgrade", "websocket".parse().unwrap());
+        // Only an Upgrade that Connection names counts
+        assert_eq!(upgrade(&headers), None);
+        headers.insert("connection", "keep-alive, Upgrade".parse().unwrap());
+        let protocol = upgrade(&headers).unwrap();
+        assert_eq!(protocol, "websocket");
+        remove_hop_by_hop_headers(&mut headers);
+        assert_eq!(upgrade(&headers), None);
+        set_upgrade(&mut headers, protocol);
+        assert_eq!(upgrade(&headers).unwrap(), "websocket");
+        assert!(!wants_close(http::Version::HTTP_11, &headers));
+    }
+
+    #[test]
+    fn test_parse_connect() {
+        // CONNECT's request target is just the host and port (RFC 7231 section 4.3.6)
+        let (request, _) = parse_request(b"CONNECT db.internal:5432 HTTP/1.1\r\n\r\n", 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method(), http::Method::CONNECT);
+        assert_eq!(request.uri().authority().unwrap(), "db.internal:5432");
+        assert_eq!(
+            format_request_line(&request),
+            "CONNECT db.internal:5432 HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn test_parse_version() {
+        let (request, _) = parse_request(b"GET / HTTP/1.0\r\n\r\n", 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.version(), http::Version::HTTP_10);
+        assert_eq!(format_request_line(&request), "GET / HTTP/1.0");
+        let (request, _) = parse_request(b"GET / HTTP/1.1\r\n\r\n", 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.version(), http::Version::HTTP_11);
+    }
+
+    #[test]
+    fn test_via() {
+        let mut headers = http::HeaderMap::new();
+        assert!(!via_contains(&headers, "balancebeam"));
+        add_via(&mut headers, "edge");
+        assert_eq!(headers[VIA_HEADER], "1.1 edge");
+        add_via(&mut headers, "balancebeam");
+        assert_eq!(headers[VIA_HEADER], "1.1 edge, 1.1 balancebeam");
+        assert!(via_contains(&headers, "edge"));
+        assert!(via_contains(&headers, "BalanceBeam"));
+        assert!(!via_contains(&headers, "other"));
+
+        // Only the received-by part counts, not the protocol or a comment
+        headers.insert(
+            VIA_HEADER,
+            "HTTP/1.0 proxy (balancebeam), 2 balancebeam-2"
+                .parse()
+                .unwrap(),
+        );
+        headers.append(VIA_HEADER, "1.1 inner".parse().unwrap());
+        assert!(!via_contains(&headers, "balancebeam"));
+        assert!(!via_contains(&headers, "1.1"));
+        assert!(via_contains(&headers, "balancebeam-2"));
+        assert!(via_contains(&headers, "inner"));
+    }
+
+    #[test]
+    fn test_check_via_token() {
+        assert!(check_via_token("balancebeam").is_ok());
+        assert!(check_via_token("edge-1.example_com").is_ok());
+        for invalid in ["", "balance beam", "a,b", "(balancebeam)"] {
+            assert!(check_via_token(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_forwarded_ipv4() {
+        let client: IpAddr = "192.0.2.43".parse().unwrap();
+        let by: SocketAddr = "10.0.0.1:1100".parse().unwrap();
+        assert_eq!(forwarded_node(Some(client), None), "192.0.2.43");
+        assert_eq!(
+            forwarded_element(Some(client), "http", Some(by)),
+            "for=192.0.2.43;proto=http;by=\"10.0.0.1:1100\""
+        );
+        let nodes = forwarded_for(&headers(&["for=192.0.2.43;proto=http"]));
+        assert_eq!(nodes, vec!["192.0.2.43"]);
+        assert_eq!(parse_forwarded_node(&nodes[0]), Some(client));
+        assert_eq!(parse_forwarded_node("192.0.2.43:4711"), Some(client));
+    }
+
+    #[test]
+    fn test_forwarded_ipv6() {
+        let client: IpAddr = "2001:db8:cafe::17".parse().unwrap();
+        let by: SocketAddr = "[::1]:1100".parse().unwrap();
+        assert_eq!(
+            forwarded_node(Some(client), None),
+            "\"[2001:db8:cafe::17]\""
+        );
+        assert_eq!(
+            forwarded_element(Some(client), "http", Some(by)),
+            "for=\"[2001:db8:cafe::17]\";proto=http;by=\"[::1]:1100\""
+        );
+        let nodes = forwarded_for(&headers(&["For=\"[2001:db8:cafe::17]:4711\""]));
+        assert_eq!(nodes, vec!["[2001:db8:cafe::17]:4711"]);
+        assert_eq!(parse_forwarded_node(&nodes[0]), Some(client));
+    }
+
+    #[test]
+    fn test_forwarded_obfuscated() {
+        let node = forwarded_node(None, None);
+        assert!(node.starts_with('_'), "{}", node);
+        assert_eq!(node.len(), 9);
+        assert!(forwarded_element(None, "http", None).starts_with("for=_"));
+        assert_eq!(parse_forwarded_node(&node), None);
+        assert_eq!(parse_forwarded_node("unknown"), None);
+    }
+
+    #[test]
+    fn test_forwarded_multiple_hops() {
+        let headers = headers(&[
+            "for=192.0.2.43, for=\"[2001:db8::1]\";by=\"a,b;c\"",
+            "proto=https;for=198.51.100.17",
+            "proto=http",
+        ]);
+        assert_eq!(
+            forwarded_for(&headers),
+            vec!["192.0.2.43", "[2001:db8::1]", "198.51.100.17", ""]
+        );
+    }
+}
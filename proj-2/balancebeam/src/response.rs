@@ -1,26 +1,81 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::chunked;
+use crate::request::{self, HeaderLimits};
+use crate::stream;
+use std::cmp::min;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::time::Duration;
 
-const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
-const MAX_NUM_HEADERS: usize = 32;
+/// How much of a streamed response body is passed on at a time.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
-    /// Client hung up before sending a complete request
-    IncompleteResponse,
-    /// Client sent an invalid HTTP request. httparse::Error contains more details
+    /// Upstream hung up before sending a complete response. IncompleteResponse contains the number
+    /// of bytes of the status line and headers (or once those have been read, of the body) that
+    /// were read before the upstream hung up
+    IncompleteResponse(usize),
+    /// Upstream sent an invalid HTTP response. httparse::Error contains more details
     MalformedResponse(httparse::Error),
+    /// The status line and headers are bigger than HeaderLimits::max_bytes, or there are more
+    /// headers than HeaderLimits::max_headers
+    HeadersTooLarge,
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The response body is bigger than balancebeam allows
     ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// Encountered an I/O error when reading/writing a stream
     ConnectionError(std::io::Error),
 }
 
+/// How the end of a response body that is still to be read will be found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    /// After this many bytes
+    Length(usize),
+    /// Transfer-Encoding: chunked
+    Chunked,
+    /// When the upstream closes the connection
+    UntilClose,
+}
+
+/// The body of a response from an upstream.
+pub enum Body {
+    /// All of it, read already
+    Full(Vec<u8>),
+    /// Still to be read from the upstream as it is passed on to the client, so that a large body
+    /// is never all in memory at once. Holds the start of it, which was read along with the
+    /// headers.
+    Streamed(Vec<u8>, Framing),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::IncompleteResponse(read) => {
+                write!(f, "upstream hung up after sending {} bytes", read)
+            }
+            Error::MalformedResponse(error) => write!(f, "malformed response: {}", error),
+            Error::HeadersTooLarge => write!(f, "response headers too large"),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::ContentLengthMismatch => write!(f, "body doesn't match its Content-Length"),
+            Error::ResponseBodyTooLarge => write!(f, "response body too large"),
+            Error::ConnectionError(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<chunked::Error> for Error {
+    fn from(error: chunked::Error) -> Error {
+        match error {
+            chunked::Error::Malformed(error) => Error::MalformedResponse(error),
+            chunked::Error::TooLarge => Error::ResponseBodyTooLarge,
+        }
+    }
+}
+
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -52,17 +107,22 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 ///   Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+#[allow(clippy::type_complexity)]
+fn parse_response(
+    buffer: &[u8],
+    max_headers: usize,
+) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(|error| match error {
+        httparse::Error::TooManyHeaders => Error::HeadersTooLarge,
+        error => Error::MalformedResponse(error),
+    })?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
             .status(resp.code.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(request::parse_version(resp.version));
         for header in resp.headers {
             response = response.header(header.name, header.value);
         }
@@ -77,135 +137,496 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// sent. This function only reads the response line and headers; the read_body function can
 /// subsequently be called in order to read the response body.
 ///
-/// Returns Ok(http::Response) if a valid response is received, or Error if not.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+/// Returns Ok(http::Response) if a valid response is received within `limits`, or Error if not.
+/// `start` is anything already read from the stream after the previous response.
+async fn read_headers(
+    stream: &mut (impl AsyncRead + Unpin),
+    limits: HeaderLimits,
+    start: Vec<u8>,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
-    let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut bytes_read = start.len();
+    let mut response_buffer = start;
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut response_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        // See if we've read a valid response so far
+        if bytes_read > 0 {
+            if let Some((mut response, headers_len)) =
+                parse_response(&response_buffer[..bytes_read], limits.max_headers)?
+            {
+                // We've read a complete set of headers. We may have also read the first part of
+                // the response body; take whatever is left over in the response buffer and save
+                // that as the start of the response body.
+                response
+                    .body_mut()
+                    .extend_from_slice(&response_buffer[headers_len..bytes_read]);
+                return Ok(response);
+            }
+        }
+
+        if !request::grow_head_buffer(&mut response_buffer, bytes_read, limits.max_bytes) {
+            return Err(Error::HeadersTooLarge);
+        }
+        // Read bytes from the connection into the buffer, starting at position bytes_read. An
+        // upstream that resets the connection before sending anything has hung up all the same.
+        let new_bytes = match stream.read(&mut response_buffer[bytes_read..]).await {
+            Ok(new_bytes) => new_bytes,
+            Err(error)
+                if bytes_read == 0 && error.kind() == std::io::ErrorKind::ConnectionReset =>
+            {
+                0
+            }
+            Err(error) => return Err(Error::ConnectionError(error)),
+        };
         if new_bytes == 0 {
             // We didn't manage to read a complete response
-            return Err(Error::IncompleteResponse);
+            return Err(Error::IncompleteResponse(bytes_read));
         }
         bytes_read += new_bytes;
+    }
+}
 
-        // See if we've read a valid response so far
-        if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
-            // We've read a complete set of headers. We may have also read the first part of the
-            // response body; take whatever is left over in the response buffer and save that as
-            // the start of the response body.
-            response
-                .body_mut()
-                .extend_from_slice(&response_buffer[headers_len..bytes_read]);
-            return Ok(response);
+/// Passes on `length` bytes of body, the first of which are `start`, counting them in `sent`.
+async fn copy_length(
+    start: &[u8],
+    length: usize,
+    upstream: &mut (impl AsyncRead + Unpin),
+    client: &mut (impl AsyncWrite + Unpin),
+    sent: &mut usize,
+) -> Result<(), Error> {
+    if start.len() > length {
+        return Err(Error::ContentLengthMismatch);
+    }
+    client
+        .write_all(start)
+        .await
+        .map_err(Error::ConnectionError)?;
+    *sent += start.len();
+    let mut buffer = vec![0_u8; min(STREAM_BUFFER_SIZE, length - *sent)];
+    while *sent < length {
+        let to_read = min(buffer.len(), length - *sent);
+        let bytes_read = upstream
+            .read(&mut buffer[..to_read])
+            .await
+            .map_err(Error::ConnectionError)?;
+        if bytes_read == 0 {
+            // Content-Length was set, but the server hung up before sending that many bytes
+            return Err(Error::ContentLengthMismatch);
         }
+        client
+            .write_all(&buffer[..bytes_read])
+            .await
+            .map_err(Error::ConnectionError)?;
+        *sent += bytes_read;
     }
+    Ok(())
 }
 
-/// This function reads the body for a response from the stream. If the Content-Length header is
-/// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
-    response: &mut http::Response<Vec<u8>>,
+/// Writes `data` as one chunk of a chunked body, unless there is none (which would end the body).
+/// If the body isn't `chunked`, `data` is written as it is.
+async fn write_chunk(
+    client: &mut (impl AsyncWrite + Unpin),
+    data: &[u8],
+    chunked: bool,
+) -> Result<(), Error> {
+    if data.is_empty() || !chunked {
+        return client.write_all(data).await.map_err(Error::ConnectionError);
+    }
+    let chunk = [format!("{:x}\r\n", data.len()).as_bytes(), data, b"\r\n"].concat();
+    client
+        .write_all(&chunk)
+        .await
+        .map_err(Error::ConnectionError)
+}
+
+/// Passes on a chunked body, which starts with `start`, chunk by chunk as it is decoded (or just
+/// decoded, if the client isn't sent it `chunked`), counting the decoded bytes in `sent`. Any
+/// trailers are dropped.
+async fn copy_chunked(
+    start: &[u8],
+    upstream: &mut (impl AsyncRead + Unpin),
+    client: &mut (impl AsyncWrite + Unpin),
+    sent: &mut usize,
+    max_size: usize,
+    chunked: bool,
 ) -> Result<(), Error> {
-    // The response may or may not supply a Content-Length header. If it provides the header, then
-    // we want to read that number of bytes; if it does not, we want to keep reading bytes until
-    // the connection is closed.
-    let content_length = get_content_length(response)?;
-
-    while content_length.is_none() || response.body().len() < content_length.unwrap() {
-        let mut buffer = [0_u8; 512];
-        let bytes_read = stream
+    let mut decoder = chunked::Decoder::new(max_size);
+    let mut buffer = vec![0_u8; STREAM_BUFFER_SIZE];
+    let mut done = decoder.push(start)?;
+    loop {
+        let decoded = decoder.take_body();
+        write_chunk(client, &decoded, chunked).await?;
+        *sent += decoded.len();
+        if done {
+            return Ok(());
+        }
+        let bytes_read = upstream
             .read(&mut buffer)
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if bytes_read == 0 {
-            // The server has hung up!
-            if content_length.is_none() {
-                // We've reached the end of the response
-                break;
-            } else {
-                // Content-Length was set, but the server hung up before we managed to read that
-                // number of bytes
-                return Err(Error::ContentLengthMismatch);
-            }
+            return Err(Error::IncompleteResponse(*sent));
         }
+        done = decoder.push(&buffer[..bytes_read])?;
+    }
+}
 
-        // Make sure the server doesn't send more bytes than it promised to send
-        if content_length.is_some() && response.body().len() + bytes_read > content_length.unwrap()
-        {
-            return Err(Error::ContentLengthMismatch);
+/// Passes on a body that ends when the upstream closes the connection, which starts with `start`,
+/// as chunks if it is sent `chunked`, counting its bytes in `sent`. The body is cut off at
+/// `max_size`.
+async fn copy_until_close(
+    start: &[u8],
+    upstream: &mut (impl AsyncRead + Unpin),
+    client: &mut (impl AsyncWrite + Unpin),
+    sent: &mut usize,
+    max_size: usize,
+    chunked: bool,
+) -> Result<(), Error> {
+    if start.len() > max_size {
+        return Err(Error::ResponseBodyTooLarge);
+    }
+    write_chunk(client, start, chunked).await?;
+    *sent += start.len();
+    let mut buffer = vec![0_u8; STREAM_BUFFER_SIZE];
+    loop {
+        let bytes_read = upstream
+            .read(&mut buffer)
+            .await
+            .map_err(Error::ConnectionError)?;
+        if bytes_read == 0 {
+            return Ok(());
         }
-
-        // Make sure server doesn't send more bytes than we allow
-        if response.body().len() + bytes_read > MAX_BODY_SIZE {
+        if bytes_read > max_size - *sent {
             return Err(Error::ResponseBodyTooLarge);
         }
-
-        // Append received bytes to the response body
-        response.body_mut().extend_from_slice(&buffer[..bytes_read]);
+        write_chunk(client, &buffer[..bytes_read], chunked).await?;
+        *sent += bytes_read;
     }
-    Ok(())
 }
 
-/// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
-///
-/// You will need to modify this function in Milestone 2.
+/// Whether chunked is the last transfer coding in these headers, which means a message's body is
+/// chunked (and any other codings apply to what the chunks hold).
+fn is_chunked(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all("transfer-encoding")
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Whether a response is only interim (1xx), so that the final response is still to follow. 101
+/// Switching Protocols is final, since the connection stops being HTTP after it.
+fn is_interim(status: http::StatusCode) -> bool {
+    status.is_informational() && status != http::StatusCode::SWITCHING_PROTOCOLS
+}
+
+/// This function reads an HTTP response's headers from a stream, returning an Error if the server
+/// closes the connection prematurely or sends an invalid response. The body is left to be streamed
+/// to the client by relay. A response whose Content-Length is bigger than `max_body_size` (if
+/// given) is refused with ResponseBodyTooLarge before any of its body is read. Interim responses
+/// are skipped, so the response returned is always the final one.
 pub async fn read_from_stream(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     request_method: &http::Method,
-) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
+    limits: HeaderLimits,
+    max_body_size: Option<usize>,
+) -> Result<http::Response<Body>, Error> {
+    let mut response = read_headers(stream, limits, Vec::new()).await?;
+    while is_interim(response.status()) {
+        let start = std::mem::take(response.body_mut());
+        response = read_headers(stream, limits, start).await?;
+    }
+    frame_body(response, request_method, max_body_size)
+}
+
+/// Reads an upstream's answer to the head of a request sent with `Expect: 100-continue`. Returns
+/// None once the upstream says 100 Continue, after which the body can be sent and the final
+/// response read with read_from_stream, or the final response if the upstream answers without
+/// wanting the body (such as with 417 Expectation Failed).
+pub async fn read_continue(
+    stream: &mut (impl AsyncRead + Unpin),
+    request_method: &http::Method,
+    limits: HeaderLimits,
+    max_body_size: Option<usize>,
+) -> Result<Option<http::Response<Body>>, Error> {
+    let mut start = Vec::new();
+    loop {
+        let response = read_headers(stream, limits, start).await?;
+        if response.status() == http::StatusCode::CONTINUE {
+            return Ok(None);
+        }
+        if !is_interim(response.status()) {
+            return frame_body(response, request_method, max_body_size).map(Some);
+        }
+        start = response.into_body();
+    }
+}
+
+/// Works out how the body of a response whose headers have been read ends, as read_from_stream
+/// describes.
+fn frame_body(
+    response: http::Response<Vec<u8>>,
+    request_method: &http::Method,
+    max_body_size: Option<usize>,
+) -> Result<http::Response<Body>, Error> {
+    // After 101 Switching Protocols, or a successful response to CONNECT, the connection becomes a
+    // tunnel, and whatever was read after the head already belongs to it. It is passed on as if it
+    // were the body.
+    if response.status() == http::StatusCode::SWITCHING_PROTOCOLS
+        || (request_method == http::Method::CONNECT && response.status().is_success())
+    {
+        return Ok(response.map(Body::Full));
+    }
+    // Otherwise a response may have a body as long as it is not responding to a HEAD request and
+    // as long as the response status code is not 1xx, 204 (no content), or 304 (not modified). Any
+    // Content-Length is kept, since it describes what the body would have been (RFC 7230 section
+    // 3.3.3).
+    if request_method == http::Method::HEAD
         || response.status().as_u16() < 200
         || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
+        || response.status() == http::StatusCode::NOT_MODIFIED
     {
-        read_body(stream, &mut response).await?;
+        return Ok(response.map(|_| Body::Full(Vec::new())));
+    }
+
+    // The body is framed by the first of these that applies (RFC 7230 section 3.3.3): a chunked
+    // Transfer-Encoding, any other Transfer-Encoding (whose body ends when the connection does,
+    // whatever Content-Length says), Content-Length, or else the connection closing.
+    let framing = if is_chunked(response.headers()) {
+        Framing::Chunked
+    } else if response.headers().contains_key("transfer-encoding") {
+        Framing::UntilClose
+    } else {
+        match get_content_length(&response)? {
+            Some(length) if max_body_size.is_some_and(|max_body_size| length > max_body_size) => {
+                return Err(Error::ResponseBodyTooLarge);
+            }
+            Some(length) => Framing::Length(length),
+            None => Framing::UntilClose,
+        }
+    };
+    // relay sends the client its own framing headers. A body that runs until the connection closes
+    // can't be followed by another response, which Connection: close tells the caller.
+    let mut response = response.map(|start| Body::Streamed(start, framing));
+    let headers = response.headers_mut();
+    headers.remove("transfer-encoding");
+    if framing == Framing::UntilClose {
+        headers.remove("content-length");
+        headers.insert("connection", http::HeaderValue::from_static("close"));
     }
     Ok(response)
 }
 
-/// This function serializes a response to bytes and writes those bytes to the provided stream.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
-    response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+/// Writes a response's status line and headers, along with `Transfer-Encoding: chunked` if its
+/// body is going to be sent chunked.
+async fn write_head<B>(
+    response: &http::Response<B>,
+    chunked: bool,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
-    stream
-        .write(&format_response_line(response).into_bytes())
-        .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    // balancebeam speaks HTTP/1.1 to clients, whichever version the upstream spoke
+    let status_line = format!(
+        "HTTP/1.1 {} {}",
+        response.status().as_str(),
+        response.status().canonical_reason().unwrap_or("")
+    );
+    stream.write_all(status_line.as_bytes()).await?;
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in response.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    if chunked {
+        stream.write_all(b"transfer-encoding: chunked\r\n").await?;
     }
+    stream.write_all(b"\r\n").await?;
     Ok(())
 }
 
-pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
+/// This function serializes a response to bytes and writes those bytes to the provided stream.
+pub async fn write_to_stream(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), std::io::Error> {
+    write_head(response, false, stream).await?;
+    if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
+    }
+    Ok(())
+}
+
+/// Whether the length of a response's body is known before it is sent, so that the client can be
+/// told it. Otherwise the body is sent chunked, or to an HTTP/1.0 client (which doesn't understand
+/// chunks) it ends when the connection is closed.
+pub fn has_known_length(response: &http::Response<Body>) -> bool {
+    match response.body() {
+        Body::Full(_) => true,
+        Body::Streamed(_, framing) => matches!(framing, Framing::Length(_)),
+    }
+}
+
+/// Reads the rest of a streamed response's body from `upstream` (as when the response is to be
+/// cached), as long as it turns out to be no bigger than `max_size`. The body is then Full, with a
+/// Content-Length saying how long it is. A bigger body is left Streamed, starting with everything
+/// read so far, for relay to pass on as usual.
+pub async fn buffer(
+    response: http::Response<Body>,
+    upstream: &mut (impl AsyncRead + Unpin),
+    max_size: usize,
+) -> Result<http::Response<Body>, Error> {
+    let (mut parts, body) = response.into_parts();
+    let (mut read, framing) = match body {
+        Body::Streamed(start, framing) => (start, framing),
+        body => return Ok(http::Response::from_parts(parts, body)),
+    };
+    let streamed = |parts, read| {
+        Ok(http::Response::from_parts(
+            parts,
+            Body::Streamed(read, framing),
+        ))
+    };
+    let mut decoder = chunked::Decoder::new(max_size);
+    let mut buffer = vec![0_u8; STREAM_BUFFER_SIZE];
+    let mut new_bytes = read.len();
+    let body = loop {
+        let to_read = match framing {
+            Framing::Length(length) if length > max_size => return streamed(parts, read),
+            Framing::Length(length) if read.len() > length => {
+                return Err(Error::ContentLengthMismatch)
+            }
+            Framing::Length(length) if read.len() == length => break read,
+            Framing::Length(length) => min(buffer.len(), length - read.len()),
+            Framing::Chunked => {
+                match decoder.push(&read[read.len() - new_bytes..]) {
+                    Ok(true) => break decoder.into_body(),
+                    Ok(false) => {}
+                    Err(chunked::Error::TooLarge) => return streamed(parts, read),
+                    Err(error) => return Err(error.into()),
+                }
+                buffer.len()
+            }
+            Framing::UntilClose if read.len() > max_size => return streamed(parts, read),
+            Framing::UntilClose => buffer.len(),
+        };
+        new_bytes = upstream
+            .read(&mut buffer[..to_read])
+            .await
+            .map_err(Error::ConnectionError)?;
+        if new_bytes == 0 {
+            match framing {
+                Framing::Length(_) => return Err(Error::ContentLengthMismatch),
+                Framing::Chunked => return Err(Error::IncompleteResponse(read.len())),
+                Framing::UntilClose => break read,
+            }
+        }
+        read.extend_from_slice(&buffer[..new_bytes]);
+    };
+    parts
+        .headers
+        .insert("content-length", http::HeaderValue::from(body.len()));
+    Ok(http::Response::from_parts(parts, Body::Full(body)))
+}
+
+/// Sends a response from `upstream` on to `client`, copying its body a piece at a time if it is
+/// streamed: chunked if its length isn't known, unless `client_version` is HTTP/1.0 (see
+/// has_known_length). Returns how many bytes of body were sent, and whether all of them were: if
+/// not, the client has been sent part of a response, so its connection can only be closed. That
+/// includes when a body of unknown length turns out to be bigger than `max_body_size` (if given),
+/// which gives ResponseBodyTooLarge. (read_from_stream has already refused bodies whose length
+/// says they are too big.)
+pub async fn relay(
+    response: &http::Response<Body>,
+    upstream: &mut (impl AsyncRead + Unpin),
+    client: &mut (impl AsyncWrite + Unpin),
+    client_version: http::Version,
+    max_body_size: Option<usize>,
+) -> (usize, Result<(), Error>) {
+    let max_body_size = max_body_size.unwrap_or(usize::MAX);
+    let mut sent = 0;
+    let result = async {
+        match response.body() {
+            Body::Full(body) => {
+                write_head(response, false, client)
+                    .await
+                    .map_err(Error::ConnectionError)?;
+                client
+                    .write_all(body)
+                    .await
+                    .map_err(Error::ConnectionError)?;
+                sent = body.len();
+                Ok(())
+            }
+            Body::Streamed(start, framing) => {
+                let chunked =
+                    !has_known_length(response) && client_version >= http::Version::HTTP_11;
+                write_head(response, chunked, client)
+                    .await
+                    .map_err(Error::ConnectionError)?;
+                match *framing {
+                    Framing::Length(length) => {
+                        copy_length(start, length, upstream, client, &mut sent).await
+                    }
+                    Framing::Chunked => {
+                        copy_chunked(start, upstream, client, &mut sent, max_body_size, chunked)
+                            .await
+                    }
+                    Framing::UntilClose => {
+                        copy_until_close(start, upstream, client, &mut sent, max_body_size, chunked)
+                            .await
+                    }
+                }?;
+                if chunked {
+                    client
+                        .write_all(b"0\r\n\r\n")
+                        .await
+                        .map_err(Error::ConnectionError)?;
+                }
+                Ok(())
+            }
+        }
+    }
+    .await;
+    (sent, result)
+}
+
+/// Tells a client that sent `Expect: 100-continue` to go ahead and send the request's body.
+pub async fn write_continue(client: &mut (impl AsyncWrite + Unpin)) -> Result<(), std::io::Error> {
+    client.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await
+}
+
+/// Tells a client that the tunnel its CONNECT request asked for is open.
+pub async fn write_connection_established(
+    client: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), std::io::Error> {
+    client
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await
+}
+
+/// Returns whether an upstream has sent anything since the end of the response that was last read
+/// from it. It can't have anything to say before it is sent another request, so it must have sent
+/// more body than it said it would, and the connection can't be used again.
+pub async fn sent_extra_bytes(upstream: &mut BufReader<impl AsyncRead + Unpin>) -> bool {
+    // Only what has already arrived counts: the zero timeout gives up as soon as peek would wait
+    let peek = tokio::time::timeout(Duration::from_secs(0), stream::peek(upstream));
+    matches!(peek.await, Ok(Ok(bytes)) if bytes > 0)
+}
+
+/// Returns whether an idle connection to an upstream can be sent another request: the upstream
+/// hasn't closed it, or sent anything on it (which couldn't be the answer to anything).
+pub async fn is_reusable(upstream: &mut BufReader<impl AsyncRead + Unpin>) -> bool {
+    let peek = tokio::time::timeout(Duration::from_secs(0), stream::peek(upstream));
+    // Only a connection with nothing to read would make peek wait
+    peek.await.is_err()
+}
+
+pub fn format_response_line<B>(response: &http::Response<B>) -> String {
     format!(
         "{:?} {} {}",
         response.version(),
@@ -217,12 +638,25 @@ pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
 /// This is a helper function that creates an http::Response containing an HTTP error that can be
 /// sent to a client.
 pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
-    let body = format!(
+    make_http_error_explained(status, "")
+}
+
+/// Makes an error response like make_http_error's, with `explanation` added to its body to say
+/// what the client did wrong.
+pub fn make_http_error_explained(
+    status: http::StatusCode,
+    explanation: &str,
+) -> http::Response<Vec<u8>> {
+    let mut body = format!(
         "HTTP {} {}",
         status.as_u16(),
         status.canonical_reason().unwrap_or("")
-    )
-    .into_bytes();
+    );
+    if !explanation.is_empty() {
+        body.push_str(": ");
+        body.push_str(explanation);
+    }
+    let body = body.into_bytes();
     http::Response::builder()
         .status(status)
         .header("Content-Type", "text/plain")
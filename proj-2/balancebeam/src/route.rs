@@ -0,0 +1,246 @@
+/// The group of upstreams that requests go to when no route matches them, and that upstreams
+/// without a group label belong to.
+pub const DEFAULT_GROUP: &str = "default";
+
+/// Checks that a group label is non-empty and only has letters, digits, `-` and `_` in it.
+pub fn parse_group(group: &str) -> Result<(), String> {
+    let valid = !group.is_empty()
+        && group
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid upstream group {:?}: expected letters, digits, - and _",
+            group
+        ))
+    }
+}
+
+/// Sends requests to groups of upstreams by the start of their paths. A request goes to the group
+/// of the longest prefix its path starts with, or to DEFAULT_GROUP if there isn't one.
+#[derive(Debug, Default)]
+pub struct Routes {
+    /// Each prefix, and the group its requests go to
+    routes: Vec<(String, String)>,
+}
+
+impl Routes {
+    /// Parses --route values: each a path prefix and a group, as prefix=group (e.g. /api=api).
+    pub fn parse(specs: &[String]) -> Result<Routes, String> {
+        let mut routes: Vec<(String, String)> = Vec::new();
+        for spec in specs {
+            let (prefix, group) = match spec.rsplit_once('=') {
+                Some((prefix, group)) if prefix.starts_with('/') => (prefix, group),
+                _ => {
+                    return Err(format!(
+                        "Invalid --route {:?}: expected a path prefix starting with / and a \
+                         group, such as /api=api",
+                        spec
+                    ))
+                }
+            };
+            parse_group(group)?;
+            if routes.iter().any(|(existing, _)| existing == prefix) {
+                return Err(format!("More than one --route for {}", prefix));
+            }
+            routes.push((prefix.to_string(), group.to_string()));
+        }
+        Ok(Routes { routes })
+    }
+
+    /// The group that requests for `path` go to.
+    pub fn group_for(&self, path: &str) -> &str {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(DEFAULT_GROUP, |(_, group)| group)
+    }
+
+    /// Each route's prefix and group, in the order they were given.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.routes
+            .iter()
+            .map(|(prefix, group)| (prefix.as_str(), group.as_str()))
+    }
+}
+
+/// The host a Host header (or the authority of a request's URI) names, as virtual hosts are looked
+/// up by: without its port or a trailing dot, and in lowercase.
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once(']').map_or(host, |(ip, _)| ip),
+        None => host.rsplit_once(':').map_or(host, |(host, _)| host),
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Sends requests to groups of upstreams by the host they are for. A host is either a name, which
+/// requests for it exactly go to, or a wildcard like *.example.com, which requests for any name
+/// ending in .example.com go to (but not example.com itself). An exact name wins over a wildcard,
+/// and a longer wildcard over a shorter one.
+#[derive(Debug, Default)]
+pub struct VirtualHosts {
+    /// Each host (normalized, as by normalize_host), and the group its requests go to
+    hosts: Vec<(String, String)>,
+}
+
+impl VirtualHosts {
+    /// Parses --vhost values: each a host and a group, as host=group (e.g. *.example.com=web).
+    pub fn parse(specs: &[String]) -> Result<VirtualHosts, String> {
+        let mut hosts: Vec<(String, String)> = Vec::new();
+        for spec in specs {
+            let invalid = || {
+                format!(
+                    "Invalid --vhost {:?}: expected a host (without a port) or a wildcard like \
+                     *.example.com, and a group, such as example.com=web",
+                    spec
+                )
+            };
+            let (host, group) = spec.rsplit_once('=').ok_or_else(invalid)?;
+            let name = host.strip_prefix("*.").unwrap_or(host);
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+            if !valid {
+                return Err(invalid());
+            }
+            parse_group(group)?;
+            let host = normalize_host(host);
+            if hosts.iter().any(|(existing, _)| *existing == host) {
+                return Err(format!("More than one --vhost for {}", host));
+            }
+            hosts.push((host, group.to_string()));
+        }
+        Ok(VirtualHosts { hosts })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    /// The group that requests for `host` (normalized) go to, if any --vhost matches it.
+    pub fn group_for(&self, host: &str) -> Option<&str> {
+        let exact = self.hosts.iter().find(|(name, _)| name == host);
+        let wildcard = || {
+            self.hosts
+                .iter()
+                .filter(|(name, _)| match name.strip_prefix('*') {
+                    Some(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+                    None => false,
+                })
+                .max_by_key(|(name, _)| name.len())
+        };
+        exact.or_else(wildcard).map(|(_, group)| group.as_str())
+    }
+
+    /// Each host and its group, in the order they were given.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.hosts
+            .iter()
+            .map(|(host, group)| (host.as_str(), group.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn routes(specs: &[&str]) -> Result<Routes, String> {
+        let specs: Vec<String> = specs.iter().map(|spec| spec.to_string()).collect();
+        Routes::parse(&specs)
+    }
+
+    #[test]
+    fn test_parse_group() {
+        assert!(parse_group("api").is_ok());
+        assert!(parse_group("group-a_2").is_ok());
+        for invalid in ["", "a b", "a/b", "a=b", "a;b"] {
+            assert!(parse_group(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(routes(&["/api=api", "/static=assets"]).is_ok());
+        for invalid in [
+            &["api=api"][..],
+            &["/api"],
+            &["/api="],
+            &["/api=a b"],
+            &["/api=api", "/api=other"],
+        ] {
+            assert!(routes(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_group_for() {
+        let routes = routes(&["/api=api", "/api/admin=admin", "/static=assets"]).unwrap();
+        assert_eq!(routes.group_for("/api/users"), "api");
+        // The longest matching prefix wins
+        assert_eq!(routes.group_for("/api/admin/users"), "admin");
+        assert_eq!(routes.group_for("/static/app.js"), "assets");
+        assert_eq!(routes.group_for("/"), DEFAULT_GROUP);
+        assert_eq!(routes.group_for("/apis"), "api");
+        assert_eq!(routes.group_for("/apple"), DEFAULT_GROUP);
+        assert_eq!(Routes::default().group_for("/api"), DEFAULT_GROUP);
+    }
+
+    fn vhosts(specs: &[&str]) -> Result<VirtualHosts, String> {
+        let specs: Vec<String> = specs.iter().map(|spec| spec.to_string()).collect();
+        VirtualHosts::parse(&specs)
+    }
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("example.com"), "example.com");
+        assert_eq!(normalize_host("Example.COM:8080"), "example.com");
+        assert_eq!(normalize_host("example.com."), "example.com");
+        assert_eq!(normalize_host("10.0.0.1:80"), "10.0.0.1");
+        assert_eq!(normalize_host("[::1]:8080"), "::1");
+        assert_eq!(normalize_host("[::1]"), "::1");
+    }
+
+    #[test]
+    fn test_parse_vhosts() {
+        assert!(vhosts(&["example.com=web", "*.example.com=web", "api.test=api"]).is_ok());
+        for invalid in [
+            &["example.com"][..],
+            &["=web"],
+            &["example.com="],
+            &["example.com:8080=web"],
+            &["*=web"],
+            &["*.=web"],
+            &["a.*.example.com=web"],
+            &["example.com=web", "Example.com=api"],
+        ] {
+            assert!(vhosts(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_vhost_group_for() {
+        let vhosts = vhosts(&[
+            "*.example.com=wild",
+            "*.api.example.com=api",
+            "www.example.com=www",
+            "Other.test=other",
+        ])
+        .unwrap();
+        assert_eq!(vhosts.group_for("www.example.com"), Some("www"));
+        assert_eq!(vhosts.group_for("shop.example.com"), Some("wild"));
+        assert_eq!(vhosts.group_for("a.b.example.com"), Some("wild"));
+        // The longest wildcard wins
+        assert_eq!(vhosts.group_for("v1.api.example.com"), Some("api"));
+        assert_eq!(vhosts.group_for("other.test"), Some("other"));
+        assert_eq!(vhosts.group_for("example.com"), None);
+        assert_eq!(vhosts.group_for("badexample.com"), None);
+        assert_eq!(vhosts.group_for("unknown.test"), None);
+        assert!(VirtualHosts::default().is_empty());
+    }
+}
@@ -0,0 +1,904 @@
+use crate::backoff::{Backoff, DEFAULT_MAX_BACKOFF};
+use crate::circuit_breaker::CircuitBreaker;
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Counts of how an upstream server's requests went. Requests update them through an Arc taken when
+/// the server is chosen, without locking the upstreams again.
+#[derive(Debug, Default)]
+pub struct UpstreamCounters {
+    /// Requests forwarded to the server that have finished, successfully or not
+    pub requests: AtomicU64,
+    /// Requests that got a 5xx response, whether from the server or from balancebeam because
+    /// forwarding to the server failed
+    pub server_errors: AtomicU64,
+    /// Failed attempts to connect to the server for a request
+    pub connect_failures: AtomicU64,
+}
+
+impl UpstreamCounters {
+    /// Counts a finished request that got a response with `status`.
+    pub fn record_response(&self, status: http::StatusCode) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if status.is_server_error() {
+            self.server_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_connect_failure(&self) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Requests that didn't get a 5xx response.
+    pub fn ok(&self) -> u64 {
+        self.requests
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.server_errors.load(Ordering::Relaxed))
+    }
+}
+
+/// What a load balancing strategy knows about an upstream server.
+#[derive(Clone, Debug)]
+pub struct UpstreamInfo {
+    pub address: String,
+    /// The hostname upstream (as given to --upstream) that the address was looked up for, if the
+    /// server was found that way
+    pub resolved_from: Option<String>,
+    /// The group of upstreams the server is in, which requests are routed to (see route::Routes)
+    pub group: String,
+    /// Relative share of requests this server gets. Servers with weight 0 are backups, which are
+    /// only used when every other server is dead.
+    pub weight: u32,
+    /// Whether the last connection attempt or health check succeeded
+    pub healthy: bool,
+    /// How many requests have been forwarded to this server and are still waiting for a response
+    pub in_flight: usize,
+    /// Moving average of how long the server takes to respond, or None if it hasn't responded
+    /// since it started or was last found dead
+    pub latency: Option<Duration>,
+    /// How many active health checks in a row the server has passed
+    pub consecutive_successes: u32,
+    /// How long to leave the server alone after connecting to it or forwarding to it has failed,
+    /// before requests try it again
+    pub reconnect_backoff: Backoff,
+    /// Stops requests going to the server for a while if it fails too many of them
+    pub circuit_breaker: CircuitBreaker,
+    /// When an active health check last brought the server back from the dead
+    pub recovered_at: Option<Instant>,
+    /// How long the server takes to work back up to its full share of requests after recovering
+    pub slow_start: Duration,
+    /// Where to send the server's active health checks, if not --active-health-check-path
+    pub health_check_path: Option<String>,
+    /// Whether the server has been taken out of use, while it finishes the requests it has already
+    /// been sent
+    pub draining: bool,
+    /// Shared with the requests sent to the server, so that they can count how they went
+    pub counters: Arc<UpstreamCounters>,
+}
+
+impl UpstreamInfo {
+    pub fn new(address: String, weight: u32) -> UpstreamInfo {
+        UpstreamInfo {
+            address,
+            resolved_from: None,
+            group: crate::route::DEFAULT_GROUP.to_string(),
+            weight,
+            healthy: true,
+            in_flight: 0,
+            latency: None,
+            consecutive_successes: 0,
+            reconnect_backoff: Backoff::new(DEFAULT_MAX_BACKOFF),
+            circuit_breaker: CircuitBreaker::disabled(),
+            recovered_at: None,
+            slow_start: Duration::from_secs(0),
+            health_check_path: None,
+            draining: false,
+            counters: Arc::new(UpstreamCounters::default()),
+        }
+    }
+
+    pub fn with_health_check_path(mut self, health_check_path: Option<String>) -> UpstreamInfo {
+        self.health_check_path = health_check_path;
+        self
+    }
+
+    /// Sets the longest the server is left alone after failing.
+    pub fn with_max_backoff(mut self, max: Duration) -> UpstreamInfo {
+        self.reconnect_backoff = Backoff::new(max);
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> UpstreamInfo {
+        self.circuit_breaker = circuit_breaker;
+        self
+    }
+
+    pub fn with_slow_start(mut self, slow_start: Duration) -> UpstreamInfo {
+        self.slow_start = slow_start;
+        self
+    }
+
+    pub fn with_group(mut self, group: String) -> UpstreamInfo {
+        self.group = group;
+        self
+    }
+
+    pub fn with_resolved_from(mut self, hostname: String) -> UpstreamInfo {
+        self.resolved_from = Some(hostname);
+        self
+    }
+
+    /// What the server is called: the hostname it was looked up for, or else its address. An
+    /// https:// server's certificate has to be for this name.
+    pub fn name(&self) -> &str {
+        self.resolved_from.as_deref().unwrap_or(&self.address)
+    }
+
+    /// How much of its usual share of requests, as a percentage, the server should get at `now`:
+    /// 10% just after it recovers, rising steadily to 100% over its slow-start period, so that it
+    /// isn't swamped while its caches are cold.
+    pub fn slow_start_percent_at(&self, now: Instant) -> u64 {
+        match self.recovered_at {
+            Some(recovered_at) if !self.slow_start.is_zero() => {
+                let progress = now.saturating_duration_since(recovered_at).as_secs_f64()
+                    / self.slow_start.as_secs_f64();
+                (10.0 + 90.0 * progress.min(1.0)).round() as u64
+            }
+            _ => 100,
+        }
+    }
+
+    /// Whether requests can be sent to the server at `now`.
+    pub fn is_available_at(&self, now: Instant) -> bool {
+        self.healthy && !self.draining && self.circuit_breaker.allows_request_at(now)
+    }
+
+    /// Records whether the server is up. A dead server's latency is forgotten, since it will be out
+    /// of date by the time the server recovers, and so is its run of passed health checks. A server
+    /// found to be up no longer needs to be left alone.
+    pub fn set_healthy(&mut self, healthy: bool) {
+        self.healthy = healthy;
+        if healthy {
+            self.reconnect_backoff.reset();
+        } else {
+            self.latency = None;
+            self.consecutive_successes = 0;
+        }
+    }
+
+    /// Records the result of an active health check. A failed check marks the server dead straight
+    /// away, but a dead server is only brought back once it has passed `recovery_threshold` checks
+    /// in a row, so that one that keeps failing under load isn't brought back after every check.
+    /// Passing a check closes the server's circuit breaker straight away.
+    pub fn record_health_check(&mut self, passed: bool, recovery_threshold: u32) {
+        if !passed {
+            self.set_healthy(false);
+            return;
+        }
+        self.circuit_breaker.close();
+        self.consecutive_successes = self.consecutive_successes.saturating_add(1);
+        if self.consecutive_successes >= recovery_threshold {
+            if !self.healthy {
+                self.recovered_at = Some(Instant::now());
+            }
+            self.set_healthy(true);
+        }
+    }
+
+    /// Adds a response time to the moving average. `decay` (between 0 and 1) is how much of the old
+    /// average is kept; the new response time makes up the rest.
+    pub fn record_latency(&mut self, latency: Duration, decay: f64) {
+        self.latency = Some(match self.latency {
+            Some(average) => average.mul_f64(decay) + latency.mul_f64(1.0 - decay),
+            None => latency,
+        });
+    }
+}
+
+/// Decides which upstream server each request is forwarded to.
+pub trait LoadBalancingStrategy {
+    /// Returns the index in `upstreams` of the server to use for a request from `client_ip`, or None
+    /// if none of them can be used.
+    fn pick(&self, upstreams: &[UpstreamInfo], client_ip: IpAddr) -> Option<usize>;
+
+    /// Called when a request is about to be forwarded to the upstream at `address`.
+    fn on_request_start(&self, _address: &str) {}
+
+    /// Called when the request forwarded to the upstream at `address` has finished, whether or not
+    /// it succeeded. The upstream may have been removed in the meantime.
+    fn on_request_end(&self, _address: &str) {}
+}
+
+/// The names accepted by `--strategy`.
+pub const STRATEGY_NAMES: &[&str] = &["random", "round-robin", "ip-hash", "p2c", "ewma"];
+
+/// Creates the strategy called `name`.
+pub fn from_name(name: &str) -> Option<Box<dyn LoadBalancingStrategy + Send + Sync>> {
+    match name {
+        "random" => Some(Box::new(Random::new())),
+        "round-robin" => Some(Box::new(RoundRobin::new())),
+        "ip-hash" => Some(Box::new(IpHash::new())),
+        "p2c" => Some(Box::new(PowerOfTwoChoices::new())),
+        "ewma" => Some(Box::new(Ewma::new())),
+        _ => None,
+    }
+}
+
+/// Returns the (index, weight) of each upstream that should share the load: the available ones
+/// (healthy, and not held back by their circuit breakers) with a non-zero weight, or if there are
+/// none of those, the available backups, each weighted equally. Recently recovered upstreams have
+/// their weights cut back while they slow-start.
+fn candidates(upstreams: &[UpstreamInfo]) -> Vec<(usize, u64)> {
+    let now = Instant::now();
+    let available = upstreams
+        .iter()
+        .enumerate()
+        .filter(|(_, upstream)| upstream.is_available_at(now));
+    let weighted: Vec<(usize, u64)> = available
+        .clone()
+        .filter(|(_, upstream)| upstream.weight > 0)
+        .map(|(idx, upstream)| {
+            let percent = upstream.slow_start_percent_at(now);
+            (idx, upstream.weight as u64 * percent)
+        })
+        .collect();
+    if !weighted.is_empty() {
+        return in_lowest_terms(weighted);
+    }
+    in_lowest_terms(
+        available
+            .map(|(idx, upstream)| (idx, upstream.slow_start_percent_at(now)))
+            .collect(),
+    )
+}
+
+/// Divides the weights by the largest number that divides them all, keeping their proportions
+/// while giving each upstream as few round-robin turns in a row as possible.
+fn in_lowest_terms(mut candidates: Vec<(usize, u64)>) -> Vec<(usize, u64)> {
+    let divisor = candidates
+        .iter()
+        .fold(0, |divisor, &(_, weight)| gcd(divisor, weight));
+    if divisor > 1 {
+        for (_, weight) in &mut candidates {
+            *weight /= divisor;
+        }
+    }
+    candidates
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Returns the index of the candidate that `target` falls on, when the candidates are laid end to
+/// end, each taking up as much room as its weight. `target` must be less than the total weight.
+fn pick_by_weight(candidates: &[(usize, u64)], mut target: u64) -> usize {
+    for &(idx, weight) in candidates {
+        if target < weight {
+            return idx;
+        }
+        target -= weight;
+    }
+    panic!("target is beyond the total weight");
+}
+
+fn total_weight(candidates: &[(usize, u64)]) -> u64 {
+    candidates.iter().map(|(_, weight)| weight).sum()
+}
+
+/// Picks a healthy upstream at random, in proportion to its weight.
+pub struct Random {
+    rng: Mutex<StdRng>,
+}
+
+impl Random {
+    pub fn new() -> Random {
+        Random {
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_seed(seed: u64) -> Random {
+        Random {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+}
+
+impl LoadBalancingStrategy for Random {
+    fn pick(&self, upstreams: &[UpstreamInfo], _client_ip: IpAddr) -> Option<usize> {
+        let candidates = candidates(upstreams);
+        if candidates.is_empty() {
+            return None;
+        }
+        let target = self.rng.lock().gen_range(0, total_weight(&candidates));
+        Some(pick_by_weight(&candidates, target))
+    }
+}
+
+/// Takes turns between the healthy upstreams, giving each as many turns in a row as its weight.
+pub struct RoundRobin {
+    /// How many picks have been made
+    turn: AtomicU64,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin {
+            turn: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LoadBalancingStrategy for RoundRobin {
+    fn pick(&self, upstreams: &[UpstreamInfo], _client_ip: IpAddr) -> Option<usize> {
+        let candidates = candidates(upstreams);
+        if candidates.is_empty() {
+            return None;
+        }
+        let turn = self.turn.fetch_add(1, Ordering::Relaxed);
+        Some(pick_by_weight(
+            &candidates,
+            turn % total_weight(&candidates),
+        ))
+    }
+}
+
+/// Picks two different healthy upstreams at random (in proportion to their weights), and uses
+/// whichever has fewer requests in flight for its weight. This avoids piling onto a busy upstream,
+/// which pure random selection does every so often, without having to look at every upstream.
+pub struct PowerOfTwoChoices {
+    rng: Mutex<StdRng>,
+}
+
+impl PowerOfTwoChoices {
+    pub fn new() -> PowerOfTwoChoices {
+        PowerOfTwoChoices {
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_seed(seed: u64) -> PowerOfTwoChoices {
+        PowerOfTwoChoices {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+}
+
+impl LoadBalancingStrategy for PowerOfTwoChoices {
+    fn pick(&self, upstreams: &[UpstreamInfo], _client_ip: IpAddr) -> Option<usize> {
+        let mut candidates = candidates(upstreams);
+        if candidates.is_empty() {
+            return None;
+        }
+        let mut rng = self.rng.lock();
+        let first = pick_by_weight(&candidates, rng.gen_range(0, total_weight(&candidates)));
+        // With only one upstream to choose from, there's nothing to compare it with
+        let first_weight = candidates.iter().find(|(idx, _)| *idx == first).unwrap().1;
+        candidates.retain(|(idx, _)| *idx != first);
+        if candidates.is_empty() {
+            return Some(first);
+        }
+        let second = pick_by_weight(&candidates, rng.gen_range(0, total_weight(&candidates)));
+        let second_weight = candidates.iter().find(|(idx, _)| *idx == second).unwrap().1;
+        // Compares in_flight / weight without dividing
+        let first_load = upstreams[first].in_flight as u64 * second_weight;
+        let second_load = upstreams[second].in_flight as u64 * first_weight;
+        if second_load < first_load {
+            Some(second)
+        } else {
+            Some(first)
+        }
+    }
+}
+
+/// Response times shorter than this count as this, so that one very fast response can't take all
+/// the traffic.
+const MIN_LATENCY: Duration = Duration::from_micros(100);
+
+/// Picks a healthy upstream at random, in proportion to its weight divided by its average response
+/// time, so traffic shifts away from servers that are slowing down. Slow servers still get some
+/// requests, so their average recovers once they speed up again. Servers without an average yet
+/// (new or recovered ones) count as having the mean of the others, so they are tried straight away
+/// without being flooded.
+pub struct Ewma {
+    rng: Mutex<StdRng>,
+}
+
+impl Ewma {
+    pub fn new() -> Ewma {
+        Ewma {
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_seed(seed: u64) -> Ewma {
+        Ewma {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+}
+
+impl LoadBalancingStrategy for Ewma {
+    fn pick(&self, upstreams: &[UpstreamInfo], _client_ip: IpAddr) -> Option<usize> {
+        let candidates = candidates(upstreams);
+        if candidates.is_empty() {
+            return None;
+        }
+        let known: Vec<f64> = candidates
+            .iter()
+            .filter_map(|&(idx, _)| upstreams[idx].latency)
+            .map(|latency| latency.max(MIN_LATENCY).as_secs_f64())
+            .collect();
+        let neutral = if known.is_empty() {
+            1.0
+        } else {
+            known.iter().sum::<f64>() / known.len() as f64
+        };
+        let scores: Vec<f64> = candidates
+            .iter()
+            .map(|&(idx, weight)| {
+                let latency = upstreams[idx]
+                    .latency
+                    .map_or(neutral, |latency| latency.max(MIN_LATENCY).as_secs_f64());
+                weight as f64 / latency
+            })
+            .collect();
+        let mut target = self.rng.lock().gen_range(0.0, scores.iter().sum::<f64>());
+        for (&(idx, _), score) in candidates.iter().zip(&scores) {
+            if target < *score {
+                return Some(idx);
+            }
+            target -= score;
+        }
+        // Rounding can leave the target just past the end
+        candidates.last().map(|&(idx, _)| idx)
+    }
+}
+
+/// How many points each unit of weight puts on the IpHash ring. More points spread the clients
+/// more evenly.
+const POINTS_PER_WEIGHT: u32 = 40;
+/// Weights above this count as this on the IpHash ring, to keep the ring small.
+const MAX_RING_WEIGHT: u32 = 64;
+
+fn hash<T: Hash>(value: T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A consistent hashing ring: each upstream owns points spread around the ring (in proportion to
+/// its weight), and a client belongs to the first point at or after its own hash, wrapping around.
+struct Ring {
+    /// The (address, weight) of each upstream the ring was built for
+    upstreams: Vec<(String, u32)>,
+    /// (hash, upstream index) for every point, sorted by hash
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn new(upstreams: &[UpstreamInfo]) -> Ring {
+        let mut points = Vec::new();
+        for (idx, upstream) in upstreams.iter().enumerate() {
+            // Backups need points too, for when they are all that's left
+            let weight = upstream.weight.clamp(1, MAX_RING_WEIGHT);
+            for point in 0..weight * POINTS_PER_WEIGHT {
+                points.push((hash((&upstream.address, point)), idx));
+            }
+        }
+        points.sort_unstable();
+        Ring {
+            upstreams: upstreams
+                .iter()
+                .map(|upstream| (upstream.address.clone(), upstream.weight))
+                .collect(),
+            points,
+        }
+    }
+
+    fn is_for(&self, upstreams: &[UpstreamInfo]) -> bool {
+        self.upstreams.len() == upstreams.len()
+            && self
+                .upstreams
+                .iter()
+                .zip(upstreams)
+                .all(|((address, weight), upstream)| {
+                    *address == upstream.address && *weight == upstream.weight
+                })
+    }
+}
+
+/// Sends each client to the same upstream every time (as long as it stays healthy), so that
+/// upstreams can keep per-client session state. Clients are placed on a consistent hashing ring,
+/// so when an upstream dies only its own clients move, each to the next healthy upstream around
+/// the ring, and they move back when it recovers.
+pub struct IpHash {
+    /// Built on first use, and rebuilt if the upstreams change
+    ring: Mutex<Option<Ring>>,
+}
+
+impl IpHash {
+    pub fn new() -> IpHash {
+        IpHash {
+            ring: Mutex::new(None),
+        }
+    }
+}
+
+impl LoadBalancingStrategy for IpHash {
+    fn pick(&self, upstreams: &[UpstreamInfo], client_ip: IpAddr) -> Option<usize> {
+        let candidates = candidates(upstreams);
+        if candidates.is_empty() {
+            return None;
+        }
+        let mut ring = self.ring.lock();
+        if !ring.as_ref().is_some_and(|ring| ring.is_for(upstreams)) {
+            *ring = Some(Ring::new(upstreams));
+        }
+        let points = &ring.as_ref().unwrap().points;
+        let start = points.partition_point(|&(point, _)| point < hash(client_ip));
+        points[start..]
+            .iter()
+            .chain(&points[..start])
+            .map(|&(_, idx)| idx)
+            .find(|idx| candidates.iter().any(|(candidate, _)| candidate == idx))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+    /// Builds an upstream list from (weight, healthy) pairs.
+    fn upstreams(servers: &[(u32, bool)]) -> Vec<UpstreamInfo> {
+        servers
+            .iter()
+            .enumerate()
+            .map(|(idx, &(weight, healthy))| UpstreamInfo {
+                address: format!("127.0.0.1:{}", 8000 + idx),
+                resolved_from: None,
+                group: crate::route::DEFAULT_GROUP.to_string(),
+                weight,
+                healthy,
+                in_flight: 0,
+                latency: None,
+                consecutive_successes: 0,
+                reconnect_backoff: Backoff::new(DEFAULT_MAX_BACKOFF),
+                circuit_breaker: CircuitBreaker::disabled(),
+                recovered_at: None,
+                slow_start: Duration::from_secs(0),
+                health_check_path: None,
+                draining: false,
+                counters: Arc::new(UpstreamCounters::default()),
+            })
+            .collect()
+    }
+
+    /// Picks an upstream many times, returning how often each one was picked.
+    fn pick_counts(strategy: &dyn LoadBalancingStrategy, upstreams: &[UpstreamInfo]) -> Vec<usize> {
+        let mut counts = vec![0; upstreams.len()];
+        for _ in 0..6000 {
+            counts[strategy.pick(upstreams, CLIENT).unwrap()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_from_name() {
+        for name in STRATEGY_NAMES {
+            assert!(from_name(name).is_some(), "{}", name);
+        }
+        assert!(from_name("fastest").is_none());
+    }
+
+    #[test]
+    fn test_random_is_weighted() {
+        let strategy = Random::with_seed(110);
+        let counts = pick_counts(&strategy, &upstreams(&[(1, true), (2, true), (3, true)]));
+        for (count, expected) in counts.iter().zip(&[1000, 2000, 3000]) {
+            assert!((*count as i64 - expected).abs() < 200, "{:?}", counts);
+        }
+        // Dead upstreams get nothing, and the rest share their load by weight
+        let counts = pick_counts(&strategy, &upstreams(&[(1, true), (2, true), (3, false)]));
+        assert_eq!(counts[2], 0);
+        assert!((counts[1] as i64 - 4000).abs() < 200, "{:?}", counts);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let strategy = RoundRobin::new();
+        let servers = upstreams(&[(1, true), (1, true), (1, true)]);
+        let picks: Vec<usize> = (0..6)
+            .map(|_| strategy.pick(&servers, CLIENT).unwrap())
+            .collect();
+        assert_eq!(picks, vec![0, 1, 2, 0, 1, 2]);
+        // Dead upstreams are skipped, and weights give upstreams more turns
+        let strategy = RoundRobin::new();
+        let servers = upstreams(&[(2, true), (5, false), (1, true)]);
+        let picks: Vec<usize> = (0..6)
+            .map(|_| strategy.pick(&servers, CLIENT).unwrap())
+            .collect();
+        assert_eq!(picks, vec![0, 0, 2, 0, 0, 2]);
+    }
+
+    #[test]
+    fn test_backups() {
+        let strategies: Vec<Box<dyn LoadBalancingStrategy>> = vec![
+            Box::new(Random::with_seed(110)),
+            Box::new(RoundRobin::new()),
+            Box::new(PowerOfTwoChoices::with_seed(110)),
+            Box::new(Ewma::with_seed(110)),
+        ];
+        for strategy in &strategies {
+            // Backups are left alone while anything else is alive
+            let counts = pick_counts(
+                strategy.as_ref(),
+                &upstreams(&[(0, true), (1, true), (0, true)]),
+            );
+            assert_eq!(counts, vec![0, 6000, 0]);
+            // ...and share the load evenly once nothing else is
+            let counts = pick_counts(
+                strategy.as_ref(),
+                &upstreams(&[(0, true), (1, false), (0, true)]),
+            );
+            assert_eq!(counts[1], 0);
+            assert!((counts[0] as i64 - 3000).abs() < 200, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn test_all_dead() {
+        let servers = upstreams(&[(1, false), (0, false)]);
+        for name in STRATEGY_NAMES {
+            let strategy = from_name(name).unwrap();
+            assert_eq!(strategy.pick(&servers, CLIENT), None, "{}", name);
+            assert_eq!(strategy.pick(&[], CLIENT), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_p2c_avoids_busy_upstreams() {
+        let strategy = PowerOfTwoChoices::with_seed(110);
+        let mut servers = upstreams(&[(1, true), (1, true), (1, true)]);
+        // With nothing in flight, it's as good as random
+        let counts = pick_counts(&strategy, &servers);
+        for count in &counts {
+            assert!((*count as i64 - 2000).abs() < 200, "{:?}", counts);
+        }
+        // The busiest upstream loses every comparison, so it's never picked
+        servers[0].in_flight = 5;
+        servers[1].in_flight = 1;
+        let counts = pick_counts(&strategy, &servers);
+        assert_eq!(counts[0], 0);
+        // upstream 2 wins whenever it's one of the two, which is two times out of three
+        assert!((counts[2] as i64 - 4000).abs() < 200, "{:?}", counts);
+        // Load is relative to weight: 4 requests for weight 4 is less than 2 for weight 1
+        let mut servers = upstreams(&[(4, true), (1, true)]);
+        servers[0].in_flight = 4;
+        servers[1].in_flight = 2;
+        assert_eq!(pick_counts(&strategy, &servers), vec![6000, 0]);
+    }
+
+    #[test]
+    fn test_p2c_with_one_healthy_upstream() {
+        let strategy = PowerOfTwoChoices::with_seed(110);
+        let mut servers = upstreams(&[(1, false), (1, true), (1, false)]);
+        servers[1].in_flight = 10;
+        assert_eq!(pick_counts(&strategy, &servers), vec![0, 6000, 0]);
+    }
+
+    #[test]
+    fn test_counters() {
+        let counters = UpstreamCounters::default();
+        counters.record_response(http::StatusCode::OK);
+        counters.record_response(http::StatusCode::NOT_FOUND);
+        counters.record_response(http::StatusCode::BAD_GATEWAY);
+        counters.record_connect_failure();
+        assert_eq!(counters.requests.load(Ordering::Relaxed), 3);
+        assert_eq!(counters.server_errors.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.connect_failures.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.ok(), 2);
+    }
+
+    #[test]
+    fn test_record_latency() {
+        let mut upstream = UpstreamInfo::new("127.0.0.1:8000".to_string(), 1);
+        upstream.record_latency(Duration::from_millis(100), 0.75);
+        assert_eq!(upstream.latency, Some(Duration::from_millis(100)));
+        upstream.record_latency(Duration::from_millis(20), 0.75);
+        assert_eq!(upstream.latency, Some(Duration::from_millis(80)));
+        // Coming back up doesn't bring back the old average
+        upstream.set_healthy(true);
+        assert!(upstream.latency.is_some());
+        upstream.set_healthy(false);
+        upstream.set_healthy(true);
+        assert_eq!(upstream.latency, None);
+    }
+
+    #[test]
+    fn test_record_health_check() {
+        let mut upstream = UpstreamInfo::new("127.0.0.1:8000".to_string(), 1);
+        upstream.record_health_check(false, 3);
+        assert!(!upstream.healthy);
+        upstream.record_health_check(true, 3);
+        upstream.record_health_check(true, 3);
+        assert!(!upstream.healthy);
+        // A failure, or being found dead while forwarding, starts the count again
+        upstream.set_healthy(false);
+        upstream.record_health_check(true, 3);
+        upstream.record_health_check(true, 3);
+        assert!(!upstream.healthy);
+        upstream.record_health_check(true, 3);
+        assert!(upstream.healthy);
+        // One failure is enough to mark it dead again
+        upstream.record_health_check(false, 3);
+        assert!(!upstream.healthy);
+        // With a threshold of 1, one success brings it back
+        upstream.record_health_check(true, 1);
+        assert!(upstream.healthy);
+    }
+
+    #[test]
+    fn test_slow_start_percent() {
+        let now = Instant::now();
+        let mut upstream = UpstreamInfo::new("127.0.0.1:8000".to_string(), 1)
+            .with_slow_start(Duration::from_secs(10));
+        assert_eq!(upstream.slow_start_percent_at(now), 100);
+        upstream.recovered_at = Some(now);
+        assert_eq!(upstream.slow_start_percent_at(now), 10);
+        assert_eq!(
+            upstream.slow_start_percent_at(now + Duration::from_secs(5)),
+            55
+        );
+        assert_eq!(
+            upstream.slow_start_percent_at(now + Duration::from_secs(10)),
+            100
+        );
+        assert_eq!(
+            upstream.slow_start_percent_at(now + Duration::from_secs(60)),
+            100
+        );
+        // Without a slow-start period, a recovered server gets its full share straight away
+        upstream.slow_start = Duration::from_secs(0);
+        assert_eq!(upstream.slow_start_percent_at(now), 100);
+    }
+
+    #[test]
+    fn test_slow_start_after_recovery() {
+        let mut servers = upstreams(&[(1, true), (2, false), (0, false)]);
+        servers[1].slow_start = Duration::from_secs(1000);
+        // Weights are kept as small as they can be when nothing is slow-starting
+        assert_eq!(candidates(&servers), vec![(0, 1)]);
+        servers[1].record_health_check(true, 1);
+        assert!(servers[1].recovered_at.is_some());
+        assert_eq!(candidates(&servers), vec![(0, 5), (1, 1)]);
+        // Backups slow-start too
+        let mut servers = upstreams(&[(0, true), (0, false)]);
+        servers[1].slow_start = Duration::from_secs(1000);
+        servers[1].record_health_check(true, 1);
+        assert_eq!(candidates(&servers), vec![(0, 10), (1, 1)]);
+        // Passing checks while healthy doesn't start it again
+        servers[0].record_health_check(true, 1);
+        assert_eq!(servers[0].recovered_at, None);
+    }
+
+    #[test]
+    fn test_open_circuit_breaker() {
+        let mut servers = upstreams(&[(1, true), (1, true), (0, true)]);
+        servers[0].circuit_breaker =
+            CircuitBreaker::new(1, Duration::from_secs(10), Duration::from_secs(60));
+        servers[0].circuit_breaker.record_failure_at(Instant::now());
+        assert!(servers[0].healthy);
+        assert_eq!(pick_counts(&RoundRobin::new(), &servers), vec![0, 6000, 0]);
+        // A passing health check closes it
+        servers[0].record_health_check(true, 1);
+        assert_eq!(
+            pick_counts(&RoundRobin::new(), &servers),
+            vec![3000, 3000, 0]
+        );
+    }
+
+    #[test]
+    fn test_ewma_prefers_fast_upstreams() {
+        let strategy = Ewma::with_seed(110);
+        // Traffic is split in inverse proportion to latency, times weight
+        let mut servers = upstreams(&[(1, true), (1, true), (2, true)]);
+        servers[0].latency = Some(Duration::from_millis(10));
+        servers[1].latency = Some(Duration::from_millis(40));
+        servers[2].latency = Some(Duration::from_millis(80));
+        let counts = pick_counts(&strategy, &servers);
+        for (count, expected) in counts.iter().zip(&[4000, 1000, 1000]) {
+            assert!((*count as i64 - expected).abs() < 200, "{:?}", counts);
+        }
+        // An upstream with no samples counts as average (25ms), so it still gets its share
+        servers[2].latency = None;
+        servers[2].weight = 1;
+        let counts = pick_counts(&strategy, &servers);
+        for (count, expected) in counts.iter().zip(&[4000, 1000, 1600]) {
+            assert!(
+                (*count as i64 - expected * 6000 / 6600).abs() < 200,
+                "{:?}",
+                counts
+            );
+        }
+        // With no samples at all, it's as good as random
+        let counts = pick_counts(&strategy, &upstreams(&[(1, true), (1, true)]));
+        assert!((counts[0] as i64 - 3000).abs() < 200, "{:?}", counts);
+    }
+
+    /// Returns the upstream that IpHash picks for each of a few hundred clients.
+    fn ip_hash_picks(strategy: &IpHash, upstreams: &[UpstreamInfo]) -> Vec<usize> {
+        (0..300)
+            .map(|n| {
+                let client = IpAddr::V4(Ipv4Addr::new(10, 0, (n / 256) as u8, (n % 256) as u8));
+                strategy.pick(upstreams, client).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_ip_hash_is_sticky() {
+        let strategy = IpHash::new();
+        let servers = upstreams(&[(1, true), (1, true), (1, true)]);
+        let first = strategy.pick(&servers, CLIENT).unwrap();
+        for _ in 0..10 {
+            assert_eq!(strategy.pick(&servers, CLIENT), Some(first));
+        }
+        // Different clients are spread over every upstream
+        let picks = ip_hash_picks(&strategy, &servers);
+        for idx in 0..servers.len() {
+            let count = picks.iter().filter(|&&pick| pick == idx).count();
+            assert!(count > 50, "upstream {} got {} of 300 clients", idx, count);
+        }
+    }
+
+    #[test]
+    fn test_ip_hash_only_moves_clients_of_dead_upstreams() {
+        let strategy = IpHash::new();
+        let mut servers = upstreams(&[(1, true), (1, true), (1, true)]);
+        let before = ip_hash_picks(&strategy, &servers);
+        servers[1].healthy = false;
+        let after = ip_hash_picks(&strategy, &servers);
+        for (before, after) in before.iter().zip(&after) {
+            if *before == 1 {
+                assert_ne!(*after, 1);
+            } else {
+                assert_eq!(before, after);
+            }
+        }
+        // Once it recovers, its clients come back
+        servers[1].healthy = true;
+        assert_eq!(ip_hash_picks(&strategy, &servers), before);
+    }
+
+    #[test]
+    fn test_ip_hash_backups() {
+        let strategy = IpHash::new();
+        let servers = upstreams(&[(0, true), (1, true)]);
+        assert!(ip_hash_picks(&strategy, &servers)
+            .iter()
+            .all(|&pick| pick == 1));
+        let servers = upstreams(&[(0, true), (1, false)]);
+        assert!(ip_hash_picks(&strategy, &servers)
+            .iter()
+            .all(|&pick| pick == 0));
+    }
+}
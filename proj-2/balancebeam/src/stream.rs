@@ -0,0 +1,143 @@
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// What balancebeam can talk to an upstream over: a TcpStream, a TLS stream over one, or a
+/// UnixStream.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Stream for T {}
+
+/// A connection to an upstream. It is buffered so that whether the upstream has sent anything can
+/// be checked (see peek) without taking it out of the stream, which only a TcpStream or UnixStream
+/// could otherwise do.
+pub type UpstreamStream = BufReader<Box<dyn Stream>>;
+
+/// The scheme that makes an upstream address (as given to --upstream) one that speaks HTTPS.
+const HTTPS_SCHEME: &str = "https://";
+
+/// The prefix that makes an upstream address the path of a Unix domain socket to connect to.
+const UNIX_PREFIX: &str = "unix:";
+
+/// The path of the Unix domain socket an upstream address names, if it names one.
+pub fn unix_path(address: &str) -> Option<&str> {
+    address.strip_prefix(UNIX_PREFIX)
+}
+
+/// Splits an upstream address into the host:port to connect to, and whether the upstream speaks
+/// HTTPS. Any scheme other than https:// has been refused by parse_scheme. (Unix socket
+/// addresses, which have no host:port, are returned as they are.)
+pub fn split_scheme(address: &str) -> (&str, bool) {
+    match address.strip_prefix(HTTPS_SCHEME) {
+        Some(host_port) => (host_port, true),
+        None => (address, false),
+    }
+}
+
+/// Checks that an upstream address is host:port, https://host:port or unix:/path.
+pub fn parse_scheme(address: &str) -> Result<(), String> {
+    match (address.split_once("://"), unix_path(address)) {
+        (Some((scheme, _)), _) if !address.starts_with(HTTPS_SCHEME) => Err(format!(
+            "Unknown scheme {:?} for upstream {:?}: expected host:port, https://host:port or \
+             unix:/path",
+            scheme, address
+        )),
+        (_, Some("")) => Err(format!(
+            "Upstream {:?} is missing the path of its Unix socket",
+            address
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The host in a host:port, as its certificate has to name it (without the brackets around an
+/// IPv6 address).
+pub fn host(host_port: &str) -> &str {
+    let host = host_port
+        .rsplit_once(':')
+        .map_or(host_port, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Connects to an upstream by its address, and if it speaks HTTPS, completes a TLS handshake with
+/// it using `tls`, expecting its certificate to be for the host in `name`: the hostname the
+/// address was resolved from, or else the address itself. Both have to be done within `timeout`.
+/// A unix:/path address is connected to over a Unix domain socket instead. If there is a
+/// `proxy_header` (see proxy_protocol), it is sent first, before any TLS handshake.
+pub async fn connect(
+    address: &str,
+    name: &str,
+    timeout: Duration,
+    tls: &tokio_tls::TlsConnector,
+    proxy_header: Option<&str>,
+) -> std::io::Result<UpstreamStream> {
+    let (host_port, https) = split_scheme(address);
+    let connect = async {
+        let mut stream: Box<dyn Stream> = match unix_path(address) {
+            Some(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+            None => Box::new(tokio::net::TcpStream::connect(host_port).await?),
+        };
+        if let Some(header) = proxy_header {
+            stream.write_all(header.as_bytes()).await?;
+        }
+        if https {
+            let (name, _) = split_scheme(name);
+            let handshake = tls.connect(host(name), stream).await;
+            stream = Box::new(handshake.map_err(std::io::Error::other)?);
+        }
+        Ok(BufReader::new(stream))
+    };
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "connection timed out",
+        )),
+    }
+}
+
+/// Waits until there is something to read from `stream`, or it is closed, and returns how many
+/// bytes are waiting (0 once it is closed), leaving them to be read.
+pub async fn peek(stream: &mut BufReader<impl AsyncRead + Unpin>) -> std::io::Result<usize> {
+    tokio::future::poll_fn(|cx| {
+        Pin::new(&mut *stream)
+            .poll_fill_buf(cx)
+            .map_ok(|buffered| buffered.len())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_scheme() {
+        assert_eq!(split_scheme("10.0.0.5:8080"), ("10.0.0.5:8080", false));
+        assert_eq!(
+            split_scheme("https://api.internal:443"),
+            ("api.internal:443", true)
+        );
+        assert!(parse_scheme("api.internal:443").is_ok());
+        assert!(parse_scheme("https://api.internal:443").is_ok());
+        assert!(parse_scheme("http://api.internal:80").is_err());
+        assert!(parse_scheme("ftp://api.internal:21").is_err());
+    }
+
+    #[test]
+    fn test_unix_path() {
+        assert_eq!(unix_path("unix:/run/app.sock"), Some("/run/app.sock"));
+        assert_eq!(unix_path("10.0.0.5:8080"), None);
+        assert_eq!(unix_path("https://api.internal:443"), None);
+        assert!(parse_scheme("unix:/run/app.sock").is_ok());
+        assert!(parse_scheme("unix:").is_err());
+        assert!(parse_scheme("unix:///run/app.sock").is_err());
+    }
+
+    #[test]
+    fn test_host() {
+        assert_eq!(host("api.internal:443"), "api.internal");
+        assert_eq!(host("127.0.0.1:8443"), "127.0.0.1");
+        assert_eq!(host("[::1]:8443"), "::1");
+    }
+}
@@ -1,7 +1,18 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, Server};
+use common::{
+    init_logging, masked_frame, random_address, read_frame, BalanceBeam, EchoServer, Framing,
+    FramingServer, ProxyProtocolServer, Server, TlsServer, WebSocketServer, CLOSE_FRAME,
+    LARGE_BODY_SIZE, OVERLONG_CLAIMED_SIZE, TEXT_FRAME, TLS_CERT, TLS_KEY, WEBSOCKET_ACCEPT,
+    WEBSOCKET_KEY,
+};
+use nix::sys::signal::Signal;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::delay_for;
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -10,6 +21,13 @@ async fn setup() -> (BalanceBeam, EchoServer) {
     (balancebeam, upstream)
 }
 
+async fn setup_with_args(args: &[&str]) -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], args).await;
+    (balancebeam, upstream)
+}
+
 /// Test the simple case: open a few connections, each with only a single request, and make sure
 /// things are delivered correctly.
 #[tokio::test]
@@ -24,6 +42,10 @@ async fn test_simple_connections() {
     assert!(response_text.contains("GET /first_url HTTP/1.1"));
     assert!(response_text.contains("x-sent-by: balancebeam-tests"));
     assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+    assert!(response_text.contains("x-real-ip: 127.0.0.1"));
+    assert!(response_text.contains("x-forwarded-proto: http"));
+    assert!(response_text.contains(&format!("x-forwarded-port: {}", port)));
 
     log::info!("Sending a POST request");
     let response_text = balancebeam
@@ -99,3 +121,1580 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// On SIGTERM, balancebeam should stop accepting connections, but finish the requests it is already
+/// handling before exiting.
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_secs(1)).await;
+    let mut balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    // Make sure balancebeam is accepting connections before asking it to shut down
+    balancebeam
+        .get("/warmup")
+        .await
+        .expect("Error sending request to balancebeam");
+    // A connection that isn't in the middle of a request shouldn't hold up shutting down
+    let _idle = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+
+    log::info!("Sending SIGTERM while a request is in flight");
+    let in_flight = balancebeam.get("/in-flight");
+    let shut_down = async {
+        delay_for(Duration::from_millis(300)).await;
+        balancebeam.send_signal(Signal::SIGTERM);
+        delay_for(Duration::from_millis(300)).await;
+        assert!(
+            TcpStream::connect(&balancebeam.address).await.is_err(),
+            "balancebeam should stop accepting connections when it starts shutting down"
+        );
+    };
+    let (response, ()) = tokio::join!(in_flight, shut_down);
+    let response_text = response.expect("The request in flight should have been answered");
+    assert!(response_text.contains("GET /in-flight HTTP/1.1"));
+
+    log::info!("Checking that balancebeam exits once the request is done");
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam should exit once the request in flight has finished");
+    assert!(status.success());
+
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam accepts connections on every --bind address, refuses to start if one of
+/// them can't be bound (unless --bind-lenient), and stops listening on all of them when shutting
+/// down.
+#[tokio::test]
+async fn test_multiple_binds() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+
+    let second_address = random_address();
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--bind", &second_address]).await;
+    for address in [&balancebeam.address, &second_address] {
+        log::info!("Sending a request to {}", address);
+        let response_text = reqwest::get(&format!("http://{}/bound", address))
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains("GET /bound HTTP/1.1"));
+    }
+    balancebeam.send_signal(Signal::SIGTERM);
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam should exit once it has stopped listening");
+    assert!(status.success());
+    for address in [&balancebeam.address, &second_address] {
+        assert!(TcpStream::connect(address).await.is_err());
+    }
+
+    // The upstream's address is already taken
+    log::info!("Binding to an address that is in use");
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--bind", &upstream.address]).await;
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam should exit when it can't bind to an address");
+    assert!(!status.success());
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--bind", &upstream.address, "--bind-lenient"],
+    )
+    .await;
+    let response_text = balancebeam
+        .get("/lenient")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /lenient HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+}
+
+fn access_log_path() -> PathBuf {
+    let log_path = std::env::temp_dir().join(format!(
+        "balancebeam-access-{}.log",
+        random_address().replace(|c: char| !c.is_ascii_digit(), "")
+    ));
+    let _ = std::fs::remove_file(&log_path);
+    log_path
+}
+
+/// Reads the access log once it has `lines` lines, since they are written in the background.
+async fn read_access_log(log_path: &Path, lines: usize) -> String {
+    for _ in 0..20 {
+        let log = std::fs::read_to_string(log_path).unwrap_or_default();
+        if log.lines().count() >= lines {
+            return log;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    panic!("Access log {:?} never got {} lines", log_path, lines);
+}
+
+/// Responses from the upstream and from balancebeam itself both go in the access log
+#[tokio::test]
+async fn test_json_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log_path = access_log_path();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "1",
+            "--access-log-format",
+            "json",
+            "--access-log-file",
+            log_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    log::info!("Sending a request, then one over the rate limit");
+    balancebeam
+        .get("/first?page=1")
+        .await
+        .expect("Error sending request to balancebeam");
+    balancebeam
+        .get("/second")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    let log = read_access_log(&log_path, 2).await;
+    let _ = std::fs::remove_file(&log_path);
+    log::info!("Access log:\n{}", log);
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("{\"time\":\""));
+    assert!(lines[0].contains(&format!(
+        "\"client\":\"127.0.0.1\",\"method\":\"GET\",\"path\":\"/first?page=1\",\
+         \"upstream\":\"{}\",\"group\":\"default\",\"status\":200,",
+        upstream.address
+    )));
+    assert!(lines[0].ends_with("\"origin\":\"upstream\"}"));
+    assert!(lines[1].contains(
+        "\"method\":\"GET\",\"path\":\"/second\",\"upstream\":null,\"group\":null,\"status\":429,"
+    ));
+    assert!(lines[1].ends_with("\"origin\":\"proxy\"}"));
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// The Common Log Format access log is reopened on SIGUSR1, after being moved aside for rotation
+#[tokio::test]
+async fn test_clf_access_log_rotation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log_path = access_log_path();
+    let rotated_path = log_path.with_extension("log.1");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log-format",
+            "clf",
+            "--access-log-file",
+            log_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    balancebeam
+        .get("/before")
+        .await
+        .expect("Error sending request to balancebeam");
+    let log = read_access_log(&log_path, 1).await;
+    log::info!("Access log:\n{}", log);
+    assert!(log.starts_with("127.0.0.1 - - ["));
+    assert!(log.contains("\"GET /before HTTP/1.1\" 200 "));
+
+    log::info!("Rotating the access log");
+    std::fs::rename(&log_path, &rotated_path).unwrap();
+    balancebeam.send_signal(Signal::SIGUSR1);
+    delay_for(Duration::from_millis(500)).await;
+    balancebeam
+        .get("/after")
+        .await
+        .expect("Error sending request to balancebeam");
+    let log = read_access_log(&log_path, 1).await;
+    let rotated = std::fs::read_to_string(&rotated_path).unwrap();
+    let _ = std::fs::remove_file(&log_path);
+    let _ = std::fs::remove_file(&rotated_path);
+    assert!(log.contains("\"GET /after HTTP/1.1\" 200 "));
+    assert!(!log.contains("/before"));
+    assert_eq!(rotated.lines().count(), 1);
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Sends a GET request to balancebeam, with an X-Request-Id if given, returning the response's
+/// X-Request-Id and body.
+async fn get_with_request_id(
+    balancebeam: &BalanceBeam,
+    path: &str,
+    request_id: Option<&str>,
+) -> (String, String) {
+    let mut request =
+        reqwest::Client::new().get(&format!("http://{}{}", balancebeam.address, path));
+    if let Some(request_id) = request_id {
+        request = request.header("x-request-id", request_id);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let response_id = response
+        .headers()
+        .get("x-request-id")
+        .expect("Response has no X-Request-Id")
+        .to_str()
+        .unwrap()
+        .to_string();
+    (response_id, response.text().await.unwrap())
+}
+
+#[tokio::test]
+async fn test_rfc7239_forwarded_header() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--forwarded-header", "rfc7239"]).await;
+
+    let response_text = balancebeam
+        .get("/")
+        .await
+        .expect("Error sending request to balancebeam");
+    let element = format!(
+        "forwarded: for=127.0.0.1;proto=http;by=\"{}\"",
+        balancebeam.address
+    );
+    assert!(response_text.contains(&element), "{}", response_text);
+    assert!(!response_text.contains("x-forwarded-for"));
+
+    Box::new(upstream).stop().await;
+}
+
+/// Make sure hop-by-hop headers, including any the Connection header names, aren't forwarded,
+/// and that a client asking for its connection to be closed has it closed after the response.
+#[tokio::test]
+async fn test_hop_by_hop_headers() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    conn.write_all(
+        b"GET /hop HTTP/1.1\r\nHost: balancebeam\r\nConnection: close, X-Secret\r\n\
+          X-Secret: hunter2\r\nKeep-Alive: timeout=5\r\nProxy-Authorization: Basic YTpi\r\n\
+          X-Sent-By: balancebeam-tests\r\n\r\n",
+    )
+    .await
+    .expect("Error writing to balancebeam");
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .expect("Error reading from balancebeam");
+    let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap());
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(head.contains("connection: close"), "{}", head);
+    assert!(body.contains("GET /hop HTTP/1.1"));
+    assert!(body.contains("x-sent-by: balancebeam-tests"));
+    for header in [
+        "connection",
+        "x-secret",
+        "keep-alive",
+        "proxy-authorization",
+    ] {
+        assert!(!body.contains(header), "{} was forwarded: {}", header, body);
+    }
+
+    Box::new(upstream).stop().await;
+}
+
+/// Make sure the --set-request-header rules and the like change the headers of forwarded requests
+/// and of responses, and that balancebeam refuses to start with a header it can't change.
+#[tokio::test]
+async fn test_header_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--set-request-header",
+            "X-Env: staging",
+            "--append-request-header",
+            "X-Sent-By:balancebeam",
+            "--remove-request-header",
+            "X-Secret",
+            "--set-response-header",
+            "Server:balancebeam",
+            "--append-response-header",
+            "X-Frame-Options:DENY",
+            "--remove-response-header",
+            "Date",
+        ],
+    )
+    .await;
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/rules", balancebeam.address))
+        .header("x-env", "production")
+        .header("x-sent-by", "balancebeam-tests")
+        .header("x-secret", "hunter2")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.headers()["server"], "balancebeam");
+    assert_eq!(response.headers()["x-frame-options"], "DENY");
+    assert!(!response.headers().contains_key("date"));
+    let body = response.text().await.unwrap();
+    assert!(body.contains("x-env: staging\n"), "{}", body);
+    assert!(!body.contains("production"), "{}", body);
+    assert!(body.contains("x-sent-by: balancebeam-tests\n"), "{}", body);
+    assert!(body.contains("x-sent-by: balancebeam\n"), "{}", body);
+    assert!(!body.contains("x-secret"), "{}", body);
+
+    log::info!("Starting balancebeam with a header it manages itself");
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--remove-response-header", "Transfer-Encoding"],
+    )
+    .await;
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam should exit when a header rule is invalid");
+    assert!(!status.success());
+
+    Box::new(upstream).stop().await;
+}
+
+/// Send a chunked body, split awkwardly across writes, and make sure the upstream gets it decoded,
+/// with a Content-Length.
+#[tokio::test]
+async fn test_chunked_request() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    for piece in [
+        &b"POST /chunked HTTP/1.1\r\nHost: balancebeam\r\nConnection: close\r\n"[..],
+        b"Transfer-Encoding: chunked\r\n\r\n6\r\nHello ",
+        b"\r\n6;ext=1\r",
+        b"\nworld!\r\n0\r\nX-Trailer: ignored\r\n\r\n",
+    ] {
+        conn.write_all(piece)
+            .await
+            .expect("Error writing to balancebeam");
+        delay_for(Duration::from_millis(50)).await;
+    }
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .expect("Error reading from balancebeam");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("POST /chunked HTTP/1.1"));
+    assert!(response.contains("content-length: 12"));
+    assert!(!response.contains("transfer-encoding"));
+    assert!(!response.contains("x-trailer"));
+    assert!(response.ends_with("\n\nHello world!"), "{}", response);
+
+    Box::new(upstream).stop().await;
+}
+
+/// Make sure responses are passed on whole however the upstream frames their bodies (with their
+/// Content-Length if they had one, or else chunked), and that more requests can follow on the same
+/// client connection.
+#[tokio::test]
+async fn test_response_framing() {
+    init_logging();
+    for framing in [
+        Framing::ContentLength,
+        Framing::Chunked,
+        Framing::UntilClose,
+    ] {
+        log::info!("Testing {:?} responses", framing);
+        let upstream = FramingServer::new(framing).await;
+        let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+        let client = reqwest::Client::new();
+        for path in ["/first", "/second"] {
+            let response = client
+                .get(&format!("http://{}{}", balancebeam.address, path))
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+            assert_eq!(response.status().as_u16(), 200);
+            let content_length = response.headers().get("content-length").cloned();
+            let transfer_encoding = response.headers().get("transfer-encoding").cloned();
+            let body = response.text().await.unwrap();
+            match framing {
+                Framing::ContentLength => {
+                    assert_eq!(content_length.unwrap(), body.len().to_string().as_str());
+                    assert!(transfer_encoding.is_none());
+                }
+                Framing::Chunked | Framing::UntilClose => {
+                    assert!(content_length.is_none());
+                    assert_eq!(transfer_encoding.unwrap(), "chunked");
+                }
+                Framing::Overlong | Framing::ClosingContentLength | Framing::Stalled => {
+                    unreachable!()
+                }
+            }
+            assert!(body.starts_with("response "), "{:?}: {}", framing, body);
+            assert!(
+                body.ends_with(&format!(" to GET {}", path)),
+                "{:?}: {}",
+                framing,
+                body
+            );
+        }
+        Box::new(upstream).stop().await;
+    }
+}
+
+/// Make sure that with --cache-max-bytes, a response that says it can be cached is passed on whole
+/// however the upstream frames it, and later requests for it are answered from the cache (keyed by
+/// Accept-Encoding as well as the path) until the cache is purged.
+#[tokio::test]
+async fn test_response_caching() {
+    init_logging();
+    for framing in [
+        Framing::ContentLength,
+        Framing::Chunked,
+        Framing::UntilClose,
+    ] {
+        log::info!("Testing caching {:?} responses", framing);
+        let upstream = FramingServer::new(framing).await;
+        let admin_address = random_address();
+        let balancebeam = BalanceBeam::new_with_args(
+            &[&upstream.address],
+            &[
+                "--cache-max-bytes",
+                "100000",
+                "--admin-bind",
+                &admin_address,
+            ],
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let get = |path: &str, accept_encoding: &str| {
+            client
+                .get(&format!("http://{}{}", balancebeam.address, path))
+                .header("accept-encoding", accept_encoding)
+                .send()
+        };
+        let read = |response: reqwest::Response| async move {
+            let x_cache = response.headers().get("x-cache").cloned();
+            let age = response.headers().get("age").cloned();
+            let content_length = response.headers().get("content-length").cloned();
+            let body = response.text().await.unwrap();
+            (x_cache, age, content_length, body)
+        };
+
+        let (x_cache, _, content_length, first) = read(get("/cached", "gzip").await.unwrap()).await;
+        assert_eq!(x_cache.unwrap(), "MISS");
+        assert!(
+            first.ends_with(" to GET /cached"),
+            "{:?}: {}",
+            framing,
+            first
+        );
+        // The body was read in full to be cached, so its length is known
+        assert_eq!(content_length.unwrap(), first.len().to_string().as_str());
+        let (x_cache, age, _, body) = read(get("/cached", "gzip").await.unwrap()).await;
+        assert_eq!(x_cache.unwrap(), "HIT");
+        assert_eq!(age.unwrap(), "0");
+        assert_eq!(body, first);
+        let (x_cache, _, _, body) = read(get("/cached", "identity").await.unwrap()).await;
+        assert_eq!(x_cache.unwrap(), "MISS");
+        assert_ne!(body, first);
+        // Responses that don't say they can be cached aren't
+        let (x_cache, _, _, uncached) = read(get("/other", "gzip").await.unwrap()).await;
+        assert_eq!(x_cache.unwrap(), "MISS");
+        let (_, _, _, body) = read(get("/other", "gzip").await.unwrap()).await;
+        assert_ne!(body, uncached);
+
+        let purge = reqwest::Client::new()
+            .delete(&format!("http://{}/cache", admin_address))
+            .send()
+            .await
+            .expect("Error sending request to the admin API");
+        assert_eq!(purge.text().await.unwrap(), "{\"entries\":0,\"bytes\":0}");
+        let (x_cache, _, _, body) = read(get("/cached", "gzip").await.unwrap()).await;
+        assert_eq!(x_cache.unwrap(), "MISS");
+        assert_ne!(body, first);
+        Box::new(upstream).stop().await;
+    }
+}
+
+/// Make sure responses to HEAD, and 304s, are passed on without waiting for a body, even though
+/// they say how long it would be, and that the connection can still be used afterwards.
+#[tokio::test]
+async fn test_responses_without_bodies() {
+    init_logging();
+    for framing in [Framing::ContentLength, Framing::Chunked] {
+        log::info!("Testing {:?} responses", framing);
+        let upstream = FramingServer::new(framing).await;
+        let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Error connecting to balancebeam");
+        let requests: [&[u8]; 3] = [
+            b"HEAD /page HTTP/1.1\r\nHost: balancebeam\r\n\r\n",
+            b"GET /page/not-modified HTTP/1.1\r\nHost: balancebeam\r\n\
+              If-None-Match: \"1\"\r\n\r\n",
+            b"GET /page HTTP/1.1\r\nHost: balancebeam\r\nConnection: close\r\n\r\n",
+        ];
+
+        conn.write_all(requests[0]).await.unwrap();
+        let head = read_head(&mut conn).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{:?}: {}", framing, head);
+        if let Framing::ContentLength = framing {
+            // The length of "response 2 to HEAD /page", after the health check
+            assert!(head.contains("content-length: 24\r\n"), "{}", head);
+        }
+        conn.write_all(requests[1]).await.unwrap();
+        let not_modified = read_head(&mut conn).await;
+        assert!(not_modified.starts_with("HTTP/1.1 304"), "{}", not_modified);
+        assert!(not_modified.contains("etag: \"1\"\r\n"), "{}", not_modified);
+        conn.write_all(requests[2]).await.unwrap();
+        let mut rest = String::new();
+        tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut rest))
+            .await
+            .expect("balancebeam didn't close the connection")
+            .expect("Error reading from balancebeam");
+        assert!(rest.starts_with("HTTP/1.1 200"), "{:?}: {}", framing, rest);
+        // The fourth request the upstream has had, so none of them were lost
+        assert!(rest.contains("response 4 "), "{:?}: {}", framing, rest);
+        Box::new(upstream).stop().await;
+    }
+}
+
+/// Make sure an upstream connection that one client is done with is used for the next client's
+/// requests, unless its response ran until the connection closed, or it has been idle for too long.
+#[tokio::test]
+async fn test_upstream_connection_pooling() {
+    init_logging();
+    for (framing, pooled) in [
+        (Framing::ContentLength, true),
+        (Framing::Chunked, true),
+        (Framing::UntilClose, false),
+    ] {
+        log::info!("Testing pooling with {:?} responses", framing);
+        let upstream = FramingServer::new(framing).await;
+        let balancebeam =
+            BalanceBeam::new_with_args(&[&upstream.address], &["--upstream-idle-timeout", "2"])
+                .await;
+        // The health check at startup has its own connection
+        let connections_before = upstream.connections_accepted();
+        for i in 0..5 {
+            let body = balancebeam
+                .get(&format!("/client-{}", i))
+                .await
+                .expect("Error sending request to balancebeam");
+            assert!(body.contains(&format!("to GET /client-{}", i)), "{}", body);
+            // Give balancebeam a moment to notice the client has hung up
+            delay_for(Duration::from_millis(100)).await;
+        }
+        let opened = upstream.connections_accepted() - connections_before;
+        assert_eq!(opened, if pooled { 1 } else { 5 }, "{:?}", framing);
+        if pooled {
+            log::info!("Letting the pooled connection go idle for too long");
+            delay_for(Duration::from_secs(3)).await;
+            balancebeam
+                .get("/client-5")
+                .await
+                .expect("Error sending request to balancebeam");
+            assert_eq!(upstream.connections_accepted() - connections_before, 2);
+        }
+        Box::new(upstream).stop().await;
+    }
+}
+
+/// Make sure that when an upstream closes a connection it has answered on before, just as the next
+/// request is sent over it, the request is sent again over a new connection rather than failing.
+/// The client sends several requests on one connection, and the upstream closes its connection
+/// after each response.
+#[tokio::test]
+async fn test_stale_upstream_connections() {
+    init_logging();
+    let upstream = FramingServer::new(Framing::ClosingContentLength).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    for i in 0..3 {
+        let request = format!("GET /request-{} HTTP/1.1\r\nHost: balancebeam\r\n\r\n", i);
+        conn.write_all(request.as_bytes())
+            .await
+            .expect("Error writing to balancebeam");
+        let head = read_head(&mut conn).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        // The health check was the first request, and none of these reached the upstream twice
+        let expected = format!("response {} to GET /request-{}", i + 2, i);
+        assert!(
+            head.contains(&format!("content-length: {}\r\n", expected.len())),
+            "{}",
+            head
+        );
+        let mut body = vec![0_u8; expected.len()];
+        conn.read_exact(&mut body).await.unwrap();
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+    }
+    // One connection for the health check, and one for each request
+    assert_eq!(upstream.connections_accepted(), 4);
+    Box::new(upstream).stop().await;
+}
+
+/// Make sure a WebSocket connection is passed through in both directions once the upstream agrees
+/// to switch to it, until the client closes it.
+#[tokio::test]
+async fn test_websocket_upgrade() {
+    init_logging();
+    let upstream = WebSocketServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    let request = format!(
+        "GET /chat HTTP/1.1\r\nHost: balancebeam\r\nConnection: Upgrade\r\n\
+         Upgrade: websocket\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        WEBSOCKET_KEY
+    );
+    conn.write_all(request.as_bytes())
+        .await
+        .expect("Error writing to balancebeam");
+    let head = read_head(&mut conn).await;
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+    assert!(head.contains("upgrade: websocket\r\n"), "{}", head);
+    assert!(head.contains("connection: upgrade\r\n"), "{}", head);
+    let accept = format!("sec-websocket-accept: {}\r\n", WEBSOCKET_ACCEPT);
+    assert!(head.contains(&accept), "{}", head);
+
+    for message in ["hello", "balancebeam"] {
+        conn.write_all(&masked_frame(TEXT_FRAME, message.as_bytes()))
+            .await
+            .expect("Error writing to balancebeam");
+        let frame = tokio::time::timeout(Duration::from_secs(5), read_frame(&mut conn))
+            .await
+            .expect("balancebeam didn't pass on the echoed message");
+        assert_eq!(frame, Some((TEXT_FRAME, message.as_bytes().to_vec())));
+    }
+    conn.write_all(&masked_frame(CLOSE_FRAME, b""))
+        .await
+        .expect("Error writing to balancebeam");
+    assert_eq!(read_frame(&mut conn).await, Some((CLOSE_FRAME, Vec::new())));
+    // The upstream hanging up is passed on too
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), conn.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .expect("Error reading from balancebeam");
+    assert!(rest.is_empty());
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// With --allow-connect, make sure a CONNECT request to an allowed destination gets a tunnel to it
+/// (not to the upstream), and one anywhere else gets a 403.
+#[tokio::test]
+async fn test_connect_tunnel() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let destination = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--allow-connect", &destination.address],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    let request = format!(
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n",
+        upstream.address
+    );
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let refused = read_head(&mut conn).await;
+    assert!(refused.starts_with("HTTP/1.1 403"), "{}", refused);
+    let length = refused
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut body = vec![0_u8; length];
+    conn.read_exact(&mut body).await.unwrap();
+
+    // The connection can still be used after a refusal
+    let request = format!(
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n",
+        destination.address
+    );
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let established = read_head(&mut conn).await;
+    assert_eq!(established, "HTTP/1.1 200 Connection Established\r\n\r\n");
+    // Anything can go through the tunnel, but the destination happens to speak HTTP. balancebeam
+    // would have added X-Forwarded-For if it had read the request.
+    conn.write_all(b"GET /tunneled HTTP/1.1\r\nHost: destination\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut response))
+        .await
+        .expect("The tunnel wasn't closed")
+        .expect("Error reading from balancebeam");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("GET /tunneled HTTP/1.1"), "{}", response);
+    assert!(!response.contains("x-forwarded-for"), "{}", response);
+
+    assert_eq!(Box::new(destination).stop().await, 1);
+    assert_eq!(Box::new(upstream).stop().await, 0);
+}
+
+/// Make sure that with a certificate and key, balancebeam only speaks HTTPS, telling upstreams so,
+/// and that clients that fail the TLS handshake aren't counted against the rate limit.
+#[tokio::test]
+async fn test_tls_termination() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            TLS_CERT,
+            "--tls-key",
+            TLS_KEY,
+            "--max-requests-per-minute",
+            "2",
+        ],
+    )
+    .await;
+
+    for _ in 0..3 {
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Error connecting to balancebeam");
+        conn.write_all(b"GET /plain HTTP/1.1\r\nHost: balancebeam\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), conn.read_to_end(&mut response))
+            .await
+            .expect("balancebeam didn't close the connection")
+            .ok();
+        assert!(!response.starts_with(b"HTTP/"), "{:?}", response);
+    }
+
+    // The test certificate is self-signed
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    for _ in 0..2 {
+        let response_text = client
+            .get(&format!("https://{}/secure", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains("GET /secure HTTP/1.1"));
+        assert!(response_text.contains("x-forwarded-proto: https"));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Make sure requests (and health checks) reach https:// upstreams over TLS, whose certificates
+/// are checked unless --upstream-insecure is given.
+#[tokio::test]
+async fn test_https_upstreams() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let tls_upstream = TlsServer::new(&upstream.address).await;
+    let address = format!("https://{}", tls_upstream.address);
+
+    for (args, works) in [
+        (&["--upstream-ca", TLS_CERT][..], true),
+        // The test certificate is self-signed, so it can't be trusted without --upstream-ca
+        (&[][..], false),
+        (&["--upstream-insecure"][..], true),
+    ] {
+        log::info!("Testing an https:// upstream with {:?}", args);
+        let mut args = args.to_vec();
+        args.extend(["--active-health-check-interval", "60"]);
+        let balancebeam = BalanceBeam::new_with_args(&[&address], &args).await;
+        let response = reqwest::get(&format!("http://{}/secure", balancebeam.address))
+            .await
+            .expect("Error sending request to balancebeam");
+        if works {
+            assert_eq!(response.status().as_u16(), 200);
+            let response_text = response.text().await.unwrap();
+            assert!(
+                response_text.contains("GET /secure HTTP/1.1"),
+                "{}",
+                response_text
+            );
+        } else {
+            assert_eq!(response.status().as_u16(), 502);
+        }
+    }
+
+    // A health check and a request each time the certificate was accepted
+    assert_eq!(Box::new(tls_upstream).stop().await, 4);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Sends `sent` over a new connection to `address`, returning everything sent back before the
+/// connection was closed. (A connection closed before all of `sent` was read may be reset, which
+/// isn't an error here.)
+async fn send_raw(address: &str, sent: &str) -> String {
+    let mut conn = TcpStream::connect(address)
+        .await
+        .expect("Error connecting to balancebeam");
+    conn.write_all(sent.as_bytes())
+        .await
+        .expect("Error writing to balancebeam");
+    let mut received = String::new();
+    let _ = tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut received))
+        .await
+        .expect("balancebeam didn't close the connection");
+    received
+}
+
+/// Make sure a client's address is taken from the PROXY protocol header its connection starts
+/// with, and that connections without a valid header are closed (unless they're allowed to go
+/// without one).
+#[tokio::test]
+async fn test_accept_proxy_protocol() {
+    let (balancebeam, upstream) = setup_with_args(&["--accept-proxy-protocol"]).await;
+    let request = "GET /proxied HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n";
+
+    let response = send_raw(
+        &balancebeam.address,
+        &format!("PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\n{}", request),
+    )
+    .await;
+    assert!(
+        response.contains("x-forwarded-for: 203.0.113.7"),
+        "{}",
+        response
+    );
+    assert!(response.contains("x-forwarded-port: 443"), "{}", response);
+
+    // The load balancer's own connections come from the load balancer
+    let response = send_raw(
+        &balancebeam.address,
+        &format!("PROXY UNKNOWN\r\n{}", request),
+    )
+    .await;
+    assert!(
+        response.contains("x-forwarded-for: 127.0.0.1"),
+        "{}",
+        response
+    );
+
+    for (sent, problem) in [
+        (request.to_string(), "without a header"),
+        (
+            format!("PROXY TCP4 nonsense\r\n{}", request),
+            "with a malformed header",
+        ),
+    ] {
+        log::info!("Sending a request {}", problem);
+        assert_eq!(send_raw(&balancebeam.address, &sent).await, "");
+    }
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("Trying a request without a header in lenient mode");
+    let (balancebeam, upstream) =
+        setup_with_args(&["--accept-proxy-protocol", "--accept-proxy-protocol-lenient"]).await;
+    let response = send_raw(&balancebeam.address, request).await;
+    assert!(
+        response.contains("x-forwarded-for: 127.0.0.1"),
+        "{}",
+        response
+    );
+    let response = send_raw(
+        &balancebeam.address,
+        &format!("PROXY TCP6 2001:db8::7 ::1 56324 443\r\n{}", request),
+    )
+    .await;
+    assert!(
+        response.contains("x-forwarded-for: 2001:db8::7"),
+        "{}",
+        response
+    );
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Make sure every connection to the upstream starts with a PROXY protocol header giving the
+/// address of the client it's for, and that health checks say they're from no client at all.
+#[tokio::test]
+async fn test_send_proxy_protocol() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let proxy_protocol_upstream = ProxyProtocolServer::new(&upstream.address).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&proxy_protocol_upstream.address],
+        &[
+            "--send-proxy-protocol",
+            "--accept-proxy-protocol",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let request = "GET /proxied HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n";
+    for source in ["203.0.113.7 10.0.0.1 56324", "198.51.100.2 10.0.0.1 40000"] {
+        let header = format!("PROXY TCP4 {} 443\r\n", source);
+        let response = send_raw(&balancebeam.address, &format!("{}{}", header, request)).await;
+        assert!(response.contains("GET /proxied HTTP/1.1"), "{}", response);
+    }
+    // Each client gets a new connection, rather than one from the pool
+    assert_eq!(
+        proxy_protocol_upstream.headers_received(),
+        vec![
+            "PROXY UNKNOWN\r\n",
+            "PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\n",
+            "PROXY TCP4 198.51.100.2 10.0.0.1 40000 443\r\n",
+        ]
+    );
+
+    Box::new(proxy_protocol_upstream).stop().await;
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Make sure copies of requests reach the --mirror-upstream, without a slow or dead one holding up
+/// the responses clients get.
+#[tokio::test]
+async fn test_mirror_upstream() {
+    init_logging();
+    let shadow = EchoServer::new_with_delay(Duration::from_secs(2)).await;
+    let (balancebeam, upstream) = setup_with_args(&["--mirror-upstream", &shadow.address]).await;
+
+    let started = std::time::Instant::now();
+    let response_text = balancebeam
+        .get("/mirrored")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /mirrored HTTP/1.1"));
+    let response_text = balancebeam
+        .post("/posted", "a body")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("a body"));
+    assert!(
+        started.elapsed() < Duration::from_secs(1),
+        "The shadow upstream held up responses for {:?}",
+        started.elapsed()
+    );
+    // Bodies streamed to the upstream can't be copied
+    let large_body = "x".repeat(100_000);
+    balancebeam
+        .post("/large", &large_body)
+        .await
+        .expect("Error sending request to balancebeam");
+    delay_for(Duration::from_secs(3)).await;
+    assert_eq!(shadow.requests_received_for("/mirrored"), 1);
+    assert_eq!(shadow.requests_received_for("/posted"), 1);
+    assert_eq!(shadow.requests_received_for("/large"), 0);
+    assert_eq!(upstream.requests_received_for("/large"), 1);
+    Box::new(upstream).stop().await;
+    Box::new(shadow).stop().await;
+
+    log::info!("Mirroring to a shadow upstream that isn't there");
+    let dead_shadow = random_address();
+    let (balancebeam, upstream) = setup_with_args(&["--mirror-upstream", &dead_shadow]).await;
+    let response_text = balancebeam
+        .get("/unmirrored")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /unmirrored HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Make sure HTTP/1.0 clients only have their connections kept alive when they ask for it, and
+/// are never sent chunks they can't understand: a body of unknown length ends with the connection.
+#[tokio::test]
+async fn test_http_10_clients() {
+    init_logging();
+    for framing in [
+        Framing::ContentLength,
+        Framing::Chunked,
+        Framing::UntilClose,
+    ] {
+        log::info!("Testing an HTTP/1.0 client with {:?} responses", framing);
+        let upstream = FramingServer::new(framing).await;
+        let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Error connecting to balancebeam");
+        conn.write_all(b"GET /page HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .await
+            .expect("Error writing to balancebeam");
+
+        if let Framing::ContentLength = framing {
+            let head = read_head(&mut conn).await;
+            assert!(head.contains("connection: keep-alive\r\n"), "{}", head);
+            // "response 2 to GET /page", after the health check
+            assert!(head.contains("content-length: 23\r\n"), "{}", head);
+            let mut body = [0_u8; 23];
+            conn.read_exact(&mut body).await.unwrap();
+            // Without asking to be kept alive, the connection is closed after the next response
+            conn.write_all(b"GET /page HTTP/1.0\r\n\r\n").await.unwrap();
+        }
+        let mut rest = String::new();
+        tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut rest))
+            .await
+            .expect("balancebeam didn't close the connection")
+            .expect("Error reading from balancebeam");
+        assert!(rest.starts_with("HTTP/1.1 200"), "{:?}: {}", framing, rest);
+        assert!(
+            rest.contains("connection: close\r\n"),
+            "{:?}: {}",
+            framing,
+            rest
+        );
+        assert!(
+            !rest.contains("transfer-encoding"),
+            "{:?}: {}",
+            framing,
+            rest
+        );
+        let (_, body) = rest.split_once("\r\n\r\n").unwrap();
+        assert!(
+            body.starts_with("response ") && body.ends_with(" to GET /page"),
+            "{}",
+            body
+        );
+        Box::new(upstream).stop().await;
+    }
+}
+
+/// Make sure a shadow upstream that stops partway through a response's body is given up on, so
+/// that it can't keep copies in flight forever.
+#[tokio::test]
+async fn test_mirror_upstream_stalled() {
+    init_logging();
+    let shadow = FramingServer::new(Framing::Stalled).await;
+    let metrics_address = random_address();
+    let (balancebeam, upstream) = setup_with_args(&[
+        "--mirror-upstream",
+        &shadow.address,
+        "--upstream-response-timeout",
+        "1",
+        "--metrics-bind",
+        &metrics_address,
+    ])
+    .await;
+
+    let response_text = balancebeam
+        .get("/mirrored")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /mirrored HTTP/1.1"));
+    delay_for(Duration::from_millis(2500)).await;
+    let metrics = reqwest::get(&format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    assert!(
+        metrics.contains("balancebeam_mirrored_requests_total{result=\"failed\"} 1\n"),
+        "{}",
+        metrics
+    );
+    Box::new(upstream).stop().await;
+    Box::new(shadow).stop().await;
+}
+
+/// Make sure a request balancebeam refuses itself (here for going over the rate limit) still
+/// closes an HTTP/1.0 client's connection unless the client asked for it to be kept alive.
+#[tokio::test]
+async fn test_http_10_clients_refused() {
+    init_logging();
+    let upstream = FramingServer::new(Framing::ContentLength).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-requests-per-minute", "1"]).await;
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+
+    for expected_status in ["200", "429"] {
+        conn.write_all(b"GET /page HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .await
+            .expect("Error writing to balancebeam");
+        let head = read_head(&mut conn).await;
+        assert!(
+            head.starts_with(&format!("HTTP/1.1 {}", expected_status)),
+            "{}",
+            head
+        );
+        assert!(head.contains("connection: keep-alive\r\n"), "{}", head);
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .expect("Response has no content-length")
+            .parse::<usize>()
+            .unwrap();
+        conn.read_exact(&mut vec![0_u8; length]).await.unwrap();
+    }
+
+    conn.write_all(b"GET /page HTTP/1.0\r\n\r\n").await.unwrap();
+    let mut rest = String::new();
+    tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut rest))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .expect("Error reading from balancebeam");
+    assert!(rest.starts_with("HTTP/1.1 429"), "{}", rest);
+    assert!(rest.contains("connection: close\r\n"), "{}", rest);
+    Box::new(upstream).stop().await;
+}
+
+/// Make sure response bodies bigger than balancebeam ever holds in memory are streamed through
+/// whole, however they are framed.
+#[tokio::test]
+async fn test_large_responses() {
+    init_logging();
+    for framing in [
+        Framing::ContentLength,
+        Framing::Chunked,
+        Framing::UntilClose,
+    ] {
+        log::info!("Testing a large {:?} response", framing);
+        let upstream = FramingServer::new(framing).await;
+        let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+        let body = balancebeam
+            .get("/large")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(body.starts_with("response "), "{:?}", framing);
+        assert_eq!(body.matches('.').count(), LARGE_BODY_SIZE, "{:?}", framing);
+        Box::new(upstream).stop().await;
+    }
+}
+
+/// Make sure request bodies much bigger than balancebeam reads at a time reach the upstream whole,
+/// and that the connection can be used again afterwards.
+#[tokio::test]
+async fn test_large_request_bodies() {
+    let (balancebeam, upstream) = setup().await;
+
+    let body: String = (0..1_000_000).map(|i| format!("{}\n", i)).collect();
+    let mut requests = Vec::new();
+    for connection in ["keep-alive", "close"] {
+        let head = format!(
+            "POST /upload HTTP/1.1\r\nHost: balancebeam\r\nConnection: {}\r\n\
+             Content-Length: {}\r\n\r\n",
+            connection,
+            body.len()
+        );
+        requests.extend_from_slice(head.as_bytes());
+        requests.extend_from_slice(body.as_bytes());
+    }
+    // The responses are as big as the requests, so they have to be read while the requests are
+    // still being sent
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (mut reader, mut writer) = stream.split();
+    let mut responses = Vec::new();
+    let (written, read) = tokio::join!(
+        writer.write_all(&requests),
+        reader.read_to_end(&mut responses)
+    );
+    written.unwrap();
+    read.unwrap();
+    let responses = String::from_utf8(responses).unwrap();
+    assert_eq!(responses.matches("POST /upload HTTP/1.1").count(), 2);
+    assert_eq!(responses.matches(&body).count(), 2);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+}
+
+/// Make sure request bodies over --max-request-body-size are refused, whether they have a
+/// Content-Length or are chunked, and that 0 lifts the limit.
+#[tokio::test]
+async fn test_max_request_body_size() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-request-body-size", "1000"])
+            .await;
+
+    log::info!("Sending bodies just under and over the limit");
+    let response_text = balancebeam
+        .post("/small", &"a".repeat(1000))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("POST /small HTTP/1.1"));
+    let response = reqwest::Client::new()
+        .post(&format!("http://{}/big", balancebeam.address))
+        .body("a".repeat(1001))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 413);
+    let body = response.text().await.unwrap();
+    assert!(body.contains("at most 1000 bytes"), "{}", body);
+
+    log::info!("Sending a chunked body over the limit");
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    conn.write_all(
+        b"POST /chunked HTTP/1.1\r\nHost: balancebeam\r\nTransfer-Encoding: chunked\r\n\r\n\
+          3e8\r\n",
+    )
+    .await
+    .expect("Error writing to balancebeam");
+    conn.write_all(format!("{}\r\n1\r\na\r\n0\r\n\r\n", "a".repeat(1000)).as_bytes())
+        .await
+        .expect("Error writing to balancebeam");
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .expect("Error reading from balancebeam");
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("Sending a big body with no limit");
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-request-body-size", "0"]).await;
+    let body = "a".repeat(12_000_000);
+    let response_text = balancebeam
+        .post("/unlimited", &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.ends_with(&body));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Make sure response bodies over --max-response-body-size aren't passed on: refused with a 502
+/// if their Content-Length says so, or cut off otherwise.
+#[tokio::test]
+async fn test_max_response_body_size() {
+    init_logging();
+    for framing in [
+        Framing::ContentLength,
+        Framing::Chunked,
+        Framing::UntilClose,
+    ] {
+        log::info!("Testing a {:?} response over the limit", framing);
+        let upstream = FramingServer::new(framing).await;
+        let args = ["--max-response-body-size", "1000000"];
+        let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+        let small = balancebeam
+            .get("/small")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(small.starts_with("response "), "{:?}", framing);
+        match reqwest::get(&format!("http://{}/large", balancebeam.address)).await {
+            Ok(response) if response.status().as_u16() == 502 => {
+                assert!(matches!(framing, Framing::ContentLength))
+            }
+            Ok(response) => {
+                assert_eq!(response.status().as_u16(), 200);
+                assert!(response.text().await.is_err(), "{:?}", framing)
+            }
+            Err(error) => panic!("{:?}: {}", framing, error),
+        }
+        Box::new(upstream).stop().await;
+    }
+}
+
+/// Make sure that an upstream that keeps sending far more body than its Content-Length only gets
+/// as much as it claimed passed on, and that none of the rest is taken for another response.
+/// (Each overlong response counts as a failure of the upstream, which the circuit breaker is set
+/// to put up with here.)
+#[tokio::test]
+async fn test_overlong_response() {
+    init_logging();
+    let upstream = FramingServer::new(Framing::Overlong).await;
+    let args = ["--circuit-breaker-failures", "3"];
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+    let memory_before = balancebeam.peak_memory_kib();
+
+    let client = reqwest::Client::new();
+    for _ in 0..2 {
+        let response = client
+            .get(&format!("http://{}/overlong", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.bytes().await.expect("Error reading response body");
+        assert_eq!(body.len(), OVERLONG_CLAIMED_SIZE);
+    }
+    let memory_growth = balancebeam.peak_memory_kib() - memory_before;
+    assert!(memory_growth < 20000, "{} KiB", memory_growth);
+    // The health check at startup, then both requests: the second went over a new connection,
+    // rather than reading the first response's excess as its own
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// Reads a response's head from `conn`, a byte at a time so that nothing after it is read.
+async fn read_head(conn: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0_u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), conn.read(&mut byte))
+            .await
+            .expect("balancebeam didn't respond")
+            .expect("Error reading from balancebeam");
+        assert_eq!(read, 1, "balancebeam hung up partway through a response");
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+/// Send a request with `Expect: 100-continue`, and only send its body once balancebeam passes on
+/// the upstream's 100 Continue. Then send one to an upstream that answers without wanting the
+/// body, and make sure the answer is passed on instead.
+#[tokio::test]
+async fn test_expect_continue() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    conn.write_all(
+        b"POST /upload HTTP/1.1\r\nHost: balancebeam\r\nConnection: close\r\n\
+          Expect: 100-continue\r\nContent-Length: 12\r\n\r\n",
+    )
+    .await
+    .expect("Error writing to balancebeam");
+    let interim = read_head(&mut conn).await;
+    assert!(interim.starts_with("HTTP/1.1 100 Continue"), "{}", interim);
+    conn.write_all(b"Hello world!")
+        .await
+        .expect("Error writing to balancebeam");
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .expect("Error reading from balancebeam");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("\n\nHello world!"), "{}", response);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("Sending a request to an upstream that doesn't want its body");
+    let upstream = FramingServer::new(Framing::ContentLength).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    conn.write_all(
+        b"POST /upload HTTP/1.1\r\nHost: balancebeam\r\nExpect: 100-continue\r\n\
+          Content-Length: 12\r\n\r\n",
+    )
+    .await
+    .expect("Error writing to balancebeam");
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .expect("Error reading from balancebeam");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with(" to POST /upload"), "{}", response);
+    assert!(response.contains("connection: close"), "{}", response);
+    Box::new(upstream).stop().await;
+}
+
+/// Send the classic request smuggling probes, which have both Content-Length and
+/// Transfer-Encoding, and make sure they are refused and their connections closed rather than
+/// forwarded.
+#[tokio::test]
+async fn test_request_smuggling_probes() {
+    let (balancebeam, upstream) = setup().await;
+
+    let probes: [&[u8]; 3] = [
+        // CL.TE: a front end going by Content-Length would forward the G as the start of the next
+        // request
+        b"POST / HTTP/1.1\r\nHost: balancebeam\r\nContent-Length: 6\r\n\
+          Transfer-Encoding: chunked\r\n\r\n0\r\n\r\nG",
+        // TE.CL: a back end going by Content-Length would take the rest for another request
+        b"POST / HTTP/1.1\r\nHost: balancebeam\r\nTransfer-Encoding: chunked\r\n\
+          Content-Length: 3\r\n\r\n8\r\nSMUGGLED\r\n0\r\n\r\n",
+        b"POST / HTTP/1.1\r\nHost: balancebeam\r\nContent-Length: 0\r\n\
+          Content-Length: 44\r\n\r\nGET /smuggled HTTP/1.1\r\nHost: balancebeam\r\n\r\n",
+    ];
+    for probe in probes.iter() {
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Error connecting to balancebeam");
+        conn.write_all(probe)
+            .await
+            .expect("Error writing to balancebeam");
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut response))
+            .await
+            .expect("balancebeam didn't close the connection")
+            .expect("Error reading from balancebeam");
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        // Only the one response: nothing after the probe was read as another request
+        assert_eq!(response.matches("HTTP/1.1").count(), 1, "{}", response);
+    }
+
+    log::info!("Checking that no probe reached the upstream");
+    assert_eq!(Box::new(upstream).stop().await, 0);
+}
+
+/// Send a request whose headers go on for 10 MB, slowly, and make sure balancebeam refuses it as
+/// soon as it is over the limit rather than holding on to all of it. Then send one with too many
+/// headers.
+#[tokio::test]
+async fn test_header_limits() {
+    let (balancebeam, upstream) = setup().await;
+    let memory_before = balancebeam.peak_memory_kib();
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    let (mut reader, mut writer) = conn.split();
+    let send = async {
+        writer
+            .write_all(b"GET / HTTP/1.1\r\nHost: balancebeam\r\nX-Huge: ")
+            .await?;
+        let piece = vec![b'a'; 64 * 1024];
+        for _ in 0..(10_000_000 / piece.len()) {
+            writer.write_all(&piece).await?;
+            delay_for(Duration::from_millis(10)).await;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let mut response = vec![0_u8; 4096];
+    let response_len = tokio::select! {
+        read = reader.read(&mut response) => read.expect("Error reading from balancebeam"),
+        _ = send => panic!("balancebeam read all of the headers without answering"),
+    };
+    let response = String::from_utf8_lossy(&response[..response_len]);
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+    // Any more from the connection would be another response
+    let mut rest = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(5), reader.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam didn't close the connection");
+    assert!(!String::from_utf8_lossy(&rest).contains("HTTP/1.1"));
+    let memory_growth = balancebeam.peak_memory_kib() - memory_before;
+    assert!(memory_growth < 5000, "{} KiB", memory_growth);
+
+    log::info!("Sending a request with too many headers");
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    let mut request = "GET / HTTP/1.1\r\nHost: balancebeam\r\n".to_string();
+    for i in 0..200 {
+        request.push_str(&format!("X-Header-{}: {}\r\n", i, i));
+    }
+    request.push_str("\r\n");
+    conn.write_all(request.as_bytes())
+        .await
+        .expect("Error writing to balancebeam");
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), conn.read_to_string(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .expect("Error reading from balancebeam");
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+
+    log::info!("Checking that neither request reached the upstream");
+    assert_eq!(Box::new(upstream).stop().await, 0);
+}
+
+/// Chain two balancebeams, and make sure each adds itself to Via, and that a request that has
+/// been through one already is refused rather than forwarded again.
+#[tokio::test]
+async fn test_via_and_loop_detection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let inner = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+    let outer = BalanceBeam::new_with_args(&[&inner.address], &["--via-token", "outer"]).await;
+
+    log::info!("Sending a request through both");
+    let response = reqwest::get(&format!("http://{}/chained", outer.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.headers()["via"], "1.1 balancebeam, 1.1 outer");
+    let body = response.text().await.unwrap();
+    assert!(body.contains("via: 1.1 outer, 1.1 balancebeam"), "{}", body);
+
+    log::info!("Sending a request that has been through the inner one already");
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/loop", outer.address))
+        .header("via", "1.1 balancebeam")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 508);
+
+    log::info!("Checking that only the first request reached the upstream");
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+#[tokio::test]
+async fn test_request_id() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-requests-per-minute", "3"]).await;
+
+    log::info!("Sending a request without an ID");
+    let (request_id, body) = get_with_request_id(&balancebeam, "/new", None).await;
+    assert_eq!(request_id.len(), 36);
+    assert!(body.contains(&format!("x-request-id: {}", request_id)));
+    let (another_id, _) = get_with_request_id(&balancebeam, "/new", None).await;
+    assert_ne!(request_id, another_id);
+
+    log::info!("Sending a request with an ID");
+    let (request_id, body) = get_with_request_id(&balancebeam, "/given", Some("trace-0")).await;
+    assert_eq!(request_id, "trace-0");
+    assert!(body.contains("x-request-id: trace-0"));
+
+    log::info!("Sending a request over the rate limit with an ID");
+    let (request_id, body) = get_with_request_id(&balancebeam, "/limited", Some("trace-1")).await;
+    assert!(body.contains("429"));
+    assert_eq!(request_id, "trace-1");
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_request_id_override() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--request-id-override"]).await;
+
+    let (request_id, body) = get_with_request_id(&balancebeam, "/", Some("trace-1")).await;
+    assert_ne!(request_id, "trace-1");
+    assert!(!body.contains("trace-1"));
+    assert!(body.contains(&format!("x-request-id: {}", request_id)));
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
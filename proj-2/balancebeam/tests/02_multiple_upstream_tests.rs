@@ -1,8 +1,14 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use common::{
+    init_logging, random_address, BalanceBeam, BlackholeServer, ClosingServer, EchoServer,
+    ErrorServer, Server, SilentServer, UnixSocketServer,
+};
 
-use std::time::Duration;
+use nix::sys::signal::Signal;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio::time::delay_for;
 
 async fn setup_with_params(
@@ -80,6 +86,195 @@ async fn test_load_distribution() {
     log::info!("All done :)");
 }
 
+/// With the round-robin strategy, requests should be spread over the upstreams exactly evenly
+#[tokio::test]
+async fn test_round_robin_strategy() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(EchoServer::new().await);
+    }
+    let upstream_addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let balancebeam =
+        BalanceBeam::new_with_args(&upstream_addresses, &["--strategy", "round-robin"]).await;
+
+    for i in 0..9 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    for upstream in upstreams {
+        assert_eq!(
+            Box::new(upstream).stop().await,
+            3,
+            "Each upstream should get every third request"
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// With the p2c strategy, requests should still reach every upstream, and once only one upstream
+/// is left, every request should go to it
+#[tokio::test]
+async fn test_p2c_strategy() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(EchoServer::new().await);
+    }
+    let upstream_addresses: Vec<String> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.clone())
+        .collect();
+    let upstream_args: Vec<&str> = upstream_addresses.iter().map(|a| a.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(&upstream_args, &["--strategy", "p2c"]).await;
+
+    for i in 0..30 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let last = upstreams.pop().unwrap();
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(Box::new(upstream).stop().await);
+    }
+
+    for i in 0..5 {
+        let path = format!("/failover-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam. p2c may not be failing over");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let last_count = Box::new(last).stop().await;
+    request_counters.push(last_count);
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert_eq!(request_counters.iter().sum::<usize>(), 35);
+    // Random choices leave an upstream with nothing 30 times in a row only about once in 60000 runs
+    assert!(
+        request_counters[..2].iter().all(|&count| count > 0) && last_count > 5,
+        "Requests should be spread over every upstream"
+    );
+
+    log::info!("All done :)");
+}
+
+/// With the ewma strategy, most requests should go to the upstream that responds quickly
+#[tokio::test]
+async fn test_ewma_strategy() {
+    init_logging();
+    let fast = EchoServer::new().await;
+    let slow = EchoServer::new_with_delay(Duration::from_millis(200)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&fast.address, &slow.address],
+        &["--strategy", "ewma", "--ewma-decay", "0.5"],
+    )
+    .await;
+
+    for i in 0..30 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let fast_count = Box::new(fast).stop().await;
+    let slow_count = Box::new(slow).stop().await;
+    log::info!(
+        "Requests received by the fast upstream: {}, slow upstream: {}",
+        fast_count,
+        slow_count
+    );
+    // Until the slow upstream has been timed, it gets half the requests
+    assert!(
+        slow_count < 8,
+        "The slow upstream should get few requests once it has been timed"
+    );
+
+    log::info!("All done :)");
+}
+
+/// With the ip-hash strategy, every request from the same client should go to the same upstream,
+/// and when that upstream dies, the client should be moved to another one rather than erroring
+#[tokio::test]
+async fn test_ip_hash_strategy() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(EchoServer::new().await);
+    }
+    let upstream_addresses: Vec<String> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.clone())
+        .collect();
+    let upstream_args: Vec<&str> = upstream_addresses.iter().map(|a| a.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(&upstream_args, &["--strategy", "ip-hash"]).await;
+
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(Box::new(upstream).stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert!(
+        request_counters.contains(&6),
+        "Every request from one client should go to the same upstream"
+    );
+
+    // Every upstream is dead now; bring back the two that weren't chosen
+    let mut survivors = Vec::new();
+    for (address, count) in upstream_addresses.iter().zip(&request_counters) {
+        if *count == 0 {
+            survivors.push(EchoServer::new_at_address(address.clone()).await);
+        }
+    }
+    for i in 0..4 {
+        let path = format!("/failover-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam. ip-hash may not be failing over");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let mut request_counters = Vec::new();
+    for upstream in survivors {
+        request_counters.push(Box::new(upstream).stop().await);
+    }
+    assert!(
+        request_counters.contains(&4),
+        "The client should stick to the same upstream after failing over"
+    );
+
+    log::info!("All done :)");
+}
+
 async fn try_failover(balancebeam: &BalanceBeam, upstreams: &mut Vec<Box<dyn Server>>) {
     // Send some initial requests. Everything should work
     log::info!("Sending some initial requests. These should definitely work.");
@@ -137,6 +332,259 @@ async fn test_passive_health_checks() {
 /// * Send a few requests
 /// * Replace one of the upstreams with a server that only returns HTTP error 500s
 /// * Send some more requests. Make sure all the requests succeed
+/// An upstream that accepts connections but hangs up without responding should be marked dead after
+/// the first failed request, rather than being sent more clients
+#[tokio::test]
+async fn test_passive_health_checks_after_connecting() {
+    init_logging();
+    let working = EchoServer::new().await;
+    // The upstream only breaks once balancebeam has checked it at startup
+    let broken = EchoServer::new().await;
+    let broken_address = broken.address.clone();
+    // Keep active health checks from noticing first, and let the failed request through
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&working.address, &broken_address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "60",
+            "--upstream-retries",
+            "0",
+        ],
+    )
+    .await;
+    Box::new(broken).stop().await;
+    let broken = ClosingServer::new_at_address(broken_address).await;
+
+    let mut failures = 0;
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        if response_text.contains("502 Bad Gateway") {
+            failures += 1;
+        } else {
+            assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        }
+    }
+    let working_count = Box::new(working).stop().await;
+    let broken_count = Box::new(broken).stop().await;
+    log::info!(
+        "Requests received by the working upstream: {}, connections to the broken upstream: {}",
+        working_count,
+        broken_count
+    );
+    assert_eq!(
+        (failures, broken_count),
+        (1, 1),
+        "Only the first request sent to the broken upstream should fail"
+    );
+    assert_eq!(working_count, 9);
+
+    log::info!("All done :)");
+}
+
+/// Starts balancebeam in front of a working upstream and one that breaks once balancebeam has
+/// checked it at startup, with active health checks kept from noticing. The broken upstream gets
+/// the first request.
+async fn setup_with_broken_upstream(args: &[&str]) -> (BalanceBeam, EchoServer, ClosingServer) {
+    let working = EchoServer::new().await;
+    let broken = EchoServer::new().await;
+    let broken_address = broken.address.clone();
+    let mut all_args = vec![
+        "--strategy",
+        "round-robin",
+        "--active-health-check-interval",
+        "60",
+    ];
+    all_args.extend_from_slice(args);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&broken_address, &working.address], &all_args).await;
+    Box::new(broken).stop().await;
+    let broken = ClosingServer::new_at_address(broken_address).await;
+    (balancebeam, working, broken)
+}
+
+#[tokio::test]
+async fn test_upstream_retries() {
+    init_logging();
+    let (balancebeam, working, broken) = setup_with_broken_upstream(&[]).await;
+
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "Requests that fail on the broken upstream should be retried on the working one"
+        );
+    }
+    assert_eq!(Box::new(working).stop().await, 10);
+    assert_eq!(Box::new(broken).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_upstream_retries_non_idempotent() {
+    init_logging();
+    let (balancebeam, working, broken) = setup_with_broken_upstream(&[]).await;
+    let response_text = balancebeam
+        .post("/first", "body")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response_text.contains("502 Bad Gateway"),
+        "A POST that may have reached an upstream shouldn't be retried"
+    );
+    assert_eq!(Box::new(working).stop().await, 0);
+    assert_eq!(Box::new(broken).stop().await, 1);
+
+    let (balancebeam, working, broken) =
+        setup_with_broken_upstream(&["--retry-non-idempotent"]).await;
+    let response_text = balancebeam
+        .post("/first", "body")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response_text.contains("POST /first HTTP/1.1"),
+        "A POST should be retried with --retry-non-idempotent"
+    );
+    assert_eq!(Box::new(working).stop().await, 1);
+    assert_eq!(Box::new(broken).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// With no backoff, an upstream a request has just failed on can be tried again straight away.
+/// Make sure the retry goes to the other upstream (dead, but working again) rather than back to the
+/// failed one.
+#[tokio::test]
+async fn test_upstream_retries_skip_tried_upstreams() {
+    init_logging();
+    let broken = EchoServer::new().await;
+    let broken_address = broken.address.clone();
+    let revived_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&broken_address, &revived_address],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--max-upstream-backoff",
+            "0",
+        ],
+    )
+    .await;
+    Box::new(broken).stop().await;
+    let broken = ClosingServer::new_at_address(broken_address).await;
+    let revived = EchoServer::new_at_address(revived_address).await;
+
+    let response_text = balancebeam
+        .get("/retried")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response_text.contains("GET /retried HTTP/1.1"),
+        "The request should have been retried on the other upstream: {}",
+        response_text
+    );
+    assert_eq!(Box::new(revived).stop().await, 1);
+    assert_eq!(Box::new(broken).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Sends `count` requests, returning how many of them failed with a 502.
+async fn count_failures(balancebeam: &BalanceBeam, count: usize) -> usize {
+    let mut failures = 0;
+    for i in 0..count {
+        let response_text = balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        if response_text.contains("502 Bad Gateway") {
+            failures += 1;
+        }
+    }
+    failures
+}
+
+#[tokio::test]
+async fn test_circuit_breaker() {
+    init_logging();
+    let (balancebeam, working, broken) = setup_with_broken_upstream(&[
+        "--circuit-breaker-failures",
+        "2",
+        "--circuit-breaker-cooldown",
+        "2",
+        "--upstream-retries",
+        "0",
+    ])
+    .await;
+    let broken_address = broken.address.clone();
+
+    // The broken upstream is still used after its first failure, but not after its second
+    assert_eq!(count_failures(&balancebeam, 10).await, 2);
+    // Once the cool-down is over, a trial request fails and opens the breaker again
+    delay_for(Duration::from_millis(2500)).await;
+    assert_eq!(count_failures(&balancebeam, 10).await, 1);
+    assert_eq!(Box::new(broken).stop().await, 3);
+
+    // Once the upstream is fixed, a trial request closes the breaker
+    let fixed = EchoServer::new_at_address(broken_address).await;
+    delay_for(Duration::from_millis(2500)).await;
+    assert_eq!(count_failures(&balancebeam, 10).await, 0);
+    assert_eq!(fixed.requests_received_for("/request-"), 5);
+    assert_eq!(working.requests_received_for("/request-"), 22);
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_all_upstreams_down_backoff() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let address = upstream.address.clone();
+    // Keep active health checks from bringing the upstream back
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&address], &["--active-health-check-interval", "60"]).await;
+    Box::new(upstream).stop().await;
+
+    // The first request finds the upstream dead, so the cluster is down for the next second
+    for _ in 0..3 {
+        let response_text = balancebeam
+            .get("/down")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains("502 Bad Gateway"));
+    }
+    let upstream = EchoServer::new_at_address(address).await;
+    let response_text = balancebeam
+        .get("/too-soon")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response_text.contains("502 Bad Gateway"),
+        "The upstream shouldn't be tried again until the backoff runs out"
+    );
+
+    // Once it has, the upstream is tried again without waiting for a health check
+    delay_for(Duration::from_millis(1500)).await;
+    let response_text = balancebeam
+        .get("/recovered")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /recovered HTTP/1.1"));
+    assert_eq!(upstream.requests_received_for("/"), 1);
+
+    log::info!("All done :)");
+}
+
 #[tokio::test]
 async fn test_active_health_checks_check_http_status() {
     let n_upstreams = 2;
@@ -198,62 +646,883 @@ async fn test_active_health_checks_check_http_status() {
 /// * Send some more requests
 /// * Bring the upstream back
 /// * Ensure requests are delivered again
+/// With --health-check-expect, an upstream that answers health checks with one of the listed
+/// statuses should stay in use
 #[tokio::test]
-async fn test_active_health_checks_restore_failed_upstream() {
-    let n_upstreams = 2;
-    let (balancebeam, mut upstreams) = setup_with_params(n_upstreams, Some(1), None).await;
-    let failed_ip = upstreams[upstreams.len() - 1].address();
-    try_failover(&balancebeam, &mut upstreams).await;
-
-    log::info!("Re-starting the \"failed\" upstream server...");
-    upstreams.push(Box::new(EchoServer::new_at_address(failed_ip).await));
+async fn test_health_check_expect() {
+    init_logging();
+    let upstream = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--health-check-expect",
+            "200-299,500",
+        ],
+    )
+    .await;
 
-    log::info!("Waiting a few seconds for the active health check to run...");
+    log::info!("Waiting for health checks to run...");
     delay_for(Duration::from_secs(3)).await;
-
-    log::info!("Sending some more requests");
-    for i in 0..5 {
-        let path = format!("/after-restore-{}", i);
-        let response_text = balancebeam
-            .get(&path)
-            .await
-            .expect("Error sending request to balancebeam");
-        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
-        assert!(response_text.contains("x-sent-by: balancebeam-tests"));
-        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
-    }
-
-    log::info!(
-        "Verifying that the previously-dead upstream got some requests after being restored"
-    );
-    let last_upstream_req_count = upstreams.pop().unwrap().stop().await;
+    let response_text = balancebeam
+        .get("/after-health-checks")
+        .await
+        .expect("Error sending request to balancebeam");
     assert!(
-        last_upstream_req_count > 0,
-        "We killed an upstream, then brought it back, but it never got any more requests!"
+        !response_text.contains("502 Bad Gateway"),
+        "The upstream was marked dead even though its 500 responses were expected"
     );
-
-    // Shut down
-    while let Some(upstream) = upstreams.pop() {
-        upstream.stop().await;
-    }
+    Box::new(upstream).stop().await;
 
     log::info!("All done :)");
 }
 
-/// Enable rate limiting and ensure that requests fail after sending more than the threshold
+/// A slow active health check shouldn't stop requests from being routed to other upstreams while it
+/// runs
 #[tokio::test]
-async fn test_rate_limiting() {
-    let n_upstreams = 1;
-    let rate_limit_threshold = 5;
-    let num_extra_requests: usize = 3;
-    let (balancebeam, mut upstreams) =
-        setup_with_params(n_upstreams, None, Some(rate_limit_threshold)).await;
+async fn test_slow_health_check_does_not_block_requests() {
+    init_logging();
+    let fast = EchoServer::new().await;
+    // A backup is health checked like any other upstream, but never gets these requests
+    let slow = EchoServer::new_with_delay(Duration::from_secs(2)).await;
+    let slow_backup = format!("{}=0", slow.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&fast.address, &slow_backup],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
 
-    log::info!(
-        "Sending some basic requests to the server, within the rate limit threshold. These \
-        should succeed."
-    );
-    for i in 0..rate_limit_threshold {
+    // Wait for the first round of checks, which runs before any clients are accepted. After that,
+    // health checks are running most of the time, each taking two seconds
+    delay_for(Duration::from_secs(2)).await;
+    for i in 0..8 {
+        let path = format!("/request-{}", i);
+        let started = std::time::Instant::now();
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(
+            started.elapsed() < Duration::from_millis(500),
+            "Request took {:?}; it may have waited for a health check to finish",
+            started.elapsed()
+        );
+        delay_for(Duration::from_millis(250)).await;
+    }
+    Box::new(fast).stop().await;
+    Box::new(slow).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// An upstream given with ;health=/path should be health checked there, while the others use
+/// --active-health-check-path
+#[tokio::test]
+async fn test_per_upstream_health_check_path() {
+    init_logging();
+    let custom = EchoServer::new().await;
+    let default = EchoServer::new().await;
+    let custom_upstream = format!("{};health=/healthz", custom.address);
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&custom_upstream, &default.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-path",
+            "/status",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to run...");
+    delay_for(Duration::from_millis(2500)).await;
+    assert!(custom.requests_received_for("/healthz") > 0);
+    assert_eq!(custom.requests_received_for("/status"), 0);
+    assert!(default.requests_received_for("/status") > 0);
+    assert_eq!(default.requests_received_for("/healthz"), 0);
+    Box::new(custom).stop().await;
+    Box::new(default).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// An upstream that is already failing its health checks when balancebeam starts should never be
+/// sent a client, even though the first interval hasn't passed
+#[tokio::test]
+async fn test_health_check_at_startup() {
+    init_logging();
+    let working = EchoServer::new().await;
+    let failing = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&working.address, &failing.address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    for i in 0..4 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "A request went to the failing upstream before it was health checked"
+        );
+    }
+    Box::new(working).stop().await;
+    Box::new(failing).stop().await;
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_active_health_checks_restore_failed_upstream() {
+    let n_upstreams = 2;
+    let (balancebeam, mut upstreams) = setup_with_params(n_upstreams, Some(1), None).await;
+    let failed_ip = upstreams[upstreams.len() - 1].address();
+    try_failover(&balancebeam, &mut upstreams).await;
+
+    log::info!("Re-starting the \"failed\" upstream server...");
+    upstreams.push(Box::new(EchoServer::new_at_address(failed_ip).await));
+
+    log::info!("Waiting a few seconds for the active health check to run...");
+    delay_for(Duration::from_secs(3)).await;
+
+    log::info!("Sending some more requests");
+    for i in 0..5 {
+        let path = format!("/after-restore-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-sent-by: balancebeam-tests"));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    log::info!(
+        "Verifying that the previously-dead upstream got some requests after being restored"
+    );
+    let last_upstream_req_count = upstreams.pop().unwrap().stop().await;
+    assert!(
+        last_upstream_req_count > 0,
+        "We killed an upstream, then brought it back, but it never got any more requests!"
+    );
+
+    // Shut down
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+
+    log::info!("All done :)");
+}
+
+/// A restored upstream should start with a small share of requests, working up to its full share
+#[tokio::test]
+async fn test_slow_start() {
+    init_logging();
+    let working = EchoServer::new().await;
+    let restored = EchoServer::new().await;
+    let restored_address = restored.address.clone();
+    Box::new(restored).stop().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&working.address, &restored_address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "1",
+            "--slow-start-seconds",
+            "10",
+        ],
+    )
+    .await;
+    let restored = EchoServer::new_at_address(restored_address).await;
+    log::info!("Waiting for the active health check to restore the upstream...");
+    delay_for(Duration::from_secs(2)).await;
+
+    let send_requests = |prefix| {
+        let balancebeam = &balancebeam;
+        async move {
+            for i in 0..40 {
+                let path = format!("/{}-{}", prefix, i);
+                let response_text = balancebeam
+                    .get(&path)
+                    .await
+                    .expect("Error sending request to balancebeam");
+                assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+            }
+        }
+    };
+    send_requests("during").await;
+    delay_for(Duration::from_secs(10)).await;
+    send_requests("after").await;
+    let during = restored.requests_received_for("/during-");
+    let after = restored.requests_received_for("/after-");
+    log::info!(
+        "The restored upstream got {} of 40 requests during the slow start and {} after",
+        during,
+        after
+    );
+    assert!(during > 0, "The restored upstream should get some requests");
+    assert!(
+        during <= 12,
+        "The restored upstream should get a small share of requests at first"
+    );
+    assert_eq!(after, 20, "The restored upstream should get its full share");
+
+    log::info!("All done :)");
+}
+
+/// Enable rate limiting and ensure that requests fail after sending more than the threshold
+/// With --health-check-recovery-threshold, a restored upstream should only get requests again once
+/// it has passed that many active health checks in a row
+/// Sends a request to balancebeam's admin API, returning the status code and body.
+async fn admin_request(
+    admin_address: &str,
+    method: reqwest::Method,
+    path: &str,
+    body: &str,
+) -> (reqwest::StatusCode, String) {
+    let response = reqwest::Client::new()
+        .request(method, &format!("http://{}{}", admin_address, path))
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    let status = response.status();
+    (status, response.text().await.unwrap())
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, path: &str, count: usize) {
+    for _ in 0..count {
+        balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+}
+
+/// Make sure an upstream given by hostname is replaced by the upstreams at the addresses it
+/// resolves to, alongside upstreams given by address.
+#[tokio::test]
+async fn test_hostname_upstreams() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let port = first.address.rsplit(':').next().unwrap();
+    let hostname = format!("localhost:{}", port);
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&hostname, &second.address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--dns-refresh-interval",
+            "1",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    // Refreshing leaves the upstream as it was, since localhost still resolves to the same address
+    delay_for(Duration::from_secs(2)).await;
+    let (status, body) =
+        admin_request(&admin_address, reqwest::Method::GET, "/upstreams", "").await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert!(
+        body.contains(&format!("\"address\":\"{}\"", first.address)),
+        "{}",
+        body
+    );
+    assert!(
+        body.contains(&format!("\"address\":\"{}\"", second.address)),
+        "{}",
+        body
+    );
+    assert!(!body.contains("localhost"), "{}", body);
+
+    send_requests(&balancebeam, "/resolved", 4).await;
+    assert_eq!(first.requests_received_for("/resolved"), 2);
+    assert_eq!(second.requests_received_for("/resolved"), 2);
+
+    Box::new(first).stop().await;
+    Box::new(second).stop().await;
+}
+
+/// Make sure an upstream listening on a Unix domain socket gets its share of the requests alongside
+/// one listening on TCP, and that requests without a Host header are given --unix-socket-host when
+/// they go to it.
+#[tokio::test]
+async fn test_unix_socket_upstreams() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let unix_upstream = UnixSocketServer::new(&first.address).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&unix_upstream.address, &second.address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--unix-socket-host",
+            "app.internal",
+        ],
+    )
+    .await;
+
+    // Both upstreams passed their health checks, or they wouldn't be taking turns
+    send_requests(&balancebeam, "/unix", 4).await;
+    assert_eq!(first.requests_received_for("/unix"), 2);
+    assert_eq!(second.requests_received_for("/unix"), 2);
+
+    // An HTTP/1.0 client needn't send a Host header
+    let mut hosts = Vec::new();
+    for _ in 0..2 {
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Error connecting to balancebeam");
+        conn.write_all(b"GET /no-host HTTP/1.0\r\n\r\n")
+            .await
+            .expect("Error writing to balancebeam");
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        assert!(response.contains(" 200 OK\r\n"), "{}", response);
+        hosts.push(response.contains("host: app.internal"));
+    }
+    hosts.sort();
+    assert_eq!(hosts, vec![false, true]);
+
+    assert!(Box::new(unix_upstream).stop().await > 0);
+    Box::new(first).stop().await;
+    Box::new(second).stop().await;
+}
+
+/// Make sure requests go to the group of upstreams their path is routed to, and only fall back to
+/// the default group when asked to.
+#[tokio::test]
+async fn test_path_routing() {
+    init_logging();
+    let api = EchoServer::new().await;
+    let web = EchoServer::new().await;
+    let grouped = format!("{};group=api", api.address);
+    let args = [
+        "--route",
+        "/api=api",
+        "--active-health-check-interval",
+        "60",
+    ];
+    let balancebeam = BalanceBeam::new_with_args(&[&grouped, &web.address], &args).await;
+    let mut fallback_args = args.to_vec();
+    fallback_args.push("--route-fallback");
+    let fallback = BalanceBeam::new_with_args(&[&grouped, &web.address], &fallback_args).await;
+
+    send_requests(&balancebeam, "/api/users", 3).await;
+    send_requests(&balancebeam, "/index.html", 3).await;
+    assert_eq!(api.requests_received_for("/api/users"), 3);
+    assert_eq!(web.requests_received_for("/index.html"), 3);
+    assert_eq!(web.requests_received_for("/api"), 0);
+
+    log::info!("Stopping the only upstream in the api group");
+    Box::new(api).stop().await;
+    let response_text = balancebeam
+        .get("/api/down")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response_text.contains("502 Bad Gateway"),
+        "Requests for a group that is down shouldn't go to another group"
+    );
+    let response_text = fallback
+        .get("/api/fallback")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /api/fallback HTTP/1.1"));
+    assert_eq!(web.requests_received_for("/api/fallback"), 1);
+
+    Box::new(web).stop().await;
+    log::info!("All done :)");
+}
+
+/// Sends a request for `path` with `host` in its Host header, returning the head of the response.
+async fn get_for_host(balancebeam: &BalanceBeam, host: &str, path: &str) -> String {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, host);
+    conn.write_all(request.as_bytes())
+        .await
+        .expect("Error writing to balancebeam");
+    // The connection is kept open for another request, so only the head can be read
+    let mut conn = tokio::io::BufReader::new(conn);
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        let read = conn
+            .read_line(&mut head)
+            .await
+            .expect("Error reading from balancebeam");
+        assert!(read > 0, "The connection closed after {:?}", head);
+    }
+    head
+}
+
+/// Make sure requests go to the group of upstreams their Host is a --vhost for, and requests for
+/// other hosts are either routed as usual or refused.
+#[tokio::test]
+async fn test_virtual_hosts() {
+    init_logging();
+    let web = EchoServer::new().await;
+    let api = EchoServer::new().await;
+    let other = EchoServer::new().await;
+    let upstreams = [
+        format!("{};group=web", web.address),
+        format!("{};group=api", api.address),
+        other.address.clone(),
+    ];
+    let upstreams: Vec<&str> = upstreams.iter().map(String::as_str).collect();
+    let args = ["--vhost", "example.com=web", "--vhost", "*.api.test=api"];
+    let balancebeam = BalanceBeam::new_with_args(&upstreams, &args).await;
+    let mut strict_args = args.to_vec();
+    strict_args.push("--reject-unknown-hosts");
+    let strict = BalanceBeam::new_with_args(&upstreams, &strict_args).await;
+
+    for (host, path) in &[
+        ("Example.COM:8080", "/web"),
+        ("v1.api.test", "/api"),
+        ("unknown.test", "/other"),
+    ] {
+        let response = get_for_host(&balancebeam, host, path).await;
+        assert!(response.contains(" 200 OK\r\n"), "{}", response);
+    }
+    assert_eq!(web.requests_received_for("/web"), 1);
+    assert_eq!(api.requests_received_for("/api"), 1);
+    assert_eq!(other.requests_received_for("/other"), 1);
+
+    log::info!("Sending requests for an unknown host with --reject-unknown-hosts");
+    let response = get_for_host(&strict, "unknown.test", "/refused").await;
+    assert!(response.starts_with("HTTP/1.1 421"), "{}", response);
+    let response = get_for_host(&strict, "example.com", "/strict").await;
+    assert!(response.contains(" 200 OK\r\n"), "{}", response);
+    assert_eq!(web.requests_received_for("/strict"), 1);
+    assert_eq!(other.requests_received_for("/refused"), 0);
+
+    Box::new(web).stop().await;
+    Box::new(api).stop().await;
+    Box::new(other).stop().await;
+    log::info!("All done :)");
+}
+
+/// Sends a request for `path` with an X-Canary header, returning the response body.
+async fn get_with_canary_header(balancebeam: &BalanceBeam, path: &str, canary: &str) -> String {
+    reqwest::Client::new()
+        .get(&format!("http://{}{}", balancebeam.address, path))
+        .header("x-canary", canary)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Error reading response from balancebeam")
+}
+
+/// Make sure the canary gets its share of the stable group's requests, which can be changed through
+/// the admin API, and that the X-Canary header overrides the split.
+#[tokio::test]
+async fn test_canary() {
+    init_logging();
+    let stable = EchoServer::new().await;
+    let canary = EchoServer::new().await;
+    let canary_upstream = format!("{};group=canary", canary.address);
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&stable.address, &canary_upstream],
+        &[
+            "--canary-group",
+            "canary",
+            "--canary-percent",
+            "0",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam, "/before", 4).await;
+    assert_eq!(stable.requests_received_for("/before"), 4);
+    get_with_canary_header(&balancebeam, "/forced", "always").await;
+    assert_eq!(canary.requests_received_for("/forced"), 1);
+
+    log::info!("Sending every request to the canary");
+    let (status, body) =
+        admin_request(&admin_address, reqwest::Method::PUT, "/canary", "100").await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert!(body.contains("\"percent\":100,"), "{}", body);
+    send_requests(&balancebeam, "/after", 4).await;
+    assert_eq!(canary.requests_received_for("/after"), 4);
+    get_with_canary_header(&balancebeam, "/kept", "never").await;
+    assert_eq!(stable.requests_received_for("/kept"), 1);
+
+    let (status, body) = admin_request(&admin_address, reqwest::Method::GET, "/canary", "").await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert!(
+        body.contains("\"canary_requests\":5,\"stable_requests\":5}"),
+        "{}",
+        body
+    );
+    let (status, _) = admin_request(&admin_address, reqwest::Method::PUT, "/canary", "101").await;
+    assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+
+    Box::new(stable).stop().await;
+    Box::new(canary).stop().await;
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_admin_api() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first.address, &second.address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "60",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    let (status, body) =
+        admin_request(&admin_address, reqwest::Method::GET, "/upstreams", "").await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert!(body.contains(&format!("\"address\":\"{}\"", first.address)));
+    assert!(body.contains(&format!("\"address\":\"{}\"", second.address)));
+
+    // The admin API is only served on the admin address
+    let response_text = balancebeam
+        .get("/upstreams")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /upstreams HTTP/1.1"));
+
+    log::info!("Draining the first upstream");
+    let drain_path = format!("/upstreams/{}/drain", first.address);
+    let (status, body) =
+        admin_request(&admin_address, reqwest::Method::POST, &drain_path, "").await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert!(body.contains("\"draining\":true"));
+    send_requests(&balancebeam, "/drained", 6).await;
+    assert_eq!(first.requests_received_for("/drained"), 0);
+    assert_eq!(second.requests_received_for("/drained"), 6);
+
+    log::info!("Removing the first upstream");
+    let upstream_path = format!("/upstreams/{}", first.address);
+    let (status, _) =
+        admin_request(&admin_address, reqwest::Method::DELETE, &upstream_path, "").await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    let (status, _) =
+        admin_request(&admin_address, reqwest::Method::DELETE, &upstream_path, "").await;
+    assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+    let (_, body) = admin_request(&admin_address, reqwest::Method::GET, "/upstreams", "").await;
+    assert!(!body.contains(&first.address));
+
+    log::info!("Adding the first upstream back");
+    let (status, body) = admin_request(
+        &admin_address,
+        reqwest::Method::POST,
+        "/upstreams",
+        &first.address,
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::CREATED);
+    assert!(body.contains("\"healthy\":true"));
+    assert!(body.contains("\"draining\":false"));
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::POST,
+        "/upstreams",
+        &first.address,
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::CONFLICT);
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::POST,
+        "/upstreams",
+        "not an address=weight",
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+    send_requests(&balancebeam, "/added", 6).await;
+    assert_eq!(first.requests_received_for("/added"), 3);
+    assert_eq!(second.requests_received_for("/added"), 3);
+
+    Box::new(first).stop().await;
+    Box::new(second).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// The status endpoint reports what has happened, even once every upstream is dead
+#[tokio::test]
+async fn test_admin_status() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let upstream_address = upstream.address.clone();
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--max-requests-per-minute",
+            "2",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    balancebeam
+        .get("/first")
+        .await
+        .expect("Error sending request to balancebeam");
+    Box::new(upstream).stop().await;
+    let response_text = balancebeam
+        .get("/second")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("502"));
+    let response_text = balancebeam
+        .get("/third")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("429"));
+
+    let (status, body) = admin_request(&admin_address, reqwest::Method::GET, "/status", "").await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    log::info!("Status: {}", body);
+    assert!(body.contains("\"requests\":3,\"errors\":2,\"rate_limited_requests\":1,"));
+    assert!(body.contains("\"rate_limited_clients\":1,\"healthy_upstreams\":0,"));
+    assert!(body.contains(&format!("\"address\":\"{}\"", upstream_address)));
+    assert!(body.contains("\"healthy\":false"));
+    assert!(body.contains("\"in_flight\":0,\"requests\":1,\"server_errors\":0,"));
+    assert!(!body.contains("\"connect_failures\":0,"));
+
+    log::info!("All done :)");
+}
+
+/// Sends a request for `path`, returning the response's status, Retry-After header and body.
+async fn get_with_retry_after(
+    balancebeam: &BalanceBeam,
+    path: &str,
+) -> (reqwest::StatusCode, Option<String>, String) {
+    let response = reqwest::get(&format!("http://{}{}", balancebeam.address, path))
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status();
+    let retry_after = response
+        .headers()
+        .get("retry-after")
+        .map(|value| value.to_str().unwrap().to_string());
+    (status, retry_after, response.text().await.unwrap())
+}
+
+/// Make sure maintenance mode turns requests away with the maintenance page until it is turned
+/// off, and can be turned on and off with the admin API and SIGUSR2.
+#[tokio::test]
+async fn test_maintenance_mode() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = random_address();
+    let page_path = std::env::temp_dir().join(format!(
+        "balancebeam-maintenance-{}.html",
+        admin_address.replace(|c: char| !c.is_ascii_digit(), "")
+    ));
+    std::fs::write(&page_path, "<h1>Back soon</h1>").unwrap();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--maintenance",
+            "--maintenance-page",
+            page_path.to_str().unwrap(),
+            "--maintenance-retry-after",
+            "120",
+            "--maintenance-allow",
+            "10.0.0.0/8",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+    let _ = std::fs::remove_file(&page_path);
+
+    let (status, retry_after, body) = get_with_retry_after(&balancebeam, "/down").await;
+    assert_eq!(status, reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(retry_after.as_deref(), Some("120"));
+    assert_eq!(body, "<h1>Back soon</h1>");
+    assert_eq!(upstream.requests_received_for("/down"), 0);
+
+    log::info!("Turning maintenance mode off with the admin API");
+    let (status, body) =
+        admin_request(&admin_address, reqwest::Method::DELETE, "/maintenance", "").await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert_eq!(body, "{\"maintenance\":false}");
+    let (status, _, body) = get_with_retry_after(&balancebeam, "/up").await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert!(body.contains("GET /up HTTP/1.1"));
+
+    log::info!("Turning maintenance mode back on with SIGUSR2");
+    balancebeam.send_signal(Signal::SIGUSR2);
+    delay_for(Duration::from_millis(300)).await;
+    let (status, _, _) = get_with_retry_after(&balancebeam, "/down-again").await;
+    assert_eq!(status, reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    let (_, body) = admin_request(&admin_address, reqwest::Method::GET, "/status", "").await;
+    assert!(body.starts_with("{\"maintenance\":true,"), "{}", body);
+    assert_eq!(upstream.requests_received_for("/down"), 0);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let metrics_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--metrics-bind",
+            &metrics_address,
+        ],
+    )
+    .await;
+
+    // Metrics are only served on the metrics address
+    for _ in 0..3 {
+        let response_text = balancebeam
+            .get("/metrics")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains("GET /metrics HTTP/1.1"));
+    }
+
+    let (status, body) =
+        admin_request(&metrics_address, reqwest::Method::GET, "/metrics", "").await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    log::info!("Metrics:\n{}", body);
+    assert!(body.contains(&format!(
+        "balancebeam_requests_total{{upstream=\"{}\",class=\"2xx\"}} 3\n",
+        upstream.address
+    )));
+    assert!(body.contains("balancebeam_upstream_response_seconds_count 3\n"));
+    assert!(body.contains("balancebeam_upstream_response_seconds_bucket{le=\"+Inf\"} 3\n"));
+    assert!(body.contains("balancebeam_upstreams 1\n"));
+    assert!(body.contains("balancebeam_healthy_upstreams 1\n"));
+    assert!(body.contains("balancebeam_rate_limited_requests_total 0\n"));
+    let (status, _) = admin_request(&metrics_address, reqwest::Method::GET, "/upstreams", "").await;
+    assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_health_check_recovery_threshold() {
+    init_logging();
+    let working = EchoServer::new().await;
+    let flapping = EchoServer::new().await;
+    let flapping_address = flapping.address.clone();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&working.address, &flapping_address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "1",
+            "--health-check-recovery-threshold",
+            "3",
+        ],
+    )
+    .await;
+
+    log::info!("Killing one upstream, and sending requests so balancebeam notices");
+    Box::new(flapping).stop().await;
+    for i in 0..2 {
+        let path = format!("/failover-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Restarting it; after a second it has passed at most two health checks");
+    let flapping = EchoServer::new_at_address(flapping_address.clone()).await;
+    delay_for(Duration::from_millis(1500)).await;
+    for i in 0..4 {
+        let path = format!("/too-soon-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    assert_eq!(
+        flapping.requests_received_for("/too-soon"),
+        0,
+        "The restored upstream got requests before passing enough health checks"
+    );
+    Box::new(flapping).stop().await;
+
+    log::info!("Restarting it again, and waiting for it to pass three health checks in a row");
+    let flapping = EchoServer::new_at_address(flapping_address).await;
+    delay_for(Duration::from_millis(4500)).await;
+    for i in 0..4 {
+        let path = format!("/restored-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    assert!(
+        flapping.requests_received_for("/restored") > 0,
+        "The restored upstream never got any more requests"
+    );
+    Box::new(flapping).stop().await;
+    Box::new(working).stop().await;
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_rate_limiting() {
+    let n_upstreams = 1;
+    let rate_limit_threshold = 5;
+    let num_extra_requests: usize = 3;
+    let (balancebeam, mut upstreams) =
+        setup_with_params(n_upstreams, None, Some(rate_limit_threshold)).await;
+
+    log::info!(
+        "Sending some basic requests to the server, within the rate limit threshold. These \
+        should succeed."
+    );
+    for i in 0..rate_limit_threshold {
         let path = format!("/request-{}", i);
         let response_text = balancebeam
             .get(&path)
@@ -294,3 +1563,678 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// Make sure a client that hit the rate limit can make requests again once its minute is up:
+///
+/// * Send requests up to the limit, then one more, which should be refused
+/// * Wait for the window to end
+/// * Send the limit's worth of requests again, which should all succeed
+#[tokio::test]
+async fn test_rate_limiting_window_resets() {
+    let rate_limit_threshold = 2;
+    let (balancebeam, mut upstreams) = setup_with_params(1, None, Some(rate_limit_threshold)).await;
+    let client = reqwest::Client::new();
+    let send = |path: String| {
+        client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+    };
+
+    log::info!("Using up the rate limit");
+    for i in 0..rate_limit_threshold {
+        let response = send(format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let response = send("/overboard".to_string())
+        .await
+        .expect("Error sending rate limited request to balancebeam");
+    assert_eq!(response.status().as_u16(), 429);
+
+    log::info!("Waiting for the rate limit window to end");
+    delay_for(Duration::from_secs(61)).await;
+
+    log::info!("Sending requests after the window, which should be accepted again");
+    for i in 0..rate_limit_threshold {
+        let response = send(format!("/later-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    let mut total_request_count = 0;
+    while let Some(upstream) = upstreams.pop() {
+        total_request_count += upstream.stop().await;
+    }
+    assert_eq!(total_request_count, rate_limit_threshold * 2);
+
+    log::info!("All done :)");
+}
+
+/// Make sure the token bucket allows a burst, then refills at the per-minute rate:
+///
+/// * Send a burst the size of the bucket, which should all succeed
+/// * Send one more, which should be refused
+/// * Wait for a token to be added, then send one more, which should succeed
+#[tokio::test]
+async fn test_token_bucket_rate_limiting() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // One token a second, so the test doesn't have to wait long for a refill
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "60",
+            "--rate-limit-algorithm",
+            "token-bucket",
+            "--rate-limit-burst",
+            "3",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let get_status = |path: &str| {
+        let request = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send();
+        async move {
+            request
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }
+    };
+
+    log::info!("Sending a burst that fills the bucket");
+    for i in 0..3 {
+        assert_eq!(get_status(&format!("/burst-{}", i)).await, 200);
+    }
+    assert_eq!(get_status("/overboard").await, 429);
+
+    log::info!("Waiting for the bucket to refill by a token");
+    delay_for(Duration::from_millis(1100)).await;
+    assert_eq!(get_status("/refilled").await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+
+    log::info!("All done :)");
+}
+
+/// Make sure clients in an exempt block aren't rate limited: the tests connect from 127.0.0.1,
+/// so every request should get through despite a limit of 1
+#[tokio::test]
+async fn test_rate_limit_exempt() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "1",
+            "--rate-limit-exempt",
+            "10.0.0.0/8,127.0.0.0/8",
+        ],
+    )
+    .await;
+
+    for i in 0..5 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(Box::new(upstream).stop().await, 5);
+
+    log::info!("All done :)");
+}
+
+/// Make sure a path can have a tighter limit than the rest of the site, and that it applies to
+/// each request rather than each connection. The client keeps its connection alive between
+/// requests, so they all arrive on the same connection.
+#[tokio::test]
+async fn test_rate_limit_path() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "10",
+            "--rate-limit-path",
+            "/api/login=2",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let get_status = |path: &str| {
+        let request = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send();
+        async move {
+            request
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }
+    };
+
+    log::info!("Using up the login limit");
+    for _ in 0..2 {
+        assert_eq!(get_status("/api/login").await, 200);
+    }
+    assert_eq!(get_status("/api/login").await, 429);
+
+    log::info!("Making sure the rest of the site has its own limit");
+    for i in 0..5 {
+        assert_eq!(get_status(&format!("/page-{}", i)).await, 200);
+    }
+    assert_eq!(get_status("/api/login").await, 429);
+    assert_eq!(Box::new(upstream).stop().await, 7);
+
+    log::info!("All done :)");
+}
+
+/// Make sure responses say how much of the limit is left, and a 429 says when to try again
+#[tokio::test]
+async fn test_rate_limit_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-requests-per-minute", "2", "--rate-limit-headers"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let mut responses = Vec::new();
+    for i in 0..3 {
+        responses.push(
+            client
+                .get(&format!("http://{}/request-{}", balancebeam.address, i))
+                .header("x-sent-by", "balancebeam-tests")
+                .send()
+                .await
+                .expect("Error sending request to balancebeam"),
+        );
+    }
+    let header = |response: &reqwest::Response, name: &str| {
+        response
+            .headers()
+            .get(name)
+            .unwrap_or_else(|| panic!("Response is missing {}", name))
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+
+    for (response, remaining) in responses.iter().zip(&["1", "0"]) {
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(header(response, "x-ratelimit-limit"), "2");
+        assert_eq!(header(response, "x-ratelimit-remaining"), *remaining);
+        assert!(response.headers().get("retry-after").is_none());
+    }
+
+    log::info!("Checking the 429 says to retry once the first request leaves the window");
+    let refused = &responses[2];
+    assert_eq!(refused.status().as_u16(), 429);
+    assert_eq!(header(refused, "x-ratelimit-limit"), "2");
+    assert_eq!(header(refused, "x-ratelimit-remaining"), "0");
+    let retry_after: u64 = header(refused, "retry-after").parse().unwrap();
+    assert!(
+        (55..=60).contains(&retry_after),
+        "Retry-After should be just under a minute, not {}",
+        retry_after
+    );
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Make sure clients behind a trusted proxy are told apart by X-Forwarded-For, and that the header
+/// is ignored otherwise (the tests connect from 127.0.0.1, which --trust-proxy trusts by default)
+#[tokio::test]
+async fn test_trust_proxy() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let send = |balancebeam: &BalanceBeam, forwarded_for: &str| {
+        reqwest::Client::new()
+            .get(&format!("http://{}/", balancebeam.address))
+            .header("x-sent-by", "balancebeam-tests")
+            .header("x-forwarded-for", forwarded_for)
+            .send()
+    };
+
+    log::info!("Checking that each client behind the proxy has its own limit");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-requests-per-minute", "1", "--trust-proxy"],
+    )
+    .await;
+    for (forwarded_for, status) in &[
+        ("203.0.113.1", 200),
+        ("203.0.113.2", 200),
+        ("203.0.113.1", 429),
+        // The client can't escape its limit by claiming to be someone else
+        ("198.51.100.7, 203.0.113.2", 429),
+    ] {
+        let response = send(&balancebeam, forwarded_for)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), *status, "{}", forwarded_for);
+    }
+    drop(balancebeam);
+
+    log::info!("Checking that X-Forwarded-For is ignored from a peer that isn't trusted");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "1",
+            "--trust-proxy",
+            "10.0.0.0/8",
+        ],
+    )
+    .await;
+    for (forwarded_for, status) in &[("203.0.113.1", 200), ("203.0.113.2", 429)] {
+        let response = send(&balancebeam, forwarded_for)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), *status, "{}", forwarded_for);
+    }
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Make sure a client can't hold more connections open than --max-connections-per-ip allows:
+///
+/// * Open as many idle connections as allowed
+/// * Open one more, which should be refused with a 503
+/// * Close one of the idle connections; a request should get through again
+#[tokio::test]
+async fn test_max_connections_per_ip() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-connections-per-ip", "2"]).await;
+
+    log::info!("Opening idle connections up to the limit");
+    let mut idle = Vec::new();
+    for _ in 0..2 {
+        idle.push(
+            TcpStream::connect(&balancebeam.address)
+                .await
+                .expect("Error connecting to balancebeam"),
+        );
+    }
+    // Give balancebeam a moment to count them
+    delay_for(Duration::from_millis(200)).await;
+
+    log::info!("Opening one connection too many");
+    let mut extra = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    let mut response = String::new();
+    extra
+        .read_to_string(&mut response)
+        .await
+        .expect("Error reading response from balancebeam");
+    assert!(
+        response.starts_with("HTTP/1.1 503"),
+        "Expected a 503, got {:?}",
+        response
+    );
+
+    log::info!("Closing an idle connection to make room");
+    idle.pop();
+    delay_for(Duration::from_millis(200)).await;
+    let response_text = balancebeam
+        .get("/after-close")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /after-close HTTP/1.1"));
+
+    drop(idle);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure connections over --max-concurrent-connections are refused with --when-saturated
+/// reject, until one of the open connections closes
+#[tokio::test]
+async fn test_max_concurrent_connections_reject() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-concurrent-connections",
+            "1",
+            "--when-saturated",
+            "reject",
+        ],
+    )
+    .await;
+
+    log::info!("Taking up the only connection");
+    let idle = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    delay_for(Duration::from_millis(200)).await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(&format!("http://{}/saturated", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+
+    log::info!("Closing the connection to make room");
+    drop(idle);
+    delay_for(Duration::from_millis(200)).await;
+    let response_text = balancebeam
+        .get("/after-close")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /after-close HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure connections over --max-concurrent-connections wait their turn by default, rather
+/// than being refused
+#[tokio::test]
+async fn test_max_concurrent_connections_wait() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-concurrent-connections", "1"])
+            .await;
+
+    log::info!("Taking up the only connection");
+    let idle = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    delay_for(Duration::from_millis(200)).await;
+
+    log::info!("Sending a request, which should wait for the connection to close");
+    let address = balancebeam.address.clone();
+    let waiting = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(&format!("http://{}/waiting", address))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Error reading response from balancebeam")
+    });
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(upstream.requests_received_for("/waiting"), 0);
+
+    drop(idle);
+    let response_text = waiting.await.unwrap();
+    assert!(response_text.contains("GET /waiting HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure a backup upstream (weight 0) only gets requests once every other upstream is dead:
+///
+/// * Send a few requests, which should all go to the primary upstream
+/// * Kill the primary upstream
+/// * Send some more requests, which should all go to the backup
+#[tokio::test]
+async fn test_backup_upstream() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let backup = EchoServer::new().await;
+    let backup_upstream = format!("{}=0", backup.address);
+    let balancebeam = BalanceBeam::new(&[&primary.address, &backup_upstream], None, None).await;
+
+    log::info!("Sending some requests while the primary upstream is alive");
+    for i in 0..5 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Killing the primary upstream");
+    assert_eq!(Box::new(primary).stop().await, 5);
+
+    log::info!("Sending some requests that only the backup can serve");
+    for i in 0..5 {
+        let path = format!("/failover-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam. The backup may not be in use");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(
+        Box::new(backup).stop().await,
+        5,
+        "The backup upstream should only get requests once the primary is dead"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Make sure a request to an upstream whose host has vanished (so connecting to it hangs, rather
+/// than being refused) gets a prompt 502 instead of waiting for the operating system to give up
+#[tokio::test]
+async fn test_upstream_connect_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let address = upstream.address.clone();
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&address], &["--upstream-connect-timeout-ms", "500"]).await;
+
+    log::info!("Replacing the upstream with one that never answers");
+    Box::new(upstream).stop().await;
+    let blackhole = BlackholeServer::new_at_address(address).await;
+
+    let started = Instant::now();
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert!(
+        started.elapsed() < Duration::from_secs(3),
+        "The 502 took {:?}",
+        started.elapsed()
+    );
+    Box::new(blackhole).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Make sure a connection that times out sends the client to another upstream, rather than
+/// failing the request
+#[tokio::test]
+async fn test_upstream_connect_timeout_failover() {
+    init_logging();
+    let working = EchoServer::new().await;
+    let vanishing = EchoServer::new().await;
+    let vanishing_address = vanishing.address.clone();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&working.address, &vanishing_address],
+        &[
+            "--upstream-connect-timeout-ms",
+            "500",
+            "--strategy",
+            "round-robin",
+        ],
+    )
+    .await;
+
+    log::info!("Replacing one upstream with one that never answers");
+    Box::new(vanishing).stop().await;
+    let blackhole = BlackholeServer::new_at_address(vanishing_address).await;
+
+    // Round-robin tries the vanished upstream for one of the first two requests
+    for i in 0..4 {
+        let path = format!("/request-{}", i);
+        let started = Instant::now();
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(
+            started.elapsed() < Duration::from_secs(3),
+            "The response took {:?}",
+            started.elapsed()
+        );
+    }
+    assert_eq!(Box::new(working).stop().await, 4);
+    Box::new(blackhole).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Make sure a request to an upstream that never responds gets a 504 once
+/// --upstream-response-timeout is up, and that the upstream stops being used
+#[tokio::test]
+async fn test_upstream_response_timeout() {
+    init_logging();
+    let working = EchoServer::new().await;
+    let silent = EchoServer::new().await;
+    let silent_address = silent.address.clone();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&silent_address, &working.address],
+        &[
+            "--upstream-response-timeout",
+            "1",
+            "--strategy",
+            "round-robin",
+        ],
+    )
+    .await;
+
+    log::info!("Replacing one upstream with one that never responds");
+    Box::new(silent).stop().await;
+    let silent = SilentServer::new_at_address(silent_address).await;
+
+    // Round-robin sends one of the first two requests to the silent upstream
+    let mut statuses = Vec::new();
+    for i in 0..2 {
+        let started = Instant::now();
+        let response = reqwest::Client::new()
+            .get(&format!("http://{}/request-{}", balancebeam.address, i))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            started.elapsed() < Duration::from_secs(3),
+            "The response took {:?}",
+            started.elapsed()
+        );
+        statuses.push(response.status().as_u16());
+    }
+    statuses.sort_unstable();
+    assert_eq!(statuses, vec![200, 504]);
+
+    log::info!("Making sure the silent upstream isn't used any more");
+    for i in 0..4 {
+        let path = format!("/after-timeout-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(Box::new(working).stop().await, 5);
+    assert_eq!(Box::new(silent).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure connections from clients that go quiet are closed once --client-idle-timeout is up:
+///
+/// * A client that never sends anything should have its connection closed without a response
+/// * A client that stops partway through its headers should get a 408 first
+#[tokio::test]
+async fn test_client_idle_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--client-idle-timeout", "1"]).await;
+
+    log::info!("Connecting and going silent");
+    let started = Instant::now();
+    let mut silent = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    let mut response = Vec::new();
+    silent
+        .read_to_end(&mut response)
+        .await
+        .expect("Error reading from balancebeam");
+    assert!(
+        response.is_empty(),
+        "Expected no response, got {:?}",
+        response
+    );
+    assert!(
+        started.elapsed() < Duration::from_secs(3),
+        "The connection was closed after {:?}",
+        started.elapsed()
+    );
+
+    log::info!("Sending part of a request and stalling");
+    let started = Instant::now();
+    let mut stalled = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stalled
+        .write_all(b"GET /stalled HTTP/1.1\r\nHost: balancebeam\r\n")
+        .await
+        .expect("Error writing to balancebeam");
+    let mut response = String::new();
+    stalled
+        .read_to_string(&mut response)
+        .await
+        .expect("Error reading from balancebeam");
+    assert!(
+        response.starts_with("HTTP/1.1 408"),
+        "Expected a 408, got {:?}",
+        response
+    );
+    assert!(
+        started.elapsed() < Duration::from_secs(3),
+        "The connection was closed after {:?}",
+        started.elapsed()
+    );
+
+    log::info!("Making sure a request sent in time still works");
+    let response_text = balancebeam
+        .get("/prompt")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /prompt HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
@@ -1,4 +1,7 @@
-use rand::Rng;
+use crate::common::{random_address, HEALTH_CHECK_PATH};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::process::ExitStatus;
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
@@ -24,28 +27,45 @@ impl BalanceBeam {
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
-        let mut rng = rand::thread_rng();
-        let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+        let mut args = Vec::new();
+        if let Some(active_health_check_interval) = active_health_check_interval {
+            args.push("--active-health-check-interval".to_string());
+            args.push(active_health_check_interval.to_string());
+        }
+        if let Some(max_requests_per_minute) = max_requests_per_minute {
+            args.push("--max-requests-per-minute".to_string());
+            args.push(max_requests_per_minute.to_string());
+        }
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &args).await
+    }
+
+    /// Starts balancebeam with the given upstreams, passing it any other command-line arguments
+    /// in `args`.
+    pub async fn new_with_args(upstreams: &[&str], args: &[&str]) -> BalanceBeam {
+        let address = random_address();
         let mut cmd = Command::new(BalanceBeam::target_bin_path());
         cmd.arg("--bind").arg(&address);
         for upstream in upstreams {
             cmd.arg("--upstream").arg(upstream);
         }
-        if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
-        }
-        if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
+        if !args
+            .iter()
+            .any(|arg| arg.starts_with("--active-health-check-path"))
+        {
+            cmd.arg("--active-health-check-path").arg(HEALTH_CHECK_PATH);
         }
+        cmd.args(args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|err| {
+            panic!(
+                "Could not execute balancebeam binary {}: {}",
+                BalanceBeam::target_bin_path().to_str().unwrap(),
+                err
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
@@ -96,6 +116,35 @@ impl BalanceBeam {
             .await
     }
 
+    /// Returns the most memory balancebeam has used so far (its peak resident set size), in KiB.
+    #[allow(dead_code)]
+    pub fn peak_memory_kib(&self) -> usize {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", self.child.id()))
+            .expect("Could not read balancebeam's status");
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .expect("balancebeam's status has no VmHWM")
+    }
+
+    /// Sends balancebeam a signal, such as SIGTERM to ask it to shut down.
+    #[allow(dead_code)]
+    pub fn send_signal(&self, signal: Signal) {
+        signal::kill(Pid::from_raw(self.child.id() as i32), signal)
+            .expect("Could not send a signal to balancebeam");
+    }
+
+    /// Waits for balancebeam to exit, returning how it exited, or None if it is still running after
+    /// `timeout`.
+    #[allow(dead_code)]
+    pub async fn wait_for_exit(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        tokio::time::timeout(timeout, &mut self.child)
+            .await
+            .ok()
+            .map(|status| status.expect("Could not wait for balancebeam to exit"))
+    }
+
     #[allow(dead_code)]
     pub async fn post(&self, path: &str, body: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
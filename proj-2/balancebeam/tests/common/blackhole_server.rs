@@ -0,0 +1,63 @@
+use crate::common::random_address;
+use crate::common::server::Server;
+use async_trait::async_trait;
+use nix::sys::socket::{self, sockopt, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::FromRawFd;
+
+/// A server that never answers, as if its host had gone away without a trace: connecting to it
+/// hangs until the connecting side gives up. It listens with no room in its accept queue and never
+/// accepts, then fills the queue with a connection of its own, so the kernel drops every other
+/// connection attempt's SYN.
+pub struct BlackholeServer {
+    _listener: TcpListener,
+    _queued: TcpStream,
+    #[allow(dead_code)]
+    pub address: String,
+}
+
+impl BlackholeServer {
+    #[allow(dead_code)]
+    pub async fn new() -> BlackholeServer {
+        BlackholeServer::new_at_address(random_address()).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn new_at_address(bind_addr_string: String) -> BlackholeServer {
+        let address: SocketAddr = bind_addr_string
+            .parse()
+            .expect("BlackholeServer needs an IP address");
+        let fd = socket::socket(
+            AddressFamily::Inet,
+            SockType::Stream,
+            SockFlag::empty(),
+            None,
+        )
+        .expect("BlackholeServer could not create a socket");
+        // Take ownership straight away, so the socket is closed if anything below fails
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        socket::setsockopt(fd, sockopt::ReuseAddr, &true)
+            .expect("BlackholeServer could not set SO_REUSEADDR");
+        socket::bind(fd, &SockAddr::new_inet(InetAddr::from_std(&address)))
+            .expect("BlackholeServer could not bind");
+        socket::listen(fd, 0).expect("BlackholeServer could not listen");
+        let queued = TcpStream::connect(address).expect("BlackholeServer could not fill its queue");
+        BlackholeServer {
+            _listener: listener,
+            _queued: queued,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for BlackholeServer {
+    /// Returns 0, since no connections are ever accepted.
+    async fn stop(self: Box<Self>) -> usize {
+        0
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -0,0 +1,86 @@
+use crate::common::random_address;
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::sync::{atomic, Arc};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub connections_accepted: atomic::AtomicUsize,
+}
+
+/// A broken server that accepts connections and then hangs up straight away, without reading a
+/// request or sending a response. Connecting to it succeeds, but forwarding a request to it fails.
+pub struct ClosingServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    #[allow(dead_code)]
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl ClosingServer {
+    #[allow(dead_code)]
+    pub async fn new() -> ClosingServer {
+        ClosingServer::new_at_address(random_address()).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn new_at_address(bind_addr_string: String) -> ClosingServer {
+        let mut listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("ClosingServer could not bind");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            connections_accepted: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    connection = listener.accept() => {
+                        // Dropping the connection closes it
+                        if connection.is_ok() {
+                            server_task_state
+                                .connections_accepted
+                                .fetch_add(1, atomic::Ordering::SeqCst);
+                        }
+                    }
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        ClosingServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for ClosingServer {
+    /// Returns the number of connections accepted, since no requests are ever read.
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("ClosingServer server task panicked");
+
+        self.state
+            .connections_accepted
+            .load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -1,23 +1,38 @@
+use crate::common::random_address;
 use crate::common::server::Server;
+use crate::common::HEALTH_CHECK_PATH;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
-use rand::Rng;
-use std::sync::{atomic, Arc};
+use std::sync::{atomic, Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::oneshot;
 
 #[derive(Debug)]
 struct ServerState {
+    /// Requests received, apart from health checks to HEALTH_CHECK_PATH
     pub requests_received: atomic::AtomicUsize,
+    /// The path of every request received, in order
+    pub paths_received: Mutex<Vec<String>>,
+    /// How long to wait before responding to each request
+    pub delay: Duration,
 }
 
 async fn echo(
     server_state: Arc<ServerState>,
     req: Request<Body>,
 ) -> Result<Response<Body>, hyper::Error> {
+    if req.uri().path() != HEALTH_CHECK_PATH {
+        server_state
+            .requests_received
+            .fetch_add(1, atomic::Ordering::SeqCst);
+    }
     server_state
-        .requests_received
-        .fetch_add(1, atomic::Ordering::SeqCst);
+        .paths_received
+        .lock()
+        .unwrap()
+        .push(req.uri().path().to_string());
+    tokio::time::delay_for(server_state.delay).await;
     let mut req_text = format!("{} {} {:?}\n", req.method(), req.uri(), req.version());
     for (header_name, header_value) in req.headers() {
         req_text += &format!(
@@ -35,17 +50,27 @@ async fn echo(
 pub struct EchoServer {
     shutdown_signal_sender: oneshot::Sender<()>,
     server_task: tokio::task::JoinHandle<()>,
+    #[allow(dead_code)]
     pub address: String,
     state: Arc<ServerState>,
 }
 
 impl EchoServer {
     pub async fn new() -> EchoServer {
-        let mut rng = rand::thread_rng();
-        EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024, 65535))).await
+        EchoServer::new_at_address(random_address()).await
+    }
+
+    /// Starts a server that waits for `delay` before each response, like an overloaded server.
+    #[allow(dead_code)]
+    pub async fn new_with_delay(delay: Duration) -> EchoServer {
+        EchoServer::start(random_address(), delay).await
     }
 
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
+        EchoServer::start(bind_addr_string, Duration::from_secs(0)).await
+    }
+
+    async fn start(bind_addr_string: String, delay: Duration) -> EchoServer {
         let bind_addr = bind_addr_string.parse().unwrap();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
@@ -53,6 +78,8 @@ impl EchoServer {
         // Start a separate server task
         let server_state = Arc::new(ServerState {
             requests_received: atomic::AtomicUsize::new(0),
+            paths_received: Mutex::new(Vec::new()),
+            delay,
         });
         let server_task_state = server_state.clone();
         let server_task = tokio::spawn(async move {
@@ -85,6 +112,21 @@ impl EchoServer {
     }
 }
 
+impl EchoServer {
+    /// Returns how many requests so far were for a path starting with `prefix`, for telling the
+    /// test's own requests apart from active health checks.
+    #[allow(dead_code)]
+    pub fn requests_received_for(&self, prefix: &str) -> usize {
+        self.state
+            .paths_received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|path| path.starts_with(prefix))
+            .count()
+    }
+}
+
 #[async_trait]
 impl Server for EchoServer {
     async fn stop(self: Box<Self>) -> usize {
@@ -0,0 +1,236 @@
+use crate::common::random_address;
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::sync::{atomic, Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::{delay_for, Duration};
+
+/// How FramingServer marks where each response body ends.
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+pub enum Framing {
+    ContentLength,
+    /// Transfer-Encoding: chunked, with the chunks sent a little while apart
+    Chunked,
+    /// No Content-Length: the body ends when the server closes the connection
+    UntilClose,
+    /// A Content-Length of OVERLONG_CLAIMED_SIZE, followed by OVERLONG_SENT_SIZE bytes of body
+    Overlong,
+    /// Content-Length, but the server closes the connection after each response without saying
+    /// so, as servers do with keep-alive connections that have been idle for too long
+    ClosingContentLength,
+    /// Content-Length, but the server sends half of the body and then nothing more, leaving the
+    /// connection open
+    Stalled,
+}
+
+#[allow(dead_code)]
+pub const LARGE_BODY_SIZE: usize = 20_000_000;
+#[allow(dead_code)]
+pub const OVERLONG_CLAIMED_SIZE: usize = 1_000_000;
+const OVERLONG_SENT_SIZE: usize = 100_000_000;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+    pub connections_accepted: atomic::AtomicUsize,
+}
+
+/// A server that answers every request with a body saying which request it was (such as
+/// "response 1 to GET /path"), framed as it was told to. Bodies for paths starting with /large
+/// are padded out with LARGE_BODY_SIZE dots. (Overlong responses are only dots.) HEAD requests, and
+/// paths ending in /not-modified (which get a 304), are answered without the body. Responses to GETs
+/// for paths starting with /cached say they can be cached for a minute. Only requests without
+/// bodies are understood.
+pub struct FramingServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    #[allow(dead_code)]
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+/// Reads the next request's headers, returning its request line, or None once the client hangs up.
+async fn read_request_line(stream: &mut TcpStream) -> Option<String> {
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        let mut byte = [0_u8; 1];
+        if stream.read(&mut byte).await.ok()? == 0 {
+            return None;
+        }
+        request.push(byte[0]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    request.lines().next().map(String::from)
+}
+
+async fn handle_connection(mut stream: TcpStream, framing: Framing, state: Arc<ServerState>) {
+    while let Some(request_line) = read_request_line(&mut stream).await {
+        let number = state
+            .requests_received
+            .fetch_add(1, atomic::Ordering::SeqCst)
+            + 1;
+        let path = request_line
+            .split(' ')
+            .take(2)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut body = format!("response {} to {}", number, path);
+        if path.starts_with("GET /large") {
+            body.push_str(&".".repeat(LARGE_BODY_SIZE));
+        }
+        let cache_control = if path.starts_with("GET /cached") {
+            "Cache-Control: max-age=60\r\n"
+        } else {
+            ""
+        };
+        // Responses to HEAD, and 304s, have no body, but still say how it would be framed
+        let head_only = path.starts_with("HEAD ");
+        if head_only || path.ends_with("/not-modified") {
+            let status = if head_only {
+                "200 OK"
+            } else {
+                "304 Not Modified\r\nETag: \"1\""
+            };
+            let framing_header = match framing {
+                Framing::Chunked => "Transfer-Encoding: chunked".to_string(),
+                _ => format!("Content-Length: {}", body.len()),
+            };
+            let response = format!("HTTP/1.1 {}\r\n{}\r\n\r\n", status, framing_header);
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        let result = match framing {
+            Framing::ContentLength | Framing::ClosingContentLength => {
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n{}",
+                    cache_control,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await
+            }
+            Framing::Chunked => {
+                let (first, second) = body.split_at(body.len() / 2);
+                let pieces = [
+                    format!(
+                        "HTTP/1.1 200 OK\r\n{}Transfer-Encoding: chunked\r\n\r\n",
+                        cache_control
+                    ),
+                    format!("{:x}\r\n{}\r\n", first.len(), first),
+                    format!("{:x};ext=1\r\n{}\r\n", second.len(), second),
+                    "0\r\nX-Trailer: ignored\r\n\r\n".to_string(),
+                ];
+                let mut result = Ok(());
+                for piece in pieces.iter() {
+                    result = stream.write_all(piece.as_bytes()).await;
+                    delay_for(Duration::from_millis(20)).await;
+                }
+                result
+            }
+            Framing::UntilClose => {
+                let response = format!("HTTP/1.1 200 OK\r\n{}\r\n{}", cache_control, body);
+                let _ = stream.write_all(response.as_bytes()).await;
+                return;
+            }
+            Framing::Stalled => {
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body.as_bytes()[..body.len() / 2]).await;
+                delay_for(Duration::from_secs(60)).await;
+                return;
+            }
+            Framing::Overlong => {
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                    OVERLONG_CLAIMED_SIZE
+                );
+                let mut result = stream.write_all(head.as_bytes()).await;
+                let piece = vec![b'.'; 64 * 1024];
+                let mut sent = 0;
+                while result.is_ok() && sent < OVERLONG_SENT_SIZE {
+                    result = stream.write_all(&piece).await;
+                    sent += piece.len();
+                }
+                result
+            }
+        };
+        if result.is_err() {
+            return;
+        }
+        if let Framing::ClosingContentLength = framing {
+            return;
+        }
+    }
+}
+
+impl FramingServer {
+    #[allow(dead_code)]
+    pub async fn new(framing: Framing) -> FramingServer {
+        let bind_addr_string = random_address();
+        let mut listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("FramingServer could not bind");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            connections_accepted: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    connection = listener.accept() => {
+                        if let Ok((stream, _)) = connection {
+                            let state = server_task_state.clone();
+                            state.connections_accepted.fetch_add(1, atomic::Ordering::SeqCst);
+                            tokio::spawn(handle_connection(stream, framing, state));
+                        }
+                    }
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        FramingServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+
+    /// Returns how many connections have been made to the server, health checks included.
+    #[allow(dead_code)]
+    pub fn connections_accepted(&self) -> usize {
+        self.state
+            .connections_accepted
+            .load(atomic::Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl Server for FramingServer {
+    /// Returns the number of requests received, health checks included.
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("FramingServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
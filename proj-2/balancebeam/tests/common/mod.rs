@@ -1,17 +1,58 @@
 mod balancebeam;
+mod blackhole_server;
+mod closing_server;
 mod echo_server;
 mod error_server;
+mod framing_server;
+mod proxy_protocol_server;
 mod server;
+mod silent_server;
+mod tls_server;
+mod unix_socket_server;
+mod websocket_server;
 
+use rand::Rng;
 use std::sync;
 
 pub use balancebeam::BalanceBeam;
+#[allow(unused_imports)]
+pub use blackhole_server::BlackholeServer;
+#[allow(unused_imports)]
+pub use closing_server::ClosingServer;
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
+#[allow(unused_imports)]
+pub use framing_server::{Framing, FramingServer, LARGE_BODY_SIZE, OVERLONG_CLAIMED_SIZE};
+#[allow(unused_imports)]
+pub use proxy_protocol_server::ProxyProtocolServer;
 pub use server::Server;
+#[allow(unused_imports)]
+pub use silent_server::SilentServer;
+#[allow(unused_imports)]
+pub use tls_server::{TlsServer, TLS_CERT, TLS_KEY};
+#[allow(unused_imports)]
+pub use unix_socket_server::UnixSocketServer;
+#[allow(unused_imports)]
+pub use websocket_server::{
+    masked_frame, read_frame, WebSocketServer, CLOSE_FRAME, TEXT_FRAME, WEBSOCKET_ACCEPT,
+    WEBSOCKET_KEY,
+};
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
+/// Where balancebeam sends active health checks, unless a test chooses a path itself. EchoServer
+/// doesn't count these as requests, so tests can count just the requests they sent.
+pub const HEALTH_CHECK_PATH: &str = "/balancebeam-health-check";
+
+/// Returns a random local address to listen on. The port is below Linux's default ephemeral port
+/// range (32768-60999), so it can't already be the local end of one of the many connections the
+/// tests make.
+pub fn random_address() -> String {
+    let mut rng = rand::thread_rng();
+    format!("127.0.0.1:{}", rng.gen_range(1024, 32768))
+}
+
 pub fn init_logging() {
     INIT_TESTS.call_once(|| {
         pretty_env_logger::formatted_builder()
@@ -0,0 +1,122 @@
+use crate::common::random_address;
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub headers_received: Mutex<Vec<String>>,
+}
+
+/// Puts another server behind a PROXY protocol header: each connection has to start with a header
+/// (which is recorded), and the rest of what the client sends is passed on to the other server,
+/// and whatever that sends back to the client.
+pub struct ProxyProtocolServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    #[allow(dead_code)]
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+/// Reads a line, including its CRLF, or returns None if the client hangs up first.
+async fn read_line(stream: &mut TcpStream) -> Option<String> {
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        let mut byte = [0_u8; 1];
+        if stream.read(&mut byte).await.ok()? == 0 {
+            return None;
+        }
+        line.push(byte[0]);
+    }
+    Some(String::from_utf8_lossy(&line).to_string())
+}
+
+async fn handle_connection(mut stream: TcpStream, backend: String, state: Arc<ServerState>) {
+    let header = match read_line(&mut stream).await {
+        Some(header) if header.starts_with("PROXY ") => header,
+        _ => return,
+    };
+    state.headers_received.lock().unwrap().push(header);
+    let backend = match TcpStream::connect(&backend).await {
+        Ok(backend) => backend,
+        Err(_) => return,
+    };
+    let (mut client_read, mut client_write) = tokio::io::split(stream);
+    let (mut backend_read, mut backend_write) = tokio::io::split(backend);
+    let _ = tokio::try_join!(
+        tokio::io::copy(&mut client_read, &mut backend_write),
+        tokio::io::copy(&mut backend_read, &mut client_write),
+    );
+}
+
+impl ProxyProtocolServer {
+    /// Starts passing on connections with PROXY protocol headers to the server at `backend`.
+    #[allow(dead_code)]
+    pub async fn new(backend: &str) -> ProxyProtocolServer {
+        let bind_addr_string = random_address();
+        let mut listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("ProxyProtocolServer could not bind");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            headers_received: Mutex::new(Vec::new()),
+        });
+        let server_task_state = server_state.clone();
+        let backend = backend.to_string();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    connection = listener.accept() => {
+                        if let Ok((stream, _)) = connection {
+                            tokio::spawn(handle_connection(
+                                stream,
+                                backend.clone(),
+                                server_task_state.clone(),
+                            ));
+                        }
+                    }
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        ProxyProtocolServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+
+    /// The headers received so far, in the order they arrived (each with its CRLF).
+    #[allow(dead_code)]
+    pub fn headers_received(&self) -> Vec<String> {
+        self.state.headers_received.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Server for ProxyProtocolServer {
+    /// Returns the number of connections that started with a PROXY protocol header.
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("ProxyProtocolServer server task panicked");
+
+        self.state.headers_received.lock().unwrap().len()
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -0,0 +1,70 @@
+use crate::common::random_address;
+use crate::common::server::Server;
+use async_trait::async_trait;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+/// A broken server that accepts connections but never responds to anything sent over them.
+/// Connections stay open until the server is stopped.
+pub struct SilentServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<usize>,
+    #[allow(dead_code)]
+    pub address: String,
+}
+
+impl SilentServer {
+    #[allow(dead_code)]
+    pub async fn new() -> SilentServer {
+        SilentServer::new_at_address(random_address()).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn new_at_address(bind_addr_string: String) -> SilentServer {
+        let mut listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("SilentServer could not bind");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task, which hands back how many connections it accepted
+        let server_task = tokio::spawn(async move {
+            // Requests are left unread in the connections' receive buffers
+            let mut connections = Vec::new();
+            loop {
+                tokio::select! {
+                    connection = listener.accept() => {
+                        if let Ok((stream, _)) = connection {
+                            connections.push(stream);
+                        }
+                    }
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+            connections.len()
+        });
+
+        SilentServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for SilentServer {
+    /// Returns the number of connections accepted, since no requests are ever answered.
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("SilentServer server task panicked")
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
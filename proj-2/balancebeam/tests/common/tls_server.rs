@@ -0,0 +1,123 @@
+use crate::common::random_address;
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::sync::{atomic, Arc};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+/// The self-signed certificate (for localhost and 127.0.0.1) and key the TLS tests use.
+#[allow(dead_code)]
+pub const TLS_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/cert.pem");
+#[allow(dead_code)]
+pub const TLS_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/key.pem");
+
+#[derive(Debug)]
+struct ServerState {
+    pub handshakes_completed: atomic::AtomicUsize,
+}
+
+/// Makes another server speak HTTPS, by completing a TLS handshake (with TLS_CERT) with each
+/// client and passing on whatever it sends to the other server, and whatever that sends back.
+pub struct TlsServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    #[allow(dead_code)]
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    acceptor: tokio_tls::TlsAcceptor,
+    backend: String,
+    state: Arc<ServerState>,
+) {
+    let stream = match acceptor.accept(stream).await {
+        Ok(stream) => stream,
+        Err(_) => return,
+    };
+    state
+        .handshakes_completed
+        .fetch_add(1, atomic::Ordering::SeqCst);
+    let backend = match TcpStream::connect(&backend).await {
+        Ok(backend) => backend,
+        Err(_) => return,
+    };
+    let (mut client_read, mut client_write) = tokio::io::split(stream);
+    let (mut backend_read, mut backend_write) = tokio::io::split(backend);
+    let _ = tokio::try_join!(
+        tokio::io::copy(&mut client_read, &mut backend_write),
+        tokio::io::copy(&mut backend_read, &mut client_write),
+    );
+}
+
+impl TlsServer {
+    /// Starts serving HTTPS for the server at `backend`.
+    #[allow(dead_code)]
+    pub async fn new(backend: &str) -> TlsServer {
+        let cert = std::fs::read(TLS_CERT).expect("Could not read the test certificate");
+        let key = std::fs::read(TLS_KEY).expect("Could not read the test key");
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key).unwrap();
+        let acceptor: tokio_tls::TlsAcceptor =
+            native_tls::TlsAcceptor::new(identity).unwrap().into();
+
+        let bind_addr_string = random_address();
+        let mut listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("TlsServer could not bind");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            handshakes_completed: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let backend = backend.to_string();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    connection = listener.accept() => {
+                        if let Ok((stream, _)) = connection {
+                            tokio::spawn(handle_connection(
+                                stream,
+                                acceptor.clone(),
+                                backend.clone(),
+                                server_task_state.clone(),
+                            ));
+                        }
+                    }
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        TlsServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for TlsServer {
+    /// Returns the number of TLS handshakes completed.
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("TlsServer server task panicked");
+
+        self.state
+            .handshakes_completed
+            .load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -0,0 +1,103 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use rand::Rng;
+use std::path::PathBuf;
+use std::sync::{atomic, Arc};
+use tokio::net::{TcpStream, UnixListener, UnixStream};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub connections_accepted: atomic::AtomicUsize,
+}
+
+/// Makes another server listen on a Unix domain socket (in the temporary directory), by passing on
+/// whatever each client sends to the other server, and whatever that sends back.
+pub struct UnixSocketServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    /// The socket's path, as an upstream address: unix:/path
+    #[allow(dead_code)]
+    pub address: String,
+    path: PathBuf,
+    state: Arc<ServerState>,
+}
+
+async fn handle_connection(stream: UnixStream, backend: String) {
+    let backend = match TcpStream::connect(&backend).await {
+        Ok(backend) => backend,
+        Err(_) => return,
+    };
+    let (mut client_read, mut client_write) = tokio::io::split(stream);
+    let (mut backend_read, mut backend_write) = tokio::io::split(backend);
+    let _ = tokio::try_join!(
+        tokio::io::copy(&mut client_read, &mut backend_write),
+        tokio::io::copy(&mut backend_read, &mut client_write),
+    );
+}
+
+impl UnixSocketServer {
+    /// Starts serving the server at `backend` on a new Unix socket.
+    #[allow(dead_code)]
+    pub async fn new(backend: &str) -> UnixSocketServer {
+        let path = std::env::temp_dir().join(format!(
+            "balancebeam-test-{}.sock",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let mut listener = UnixListener::bind(&path).expect("UnixSocketServer could not bind");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            connections_accepted: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let backend = backend.to_string();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    connection = listener.accept() => {
+                        if let Ok((stream, _)) = connection {
+                            server_task_state
+                                .connections_accepted
+                                .fetch_add(1, atomic::Ordering::SeqCst);
+                            tokio::spawn(handle_connection(stream, backend.clone()));
+                        }
+                    }
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        UnixSocketServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            address: format!("unix:{}", path.display()),
+            path,
+            state: server_state,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for UnixSocketServer {
+    /// Returns the number of connections accepted.
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("UnixSocketServer server task panicked");
+        let _ = std::fs::remove_file(&self.path);
+
+        self.state
+            .connections_accepted
+            .load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -0,0 +1,177 @@
+use crate::common::random_address;
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::sync::{atomic, Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+/// The sample Sec-WebSocket-Key from RFC 6455, and the Sec-WebSocket-Accept it gets. Working out
+/// the answer to any other key takes SHA-1, so WebSocketServer only understands this one.
+#[allow(dead_code)]
+pub const WEBSOCKET_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+#[allow(dead_code)]
+pub const WEBSOCKET_ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+/// The opcodes of the WebSocket frames the tests use.
+#[allow(dead_code)]
+pub const TEXT_FRAME: u8 = 0x1;
+#[allow(dead_code)]
+pub const CLOSE_FRAME: u8 = 0x8;
+
+#[derive(Debug)]
+struct ServerState {
+    pub messages_echoed: atomic::AtomicUsize,
+}
+
+/// A minimal WebSocket server, which sends back every message it is sent, and answers other
+/// requests (such as health checks) with an empty 200. It only understands unfragmented frames of
+/// less than 126 bytes, and requests with WEBSOCKET_KEY.
+pub struct WebSocketServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    #[allow(dead_code)]
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+/// Makes a frame the way a client has to, with the payload masked.
+#[allow(dead_code)]
+pub fn masked_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4]),
+    );
+    frame
+}
+
+/// Reads a frame, returning its opcode and (unmasked) payload, or None once the other side hangs
+/// up.
+#[allow(dead_code)]
+pub async fn read_frame(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+    let mut header = [0_u8; 2];
+    stream.read_exact(&mut header).await.ok()?;
+    let mut mask = [0_u8; 4];
+    if header[1] & 0x80 != 0 {
+        stream.read_exact(&mut mask).await.ok()?;
+    }
+    let mut payload = vec![0_u8; (header[1] & 0x7f) as usize];
+    stream.read_exact(&mut payload).await.ok()?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Some((header[0] & 0x0f, payload))
+}
+
+/// Reads a request's headers, or returns None once the client hangs up.
+async fn read_request_head(stream: &mut TcpStream) -> Option<String> {
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        let mut byte = [0_u8; 1];
+        if stream.read(&mut byte).await.ok()? == 0 {
+            return None;
+        }
+        request.push(byte[0]);
+    }
+    Some(String::from_utf8_lossy(&request).to_lowercase())
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<ServerState>) {
+    while let Some(head) = read_request_head(&mut stream).await {
+        if !head.contains("\r\nupgrade: websocket\r\n") {
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        if !head.contains(&format!(
+            "\r\nsec-websocket-key: {}\r\n",
+            WEBSOCKET_KEY.to_lowercase()
+        )) {
+            let response = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
+            let _ = stream.write_all(response.as_bytes()).await;
+            return;
+        }
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            WEBSOCKET_ACCEPT
+        );
+        if stream.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+        // Only WebSocket frames from here on. The server's frames aren't masked.
+        while let Some((opcode, payload)) = read_frame(&mut stream).await {
+            let mut frame = vec![0x80 | opcode, payload.len() as u8];
+            frame.extend_from_slice(&payload);
+            if stream.write_all(&frame).await.is_err() || opcode == CLOSE_FRAME {
+                return;
+            }
+            state.messages_echoed.fetch_add(1, atomic::Ordering::SeqCst);
+        }
+        return;
+    }
+}
+
+impl WebSocketServer {
+    #[allow(dead_code)]
+    pub async fn new() -> WebSocketServer {
+        let bind_addr_string = random_address();
+        let mut listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("WebSocketServer could not bind");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            messages_echoed: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    connection = listener.accept() => {
+                        if let Ok((stream, _)) = connection {
+                            let state = server_task_state.clone();
+                            tokio::spawn(handle_connection(stream, state));
+                        }
+                    }
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        WebSocketServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for WebSocketServer {
+    /// Returns the number of messages echoed (not counting close frames).
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("WebSocketServer server task panicked");
+
+        self.state.messages_echoed.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
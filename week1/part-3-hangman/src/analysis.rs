@@ -0,0 +1,302 @@
+// Working out which dictionary words are still possible, and which letters are worth guessing.
+use fold_case;
+use std::collections::{BTreeSet, HashMap};
+use GameState;
+
+/// How many letters the trainer suggests before each guess.
+const TRAINER_SUGGESTIONS: usize = 3;
+
+/// Whether `word` could be the secret word, given the revealed `pattern` (None for a blank) and
+/// the letters already known not to be in the word. Because a correct guess reveals every
+/// occurrence of a letter, a blank can't hide a letter that is revealed elsewhere either.
+pub fn consistent(word: &str, pattern: &[Option<char>], wrong_letters: &BTreeSet<char>) -> bool {
+    let word: Vec<char> = word.chars().map(fold_case).collect();
+    if word.len() != pattern.len() {
+        return false;
+    }
+    let revealed: BTreeSet<char> = pattern.iter().filter_map(|&c| c).collect();
+    word.iter()
+        .zip(pattern.iter())
+        .all(|(&c, &shown)| match shown {
+            Some(shown) => c == shown,
+            None => !revealed.contains(&c) && !wrong_letters.contains(&c),
+        })
+}
+
+/// Returns the dictionary words that could still be the secret word in `game`.
+pub fn candidates<'a>(dictionary: &'a [String], game: &GameState) -> Vec<&'a str> {
+    let pattern = game.pattern();
+    let wrong_letters = game.wrong_letters();
+    dictionary
+        .iter()
+        .filter(|word| consistent(word, &pattern, &wrong_letters))
+        .map(|word| word.as_str())
+        .collect()
+}
+
+/// Counts how many of the candidates contain each letter that hasn't been guessed yet.
+pub fn letter_counts(candidates: &[&str], guessed: &BTreeSet<char>) -> HashMap<char, usize> {
+    let mut counts = HashMap::new();
+    for word in candidates {
+        let letters: BTreeSet<char> = word
+            .chars()
+            .map(fold_case)
+            .filter(|c| c.is_alphabetic() && !guessed.contains(c))
+            .collect();
+        for letter in letters {
+            *counts.entry(letter).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+/// The expected information (in bits) that guessing `letter` reveals about which candidate is the
+/// secret word, assuming every candidate is equally likely. Guessing a letter splits the
+/// candidates into groups by where the letter appears (or that it doesn't); the gain is the
+/// entropy of that split.
+pub fn information_gain(candidates: &[&str], letter: char) -> f64 {
+    let mut groups: HashMap<Vec<bool>, usize> = HashMap::new();
+    for word in candidates {
+        let positions: Vec<bool> = word.chars().map(|c| fold_case(c) == letter).collect();
+        *groups.entry(positions).or_insert(0) += 1;
+    }
+    let total = candidates.len() as f64;
+    groups
+        .values()
+        .map(|&count| {
+            let p = count as f64 / total;
+            p * (1.0 / p).log2()
+        })
+        .sum()
+}
+
+/// Returns up to `n` unguessed letters with the highest information gain over `candidates`, best
+/// first. Letters that no candidate contains are never suggested.
+pub fn best_guesses(candidates: &[&str], guessed: &BTreeSet<char>, n: usize) -> Vec<(char, f64)> {
+    let mut scored: Vec<(char, f64)> = letter_counts(candidates, guessed)
+        .keys()
+        .map(|&letter| (letter, information_gain(candidates, letter)))
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    scored.truncate(n);
+    scored
+}
+
+/// Formats the trainer's suggestions for the next guess.
+pub fn trainer_hint(candidates: &[&str], guessed: &BTreeSet<char>) -> String {
+    if candidates.is_empty() {
+        return String::from("Trainer: no dictionary words fit the board");
+    }
+    let suggestions: Vec<String> = best_guesses(candidates, guessed, TRAINER_SUGGESTIONS)
+        .iter()
+        .map(|(letter, gain)| format!("{} ({:.2} bits)", letter, gain))
+        .collect();
+    format!(
+        "Trainer: {} candidate word{}, best guesses: {}",
+        candidates.len(),
+        if candidates.len() == 1 { "" } else { "s" },
+        if suggestions.is_empty() {
+            String::from("none")
+        } else {
+            suggestions.join(", ")
+        }
+    )
+}
+
+/// How one letter guess looked at the time it was made.
+#[derive(Debug, PartialEq)]
+pub struct GuessAnalysis {
+    pub letter: char,
+    /// Dictionary words that could have been the secret word
+    pub candidates: usize,
+    /// How many of those contained the guessed letter
+    pub containing: usize,
+    /// The unguessed letter contained in the most candidates, and in how many
+    pub most_common: Option<(char, usize)>,
+}
+
+impl GuessAnalysis {
+    /// A guess is good if the letter was (nearly) as likely to be in the word as any other.
+    pub fn is_good(&self) -> bool {
+        match self.most_common {
+            Some((_, most)) => self.containing * 4 >= most * 3,
+            None => true,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let mut description = format!(
+            "'{}': in {} of {} candidate words",
+            self.letter, self.containing, self.candidates
+        );
+        if let Some((letter, count)) = self.most_common {
+            if count > self.containing {
+                description += &format!(" (best was '{}', in {})", letter, count);
+            }
+        }
+        format!(
+            "{} - {}",
+            description,
+            if self.is_good() { "good" } else { "poor" }
+        )
+    }
+}
+
+/// Analyzes guessing `letter` when `candidates` were the possible words.
+pub fn analyze_guess(candidates: &[&str], guessed: &BTreeSet<char>, letter: char) -> GuessAnalysis {
+    let letter = fold_case(letter);
+    let counts = letter_counts(candidates, guessed);
+    let most_common = counts
+        .iter()
+        .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+        .map(|(&letter, &count)| (letter, count));
+    GuessAnalysis {
+        letter,
+        candidates: candidates.len(),
+        containing: counts.get(&letter).cloned().unwrap_or(0),
+        most_common,
+    }
+}
+
+/// Formats the post-game report over every letter guess, in the order they were made.
+pub fn report(turns: &[GuessAnalysis]) -> String {
+    let mut lines = vec![String::from("Guess analysis:")];
+    for (idx, turn) in turns.iter().enumerate() {
+        lines.push(format!("  {}. {}", idx + 1, turn.describe()));
+    }
+    let good = turns.iter().filter(|turn| turn.is_good()).count();
+    lines.push(format!("  {} of {} guesses were good", good, turns.len()));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn letters(letters: &str) -> BTreeSet<char> {
+        letters.chars().collect()
+    }
+
+    fn pattern(pattern: &str) -> Vec<Option<char>> {
+        pattern
+            .chars()
+            .map(|c| if c == '_' { None } else { Some(c) })
+            .collect()
+    }
+
+    #[test]
+    fn test_consistent() {
+        let wrong = letters("z");
+        assert!(consistent("lobster", &pattern("_o_____"), &wrong));
+        assert!(consistent("LOBSTER", &pattern("_o_____"), &wrong));
+        // Wrong length, wrong revealed letter, contains a wrong letter
+        assert!(!consistent("lobsters", &pattern("_o_____"), &wrong));
+        assert!(!consistent("lobster", &pattern("_a_____"), &wrong));
+        assert!(!consistent("zobster", &pattern("_o_____"), &wrong));
+        // A blank can't hide a letter that is revealed elsewhere
+        assert!(!consistent("boot", &pattern("_o__"), &wrong));
+        assert!(consistent("boot", &pattern("_oo_"), &wrong));
+        // Separators must line up
+        assert!(consistent("ice cream", &pattern("___ _____"), &wrong));
+        assert!(!consistent("icecreams", &pattern("___ _____"), &wrong));
+    }
+
+    #[test]
+    fn test_candidates_follow_the_game() {
+        let dictionary: Vec<String> = ["lobster", "monster", "hamster", "crawfish", "lobbies"]
+            .iter()
+            .map(|word| word.to_string())
+            .collect();
+        let mut game = GameState::new("lobster", 5);
+        assert_eq!(
+            candidates(&dictionary, &game),
+            vec!["lobster", "monster", "hamster", "lobbies"]
+        );
+        game.guess_letter('s');
+        assert_eq!(
+            candidates(&dictionary, &game),
+            vec!["lobster", "monster", "hamster"]
+        );
+        game.guess_letter('a');
+        assert_eq!(candidates(&dictionary, &game), vec!["lobster", "monster"]);
+        game.guess_letter('o');
+        assert_eq!(candidates(&dictionary, &game), vec!["lobster", "monster"]);
+        game.guess_letter('l');
+        assert_eq!(candidates(&dictionary, &game), vec!["lobster"]);
+    }
+
+    #[test]
+    fn test_letter_counts() {
+        let counts = letter_counts(&["abc", "abd", "aee"], &letters("a"));
+        assert_eq!(counts.get(&'a'), None);
+        assert_eq!(counts[&'b'], 2);
+        // Repeated letters count once per word
+        assert_eq!(counts[&'e'], 1);
+    }
+
+    #[test]
+    fn test_information_gain() {
+        let candidates = ["abc", "abd", "xbe", "xbf"];
+        // Every candidate has 'b' in the same place: nothing learned
+        assert_eq!(information_gain(&candidates, 'b'), 0.0);
+        // 'a' splits the candidates in half: one bit
+        assert!((information_gain(&candidates, 'a') - 1.0).abs() < 1e-9);
+        // 'c' singles out one of four candidates
+        let gain = information_gain(&candidates, 'c');
+        assert!((gain - 0.8112781244591328).abs() < 1e-9, "{}", gain);
+    }
+
+    #[test]
+    fn test_best_guesses() {
+        let candidates = ["abc", "abd", "xbe", "xbf"];
+        let best = best_guesses(&candidates, &letters(""), 3);
+        let best_letters: Vec<char> = best.iter().map(|(letter, _)| *letter).collect();
+        assert_eq!(best_letters, vec!['a', 'x', 'c']);
+        // Guessed letters aren't suggested again
+        let best = best_guesses(&candidates, &letters("ax"), 1);
+        assert_eq!(best[0].0, 'c');
+        assert!(best_guesses(&[], &letters(""), 3).is_empty());
+    }
+
+    #[test]
+    fn test_analyze_guess() {
+        let candidates = ["lobster", "monster", "hamster"];
+        let good = analyze_guess(&candidates, &letters(""), 's');
+        assert_eq!(
+            good,
+            GuessAnalysis {
+                letter: 's',
+                candidates: 3,
+                containing: 3,
+                most_common: Some(('e', 3)),
+            }
+        );
+        assert!(good.is_good());
+        let poor = analyze_guess(&candidates, &letters(""), 'l');
+        assert_eq!(poor.containing, 1);
+        assert!(!poor.is_good());
+        assert_eq!(
+            poor.describe(),
+            "'l': in 1 of 3 candidate words (best was 'e', in 3) - poor"
+        );
+        assert_eq!(
+            report(&[good, poor]),
+            "Guess analysis:\n\
+             \x20 1. 's': in 3 of 3 candidate words - good\n\
+             \x20 2. 'l': in 1 of 3 candidate words (best was 'e', in 3) - poor\n\
+             \x20 1 of 2 guesses were good"
+        );
+    }
+
+    #[test]
+    fn test_trainer_hint() {
+        assert_eq!(
+            trainer_hint(&["abc", "abd", "xbe", "xbf"], &letters("")),
+            "Trainer: 4 candidate words, best guesses: a (1.00 bits), x (1.00 bits), c (0.81 bits)"
+        );
+        assert_eq!(
+            trainer_hint(&[], &letters("")),
+            "Trainer: no dictionary words fit the board"
+        );
+    }
+}
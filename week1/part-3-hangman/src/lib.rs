@@ -0,0 +1,539 @@
+// The rules of the game, kept apart from the terminal I/O in main.rs so they can be tested.
+extern crate rand;
+
+pub mod analysis;
+pub mod secret;
+pub mod stats;
+pub mod words;
+
+use std::collections::BTreeSet;
+
+/// How many incorrect guesses a wrong guess of the whole word costs.
+pub const WRONG_WORD_PENALTY: u32 = 1;
+
+/// Characters that are shown from the start of the game and never need to be guessed.
+const AUTO_REVEALED: [char; 3] = [' ', '-', '\''];
+
+/// Whether `c` is a combining mark (e.g. the accent in a decomposed "é"), which is displayed as
+/// part of the letter before it rather than as a letter of its own.
+fn is_combining_mark(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x0300..=0x036F | 0x1AB0..=0x1AFF | 0x1DC0..=0x1DFF | 0x20D0..=0x20FF | 0xFE20..=0xFE2F
+    )
+}
+
+/// Returns the lowercase form of a letter, so that guessing `É` matches `é`. Letters whose
+/// lowercase form is more than one character are left alone.
+pub fn fold_case(letter: char) -> char {
+    let mut lower = letter.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(lower), None) => lower,
+        _ => letter,
+    }
+}
+
+/// The result of a single guess.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GuessOutcome {
+    /// The letter is in the word (or the word was guessed outright)
+    Correct,
+    /// The guess was wrong and cost the player some of their remaining guesses
+    Incorrect,
+    /// The letter had already been guessed; nothing changed
+    AlreadyGuessed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GameStatus {
+    InProgress,
+    Won,
+    Lost,
+}
+
+/// The state of a game in progress: the secret word, which of its letters have been revealed,
+/// which letters have been guessed, and how many more incorrect guesses the player can make.
+#[derive(Debug)]
+pub struct GameState {
+    secret: Vec<char>,
+    revealed: Vec<bool>,
+    guessed: BTreeSet<char>,
+    wrong_remaining: u32,
+    /// The number of incorrect guesses allowed at the start of the game
+    guesses: u32,
+}
+
+impl GameState {
+    /// Starts a game with the secret word (or phrase). Spaces, hyphens and apostrophes are
+    /// revealed straight away and don't count towards the letters to find.
+    pub fn new(secret: &str, guesses: u32) -> GameState {
+        let secret: Vec<char> = secret.chars().collect();
+        GameState {
+            revealed: secret
+                .iter()
+                .map(|&c| AUTO_REVEALED.contains(&c) || is_combining_mark(c))
+                .collect(),
+            secret,
+            guessed: BTreeSet::new(),
+            wrong_remaining: guesses,
+            guesses,
+        }
+    }
+
+    /// Reveals every position of `letter` in the word, ignoring case. Guessing a letter that isn't
+    /// in the word costs one guess; guessing a letter again has no effect.
+    pub fn guess_letter(&mut self, letter: char) -> GuessOutcome {
+        let letter = fold_case(letter);
+        if !self.guessed.insert(letter) {
+            return GuessOutcome::AlreadyGuessed;
+        }
+        let mut found = false;
+        for (secret_letter, revealed) in self.secret.iter().zip(self.revealed.iter_mut()) {
+            if fold_case(*secret_letter) == letter {
+                *revealed = true;
+                found = true;
+            }
+        }
+        if found {
+            GuessOutcome::Correct
+        } else {
+            self.wrong_remaining = self.wrong_remaining.saturating_sub(1);
+            GuessOutcome::Incorrect
+        }
+    }
+
+    /// Guesses the whole word, ignoring case. A correct guess reveals the word and wins the game;
+    /// a wrong one costs WRONG_WORD_PENALTY guesses and leaves the board unchanged.
+    pub fn guess_word(&mut self, word: &str) -> GuessOutcome {
+        if word.trim().to_lowercase() == self.secret().to_lowercase() {
+            self.revealed = vec![true; self.secret.len()];
+            GuessOutcome::Correct
+        } else {
+            self.wrong_remaining = self.wrong_remaining.saturating_sub(WRONG_WORD_PENALTY);
+            GuessOutcome::Incorrect
+        }
+    }
+
+    /// Revealing the whole word wins, even if it happens on the player's last guess.
+    pub fn status(&self) -> GameStatus {
+        if self.revealed.iter().all(|&revealed| revealed) {
+            GameStatus::Won
+        } else if self.wrong_remaining == 0 {
+            GameStatus::Lost
+        } else {
+            GameStatus::InProgress
+        }
+    }
+
+    /// Returns the word with unrevealed letters as blanks, e.g. `_ o _ s _ _ _`. Combining marks
+    /// stay attached to their letter, and only appear once that letter has been revealed.
+    pub fn display_word(&self) -> String {
+        let mut slots: Vec<String> = Vec::new();
+        let mut previous_revealed = false;
+        for (&c, &revealed) in self.secret.iter().zip(self.revealed.iter()) {
+            if is_combining_mark(c) && !slots.is_empty() {
+                if previous_revealed {
+                    slots.last_mut().unwrap().push(c);
+                }
+                continue;
+            }
+            slots.push(if revealed { c } else { '_' }.to_string());
+            previous_revealed = revealed;
+        }
+        slots.join(" ")
+    }
+
+    /// Returns the board as the trainer sees it: the lowercase letter at each revealed position,
+    /// and None for each blank.
+    pub fn pattern(&self) -> Vec<Option<char>> {
+        self.secret
+            .iter()
+            .zip(self.revealed.iter())
+            .map(|(&c, &revealed)| if revealed { Some(fold_case(c)) } else { None })
+            .collect()
+    }
+
+    /// The guessed letters that aren't in the word.
+    pub fn wrong_letters(&self) -> BTreeSet<char> {
+        self.guessed
+            .iter()
+            .filter(|&&letter| !self.contains_letter(letter))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether the secret word contains `letter`, ignoring case.
+    pub fn contains_letter(&self, letter: char) -> bool {
+        let letter = fold_case(letter);
+        self.secret.iter().any(|&c| fold_case(c) == letter)
+    }
+
+    pub fn secret(&self) -> String {
+        self.secret.iter().collect()
+    }
+
+    /// The letters guessed so far, in alphabetical order.
+    pub fn guessed(&self) -> &BTreeSet<char> {
+        &self.guessed
+    }
+
+    pub fn wrong_remaining(&self) -> u32 {
+        self.wrong_remaining
+    }
+
+    pub fn wrong_guesses(&self) -> u32 {
+        self.guesses - self.wrong_remaining
+    }
+}
+
+/// The gallows, from an empty scaffold to a complete figure. The number of incorrect guesses is
+/// scaled onto these stages, so the figure is always complete when the player runs out of guesses.
+const GALLOWS: [&str; 7] = [
+    "  +---+\n  |   |\n      |\n      |\n      |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n      |\n      |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n  |   |\n      |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n /|   |\n      |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n /|\\  |\n      |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n /|\\  |\n /    |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n /|\\  |\n / \\  |\n      |\n=========",
+];
+
+/// Returns which gallows stage to draw after `wrong_guesses` out of `max_wrong_guesses`, rounding
+/// up so that every incorrect guess adds to the drawing when the budget is small.
+fn gallows_stage(wrong_guesses: u32, max_wrong_guesses: u32) -> usize {
+    let last_stage = GALLOWS.len() as u32 - 1;
+    if max_wrong_guesses == 0 {
+        return last_stage as usize;
+    }
+    let wrong_guesses = wrong_guesses.min(max_wrong_guesses);
+    (wrong_guesses * last_stage).div_ceil(max_wrong_guesses) as usize
+}
+
+/// Joins letters with spaces, or returns "none" if there are no letters.
+fn letter_list<I: Iterator<Item = char>>(letters: I) -> String {
+    let letters: Vec<String> = letters.map(|letter| letter.to_string()).collect();
+    if letters.is_empty() {
+        String::from("none")
+    } else {
+        letters.join(" ")
+    }
+}
+
+/// Draws the board: the gallows, the word with unrevealed letters as blanks, the correct and
+/// incorrect letters guessed so far, and the number of guesses left.
+pub fn render(state: &GameState) -> String {
+    let (correct, incorrect): (Vec<char>, Vec<char>) = state
+        .guessed
+        .iter()
+        .partition(|&&letter| state.contains_letter(letter));
+    format!(
+        "{}\n\nWord: {}\nCorrect guesses: {}\nIncorrect guesses: {}\nYou have {} guesses left",
+        GALLOWS[gallows_stage(state.wrong_guesses(), state.guesses)],
+        state.display_word(),
+        letter_list(correct.into_iter()),
+        letter_list(incorrect.into_iter()),
+        state.wrong_remaining()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GUESSES: u32 = 5;
+
+    #[test]
+    fn test_new_game() {
+        let game = GameState::new("lobster", GUESSES);
+        assert_eq!(game.status(), GameStatus::InProgress);
+        assert_eq!(game.display_word(), "_ _ _ _ _ _ _");
+        assert_eq!(game.wrong_remaining(), GUESSES);
+        assert_eq!(game.wrong_guesses(), 0);
+        assert!(game.guessed().is_empty());
+    }
+
+    #[test]
+    fn test_correct_letter() {
+        let mut game = GameState::new("hello", GUESSES);
+        assert_eq!(game.guess_letter('h'), GuessOutcome::Correct);
+        assert_eq!(game.display_word(), "h _ _ _ _");
+        assert_eq!(game.wrong_remaining(), GUESSES);
+    }
+
+    #[test]
+    fn test_duplicate_letters_all_revealed() {
+        let mut game = GameState::new("bookkeeper", GUESSES);
+        assert_eq!(game.guess_letter('e'), GuessOutcome::Correct);
+        assert_eq!(game.display_word(), "_ _ _ _ _ e e _ e _");
+        assert_eq!(game.guess_letter('o'), GuessOutcome::Correct);
+        assert_eq!(game.guess_letter('k'), GuessOutcome::Correct);
+        assert_eq!(game.display_word(), "_ o o k k e e _ e _");
+    }
+
+    #[test]
+    fn test_repeat_guesses_are_free() {
+        let mut game = GameState::new("hello", GUESSES);
+        assert_eq!(game.guess_letter('l'), GuessOutcome::Correct);
+        assert_eq!(game.guess_letter('l'), GuessOutcome::AlreadyGuessed);
+        assert_eq!(game.guess_letter('z'), GuessOutcome::Incorrect);
+        assert_eq!(game.guess_letter('z'), GuessOutcome::AlreadyGuessed);
+        assert_eq!(game.wrong_remaining(), GUESSES - 1);
+        assert_eq!(game.display_word(), "_ _ l l _");
+        assert_eq!(game.guessed().iter().collect::<String>(), "lz");
+    }
+
+    #[test]
+    fn test_only_wrong_guesses_count() {
+        let mut game = GameState::new("lobster", GUESSES);
+        assert_eq!(game.guess_letter('z'), GuessOutcome::Incorrect);
+        assert_eq!(game.wrong_remaining(), GUESSES - 1);
+        assert_eq!(game.guess_letter('l'), GuessOutcome::Correct);
+        assert_eq!(game.wrong_remaining(), GUESSES - 1);
+        assert_eq!(game.wrong_guesses(), 1);
+    }
+
+    #[test]
+    fn test_all_correct_guesses_win() {
+        // More correct guesses than the guess budget must not run the player out of guesses
+        let mut game = GameState::new("crawfish", GUESSES);
+        for letter in "crawfis".chars() {
+            assert_eq!(game.guess_letter(letter), GuessOutcome::Correct);
+            assert_eq!(game.status(), GameStatus::InProgress);
+        }
+        assert_eq!(game.guess_letter('h'), GuessOutcome::Correct);
+        assert_eq!(game.status(), GameStatus::Won);
+    }
+
+    #[test]
+    fn test_win_on_last_guess() {
+        let mut game = GameState::new("ox", 2);
+        assert_eq!(game.guess_letter('a'), GuessOutcome::Incorrect);
+        assert_eq!(game.guess_letter('o'), GuessOutcome::Correct);
+        assert_eq!(game.wrong_remaining(), 1);
+        assert_eq!(game.guess_letter('x'), GuessOutcome::Correct);
+        assert_eq!(game.status(), GameStatus::Won);
+    }
+
+    #[test]
+    fn test_loss_with_letters_remaining() {
+        let mut game = GameState::new("lobster", GUESSES);
+        assert_eq!(game.guess_letter('o'), GuessOutcome::Correct);
+        for (i, letter) in "zqxjk".chars().enumerate() {
+            assert_eq!(game.status(), GameStatus::InProgress);
+            assert_eq!(game.guess_letter(letter), GuessOutcome::Incorrect);
+            assert_eq!(game.wrong_remaining(), GUESSES - 1 - i as u32);
+        }
+        assert_eq!(game.status(), GameStatus::Lost);
+        assert_eq!(game.display_word(), "_ o _ _ _ _ _");
+        assert_eq!(game.wrong_remaining(), 0);
+    }
+
+    #[test]
+    fn test_custom_guess_budget() {
+        let mut game = GameState::new("lobster", 2);
+        assert_eq!(game.guess_letter('z'), GuessOutcome::Incorrect);
+        assert_eq!(game.status(), GameStatus::InProgress);
+        assert_eq!(game.guess_letter('q'), GuessOutcome::Incorrect);
+        assert_eq!(game.status(), GameStatus::Lost);
+    }
+
+    #[test]
+    fn test_correct_word_guess_wins() {
+        let mut game = GameState::new("starfish", GUESSES);
+        assert_eq!(game.guess_letter('s'), GuessOutcome::Correct);
+        assert_eq!(game.guess_word("STARFISH"), GuessOutcome::Correct);
+        assert_eq!(game.status(), GameStatus::Won);
+        assert_eq!(game.display_word(), "s t a r f i s h");
+        assert_eq!(game.wrong_remaining(), GUESSES);
+    }
+
+    #[test]
+    fn test_wrong_word_guess_costs_a_guess() {
+        let mut game = GameState::new("starfish", GUESSES);
+        assert_eq!(game.guess_letter('s'), GuessOutcome::Correct);
+        assert_eq!(game.guess_word("crawfish"), GuessOutcome::Incorrect);
+        assert_eq!(game.status(), GameStatus::InProgress);
+        assert_eq!(game.display_word(), "s _ _ _ _ _ s _");
+        assert_eq!(game.wrong_remaining(), GUESSES - WRONG_WORD_PENALTY);
+    }
+
+    #[test]
+    fn test_wrong_word_guess_can_lose() {
+        let mut game = GameState::new("starfish", 1);
+        assert_eq!(game.guess_word("crawfish"), GuessOutcome::Incorrect);
+        assert_eq!(game.status(), GameStatus::Lost);
+    }
+
+    #[test]
+    fn test_gallows_stage_scaling() {
+        // A budget of six matches the six parts GALLOWS draws, so every wrong guess adds one
+        assert_eq!(gallows_stage(0, 6), 0);
+        assert_eq!(gallows_stage(3, 6), 3);
+        assert_eq!(gallows_stage(6, 6), 6);
+        // Small budgets skip stages but still finish the figure on the last guess
+        assert_eq!(gallows_stage(1, 4), 2);
+        assert_eq!(gallows_stage(4, 4), 6);
+        // Large budgets add a part every few guesses
+        assert_eq!(gallows_stage(1, 12), 1);
+        assert_eq!(gallows_stage(2, 12), 1);
+        assert_eq!(gallows_stage(12, 12), 6);
+        assert_eq!(gallows_stage(20, 12), 6);
+    }
+
+    #[test]
+    fn test_render_empty_board() {
+        let game = GameState::new("lobster", GUESSES);
+        assert_eq!(
+            render(&game),
+            "  +---+\n\
+             \x20 |   |\n\
+             \x20     |\n\
+             \x20     |\n\
+             \x20     |\n\
+             \x20     |\n\
+             =========\n\
+             \n\
+             Word: _ _ _ _ _ _ _\n\
+             Correct guesses: none\n\
+             Incorrect guesses: none\n\
+             You have 5 guesses left"
+        );
+    }
+
+    #[test]
+    fn test_render_mid_game() {
+        let mut game = GameState::new("lobster", 6);
+        game.guess_letter('o');
+        game.guess_letter('z');
+        game.guess_letter('s');
+        game.guess_letter('a');
+        assert_eq!(
+            render(&game),
+            "  +---+\n\
+             \x20 |   |\n\
+             \x20 O   |\n\
+             \x20 |   |\n\
+             \x20     |\n\
+             \x20     |\n\
+             =========\n\
+             \n\
+             Word: _ o _ s _ _ _\n\
+             Correct guesses: o s\n\
+             Incorrect guesses: a z\n\
+             You have 4 guesses left"
+        );
+    }
+
+    #[test]
+    fn test_render_lost_board() {
+        let mut game = GameState::new("lobster", GUESSES);
+        game.guess_letter('r');
+        for letter in "zqxjk".chars() {
+            game.guess_letter(letter);
+        }
+        assert_eq!(game.status(), GameStatus::Lost);
+        assert_eq!(
+            render(&game),
+            "  +---+\n\
+             \x20 |   |\n\
+             \x20 O   |\n\
+             \x20/|\\  |\n\
+             \x20/ \\  |\n\
+             \x20     |\n\
+             =========\n\
+             \n\
+             Word: _ _ _ _ _ _ r\n\
+             Correct guesses: r\n\
+             Incorrect guesses: j k q x z\n\
+             You have 0 guesses left"
+        );
+    }
+
+    #[test]
+    fn test_pattern_and_wrong_letters() {
+        let mut game = GameState::new("Ice cream", GUESSES);
+        game.guess_letter('c');
+        game.guess_letter('i');
+        game.guess_letter('z');
+        assert_eq!(
+            game.pattern(),
+            vec![
+                Some('i'),
+                Some('c'),
+                None,
+                Some(' '),
+                Some('c'),
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+        assert_eq!(game.wrong_letters().into_iter().collect::<String>(), "z");
+    }
+
+    #[test]
+    fn test_phrase_reveals_separators() {
+        let mut game = GameState::new("ice cream", GUESSES);
+        assert_eq!(game.display_word(), "_ _ _   _ _ _ _ _");
+        assert_eq!(game.guess_letter('c'), GuessOutcome::Correct);
+        assert_eq!(game.guess_letter('e'), GuessOutcome::Correct);
+        assert_eq!(game.display_word(), "_ c e   c _ e _ _");
+        for letter in "iram".chars() {
+            assert_eq!(game.status(), GameStatus::InProgress);
+            assert_eq!(game.guess_letter(letter), GuessOutcome::Correct);
+        }
+        // The space never had to be guessed
+        assert_eq!(game.status(), GameStatus::Won);
+
+        let game = GameState::new("jack-o'-lantern", GUESSES);
+        assert_eq!(game.display_word(), "_ _ _ _ - _ ' - _ _ _ _ _ _ _");
+    }
+
+    #[test]
+    fn test_phrase_word_guess() {
+        let mut game = GameState::new("ice cream", GUESSES);
+        assert_eq!(game.guess_word("icecream"), GuessOutcome::Incorrect);
+        assert_eq!(game.guess_word(" Ice Cream "), GuessOutcome::Correct);
+        assert_eq!(game.status(), GameStatus::Won);
+    }
+
+    #[test]
+    fn test_long_word() {
+        let mut game = GameState::new("uncharacteristically", GUESSES);
+        assert_eq!(game.display_word().matches('_').count(), 20);
+        for letter in "uncharteisly".chars() {
+            assert_eq!(game.guess_letter(letter), GuessOutcome::Correct);
+        }
+        assert_eq!(game.status(), GameStatus::Won);
+        assert_eq!(
+            game.display_word(),
+            "u n c h a r a c t e r i s t i c a l l y"
+        );
+    }
+
+    #[test]
+    fn test_unicode_case_folding() {
+        let mut game = GameState::new("café", GUESSES);
+        assert_eq!(game.guess_letter('É'), GuessOutcome::Correct);
+        assert_eq!(game.display_word(), "_ _ _ é");
+        assert_eq!(game.guess_letter('é'), GuessOutcome::AlreadyGuessed);
+        // An unaccented e is a different letter
+        assert_eq!(game.guess_letter('e'), GuessOutcome::Incorrect);
+        assert_eq!(game.guess_word("CAFÉ"), GuessOutcome::Correct);
+        assert_eq!(game.status(), GameStatus::Won);
+    }
+
+    #[test]
+    fn test_combining_marks_stay_with_their_letter() {
+        // "café" spelled with a plain e followed by a combining acute accent
+        let mut game = GameState::new("cafe\u{301}", GUESSES);
+        assert_eq!(game.display_word(), "_ _ _ _");
+        assert_eq!(game.guess_letter('e'), GuessOutcome::Correct);
+        assert_eq!(game.display_word(), "_ _ _ e\u{301}");
+        for letter in "caf".chars() {
+            game.guess_letter(letter);
+        }
+        assert_eq!(game.status(), GameStatus::Won);
+    }
+}
@@ -1,6 +1,6 @@
 // Simple Hangman Program
-// User gets five incorrect guesses
-// Word chosen randomly from words.txt
+// User gets five incorrect guesses (or --guesses <n>, or a budget set by --difficulty)
+// Word chosen randomly from words.txt (or --words <path>)
 // Inspiration from: https://doc.rust-lang.org/book/ch02-00-guessing-game-tutorial.html
 // This assignment will introduce you to some fundamental syntax in Rust:
 // - variable declaration
@@ -12,81 +12,516 @@
 // - user input
 // We've tried to limit/hide Rust's quirks since we'll discuss those details
 // more in depth in the coming lectures.
-extern crate rand;
-use rand::Rng;
-use std::fs;
+extern crate hangman;
+
+use hangman::words::{self, Difficulty, WordList};
+use hangman::{analysis, fold_case, render, secret, stats, GameState, GameStatus, GuessOutcome};
+use std::collections::BTreeSet;
+use std::env;
 use std::io;
-use std::io::Write;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::process;
 
 const NUM_INCORRECT_GUESSES: u32 = 5;
 const WORDS_PATH: &str = "words.txt";
+/// Characters other than letters that may appear in a guess of a whole phrase.
+const PHRASE_SEPARATORS: [char; 3] = [' ', '-', '\''];
+
+const USAGE: &str = "Usage: hangman [--words <path>] [--guesses <n>] [--word <secret>] \
+                     [--seed <n>] [--difficulty easy|medium|hard] [--cheat] \
+                     [--stats-file <path>] [--two-player] [--category <name>] \
+                     [--list-categories] [--trainer]";
 
-fn pick_a_random_word() -> String {
-    let file_string = fs::read_to_string(WORDS_PATH).expect("Unable to read file.");
-    let words: Vec<&str> = file_string.split('\n').collect();
-    String::from(words[rand::thread_rng().gen_range(0, words.len())].trim())
+/// Settings that can be changed from the command line.
+#[derive(Debug, PartialEq)]
+struct Options {
+    /// File to pick the secret word from
+    words_path: String,
+    /// Number of incorrect guesses allowed, if set explicitly
+    guesses: Option<u32>,
+    /// Use this secret word instead of picking one from the word list
+    word: Option<String>,
+    /// Seed for the random word pick, to make it reproducible
+    seed: Option<u64>,
+    /// Restricts the word list and sets the guess budget
+    difficulty: Option<Difficulty>,
+    /// Print the secret word at the start of the game
+    cheat: bool,
+    /// Where to keep lifetime statistics, instead of ~/.hangman_stats.json
+    stats_file: Option<String>,
+    /// Player 1 types the secret word instead of picking one from the word list
+    two_player: bool,
+    /// Only pick words from this category of the word list
+    category: Option<String>,
+    /// Print the word list's categories and exit
+    list_categories: bool,
+    /// Suggest the most informative letters before each guess
+    trainer: bool,
 }
 
-fn main() {
-    let secret_word = pick_a_random_word();
-    // Note: given what you know about Rust so far, it's easier to pull characters out of a
-    // vector than it is to pull them out of a string. You can get the ith character of
-    // secret_word by doing secret_word_chars[i].
-    let secret_word_chars: Vec<char> = secret_word.chars().collect();
-    // Uncomment for debugging:
-    println!("random word: {}", secret_word);
-    // println!("{}", mem::size_of_val(&secret_word_chars));
-
-    let mut guessed_letters: Vec<char> = Vec::new();
-    let mut word_so_far: Vec<char> = String::from("----------------")[0..secret_word_chars.len()]
-        .chars()
-        .collect();
-    // Your code here! :)
-    let mut match_letters_cnt = 0;
-    let mut already_try: u32 = 0;
-    loop {
-        if already_try == NUM_INCORRECT_GUESSES {
-            println!("Sorry, you ran out of guesses!");
-            break;
+impl Options {
+    /// The number of incorrect guesses allowed: --guesses if given, otherwise the difficulty's
+    /// budget, otherwise the default.
+    fn guess_budget(&self) -> u32 {
+        match (self.guesses, self.difficulty) {
+            (Some(guesses), _) => guesses,
+            (None, Some(difficulty)) => difficulty.guesses(),
+            (None, None) => NUM_INCORRECT_GUESSES,
         }
+    }
+}
 
-        println!("The word so far is {:?}", word_so_far);
-        println!(
-            "You have guessed the following letters: {:?}",
-            guessed_letters
-        );
-        print!(
-            "You have {} guesses left\n",
-            secret_word_chars.len() - match_letters_cnt
-        );
-        print!("Please guess a letter: ");
-        // Make sure the prompt from the previous line gets displayed;
-        io::stdout().flush().expect("Error flushing stdout.");
+/// Parses the command-line arguments (not including the program name).
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        words_path: String::from(WORDS_PATH),
+        guesses: None,
+        word: None,
+        seed: None,
+        difficulty: None,
+        cheat: false,
+        stats_file: None,
+        two_player: false,
+        category: None,
+        list_categories: false,
+        trainer: false,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("{} requires a value", arg))
+        };
+        match arg.as_str() {
+            "--words" => options.words_path = value()?.clone(),
+            "--guesses" => {
+                let value = value()?;
+                options.guesses = match value.parse::<u32>() {
+                    Ok(guesses) if guesses > 0 => Some(guesses),
+                    _ => {
+                        return Err(format!(
+                            "--guesses must be a positive number, not {}",
+                            value
+                        ))
+                    }
+                };
+            }
+            "--word" => {
+                let word = value()?.trim().to_lowercase();
+                if word.is_empty() {
+                    return Err(String::from("--word must not be empty"));
+                }
+                options.word = Some(word);
+            }
+            "--seed" => {
+                let value = value()?;
+                options.seed = Some(
+                    value
+                        .parse::<u64>()
+                        .map_err(|_| format!("--seed must be a number, not {}", value))?,
+                );
+            }
+            "--difficulty" => {
+                let value = value()?;
+                options.difficulty = Some(Difficulty::parse(value).ok_or_else(|| {
+                    format!("--difficulty must be easy, medium or hard, not {}", value)
+                })?);
+            }
+            "--cheat" => options.cheat = true,
+            "--stats-file" => options.stats_file = Some(value()?.clone()),
+            "--two-player" => options.two_player = true,
+            "--category" => options.category = Some(value()?.clone()),
+            "--list-categories" => options.list_categories = true,
+            "--trainer" => options.trainer = true,
+            _ => return Err(format!("Unrecognized argument: {}", arg)),
+        }
+    }
+    if options.two_player && (options.word.is_some() || options.cheat) {
+        return Err(String::from(
+            "--two-player can't be combined with --word or --cheat",
+        ));
+    }
+    Ok(options)
+}
+
+/// A player's guess: either a single letter or an attempt at the whole word.
+#[derive(Debug, PartialEq)]
+enum Guess {
+    Letter(char),
+    Word(String),
+}
+
+/// Prompts for a guess on `output` and reads it from `input`, re-prompting (without costing the
+/// player a guess) until they enter either one letter that they haven't guessed already, or a
+/// word made only of letters (and the spaces, hyphens and apostrophes of a phrase). Returns the
+/// guess in lowercase, or an UnexpectedEof error if the input runs out.
+fn read_guess<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    already_guessed: &BTreeSet<char>,
+) -> io::Result<Guess> {
+    loop {
+        write!(output, "Please guess a letter (or the whole word): ")?;
+        // Make sure the prompt gets displayed before we block on input
+        output.flush()?;
         let mut guess = String::new();
-        io::stdin()
-            .read_line(&mut guess)
-            .expect("Error reading line.");
-        let guess_letters: Vec<char> = guess.chars().collect();
-        guessed_letters.push(guess_letters[0]);
-
-        let mut flag: bool = false;
-        for idx in 0..secret_word_chars.len() {
-            if guess_letters[0] == secret_word_chars[idx] && word_so_far[idx] == '-' {
-                match_letters_cnt += 1;
-                word_so_far[idx] = guess_letters[0];
-                flag = true;
-                break;
+        if input.read_line(&mut guess)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no more input",
+            ));
+        }
+        let guess_letters: Vec<char> = guess.trim().chars().collect();
+        let is_valid_char = |c: &char| c.is_alphabetic() || PHRASE_SEPARATORS.contains(c);
+        if !guess_letters.iter().any(|c| c.is_alphabetic())
+            || !guess_letters.iter().all(is_valid_char)
+        {
+            writeln!(output, "Please enter a single letter or a word.")?;
+            continue;
+        }
+        if guess_letters.len() > 1 {
+            let word: String = guess_letters.iter().collect();
+            return Ok(Guess::Word(word.to_lowercase()));
+        }
+        let letter = fold_case(guess_letters[0]);
+        if already_guessed.contains(&letter) {
+            writeln!(
+                output,
+                "You already guessed '{}', try another letter.",
+                letter
+            )?;
+            continue;
+        }
+        return Ok(Guess::Letter(letter));
+    }
+}
+
+/// Loads the word list, printing any warnings. Exits if the list can't be loaded.
+fn load_word_list(path: &str) -> WordList {
+    match words::load_words(path) {
+        Ok(list) => {
+            for warning in list.warnings.iter() {
+                println!("Warning: {}", warning);
+            }
+            list
+        }
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            println!("{}\n{}", err, USAGE);
+            process::exit(1);
+        }
+    };
+    if options.list_categories {
+        let list = load_word_list(&options.words_path);
+        let categories = list.categories();
+        if categories.is_empty() {
+            println!("{} has no categories", options.words_path);
+        }
+        for category in categories {
+            println!("{}", category);
+        }
+        return;
+    }
+    // The word list is also the dictionary for the trainer and the post-game analysis, so load it
+    // even if the secret word comes from somewhere else (but don't insist on it then)
+    let word_list = if options.two_player || options.word.is_some() {
+        words::load_words(&options.words_path).ok()
+    } else {
+        Some(load_word_list(&options.words_path))
+    };
+    let secret_word = if options.two_player {
+        match secret::read_secret_word(&mut secret::TerminalInput, &mut io::stdout()) {
+            Ok(word) => word,
+            Err(err) => {
+                println!("\nError reading secret word: {}", err);
+                process::exit(1);
             }
         }
-        if !flag {
-            println!("Sorry, that letter is not in the word");
+    } else if let Some(word) = options.word.clone() {
+        word
+    } else {
+        let list = word_list.as_ref().expect("word list is loaded");
+        match words::pick_a_random_word(
+            list,
+            options.category.as_deref(),
+            options.difficulty,
+            options.seed,
+        ) {
+            Ok(word) => word,
+            Err(err) => {
+                println!("{}", err);
+                process::exit(1);
+            }
+        }
+    };
+    if options.cheat {
+        println!("random word: {}", secret_word);
+    }
+
+    let stats_path = options
+        .stats_file
+        .as_ref()
+        .map_or_else(stats::default_path, PathBuf::from);
+    let mut lifetime_stats = match stats::load(&stats_path) {
+        Ok(Some(lifetime_stats)) => {
+            println!("{}", lifetime_stats.summary());
+            lifetime_stats
+        }
+        Ok(None) => stats::Stats::default(),
+        Err(err) => {
+            println!("Warning: {}, starting fresh", err);
+            stats::Stats::default()
+        }
+    };
+
+    let dictionary: Vec<String> = word_list
+        .map(|list| list.entries.into_iter().map(|entry| entry.word).collect())
+        .unwrap_or_default();
+    let mut turns = Vec::new();
+
+    let mut game = GameState::new(&secret_word, options.guess_budget());
+    while game.status() == GameStatus::InProgress {
+        println!("{}", render(&game));
+        let candidates = analysis::candidates(&dictionary, &game);
+        if options.trainer {
+            println!("{}", analysis::trainer_hint(&candidates, game.guessed()));
+        }
+        let stdin = io::stdin();
+        let guess = match read_guess(&mut stdin.lock(), &mut io::stdout(), game.guessed()) {
+            Ok(guess) => guess,
+            Err(err) => {
+                println!("\nError reading guess: {}", err);
+                return;
+            }
+        };
+
+        if let Guess::Letter(letter) = guess {
+            turns.push(analysis::analyze_guess(&candidates, game.guessed(), letter));
+        }
+        match guess {
+            Guess::Letter(letter) => match game.guess_letter(letter) {
+                GuessOutcome::Correct => {}
+                GuessOutcome::Incorrect => println!("Sorry, that letter is not in the word"),
+                GuessOutcome::AlreadyGuessed => {
+                    println!("You already guessed '{}', try another letter.", letter)
+                }
+            },
+            Guess::Word(word) => {
+                if game.guess_word(&word) == GuessOutcome::Incorrect {
+                    println!("Sorry, the word is not \"{}\"", word);
+                }
+            }
         }
         println!("\n");
+    }
+
+    println!("{}\n", render(&game));
+    let won = game.status() == GameStatus::Won;
+    if won {
+        println!("Congratulations you guessed the secret word: {secret_word}!");
+    } else {
+        println!("Sorry, you ran out of guesses! The word was: {secret_word}");
+    }
+    if !dictionary.is_empty() && !turns.is_empty() {
+        println!("\n{}", analysis::report(&turns));
+    }
+
+    lifetime_stats.record(won, game.secret().chars().count());
+    if let Err(err) = stats::save(&stats_path, &lifetime_stats) {
+        println!(
+            "Warning: unable to save stats to {}: {}",
+            stats_path.display(),
+            err
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    /// Runs read_guess over scripted input, returning the result and everything it printed.
+    fn scripted_guess(input: &str, already_guessed: &[char]) -> (io::Result<Guess>, String) {
+        let already_guessed: BTreeSet<char> = already_guessed.iter().cloned().collect();
+        let mut output = Vec::new();
+        let result = read_guess(&mut Cursor::new(input), &mut output, &already_guessed);
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args_defaults() {
+        let options = parse_args(&[]).unwrap();
+        assert_eq!(options.words_path, WORDS_PATH);
+        assert_eq!(options.guesses, None);
+        assert_eq!(options.guess_budget(), NUM_INCORRECT_GUESSES);
+        assert_eq!(options.word, None);
+        assert_eq!(options.seed, None);
+        assert_eq!(options.difficulty, None);
+        assert!(!options.cheat);
+    }
 
-        if match_letters_cnt == secret_word_chars.len() {
-            println!("Congratulations you guessed the secret word: {secret_word}!");
-            break;
+    #[test]
+    fn test_parse_args_all_options() {
+        let options = parse_args(&args(&[
+            "--words",
+            "animals.txt",
+            "--guesses",
+            "8",
+            "--word",
+            "Lobster",
+            "--seed",
+            "42",
+            "--difficulty",
+            "hard",
+            "--cheat",
+            "--stats-file",
+            "stats.json",
+            "--category",
+            "animals",
+            "--list-categories",
+            "--trainer",
+        ]))
+        .unwrap();
+        assert_eq!(
+            options,
+            Options {
+                words_path: String::from("animals.txt"),
+                guesses: Some(8),
+                word: Some(String::from("lobster")),
+                seed: Some(42),
+                difficulty: Some(Difficulty::Hard),
+                cheat: true,
+                stats_file: Some(String::from("stats.json")),
+                two_player: false,
+                category: Some(String::from("animals")),
+                list_categories: true,
+                trainer: true,
+            }
+        );
+        // --guesses overrides the difficulty's budget
+        assert_eq!(options.guess_budget(), 8);
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse_args(&args(&["--guesses"])).is_err());
+        assert!(parse_args(&args(&["--guesses", "0"])).is_err());
+        assert!(parse_args(&args(&["--guesses", "many"])).is_err());
+        assert!(parse_args(&args(&["--seed", "-1"])).is_err());
+        assert!(parse_args(&args(&["--word", " "])).is_err());
+        assert!(parse_args(&args(&["--cheat-codes"])).is_err());
+        assert!(parse_args(&args(&["--difficulty", "nightmare"])).is_err());
+        assert!(parse_args(&args(&["--two-player", "--word", "crab"])).is_err());
+        assert!(parse_args(&args(&["--cheat", "--two-player"])).is_err());
+    }
+
+    #[test]
+    fn test_difficulty_guess_budget() {
+        for (name, budget) in [("easy", 8), ("medium", 6), ("hard", 4)].iter() {
+            let options = parse_args(&args(&["--difficulty", name])).unwrap();
+            assert_eq!(options.guess_budget(), *budget);
         }
-        already_try += 1;
+    }
+
+    #[test]
+    fn test_read_guess_simple() {
+        let (result, output) = scripted_guess("e\n", &[]);
+        assert_eq!(result.unwrap(), Guess::Letter('e'));
+        assert_eq!(output, "Please guess a letter (or the whole word): ");
+    }
+
+    #[test]
+    fn test_read_guess_reprompts_on_invalid_input() {
+        let (result, output) = scripted_guess("\n   \n7\n?\nab1\n q \n", &[]);
+        assert_eq!(result.unwrap(), Guess::Letter('q'));
+        assert_eq!(output.matches("Please guess a letter").count(), 6);
+        assert_eq!(
+            output
+                .matches("Please enter a single letter or a word.")
+                .count(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_read_guess_lowercases() {
+        let (result, _) = scripted_guess("A\n", &[]);
+        assert_eq!(result.unwrap(), Guess::Letter('a'));
+        let (result, _) = scripted_guess("LobSter\n", &[]);
+        assert_eq!(result.unwrap(), Guess::Word(String::from("lobster")));
+        let (result, _) = scripted_guess("É\n", &[]);
+        assert_eq!(result.unwrap(), Guess::Letter('é'));
+    }
+
+    #[test]
+    fn test_read_guess_rejects_repeats() {
+        let (result, output) = scripted_guess("e\nE\nx\n", &['e']);
+        assert_eq!(result.unwrap(), Guess::Letter('x'));
+        assert_eq!(output.matches("You already guessed 'e'").count(), 2);
+    }
+
+    #[test]
+    fn test_read_guess_eof() {
+        let (result, _) = scripted_guess("a1b\n", &[]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let (result, _) = scripted_guess("", &[]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_read_guess_word() {
+        let (result, output) = scripted_guess("abc\n", &['a']);
+        assert_eq!(result.unwrap(), Guess::Word(String::from("abc")));
+        assert!(!output.contains("already guessed"));
+    }
+
+    #[test]
+    fn test_read_guess_phrase() {
+        let (result, _) = scripted_guess("  Ice Cream \n", &[]);
+        assert_eq!(result.unwrap(), Guess::Word(String::from("ice cream")));
+        let (result, _) = scripted_guess("jack-o'-lantern\n", &[]);
+        assert_eq!(
+            result.unwrap(),
+            Guess::Word(String::from("jack-o'-lantern"))
+        );
+        // Separators alone aren't a guess
+        let (result, output) = scripted_guess("-\n' '\nx\n", &[]);
+        assert_eq!(result.unwrap(), Guess::Letter('x'));
+        assert_eq!(
+            output
+                .matches("Please enter a single letter or a word.")
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_read_guess_rejects_non_alphabetic_word() {
+        // Rejected words only re-prompt, so the next (valid) line is returned
+        let (result, output) = scripted_guess("ice-cr3am\nstar_fish\nstarfish\n", &[]);
+        assert_eq!(result.unwrap(), Guess::Word(String::from("starfish")));
+        assert_eq!(
+            output
+                .matches("Please enter a single letter or a word.")
+                .count(),
+            2
+        );
     }
 }
@@ -0,0 +1,144 @@
+// Reading the secret word in two-player mode without showing it to the other player.
+use std::io;
+use std::io::{BufRead, IsTerminal, Write};
+use std::process::{Command, Stdio};
+
+const MIN_SECRET_LEN: usize = 3;
+/// How many blank lines to print to scroll the secret word away if echo can't be turned off.
+const SCROLL_LINES: usize = 50;
+/// ANSI escape sequence that clears the screen and moves the cursor to the top left.
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Reads one line of input that shouldn't be visible to anyone watching the screen.
+pub trait HiddenInput {
+    /// Reads a line, without the trailing newline. Returns an UnexpectedEof error if there is no
+    /// more input.
+    fn read_hidden_line(&mut self) -> io::Result<String>;
+}
+
+/// Reads from stdin. If stdin is a terminal, echo is turned off with `stty` while the line is
+/// typed; if that isn't possible, the typed line is scrolled off the screen afterwards.
+pub struct TerminalInput;
+
+/// Runs `stty` with the given setting on the terminal attached to stdin.
+fn stty(setting: &str) -> bool {
+    Command::new("stty")
+        .arg(setting)
+        .stdin(Stdio::inherit())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+impl HiddenInput for TerminalInput {
+    fn read_hidden_line(&mut self) -> io::Result<String> {
+        let stdin = io::stdin();
+        let is_terminal = stdin.is_terminal();
+        let echo_disabled = is_terminal && stty("-echo");
+        let mut line = String::new();
+        let result = stdin.lock().read_line(&mut line);
+        if echo_disabled {
+            stty("echo");
+            // The newline the player typed wasn't echoed either
+            println!();
+        } else if is_terminal {
+            print!("{}", "\n".repeat(SCROLL_LINES));
+        }
+        if result? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no more input",
+            ));
+        }
+        Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+    }
+}
+
+/// Returns why `word` can't be used as a secret word, or None if it can.
+fn validate_secret(word: &str) -> Option<String> {
+    if !word.chars().all(|c| c.is_alphabetic()) {
+        return Some(String::from("The secret word may only contain letters."));
+    }
+    if word.chars().count() < MIN_SECRET_LEN {
+        return Some(format!(
+            "The secret word must be at least {} letters long.",
+            MIN_SECRET_LEN
+        ));
+    }
+    None
+}
+
+/// Asks Player 1 for the secret word, re-prompting until they enter a valid one, then clears
+/// the screen for Player 2. Returns the word in lowercase.
+pub fn read_secret_word<I: HiddenInput, W: Write>(
+    input: &mut I,
+    output: &mut W,
+) -> io::Result<String> {
+    loop {
+        write!(
+            output,
+            "Player 1, enter the secret word (it won't be shown): "
+        )?;
+        output.flush()?;
+        let word = input.read_hidden_line()?.trim().to_lowercase();
+        match validate_secret(&word) {
+            Some(problem) => writeln!(output, "{}", problem)?,
+            None => {
+                writeln!(output, "{}Player 2, start guessing!\n", CLEAR_SCREEN)?;
+                return Ok(word);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Plays back scripted lines instead of reading from the terminal.
+    struct FakeInput {
+        lines: VecDeque<&'static str>,
+    }
+
+    impl HiddenInput for FakeInput {
+        fn read_hidden_line(&mut self) -> io::Result<String> {
+            self.lines
+                .pop_front()
+                .map(String::from)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no more input"))
+        }
+    }
+
+    fn scripted_secret(lines: &[&'static str]) -> (io::Result<String>, String) {
+        let mut input = FakeInput {
+            lines: lines.iter().cloned().collect(),
+        };
+        let mut output = Vec::new();
+        let result = read_secret_word(&mut input, &mut output);
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_valid_secret() {
+        let (result, output) = scripted_secret(&["Lobster"]);
+        assert_eq!(result.unwrap(), "lobster");
+        assert!(output.contains(CLEAR_SCREEN));
+        assert!(!output.contains("lobster"));
+    }
+
+    #[test]
+    fn test_invalid_secrets_reprompt() {
+        let (result, output) = scripted_secret(&["ab", "", "crab cake", "l0bster", "crab"]);
+        assert_eq!(result.unwrap(), "crab");
+        assert_eq!(output.matches("Player 1, enter the secret word").count(), 5);
+        assert_eq!(output.matches("at least 3 letters").count(), 2);
+        assert_eq!(output.matches("only contain letters").count(), 2);
+    }
+
+    #[test]
+    fn test_secret_eof() {
+        let (result, _) = scripted_secret(&["ab"]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,335 @@
+// Lifetime win/loss statistics, kept in a small JSON file between sessions.
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+
+const STATS_FILE_NAME: &str = ".hangman_stats.json";
+
+/// Games played and won with secret words of one particular length.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LengthStats {
+    pub games: u32,
+    pub wins: u32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub games: u32,
+    pub wins: u32,
+    pub losses: u32,
+    /// Wins in a row up to and including the most recent game
+    pub current_streak: u32,
+    pub best_streak: u32,
+    /// Keyed by the number of letters in the secret word
+    pub by_length: BTreeMap<usize, LengthStats>,
+}
+
+impl Stats {
+    /// Records the outcome of one game played with a secret word of `word_len` letters.
+    pub fn record(&mut self, won: bool, word_len: usize) {
+        self.games += 1;
+        let length_stats = self.by_length.entry(word_len).or_default();
+        length_stats.games += 1;
+        if won {
+            self.wins += 1;
+            length_stats.wins += 1;
+            self.current_streak += 1;
+            self.best_streak = self.best_streak.max(self.current_streak);
+        } else {
+            self.losses += 1;
+            self.current_streak = 0;
+        }
+    }
+
+    /// Returns a one-line description of the lifetime statistics.
+    pub fn summary(&self) -> String {
+        let win_rate = if self.games == 0 {
+            0.0
+        } else {
+            100.0 * self.wins as f64 / self.games as f64
+        };
+        format!(
+            "Lifetime stats: {} games, {} wins ({:.0}%), {} losses, best streak {}",
+            self.games, self.wins, win_rate, self.losses, self.best_streak
+        )
+    }
+
+    pub fn to_json(&self) -> String {
+        let by_length: Vec<String> = self
+            .by_length
+            .iter()
+            .map(|(len, stats)| {
+                format!(
+                    "    \"{}\": {{\"games\": {}, \"wins\": {}}}",
+                    len, stats.games, stats.wins
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"games\": {},\n  \"wins\": {},\n  \"losses\": {},\n  \
+             \"current_streak\": {},\n  \"best_streak\": {},\n  \"by_length\": {{\n{}\n  }}\n}}\n",
+            self.games,
+            self.wins,
+            self.losses,
+            self.current_streak,
+            self.best_streak,
+            by_length.join(",\n")
+        )
+    }
+
+    pub fn from_json(json: &str) -> Result<Stats, String> {
+        let mut parser = Parser {
+            chars: json.chars().peekable(),
+        };
+        let value = parser.parse_value()?;
+        parser.skip_whitespace();
+        if parser.chars.peek().is_some() {
+            return Err(String::from("unexpected data after the end of the object"));
+        }
+
+        let mut stats = Stats::default();
+        for (key, value) in value.into_object()? {
+            match key.as_str() {
+                "games" => stats.games = value.into_number()?,
+                "wins" => stats.wins = value.into_number()?,
+                "losses" => stats.losses = value.into_number()?,
+                "current_streak" => stats.current_streak = value.into_number()?,
+                "best_streak" => stats.best_streak = value.into_number()?,
+                "by_length" => {
+                    for (len, value) in value.into_object()? {
+                        let len = len
+                            .parse::<usize>()
+                            .map_err(|_| format!("invalid word length {:?}", len))?;
+                        let mut length_stats = LengthStats::default();
+                        for (key, value) in value.into_object()? {
+                            match key.as_str() {
+                                "games" => length_stats.games = value.into_number()?,
+                                "wins" => length_stats.wins = value.into_number()?,
+                                _ => return Err(format!("unknown key {:?}", key)),
+                            }
+                        }
+                        stats.by_length.insert(len, length_stats);
+                    }
+                }
+                _ => return Err(format!("unknown key {:?}", key)),
+            }
+        }
+        if stats.wins.checked_add(stats.losses) != Some(stats.games) {
+            return Err(String::from("wins and losses don't add up to games"));
+        }
+        Ok(stats)
+    }
+}
+
+/// Returns the default stats file location, `~/.hangman_stats.json`, falling back to the current
+/// directory if there is no home directory.
+pub fn default_path() -> PathBuf {
+    match env::var_os("HOME") {
+        Some(home) => Path::new(&home).join(STATS_FILE_NAME),
+        None => PathBuf::from(STATS_FILE_NAME),
+    }
+}
+
+/// Loads the stats file at `path`. Returns Ok(None) if the file doesn't exist yet, and an error
+/// if it exists but can't be read or parsed.
+pub fn load(path: &Path) -> Result<Option<Stats>, String> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("Unable to read {}: {}", path.display(), err)),
+    };
+    Stats::from_json(&json)
+        .map(Some)
+        .map_err(|err| format!("{} is corrupt: {}", path.display(), err))
+}
+
+pub fn save(path: &Path, stats: &Stats) -> io::Result<()> {
+    fs::write(path, stats.to_json())
+}
+
+/// A parsed JSON value. The stats file only ever contains objects and non-negative integers.
+enum Value {
+    Number(u32),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn into_number(self) -> Result<u32, String> {
+        match self {
+            Value::Number(num) => Ok(num),
+            Value::Object(_) => Err(String::from("expected a number, found an object")),
+        }
+    }
+
+    fn into_object(self) -> Result<Vec<(String, Value)>, String> {
+        match self {
+            Value::Object(fields) => Ok(fields),
+            Value::Number(_) => Err(String::from("expected an object, found a number")),
+        }
+    }
+}
+
+/// A minimal JSON parser for the subset of JSON that to_json produces.
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected '{}', found '{}'", expected, c)),
+            None => Err(format!("expected '{}', found end of file", expected)),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('{') => self.parse_object(),
+            Some(c) if c.is_ascii_digit() => self.parse_number(),
+            Some(c) => Err(format!("unexpected '{}'", c)),
+            None => Err(String::from("unexpected end of file")),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            let key = self.parse_string()?;
+            self.expect(':')?;
+            fields.push((key, self.parse_value()?));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Object(fields)),
+                Some(c) => return Err(format!("expected ',' or '}}', found '{}'", c)),
+                None => return Err(String::from("unterminated object")),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => return Err(String::from("escape sequences are not supported")),
+                Some(c) => string.push(c),
+                None => return Err(String::from("unterminated string")),
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Value, String> {
+        let mut digits = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            digits.push(c);
+            self.chars.next();
+        }
+        digits
+            .parse::<u32>()
+            .map(Value::Number)
+            .map_err(|_| format!("number {} is out of range", digits))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("hangman-stats-test-{}-{}", process::id(), name))
+    }
+
+    fn sample_stats() -> Stats {
+        let mut stats = Stats::default();
+        stats.record(true, 7);
+        stats.record(true, 5);
+        stats.record(false, 7);
+        stats.record(true, 9);
+        stats.record(true, 7);
+        stats.record(true, 7);
+        stats
+    }
+
+    #[test]
+    fn test_record() {
+        let stats = sample_stats();
+        assert_eq!(stats.games, 6);
+        assert_eq!(stats.wins, 5);
+        assert_eq!(stats.losses, 1);
+        assert_eq!(stats.current_streak, 3);
+        assert_eq!(stats.best_streak, 3);
+        assert_eq!(stats.by_length[&7], LengthStats { games: 4, wins: 3 });
+        assert_eq!(stats.by_length[&5], LengthStats { games: 1, wins: 1 });
+        assert_eq!(
+            stats.summary(),
+            "Lifetime stats: 6 games, 5 wins (83%), 1 losses, best streak 3"
+        );
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let stats = sample_stats();
+        assert_eq!(Stats::from_json(&stats.to_json()), Ok(stats));
+        assert_eq!(
+            Stats::from_json(&Stats::default().to_json()),
+            Ok(Stats::default())
+        );
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let path = temp_path("round-trip.json");
+        let stats = sample_stats();
+        save(&path, &stats).unwrap();
+        assert_eq!(load(&path), Ok(Some(stats)));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(load(&path), Ok(None));
+    }
+
+    #[test]
+    fn test_corrupt_file() {
+        let path = temp_path("corrupt.json");
+        let corrupt = [
+            "",
+            "not json",
+            "{\"games\": 3",
+            "{\"games\": -1}",
+            "{\"games\": 1, \"wins\": 1, \"losses\": 1}",
+            "{\"games\": 0, \"wins\": 4294967295, \"losses\": 1}",
+            "{\"games\": {}}",
+            "{\"by_length\": {\"five\": {\"games\": 0, \"wins\": 0}}}",
+            "{\"games\": 0} trailing",
+        ];
+        for contents in corrupt.iter() {
+            fs::write(&path, contents).unwrap();
+            let err = load(&path).unwrap_err();
+            assert!(err.contains("is corrupt"), "{:?} gave {}", contents, err);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
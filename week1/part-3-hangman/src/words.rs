@@ -0,0 +1,428 @@
+// Loading the word list and picking a secret word from it.
+//
+// The word list has one word (or phrase) per line, optionally followed by a weight that makes it
+// more likely to be picked (`elephant 5`; the default weight is 1). Lines starting with `#` are
+// comments, except for `# category: <name>`, which puts the words after it in that category.
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+
+/// Letters that make a word hard to guess no matter how long it is.
+const RARE_LETTERS: [char; 4] = ['q', 'z', 'x', 'j'];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    pub fn parse(name: &str) -> Option<Difficulty> {
+        match name {
+            "easy" => Some(Difficulty::Easy),
+            "medium" => Some(Difficulty::Medium),
+            "hard" => Some(Difficulty::Hard),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Medium => "medium",
+            Difficulty::Hard => "hard",
+        }
+    }
+
+    /// The number of incorrect guesses allowed at this difficulty, unless --guesses says otherwise.
+    pub fn guesses(self) -> u32 {
+        match self {
+            Difficulty::Easy => 8,
+            Difficulty::Medium => 6,
+            Difficulty::Hard => 4,
+        }
+    }
+
+    /// Whether `word` belongs in this difficulty's word pool. Easy words have 4-6 distinct
+    /// letters, medium words 6-9, and hard words 9 or more or at least one rare letter.
+    fn accepts(self, word: &str) -> bool {
+        let unique_letters = word
+            .chars()
+            .filter(|c| c.is_alphabetic())
+            .collect::<HashSet<char>>()
+            .len();
+        match self {
+            Difficulty::Easy => (4..=6).contains(&unique_letters),
+            Difficulty::Medium => (6..=9).contains(&unique_letters),
+            Difficulty::Hard => {
+                unique_letters >= 9 || word.chars().any(|c| RARE_LETTERS.contains(&c))
+            }
+        }
+    }
+}
+
+const CATEGORY_PREFIX: &str = "category:";
+
+/// One word from the word list.
+#[derive(Debug, PartialEq)]
+pub struct WordEntry {
+    pub word: String,
+    /// How likely this word is to be picked, relative to the other candidates
+    pub weight: u32,
+    /// The category header the word appeared under, if any
+    pub category: Option<String>,
+}
+
+/// The parsed contents of a word list file.
+#[derive(Debug)]
+pub struct WordList {
+    pub entries: Vec<WordEntry>,
+    /// Problems that didn't stop the list from loading, such as duplicate words
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io { path: String, err: io::Error },
+    Empty { path: String },
+    InvalidWeight { path: String, line: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io { path, err } => write!(f, "Unable to read word list {}: {}", path, err),
+            LoadError::Empty { path } => write!(f, "Word list {} doesn't contain any words", path),
+            LoadError::InvalidWeight { path, line } => {
+                write!(f, "{}:{}: word weights must be at least 1", path, line)
+            }
+        }
+    }
+}
+
+impl WordList {
+    /// Parses the contents of a word list. `path` is only used in error messages.
+    pub fn parse(path: &str, contents: &str) -> Result<WordList, LoadError> {
+        let mut entries: Vec<WordEntry> = Vec::new();
+        let mut warnings = Vec::new();
+        let mut seen = HashSet::new();
+        let mut category = None;
+        // `lines` also strips the \r from CRLF line endings
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Some(name) = comment.trim().strip_prefix(CATEGORY_PREFIX) {
+                    category = Some(name.trim().to_lowercase());
+                }
+                continue;
+            }
+            let (word, weight) = match line.rsplit_once(char::is_whitespace) {
+                Some((word, weight)) if weight.chars().all(|c| c.is_ascii_digit()) => {
+                    match weight.parse::<u32>() {
+                        Ok(weight) if weight > 0 => (word.trim(), weight),
+                        _ => {
+                            return Err(LoadError::InvalidWeight {
+                                path: String::from(path),
+                                line: idx + 1,
+                            })
+                        }
+                    }
+                }
+                _ => (line, 1),
+            };
+            if !seen.insert(word.to_lowercase()) {
+                warnings.push(format!(
+                    "{}:{}: ignoring duplicate word \"{}\"",
+                    path,
+                    idx + 1,
+                    word
+                ));
+                continue;
+            }
+            entries.push(WordEntry {
+                word: String::from(word),
+                weight,
+                category: category.clone(),
+            });
+        }
+        if entries.is_empty() {
+            return Err(LoadError::Empty {
+                path: String::from(path),
+            });
+        }
+        Ok(WordList { entries, warnings })
+    }
+
+    /// Returns the category names in the order they first appear in the file.
+    pub fn categories(&self) -> Vec<&str> {
+        let mut categories: Vec<&str> = Vec::new();
+        for entry in self.entries.iter() {
+            if let Some(category) = &entry.category {
+                if !categories.contains(&category.as_str()) {
+                    categories.push(category);
+                }
+            }
+        }
+        categories
+    }
+}
+
+/// Reads and parses the word list at `path`.
+pub fn load_words(path: &str) -> Result<WordList, LoadError> {
+    let contents = fs::read_to_string(path).map_err(|err| LoadError::Io {
+        path: String::from(path),
+        err,
+    })?;
+    WordList::parse(path, &contents)
+}
+
+/// Returns the words that belong in the pool for `difficulty`.
+pub fn filter_words<'a>(entries: &[&'a WordEntry], difficulty: Difficulty) -> Vec<&'a WordEntry> {
+    entries
+        .iter()
+        .filter(|entry| difficulty.accepts(&entry.word))
+        .cloned()
+        .collect()
+}
+
+/// Picks one of `entries` at random, with probability proportional to its weight. `entries` must
+/// not be empty.
+pub fn pick_weighted<'a, R: Rng>(entries: &[&'a WordEntry], rng: &mut R) -> &'a WordEntry {
+    let total: u64 = entries.iter().map(|entry| entry.weight as u64).sum();
+    let mut target = rng.gen_range(0, total);
+    for entry in entries.iter() {
+        if target < entry.weight as u64 {
+            return entry;
+        }
+        target -= entry.weight as u64;
+    }
+    unreachable!("target is less than the total weight")
+}
+
+/// Picks a random word from `list`, restricted to `category` and `difficulty` if given. If no
+/// word in the category matches the difficulty, warns and ignores the difficulty instead. If
+/// `seed` is given, the same seed always picks the same word from the same list.
+pub fn pick_a_random_word(
+    list: &WordList,
+    category: Option<&str>,
+    difficulty: Option<Difficulty>,
+    seed: Option<u64>,
+) -> Result<String, String> {
+    let candidates: Vec<&WordEntry> = match category {
+        Some(category) => {
+            let category = category.to_lowercase();
+            let candidates: Vec<&WordEntry> = list
+                .entries
+                .iter()
+                .filter(|entry| entry.category.as_ref() == Some(&category))
+                .collect();
+            if candidates.is_empty() {
+                return Err(format!(
+                    "There is no category called {} (available: {})",
+                    category,
+                    list.categories().join(", ")
+                ));
+            }
+            candidates
+        }
+        None => list.entries.iter().collect(),
+    };
+    let mut pool = match difficulty {
+        Some(difficulty) => filter_words(&candidates, difficulty),
+        None => candidates.clone(),
+    };
+    if pool.is_empty() {
+        println!(
+            "Warning: no words match the {} difficulty, picking from all of them",
+            difficulty.map_or("", Difficulty::name)
+        );
+        pool = candidates;
+    }
+    let entry = match seed {
+        Some(seed) => pick_weighted(&pool, &mut StdRng::seed_from_u64(seed)),
+        None => pick_weighted(&pool, &mut rand::thread_rng()),
+    };
+    Ok(entry.word.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn parse(contents: &str) -> WordList {
+        WordList::parse("test.txt", contents).unwrap()
+    }
+
+    fn words(list: &WordList) -> Vec<&str> {
+        list.entries
+            .iter()
+            .map(|entry| entry.word.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_load_categories_fixture() {
+        let list = load_words(&fixture("categories.txt")).unwrap();
+        assert_eq!(list.categories(), vec!["animals", "food"]);
+        assert_eq!(
+            words(&list),
+            vec![
+                "lobster",
+                "elephant",
+                "starfish",
+                "ice cream",
+                "café",
+                "noodle"
+            ]
+        );
+        assert_eq!(
+            list.entries[1],
+            WordEntry {
+                word: String::from("elephant"),
+                weight: 5,
+                category: Some(String::from("animals")),
+            }
+        );
+        assert_eq!(list.entries[3].category, Some(String::from("food")));
+        assert_eq!(list.entries[3].weight, 1);
+        assert_eq!(list.entries[4].weight, 3);
+        // "Lobster" appears twice (differing only in case) and is kept once, with a warning
+        assert_eq!(list.warnings.len(), 1);
+        assert!(list.warnings[0].contains("duplicate word \"Lobster\""));
+    }
+
+    #[test]
+    fn test_parse_flat_list() {
+        let list = parse("alpha\n\n  bravo  \ncharlie\n");
+        assert_eq!(words(&list), vec!["alpha", "bravo", "charlie"]);
+        assert!(list.categories().is_empty());
+        assert!(list.entries.iter().all(|entry| entry.weight == 1));
+        assert!(list.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_parse_crlf() {
+        let list = parse("# category: Birds\r\nrobin 2\r\n\r\nblue jay\r\n");
+        assert_eq!(words(&list), vec!["robin", "blue jay"]);
+        assert_eq!(list.entries[0].weight, 2);
+        assert_eq!(list.categories(), vec!["birds"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        match WordList::parse("test.txt", "\n# category: empty\n\n") {
+            Err(LoadError::Empty { .. }) => {}
+            other => panic!("expected Empty, got {:?}", other),
+        }
+        match WordList::parse("test.txt", "alpha\nbravo 0\n") {
+            Err(LoadError::InvalidWeight { line: 2, .. }) => {}
+            other => panic!("expected InvalidWeight, got {:?}", other),
+        }
+        let err = load_words("/nonexistent/hangman/words.txt").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Unable to read word list /nonexistent/hangman/words.txt"));
+    }
+
+    #[test]
+    fn test_filter_words() {
+        let list = parse(
+            "cat\nlobster\nnoodle\nabcdefghij\nbanana\ncrawfish\nquiz\njazz\nmississippi\n\
+             boxes\nkeyboards\nice cream\n",
+        );
+        let entries: Vec<&WordEntry> = list.entries.iter().collect();
+        let filtered = |difficulty| -> Vec<&str> {
+            filter_words(&entries, difficulty)
+                .into_iter()
+                .map(|entry| entry.word.as_str())
+                .collect()
+        };
+        // Distinct letters: cat 3, lobster 7, noodle 4, abcdefghij 10, banana 3, crawfish 8,
+        // quiz 4, jazz 3, mississippi 4, boxes 5, keyboards 9, ice cream 6 (the space doesn't count)
+        assert_eq!(
+            filtered(Difficulty::Easy),
+            vec!["noodle", "quiz", "mississippi", "boxes", "ice cream"]
+        );
+        assert_eq!(
+            filtered(Difficulty::Medium),
+            vec!["lobster", "crawfish", "keyboards", "ice cream"]
+        );
+        assert_eq!(
+            filtered(Difficulty::Hard),
+            vec!["abcdefghij", "quiz", "jazz", "boxes", "keyboards"]
+        );
+    }
+
+    #[test]
+    fn test_pick_weighted_distribution() {
+        let list = load_words(&fixture("weighted.txt")).unwrap();
+        let entries: Vec<&WordEntry> = list.entries.iter().collect();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut counts = [0; 3];
+        for _ in 0..10000 {
+            let picked = pick_weighted(&entries, &mut rng);
+            let idx = entries.iter().position(|entry| *entry == picked).unwrap();
+            counts[idx] += 1;
+        }
+        // Weights 1, 3 and 6 out of 10
+        assert!((800..1200).contains(&counts[0]), "{:?}", counts);
+        assert!((2700..3300).contains(&counts[1]), "{:?}", counts);
+        assert!((5600..6400).contains(&counts[2]), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_pick_respects_category() {
+        let list = load_words(&fixture("categories.txt")).unwrap();
+        for seed in 0..20 {
+            let word = pick_a_random_word(&list, Some("Food"), None, Some(seed)).unwrap();
+            assert!(["ice cream", "café", "noodle"].contains(&word.as_str()));
+        }
+        let err = pick_a_random_word(&list, Some("plants"), None, Some(1)).unwrap_err();
+        assert!(err.contains("available: animals, food"));
+    }
+
+    #[test]
+    fn test_pick_respects_difficulty() {
+        let list = parse("cat\nnoodle\nlobster\njazz\n");
+        for seed in 0..20 {
+            let word = pick_a_random_word(&list, None, Some(Difficulty::Medium), Some(seed));
+            assert_eq!(word.unwrap(), "lobster");
+        }
+    }
+
+    #[test]
+    fn test_pick_falls_back_when_no_words_match() {
+        let list = parse("cat\ndog\n");
+        for seed in 0..20 {
+            let word = pick_a_random_word(&list, None, Some(Difficulty::Medium), Some(seed));
+            let word = word.unwrap();
+            assert!(word == "cat" || word == "dog");
+        }
+    }
+
+    #[test]
+    fn test_seeded_pick_is_reproducible() {
+        let list = parse("alpha\nbravo\ncharlie\ndelta\necho\nfoxtrot\n");
+        for seed in 0..20 {
+            let first = pick_a_random_word(&list, None, None, Some(seed)).unwrap();
+            let second = pick_a_random_word(&list, None, None, Some(seed)).unwrap();
+            assert_eq!(first, second);
+        }
+        // Different seeds should not all land on the same word
+        let picks: HashSet<String> = (0..20)
+            .map(|seed| pick_a_random_word(&list, None, None, Some(seed)).unwrap())
+            .collect();
+        assert!(picks.len() > 1);
+    }
+}
@@ -0,0 +1,54 @@
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Runs the hangman binary with `args`, feeding it `input` on stdin, and returns its stdout.
+fn run_hangman(args: &[&str], input: &str) -> String {
+    let stats_file =
+        env::temp_dir().join(format!("hangman-two-player-{}.json", std::process::id()));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_hangman"))
+        .args(args)
+        .arg("--stats-file")
+        .arg(&stats_file)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start hangman");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let _ = std::fs::remove_file(&stats_file);
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_two_player_game() {
+    // Player 1 first tries a word that's too short, then sets "crab"; Player 2 misses once on
+    // 'z' and then finds every letter
+    let output = run_hangman(&["--two-player"], "ab\ncrab\nz\nc\nr\na\nb\n");
+
+    let secret_prompts = output.matches("Player 1, enter the secret word").count();
+    assert_eq!(secret_prompts, 2);
+    assert!(output.contains("The secret word must be at least 3 letters long."));
+    assert!(output.contains("Player 2, start guessing!"));
+    assert!(output.contains("Sorry, that letter is not in the word"));
+    assert!(output.contains("Congratulations you guessed the secret word: crab!"));
+    // Nothing may reveal the word before Player 2 has guessed it
+    let before_win = &output[..output.find("Congratulations").unwrap()];
+    assert!(!before_win.contains("random word"));
+    assert!(!before_win.contains("crab"));
+}
+
+#[test]
+fn test_two_player_rejects_fixed_word() {
+    let output = Command::new(env!("CARGO_BIN_EXE_hangman"))
+        .args(["--two-player", "--word", "crab"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
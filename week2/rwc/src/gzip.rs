@@ -0,0 +1,134 @@
+// Counting gzip-compressed files by their decompressed contents.
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+
+/// Every gzip stream starts with these two bytes.
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Returns whether `file` holds gzip data, going by its first two bytes rather than its name, so
+/// that a plain text file called `something.gz` is still counted as text. The file is left at its
+/// start.
+pub fn is_gzip(file: &mut File) -> io::Result<bool> {
+    let mut start = Vec::with_capacity(MAGIC.len());
+    file.by_ref()
+        .take(MAGIC.len() as u64)
+        .read_to_end(&mut start)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(start == MAGIC)
+}
+
+/// Returns the size of the decompressed contents, which takes decompressing them: the size
+/// recorded at the end of a gzip stream only covers the last of the streams a file can be made of
+/// (and only modulo 2^32). A corrupt stream is an InvalidData error. The file is left at its start.
+pub fn uncompressed_size(file: &mut File) -> io::Result<u64> {
+    let size = io::copy(&mut Decoder(MultiGzDecoder::new(&*file)), &mut io::sink())?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(size)
+}
+
+/// Decompresses `file` on the fly and passes the decompressed stream to `read`. Like `gzip -dc`,
+/// a file made of several gzip streams one after another is decompressed as a whole. A corrupt or
+/// truncated stream is an InvalidData error.
+pub fn decompress<T, F>(file: File, read: F) -> io::Result<T>
+where
+    F: FnOnce(&mut dyn BufRead) -> io::Result<T>,
+{
+    let decoder = Decoder(MultiGzDecoder::new(file));
+    read(&mut io::BufReader::new(decoder))
+}
+
+/// A gzip decoder whose errors about the stream itself are InvalidData, like uncompressed_size's,
+/// rather than the InvalidInput or UnexpectedEof that flate2 reports them as.
+struct Decoder<R>(MultiGzDecoder<R>);
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(|err| match err.kind() {
+            io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof => {
+                io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+            }
+            _ => err,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixture(name: &str) -> File {
+        File::open(format!(
+            "{}/tests/fixtures/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap()
+    }
+
+    fn read_all(reader: &mut dyn BufRead) -> io::Result<String> {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn test_is_gzip() {
+        let mut file = fixture("whitespace.txt.gz");
+        assert!(is_gzip(&mut file).unwrap());
+        // The file is rewound afterwards
+        assert_eq!(file.stream_position().unwrap(), 0);
+        assert!(!is_gzip(&mut fixture("plain.txt.gz")).unwrap());
+        assert!(!is_gzip(&mut fixture("empty.txt")).unwrap());
+    }
+
+    #[test]
+    fn test_uncompressed_size() {
+        let mut file = fixture("whitespace.txt.gz");
+        assert_eq!(uncompressed_size(&mut file).unwrap(), 90);
+        assert_eq!(file.stream_position().unwrap(), 0);
+        // Every stream counts, not just the last
+        assert_eq!(
+            uncompressed_size(&mut fixture("twice.txt.gz")).unwrap(),
+            180
+        );
+    }
+
+    #[test]
+    fn test_uncompressed_size_corrupt() {
+        let path = std::env::temp_dir().join(format!("rwc-gzip-size-{}.gz", std::process::id()));
+        std::fs::write(&path, b"\x1f\x8b\x08\x00garbage").unwrap();
+        let err = uncompressed_size(&mut File::open(&path).unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_decompress() {
+        let mut expected = String::new();
+        fixture("whitespace.txt")
+            .read_to_string(&mut expected)
+            .unwrap();
+        let contents = decompress(fixture("whitespace.txt.gz"), read_all).unwrap();
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn test_decompress_concatenated() {
+        let mut expected = String::new();
+        fixture("whitespace.txt")
+            .read_to_string(&mut expected)
+            .unwrap();
+        let contents = decompress(fixture("twice.txt.gz"), read_all).unwrap();
+        assert_eq!(contents, expected.repeat(2));
+    }
+
+    #[test]
+    fn test_decompress_corrupt() {
+        // Valid magic bytes, but not a valid stream
+        let path = std::env::temp_dir().join(format!("rwc-gzip-{}.gz", std::process::id()));
+        std::fs::write(&path, b"\x1f\x8b\x08\x00garbage").unwrap();
+        let err = decompress(File::open(&path).unwrap(), read_all).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!err.to_string().is_empty());
+    }
+}
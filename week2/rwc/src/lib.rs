@@ -0,0 +1,776 @@
+// Counting lines, words, characters and bytes, like wc.
+pub mod gzip;
+pub mod pattern;
+pub mod pool;
+pub mod walker;
+
+use pattern::Pattern;
+use std::io::{self, BufRead};
+use std::ops::{Add, AddAssign};
+
+/// Tab stops are every this many columns, as on a terminal.
+const TAB_WIDTH: usize = 8;
+/// How much of the input is read at a time.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Counts for a single line.
+#[derive(Debug, PartialEq)]
+struct LineCounts {
+    words: usize,
+    chars: usize,
+    /// Display width in columns, not counting the trailing newline
+    width: usize,
+}
+
+/// Returns how many columns `line` takes up when displayed: every character is one column, except
+/// that a tab moves to the next multiple of `TAB_WIDTH` and a carriage return moves back to the
+/// start of the line (so `abc\r\n` is 3 columns wide, as in GNU wc). Wide characters (such as CJK)
+/// are also counted as one column, although a terminal shows them as two.
+fn display_width(line: &str) -> usize {
+    let mut column = 0;
+    let mut widest = 0;
+    for c in line.chars() {
+        column = match c {
+            '\t' => (column / TAB_WIDTH + 1) * TAB_WIDTH,
+            '\r' => 0,
+            _ => column + 1,
+        };
+        widest = widest.max(column);
+    }
+    widest
+}
+
+/// Counts the words and characters in one line. Like `wc -w`, a word is a run of
+/// non-whitespace characters, so repeated, leading and trailing whitespace don't add words. The
+/// `\r` of a CRLF line ending is whitespace, so it never sticks to the last word, but it is still
+/// counted as a character.
+fn count_line(line: &str) -> LineCounts {
+    LineCounts {
+        words: line.split_whitespace().count(),
+        chars: line.chars().count(),
+        width: display_width(line.strip_suffix('\n').unwrap_or(line)),
+    }
+}
+
+/// Counts the newlines in `bytes`. The count for each block of up to 255 bytes is summed in a `u8`,
+/// which can't overflow, and which lets the compiler compare many bytes at once.
+fn count_newlines(bytes: &[u8]) -> usize {
+    bytes
+        .chunks(255)
+        .map(|block| {
+            block
+                .iter()
+                .fold(0u8, |newlines, &byte| newlines + (byte == b'\n') as u8) as usize
+        })
+        .sum()
+}
+
+/// Returns whether `byte` separates words when counting them by ASCII whitespace: a space, or one
+/// of `\t \n \v \f \r` (which is what `isspace` means in the C locale).
+fn is_ascii_space(byte: u8) -> bool {
+    byte == b' ' || (b'\t'..=b'\r').contains(&byte)
+}
+
+/// Counts the words that start in `bytes`, separated by ASCII whitespace. `in_word` says whether
+/// the bytes before these ended in the middle of a word, so a word split across two chunks is only
+/// counted once; it is updated to say whether these bytes do.
+fn count_ascii_words(bytes: &[u8], in_word: &mut bool) -> usize {
+    let mut words = 0;
+    let mut after_space = !*in_word;
+    for &byte in bytes {
+        let space = is_ascii_space(byte);
+        words += (after_space && !space) as usize;
+        after_space = space;
+    }
+    *in_word = !after_space;
+    words
+}
+
+/// Totals for a whole file, or for several files added together.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counts {
+    /// Number of newline characters, so a last line without one isn't counted (like wc)
+    pub lines: usize,
+    pub words: usize,
+    /// Unicode scalar values, including newlines
+    pub chars: usize,
+    pub bytes: usize,
+    /// Display width of the longest line
+    pub max_line_len: usize,
+    /// Lines selected by the counter's pattern
+    pub matching_lines: usize,
+    /// Matches of the counter's pattern, which can be several per line
+    pub matches: usize,
+}
+
+/// Adding counts gives the total, as in wc's `total` row: everything is summed, except the
+/// longest line, which is the longest of either.
+impl AddAssign for Counts {
+    fn add_assign(&mut self, other: Counts) {
+        self.lines += other.lines;
+        self.words += other.words;
+        self.chars += other.chars;
+        self.bytes += other.bytes;
+        self.max_line_len = self.max_line_len.max(other.max_line_len);
+        self.matching_lines += other.matching_lines;
+        self.matches += other.matches;
+    }
+}
+
+impl Add for Counts {
+    type Output = Counts;
+
+    fn add(mut self, other: Counts) -> Counts {
+        self += other;
+        self
+    }
+}
+
+/// Counts a stream as it is read, a chunk at a time, so even huge inputs are never held in memory.
+/// By default every field of `Counts` is counted except the pattern matches, which need a
+/// `pattern`.
+#[derive(Clone, Debug)]
+pub struct Counter {
+    /// Whether to decode each line to count words, characters and the longest line
+    text: bool,
+    /// Whether to count words by ASCII whitespace when the lines aren't decoded
+    ascii_words: bool,
+    pattern: Option<Pattern>,
+}
+
+impl Default for Counter {
+    fn default() -> Counter {
+        Counter::new()
+    }
+}
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter {
+            text: true,
+            ascii_words: false,
+            pattern: None,
+        }
+    }
+
+    /// Only count lines and bytes (and pattern matches, if there is a pattern), leaving the other
+    /// fields at 0. Lines then don't need to be decoded, which makes `wc -l` and `wc -c` cheap.
+    pub fn lines_and_bytes_only(mut self) -> Counter {
+        self.text = false;
+        self.ascii_words = false;
+        self
+    }
+
+    /// Only count lines, words and bytes (and pattern matches, if there is a pattern), leaving the
+    /// other fields at 0. Words are separated by ASCII whitespace, as in wc's C locale, so
+    /// Unicode spaces such as U+00A0 don't split words the way they do when every field is
+    /// counted; in exchange the input doesn't need to be decoded.
+    pub fn lines_words_and_bytes_only(mut self) -> Counter {
+        self.text = false;
+        self.ascii_words = true;
+        self
+    }
+
+    /// Also count the lines matching `pattern`, and its matches.
+    pub fn pattern(mut self, pattern: Pattern) -> Counter {
+        self.pattern = Some(pattern);
+        self
+    }
+
+    /// Counts everything in `reader`. The input is read as raw bytes so that newlines are
+    /// included in the byte and character counts, and so that invalid UTF-8 can't stop the count:
+    /// each invalid sequence counts as one (non-whitespace) replacement character. GNU wc -m
+    /// skips invalid bytes instead, so the character count can differ from wc's for such files.
+    pub fn count<R: BufRead>(&self, mut reader: R) -> io::Result<Counts> {
+        let mut running = self.start();
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let len = match reader.read(&mut chunk) {
+                Ok(0) => return Ok(running.counts()),
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            running.update(&chunk[..len]);
+        }
+    }
+
+    /// Starts a count that is fed its input a piece at a time, for input that is still arriving.
+    pub fn start(&self) -> RunningCount {
+        RunningCount {
+            counter: self.clone(),
+            counts: Counts::default(),
+            partial_line: Vec::new(),
+            in_word: false,
+        }
+    }
+
+    /// Whether the input has to be split into lines, rather than just scanned.
+    fn splits_lines(&self) -> bool {
+        self.text || self.pattern.is_some()
+    }
+
+    /// Adds one line (including its newline, if it has one) to `counts`.
+    fn count_line(&self, counts: &mut Counts, line: &[u8]) {
+        if line.ends_with(b"\n") {
+            counts.lines += 1;
+        }
+        counts.bytes += line.len();
+        if !self.text && self.ascii_words {
+            // A line ends in whitespace, or at the end of the input, so no word carries over
+            counts.words += count_ascii_words(line, &mut false);
+        }
+        // Decoding only allocates if the line isn't valid UTF-8
+        let text = String::from_utf8_lossy(line);
+        if self.text {
+            let line_counts = count_line(&text);
+            counts.words += line_counts.words;
+            counts.chars += line_counts.chars;
+            counts.max_line_len = counts.max_line_len.max(line_counts.width);
+        }
+        if let Some(pattern) = &self.pattern {
+            let text = text.strip_suffix('\n').unwrap_or(&text);
+            let line_matches = pattern.match_line(text.strip_suffix('\r').unwrap_or(text));
+            if line_matches.selected {
+                counts.matching_lines += 1;
+            }
+            counts.matches += line_matches.matches;
+        }
+    }
+}
+
+/// A count in progress, from `Counter::start`. It can be given more input at any time, and asked
+/// for the counts so far.
+#[derive(Clone, Debug)]
+pub struct RunningCount {
+    counter: Counter,
+    /// Counts for the input so far, except for `partial_line`
+    counts: Counts,
+    /// The start of a line whose newline hasn't arrived yet, when the input is split into lines
+    partial_line: Vec<u8>,
+    /// Whether the input so far ends in the middle of a word, when the input is only scanned
+    in_word: bool,
+}
+
+impl RunningCount {
+    /// Counts the next piece of the input. When nothing needs decoding, the piece is scanned as it
+    /// is: newlines are counted many at a time, and words are tracked across pieces. Otherwise it
+    /// is split into lines, and a line that runs past the end of the piece is kept until the rest
+    /// of it arrives.
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        if !self.counter.splits_lines() {
+            self.counts.lines += count_newlines(bytes);
+            self.counts.bytes += bytes.len();
+            if self.counter.ascii_words {
+                self.counts.words += count_ascii_words(bytes, &mut self.in_word);
+            }
+            return;
+        }
+        while let Some(end) = bytes.iter().position(|&byte| byte == b'\n') {
+            let (line, rest) = bytes.split_at(end + 1);
+            if self.partial_line.is_empty() {
+                self.counter.count_line(&mut self.counts, line);
+            } else {
+                self.partial_line.extend_from_slice(line);
+                self.counter
+                    .count_line(&mut self.counts, &self.partial_line);
+                self.partial_line.clear();
+            }
+            bytes = rest;
+        }
+        self.partial_line.extend_from_slice(bytes);
+    }
+
+    /// Returns the counts for all the input so far, as if it ended here.
+    pub fn counts(&self) -> Counts {
+        let mut counts = self.counts;
+        if !self.partial_line.is_empty() {
+            self.counter.count_line(&mut counts, &self.partial_line);
+        }
+        counts
+    }
+}
+
+/// Counts the lines, words, characters and bytes in `reader`, and the longest line.
+pub fn count<R: BufRead>(reader: R) -> io::Result<Counts> {
+    Counter::new().count(reader)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::io::Cursor;
+
+    fn count_fixture(name: &str) -> Counts {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        count(io::BufReader::new(File::open(path).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_count_every_field() {
+        let input = Cursor::new("héllo  wörld\n\tsecond line\nno newline");
+        assert_eq!(
+            count(input).unwrap(),
+            Counts {
+                lines: 2,
+                words: 6,
+                chars: 36,
+                bytes: 38,
+                max_line_len: 19,
+                matching_lines: 0,
+                matches: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_lines_and_bytes_only() {
+        let counter = Counter::new().lines_and_bytes_only();
+        let counts = counter.count(Cursor::new("one two\nthree\n")).unwrap();
+        assert_eq!(
+            counts,
+            Counts {
+                lines: 2,
+                bytes: 14,
+                ..Counts::default()
+            }
+        );
+        // Pattern matches are still counted
+        let counter = counter.pattern(Pattern::new("o", false, false).unwrap());
+        let counts = counter.count(Cursor::new("one two\nthree\n")).unwrap();
+        assert_eq!(
+            (counts.words, counts.matching_lines, counts.matches),
+            (0, 1, 2)
+        );
+    }
+
+    #[test]
+    fn test_lines_words_and_bytes_only() {
+        let counter = Counter::new().lines_words_and_bytes_only();
+        let counts = counter
+            .count(Cursor::new("  one\ttwo\x0bthree\r\nfour"))
+            .unwrap();
+        assert_eq!(
+            counts,
+            Counts {
+                lines: 1,
+                words: 4,
+                bytes: 21,
+                ..Counts::default()
+            }
+        );
+        // Only ASCII whitespace separates words
+        let counts = counter.count(Cursor::new("one\u{a0}two\n")).unwrap();
+        assert_eq!(counts.words, 1);
+        // Words are still counted on the line-by-line path that a pattern needs
+        let counter = counter.pattern(Pattern::new("o", false, false).unwrap());
+        let counts = counter.count(Cursor::new("one two\nthree\n")).unwrap();
+        assert_eq!(
+            (counts.words, counts.matching_lines, counts.matches),
+            (3, 1, 2)
+        );
+    }
+
+    #[test]
+    fn test_word_across_chunks() {
+        // The first chunk ends in the middle of the second word
+        let mut input = vec![b'a'; CHUNK_SIZE - 3];
+        input.extend_from_slice(b" bcdef ghi\n");
+        let counts = Counter::new()
+            .lines_words_and_bytes_only()
+            .count(Cursor::new(&input))
+            .unwrap();
+        assert_eq!((counts.lines, counts.words), (1, 3));
+    }
+
+    /// A reader that hands out its data a few bytes at a time, as a pipe might, so that chunks
+    /// end in arbitrary places.
+    struct ShortReads {
+        data: Cursor<Vec<u8>>,
+        rng: XorShift,
+    }
+
+    impl io::Read for ShortReads {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1 + self.rng.below(100));
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    /// A tiny deterministic random number generator, so the tests don't need a dependency.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn test_fast_path_matches_slow_path() {
+        // ASCII whitespace of every kind, multi-byte characters and invalid UTF-8, weighted
+        // towards letters so there are long words to split
+        let pieces: &[&[u8]] = &[
+            b"a",
+            b"b",
+            b"c",
+            b"x",
+            b"yz",
+            b" ",
+            b"  ",
+            b"\t",
+            b"\n",
+            b"\r\n",
+            b"\x0b",
+            b"\x0c",
+            "é".as_bytes(),
+            "日本".as_bytes(),
+            b"\xff",
+            b"\xe6\x97",
+        ];
+        let mut rng = XorShift(0x5eed);
+        for _ in 0..100 {
+            let mut input = Vec::new();
+            let len = rng.below(3 * CHUNK_SIZE);
+            while input.len() < len {
+                // Long runs of one piece, so words (and gaps) can be longer than a read
+                let piece = pieces[rng.below(pieces.len())];
+                for _ in 0..1 + rng.below(8) {
+                    input.extend_from_slice(piece);
+                }
+            }
+            let slow = count(Cursor::new(&input)).unwrap();
+            let fast = Counter::new()
+                .lines_words_and_bytes_only()
+                .count(Cursor::new(&input))
+                .unwrap();
+            let trickled = Counter::new()
+                .lines_words_and_bytes_only()
+                .count(io::BufReader::new(ShortReads {
+                    data: Cursor::new(input.clone()),
+                    rng: XorShift(len as u64 + 1),
+                }))
+                .unwrap();
+            let expected = (slow.lines, slow.words, slow.bytes);
+            assert_eq!((fast.lines, fast.words, fast.bytes), expected);
+            assert_eq!((trickled.lines, trickled.words, trickled.bytes), expected);
+            // Lines split across reads are put back together
+            let trickled_slow = count(io::BufReader::new(ShortReads {
+                data: Cursor::new(input.clone()),
+                rng: XorShift(len as u64 + 2),
+            }))
+            .unwrap();
+            assert_eq!(trickled_slow, slow);
+        }
+    }
+
+    #[test]
+    fn test_running_count() {
+        let mut running = Counter::new().start();
+        running.update("héllo wor".as_bytes());
+        // The unfinished line is counted as if the input ended there
+        let counts = running.counts();
+        assert_eq!(
+            (counts.lines, counts.words, counts.chars, counts.bytes),
+            (0, 2, 9, 10)
+        );
+        running.update(b"ld\nsecond");
+        running.update(b" line\n");
+        let counts = running.counts();
+        assert_eq!(
+            (
+                counts.lines,
+                counts.words,
+                counts.chars,
+                counts.max_line_len
+            ),
+            (2, 4, 24, 11)
+        );
+        assert_eq!(
+            counts,
+            count(Cursor::new("héllo world\nsecond line\n")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_running_count_fast_path() {
+        let mut running = Counter::new().lines_words_and_bytes_only().start();
+        running.update(b"one tw");
+        assert_eq!(running.counts().words, 2);
+        // The word carries on into the next piece, so it isn't counted again
+        running.update(b"o three\n");
+        let counts = running.counts();
+        assert_eq!((counts.lines, counts.words, counts.bytes), (1, 3, 14));
+    }
+
+    #[test]
+    fn test_add() {
+        let a = Counts {
+            lines: 1,
+            words: 2,
+            chars: 3,
+            bytes: 4,
+            max_line_len: 10,
+            matching_lines: 1,
+            matches: 2,
+        };
+        let b = Counts {
+            lines: 10,
+            words: 20,
+            chars: 30,
+            bytes: 40,
+            max_line_len: 5,
+            matching_lines: 0,
+            matches: 1,
+        };
+        let total = Counts {
+            lines: 11,
+            words: 22,
+            chars: 33,
+            bytes: 44,
+            max_line_len: 10,
+            matching_lines: 1,
+            matches: 3,
+        };
+        assert_eq!(a + b, total);
+        let mut sum = Counts::default();
+        sum += a;
+        sum += b;
+        assert_eq!(sum, total);
+    }
+
+    #[test]
+    fn test_count_line_empty() {
+        assert_eq!(
+            count_line(""),
+            LineCounts {
+                words: 0,
+                chars: 0,
+                width: 0
+            }
+        );
+        assert_eq!(
+            count_line("   "),
+            LineCounts {
+                words: 0,
+                chars: 3,
+                width: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_count_line_consecutive_spaces() {
+        assert_eq!(
+            count_line("a  b"),
+            LineCounts {
+                words: 2,
+                chars: 4,
+                width: 4
+            }
+        );
+        assert_eq!(
+            count_line("abc acd"),
+            LineCounts {
+                words: 2,
+                chars: 7,
+                width: 7
+            }
+        );
+    }
+
+    #[test]
+    fn test_count_line_tabs() {
+        assert_eq!(
+            count_line("\ta\t\tb"),
+            LineCounts {
+                words: 2,
+                chars: 5,
+                width: 25
+            }
+        );
+    }
+
+    #[test]
+    fn test_count_line_leading_and_trailing_whitespace() {
+        assert_eq!(
+            count_line("  a b  "),
+            LineCounts {
+                words: 2,
+                chars: 7,
+                width: 7
+            }
+        );
+        assert_eq!(
+            count_line("word\t"),
+            LineCounts {
+                words: 1,
+                chars: 5,
+                width: 8
+            }
+        );
+    }
+
+    #[test]
+    fn test_count_line_punctuation_and_unicode() {
+        assert_eq!(
+            count_line("don't, stop!"),
+            LineCounts {
+                words: 2,
+                chars: 12,
+                width: 12
+            }
+        );
+        assert_eq!(
+            count_line("héllo wörld"),
+            LineCounts {
+                words: 2,
+                chars: 11,
+                width: 11
+            }
+        );
+    }
+
+    #[test]
+    fn test_count_line_width() {
+        // Tabs move to the next multiple of 8, wherever they start
+        assert_eq!(count_line("\t").width, 8);
+        assert_eq!(count_line("abc\tx").width, 9);
+        assert_eq!(count_line("abcdefg\t").width, 8);
+        assert_eq!(count_line("abcdefgh\t").width, 16);
+        // The trailing newline isn't part of the line
+        assert_eq!(count_line("abc\n").width, 3);
+        assert_eq!(count_line("\n").width, 0);
+        // A carriage return goes back to the start of the line
+        assert_eq!(count_line("abc\r\n").width, 3);
+        assert_eq!(count_line("abcdef\rxy\n").width, 6);
+        // Wide characters count as a single column
+        assert_eq!(count_line("日本語").width, 3);
+    }
+
+    #[test]
+    fn test_empty_file() {
+        assert_eq!(count(Cursor::new(&b""[..])).unwrap(), Counts::default());
+    }
+
+    #[test]
+    fn test_fixture_matches_wc() {
+        // `wc -lwmcL` prints 7 14 90 90 47 for this file
+        assert_eq!(
+            count_fixture("whitespace.txt"),
+            Counts {
+                lines: 7,
+                words: 14,
+                chars: 90,
+                bytes: 90,
+                max_line_len: 47,
+                matching_lines: 0,
+                matches: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_utf8_bytes_differ_from_chars() {
+        // `LC_ALL=C.UTF-8 wc -lwmcL` prints 3 7 32 44 15 for this file
+        assert_eq!(
+            count_fixture("utf8.txt"),
+            Counts {
+                lines: 3,
+                words: 7,
+                chars: 32,
+                bytes: 44,
+                max_line_len: 15,
+                matching_lines: 0,
+                matches: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_no_trailing_newline() {
+        // `wc -lwmcL` prints 1 4 24 24 12: the last line has no newline, so it isn't counted as a
+        // line, but it is the longest one
+        assert_eq!(
+            count_fixture("no_newline.txt"),
+            Counts {
+                lines: 1,
+                words: 4,
+                chars: 24,
+                bytes: 24,
+                max_line_len: 12,
+                matching_lines: 0,
+                matches: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_line_endings_match_wc() {
+        // `wc -lwmcL` for each file. Lines are newline characters, so `abc` with no newline is 0
+        // lines, and the CR of a CRLF is a character but not part of any word
+        let expected = [
+            ("empty.txt", [0, 0, 0, 0, 0]),
+            ("newline_only.txt", [1, 0, 1, 1, 0]),
+            ("abc_no_newline.txt", [0, 1, 3, 3, 3]),
+            ("crlf.txt", [3, 6, 31, 31, 13]),
+        ];
+        for (name, [lines, words, chars, bytes, max_line_len]) in expected.iter() {
+            assert_eq!(
+                count_fixture(name),
+                Counts {
+                    lines: *lines,
+                    words: *words,
+                    chars: *chars,
+                    bytes: *bytes,
+                    max_line_len: *max_line_len,
+                    matching_lines: 0,
+                    matches: 0
+                },
+                "{}",
+                name
+            );
+        }
+        assert_eq!(count_line("four five six\r\n").words, 3);
+    }
+
+    #[test]
+    fn test_count_matches() {
+        let pattern = Pattern::new("o", false, false).unwrap();
+        let counts = Counter::new()
+            .pattern(pattern)
+            .count(Cursor::new(&b"foo\r\nbar\nboo boo"[..]))
+            .unwrap();
+        assert_eq!((counts.matching_lines, counts.matches), (2, 6));
+        // The line ending isn't part of the line
+        let pattern = Pattern::new("o$", false, true).unwrap();
+        let counts = Counter::new()
+            .pattern(pattern)
+            .count(Cursor::new(&b"foo\r\nbar\nboo boo"[..]))
+            .unwrap();
+        assert_eq!((counts.matching_lines, counts.matches), (1, 2));
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        // 0xff and 0xfe can't appear in UTF-8; each becomes one replacement character, which is
+        // part of a word (GNU wc prints 2 2 6 8 here, as it skips invalid bytes)
+        let counts = count(Cursor::new(&b"a\xffb c\n\xfe\n"[..])).unwrap();
+        assert_eq!(
+            counts,
+            Counts {
+                lines: 2,
+                words: 3,
+                chars: 8,
+                bytes: 8,
+                max_line_len: 5,
+                matching_lines: 0,
+                matches: 0
+            }
+        );
+    }
+}
@@ -1,34 +1,850 @@
+mod output;
+mod watch;
+
+use output::{Format, Serializer};
+use rwc::gzip;
+use rwc::pattern::Pattern;
+use rwc::pool;
+use rwc::walker::Walker;
+use rwc::{Counter, Counts};
 use std::env;
-use std::fs::File;
-use std::io::{self, BufRead};
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::Path;
 use std::process;
+use std::thread;
+use std::time::Duration;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("Too few arguments.");
-        process::exit(1);
+const USAGE: &str = "Usage: rwc [-lwmcLr] [--follow-symlinks] [--format text|json|csv] \
+                     [--jobs <n>] [--no-decompress] \
+                     [--pattern <regex> [--ignore-case] [--invert]] \
+                     [<file>... | --files0-from <file> | --files-from <file> | \
+                     --watch [--interval <ms>] <file>]";
+/// The filename that stands for standard input, as in wc. With no filenames at all, standard
+/// input is counted too.
+const STDIN: &str = "-";
+/// Columns are at least this wide when standard input is counted, since its size isn't known up
+/// front (GNU wc does the same).
+const STDIN_WIDTH: usize = 7;
+/// Without `--jobs`, files are only counted in parallel when there are more than this many.
+const PARALLEL_THRESHOLD: usize = 4;
+/// How often `--watch` checks the file, without `--interval`.
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(1000);
+/// Which counts to print.
+#[derive(Debug, PartialEq)]
+struct Columns {
+    lines: bool,
+    words: bool,
+    chars: bool,
+    bytes: bool,
+    max_line_len: bool,
+    matching_lines: bool,
+    matches: bool,
+}
+
+impl Columns {
+    /// What wc prints when no columns are requested: lines, words and bytes.
+    const DEFAULT: Columns = Columns {
+        lines: true,
+        words: true,
+        chars: false,
+        bytes: true,
+        max_line_len: false,
+        matching_lines: false,
+        matches: false,
+    };
+    const NONE: Columns = Columns {
+        lines: false,
+        words: false,
+        chars: false,
+        bytes: false,
+        max_line_len: false,
+        matching_lines: false,
+        matches: false,
+    };
+
+    /// Returns the selected counts, in the order wc prints them.
+    fn select(&self, counts: &Counts) -> Vec<usize> {
+        let mut values = Vec::new();
+        if self.lines {
+            values.push(counts.lines);
+        }
+        if self.words {
+            values.push(counts.words);
+        }
+        if self.chars {
+            values.push(counts.chars);
+        }
+        if self.bytes {
+            values.push(counts.bytes);
+        }
+        if self.max_line_len {
+            values.push(counts.max_line_len);
+        }
+        if self.matching_lines {
+            values.push(counts.matching_lines);
+        }
+        if self.matches {
+            values.push(counts.matches);
+        }
+        values
+    }
+}
+
+/// A file holding the names of the files to count, for `--files0-from` and `--files-from`.
+#[derive(Debug, PartialEq)]
+struct FileList {
+    /// Where to read the names from, or `STDIN`
+    path: String,
+    /// What ends each name: NUL for `--files0-from`, newline for `--files-from`
+    terminator: u8,
+}
+
+impl FileList {
+    /// The option the list was given with, for error messages.
+    fn option(&self) -> &'static str {
+        if self.terminator == b'\0' {
+            "--files0-from"
+        } else {
+            "--files-from"
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    columns: Columns,
+    filenames: Vec<String>,
+    /// Read the names of the files to count from here, instead of from `filenames`
+    file_list: Option<FileList>,
+    /// Count the files inside directory arguments, instead of reporting them as errors
+    recursive: bool,
+    /// Walk into symlinked directories when recursing
+    follow_symlinks: bool,
+    format: Format,
+    /// How many files to count at once, or None to decide based on the number of files
+    jobs: Option<usize>,
+    /// Count the decompressed contents of gzip files, rather than the compressed bytes
+    decompress: bool,
+    /// Also count the lines matching this, and the matches
+    pattern: Option<Pattern>,
+    /// Keep counting the one file as it grows, until interrupted
+    watch: bool,
+    /// How often to check the watched file
+    interval: Duration,
+}
+
+/// Returns the value of a long option: the part after the `=` if there is one, otherwise the next
+/// argument.
+fn option_value(
+    option: &str,
+    value: Option<String>,
+    args: &mut std::slice::Iter<String>,
+) -> Result<String, String> {
+    value
+        .or_else(|| args.next().cloned())
+        .ok_or_else(|| format!("option '{}' requires an argument", option))
+}
+
+/// Parses the command-line arguments (not including the program name). Flags may be combined, as
+/// in `-lw`.
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut columns = Columns::NONE;
+    let mut filenames = Vec::new();
+    let mut recursive = false;
+    let mut follow_symlinks = false;
+    let mut format = Format::Text;
+    let mut jobs = None;
+    let mut decompress = true;
+    let mut pattern = None;
+    let mut ignore_case = false;
+    let mut invert = false;
+    let mut file_list: Option<FileList> = None;
+    let mut watch = false;
+    let mut interval = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg.starts_with("--") {
+            // Options that take a value accept it either as `--option=value` or `--option value`
+            let (option, value) = match arg.find('=') {
+                Some(idx) => (&arg[..idx], Some(arg[idx + 1..].to_string())),
+                None => (arg.as_str(), None),
+            };
+            match option {
+                "--recursive" => recursive = true,
+                "--follow-symlinks" => follow_symlinks = true,
+                "--no-decompress" => decompress = false,
+                "--pattern" | "--count-matches" => {
+                    pattern = Some(option_value(option, value, &mut args)?)
+                }
+                "--ignore-case" => ignore_case = true,
+                "--files0-from" | "--files-from" => {
+                    let path = option_value(option, value, &mut args)?;
+                    if let Some(list) = &file_list {
+                        return Err(format!(
+                            "{} and {} can't be combined",
+                            list.option(),
+                            option
+                        ));
+                    }
+                    let terminator = if option == "--files0-from" {
+                        b'\0'
+                    } else {
+                        b'\n'
+                    };
+                    file_list = Some(FileList { path, terminator });
+                }
+                "--invert" => invert = true,
+                "--watch" => watch = true,
+                "--interval" => {
+                    let value = option_value(option, value, &mut args)?;
+                    interval = match value.parse() {
+                        Ok(0) | Err(_) => return Err(format!("invalid interval '{}'", value)),
+                        Ok(ms) => Some(Duration::from_millis(ms)),
+                    };
+                }
+                "--format" => {
+                    let value = option_value(option, value, &mut args)?;
+                    format = match Format::parse(&value) {
+                        Some(format) => format,
+                        None => return Err(format!("invalid output format '{}'", value)),
+                    };
+                }
+                "--jobs" => {
+                    let value = option_value(option, value, &mut args)?;
+                    jobs = match value.parse() {
+                        Ok(0) | Err(_) => {
+                            return Err(format!("invalid number of jobs '{}'", value))
+                        }
+                        Ok(n) => Some(n),
+                    };
+                }
+                _ => return Err(format!("unrecognized option '{}'", arg)),
+            }
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            for flag in arg[1..].chars() {
+                match flag {
+                    'l' => columns.lines = true,
+                    'w' => columns.words = true,
+                    'm' => columns.chars = true,
+                    'c' => columns.bytes = true,
+                    'L' => columns.max_line_len = true,
+                    'r' => recursive = true,
+                    _ => return Err(format!("invalid option -- '{}'", flag)),
+                }
+            }
+        } else {
+            filenames.push(arg.clone());
+        }
     }
-    let filename = &args[1];
+    // As in GNU wc, the files to count come from one place or the other
+    if let (Some(list), Some(filename)) = (&file_list, filenames.first()) {
+        return Err(format!(
+            "extra operand '{}'\nfile operands cannot be combined with {}",
+            filename,
+            list.option()
+        ));
+    }
+    // Only a single, named file can be watched, and only its row is printed, so it's always text
+    if watch {
+        if file_list.is_some() || filenames.len() != 1 || filenames[0] == STDIN {
+            return Err(String::from("--watch needs exactly one file"));
+        }
+        if format != Format::Text {
+            return Err(String::from("--watch only supports the text format"));
+        }
+    } else if interval.is_some() {
+        return Err(String::from("--interval needs --watch"));
+    }
+    let pattern = match pattern {
+        Some(pattern) => Some(
+            Pattern::new(&pattern, ignore_case, invert)
+                .map_err(|err| format!("invalid pattern: {}", err))?,
+        ),
+        None if ignore_case || invert => {
+            return Err(String::from("--ignore-case and --invert need a --pattern"))
+        }
+        None => None,
+    };
+    // The match counts come after any other counts asked for, or on their own if there are none
+    if columns == Columns::NONE && pattern.is_none() {
+        columns = Columns::DEFAULT;
+    }
+    if pattern.is_some() {
+        columns.matching_lines = true;
+        columns.matches = true;
+    }
+    Ok(Options {
+        columns,
+        filenames,
+        file_list,
+        recursive,
+        follow_symlinks,
+        format,
+        jobs,
+        decompress,
+        pattern,
+        watch,
+        interval: interval.unwrap_or(DEFAULT_INTERVAL),
+    })
+}
 
-    // Your code here :)
+/// Reads the names in a file list. As with GNU wc's `--files0-from`, the last name doesn't need a
+/// terminator, an empty name is an error, and so is `-` when the list itself is standard input.
+/// Each entry is either a name or an error message; an error reading the list itself is returned
+/// as the error.
+fn read_file_list(list: &FileList) -> Result<Vec<Result<String, String>>, String> {
+    let mut contents = Vec::new();
+    let result = if list.path == STDIN {
+        io::stdin().lock().read_to_end(&mut contents)
+    } else {
+        File::open(&list.path).and_then(|mut file| file.read_to_end(&mut contents))
+    };
+    result.map_err(|err| format!("cannot open '{}' for reading: {}", list.path, err))?;
+    let mut names: Vec<&[u8]> = contents.split(|&byte| byte == list.terminator).collect();
+    if names.last().is_some_and(|name| name.is_empty()) {
+        names.pop();
+    }
+    let names = names.into_iter().enumerate().map(|(idx, name)| {
+        let entry = format!("{}:{}", list.path, idx + 1);
+        if name.is_empty() {
+            return Err(format!("{}: invalid zero-length file name", entry));
+        }
+        let name = String::from_utf8(name.to_vec())
+            .map_err(|_| format!("{}: file name isn't valid UTF-8", entry))?;
+        if name == STDIN && list.path == STDIN {
+            return Err(String::from(
+                "when reading file names from stdin, no file name of '-' allowed",
+            ));
+        }
+        Ok(name)
+    });
+    Ok(names.collect())
+}
 
-    let file = File::open(filename).expect("Invalid file name");
-    let mut lines_cnt = 0;
-    let mut letters_cnt = 0;
-    let mut words_cnt = 0;
-    for line in io::BufReader::new(file).lines() {
-        let line_str = line.unwrap();
-        println!("lines: {:?}", line_str);
-        lines_cnt += 1;
-        for letter in line_str.chars().map(|c| c.to_string()) {
-            if letter == " " {
-                words_cnt += 1;
-            } else {
-                letters_cnt += 1;
+/// Turns the filename arguments (or the names in the file list) into the list of files to count,
+/// walking directories if `options.recursive` is set. Each entry is either a file to count or an
+/// error message for a name or directory entry that can't be counted. Returns an error if the
+/// file list can't be read.
+fn expand_inputs(options: &Options) -> Result<Vec<Result<String, String>>, String> {
+    let names = match &options.file_list {
+        Some(list) => read_file_list(list)?,
+        None if options.filenames.is_empty() => return Ok(vec![Ok(String::from(STDIN))]),
+        None => options.filenames.iter().cloned().map(Ok).collect(),
+    };
+    let mut inputs = Vec::new();
+    for name in names {
+        let filename = match name {
+            Ok(filename) => filename,
+            Err(err) => {
+                inputs.push(Err(err));
+                continue;
             }
+        };
+        if filename == STDIN || !fs::metadata(&filename).is_ok_and(|metadata| metadata.is_dir()) {
+            inputs.push(Ok(filename));
+        } else if options.recursive {
+            inputs.extend(
+                Walker::new(Path::new(&filename), options.follow_symlinks).map(|result| {
+                    result
+                        .map(|path| path.display().to_string())
+                        .map_err(|err| err.to_string())
+                }),
+            );
+        } else {
+            inputs.push(Err(format!("{}: Is a directory", filename)));
         }
-        words_cnt += 1;
     }
-    println!("lines: {lines_cnt}, words: {words_cnt}, letters: {letters_cnt}");
+    Ok(inputs)
+}
+
+/// Returns how many bytes will be counted for `filename`, or None if it isn't a regular file (or
+/// can't be read). For gzip files being decompressed, that's the decompressed size (which means
+/// decompressing them twice), or None if the stream is corrupt (it will fail when counted).
+fn counted_size(filename: &str, decompress: bool) -> Option<u64> {
+    let metadata = fs::metadata(filename)
+        .ok()
+        .filter(|metadata| metadata.is_file())?;
+    if decompress {
+        let mut file = File::open(filename).ok()?;
+        if gzip::is_gzip(&mut file).ok()? {
+            return gzip::uncompressed_size(&mut file).ok();
+        }
+    }
+    Some(metadata.len())
+}
+
+/// Returns the width of each output column. Like GNU wc, this is the number of digits in the
+/// combined size of the regular files being counted: no count can be larger than that, so every
+/// row (including the total) lines up even though rows are printed as each file finishes. A lone
+/// column for a lone file isn't padded at all.
+fn column_width(filenames: &[String], columns: &Columns, decompress: bool) -> usize {
+    if filenames.len() == 1 && columns.select(&Counts::default()).len() == 1 {
+        return 1;
+    }
+    let total_size: u64 = filenames
+        .iter()
+        .filter(|filename| filename.as_str() != STDIN)
+        .filter_map(|filename| counted_size(filename, decompress))
+        .sum();
+    let width = total_size.to_string().len();
+    if filenames.iter().any(|filename| filename == STDIN) {
+        width.max(STDIN_WIDTH)
+    } else {
+        width
+    }
+}
+
+/// Opens and counts one file, or standard input for `STDIN`. If `decompress` is set and the file
+/// is gzip-compressed, its decompressed contents are counted instead, so `-c` is the number of
+/// bytes after decompression (which is what `zcat file | wc -c` would print), not the size of the
+/// file on disk. Standard input is always counted as it is.
+fn count_file(filename: &str, decompress: bool, counter: &Counter) -> io::Result<Counts> {
+    if filename == STDIN {
+        return counter.count(io::stdin().lock());
+    }
+    let mut file = File::open(filename)?;
+    if decompress && gzip::is_gzip(&mut file)? {
+        return gzip::decompress(file, |reader| counter.count(reader));
+    }
+    counter.count(io::BufReader::new(file))
+}
+
+/// Returns a counter for everything `options` needs.
+fn counter(options: &Options) -> Counter {
+    let columns = &options.columns;
+    let mut counter = Counter::new();
+    // JSON and CSV always show every count. Otherwise only -m and -L need the input decoded, and
+    // without them words are split on ASCII whitespace.
+    if options.format == Format::Text && !(columns.chars || columns.max_line_len) {
+        counter = if columns.words {
+            counter.lines_words_and_bytes_only()
+        } else {
+            counter.lines_and_bytes_only()
+        };
+    }
+    if let Some(pattern) = &options.pattern {
+        counter = counter.pattern(pattern.clone());
+    }
+    counter
+}
+
+/// Counts every input, printing a row for each one that succeeds and an error for each one that
+/// doesn't. Returns whether every input was counted, or an error if the output can't be written.
+fn run<W: Write>(options: &Options, output: &mut W) -> io::Result<bool> {
+    let columns = &options.columns;
+    let inputs = match expand_inputs(options) {
+        Ok(inputs) => inputs,
+        Err(err) => {
+            eprintln!("rwc: {}", err);
+            return Ok(false);
+        }
+    };
+    let filenames: Vec<String> = inputs
+        .iter()
+        .filter_map(|input| input.clone().ok())
+        .collect();
+    let serializer = Serializer::new(
+        options.format,
+        columns,
+        column_width(&filenames, columns, options.decompress),
+    );
+    let jobs = options.jobs.unwrap_or_else(|| {
+        if filenames.len() > PARALLEL_THRESHOLD {
+            thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            1
+        }
+    });
+    // An empty file list still gets a (zero) total, so there's always something to read
+    let multiple = inputs.len() > 1 || (options.file_list.is_some() && inputs.is_empty());
+    // Rows are printed in the order of the inputs, whichever order the files finish in
+    let decompress = options.decompress;
+    let counter = counter(options);
+    let results = pool::map_ordered(inputs, jobs, move |input| {
+        input.map(|filename| {
+            let result = count_file(&filename, decompress, &counter);
+            (filename, result)
+        })
+    });
+    // Standard input is left unnamed when it's counted because there were no filenames
+    let unnamed = options.filenames.is_empty() && options.file_list.is_none();
+
+    write!(output, "{}", serializer.begin())?;
+    let mut total = Counts::default();
+    let mut succeeded = true;
+    for result in results {
+        match result {
+            Ok((filename, Ok(counts))) => {
+                let path = if unnamed {
+                    None
+                } else {
+                    Some(filename.as_str())
+                };
+                write!(output, "{}", serializer.row(path, &counts))?;
+                total += counts;
+            }
+            Ok((filename, Err(err))) => {
+                eprintln!("rwc: {}: {}", filename, err);
+                succeeded = false;
+            }
+            Err(err) => {
+                eprintln!("rwc: {}", err);
+                succeeded = false;
+            }
+        }
+    }
+    write!(output, "{}", serializer.end(&total, multiple))?;
+    output.flush()?;
+    Ok(succeeded)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("rwc: {}\n{}", err, USAGE);
+            process::exit(1);
+        }
+    };
+
+    // Like coreutils, exit with 0 only if every input was counted and printed
+    let stdout = io::stdout();
+    let result = if options.watch {
+        // The file will grow, so its columns are as wide as for standard input. On a terminal,
+        // each new row replaces the last.
+        let columns = &options.columns;
+        let width = column_width(&options.filenames, columns, false).max(STDIN_WIDTH);
+        watch::watch(
+            &options.filenames[0],
+            &counter(&options),
+            &Serializer::new(Format::Text, columns, width),
+            options.interval,
+            stdout.is_terminal(),
+            &mut stdout.lock(),
+        )
+    } else {
+        run(&options, &mut stdout.lock())
+    };
+    match result {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        // Whoever was reading the output has gone away, so there's no one to tell
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => process::exit(1),
+        Err(err) => {
+            eprintln!("rwc: write error: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn count_fixture(name: &str) -> Counts {
+        rwc::count(io::BufReader::new(File::open(fixture(name)).unwrap())).unwrap()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_gzip() {
+        // The decompressed contents are counted, including -c
+        let whitespace = count_fixture("whitespace.txt");
+        assert_eq!(
+            count_file(&fixture("whitespace.txt.gz"), true, &Counter::new()).unwrap(),
+            whitespace
+        );
+        // unless decompression is turned off
+        let raw = count_file(&fixture("whitespace.txt.gz"), false, &Counter::new()).unwrap();
+        assert_eq!(raw.bytes, 97);
+        // A plain text file with a .gz name is counted as it is
+        assert_eq!(
+            count_file(&fixture("plain.txt.gz"), true, &Counter::new()).unwrap(),
+            count_file(&fixture("plain.txt.gz"), false, &Counter::new()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pattern_columns() {
+        // Only the match counts, unless other counts are asked for too
+        let options = parse_args(&args(&["--pattern", "a", "f"])).unwrap();
+        assert_eq!(
+            options.pattern,
+            Some(Pattern::new("a", false, false).unwrap())
+        );
+        assert_eq!(
+            options.columns,
+            Columns {
+                matching_lines: true,
+                matches: true,
+                ..Columns::NONE
+            }
+        );
+        let options = parse_args(&args(&["-l", "--pattern=a", "--ignore-case", "f"])).unwrap();
+        assert_eq!(
+            options.pattern,
+            Some(Pattern::new("a", true, false).unwrap())
+        );
+        assert_eq!(
+            options.columns,
+            Columns {
+                lines: true,
+                matching_lines: true,
+                matches: true,
+                ..Columns::NONE
+            }
+        );
+    }
+
+    #[test]
+    fn test_counter() {
+        let input = "one two\nthree\n";
+        let counts = counter(&parse_args(&args(&["-lc", "f"])).unwrap())
+            .count(input.as_bytes())
+            .unwrap();
+        assert_eq!((counts.lines, counts.words, counts.bytes), (2, 0, 14));
+        // Without -m or -L, words are split on ASCII whitespace only
+        let input = "one\u{a0}two\n";
+        let counts = counter(&parse_args(&args(&["f"])).unwrap())
+            .count(input.as_bytes())
+            .unwrap();
+        assert_eq!((counts.lines, counts.words, counts.chars), (1, 1, 0));
+        let counts = counter(&parse_args(&args(&["-wm", "f"])).unwrap())
+            .count(input.as_bytes())
+            .unwrap();
+        assert_eq!((counts.words, counts.chars), (2, 8));
+        let input = "one two\nthree\n";
+        let counts = counter(&parse_args(&args(&["-lc", "--format=json", "f"])).unwrap())
+            .count(input.as_bytes())
+            .unwrap();
+        assert_eq!((counts.lines, counts.words, counts.bytes), (2, 3, 14));
+        let counts = counter(&parse_args(&args(&["-c", "--pattern", "o", "f"])).unwrap())
+            .count(input.as_bytes())
+            .unwrap();
+        assert_eq!((counts.bytes, counts.matches), (14, 2));
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            parse_args(&args(&["test.txt"])),
+            Ok(Options {
+                columns: Columns::DEFAULT,
+                filenames: vec![String::from("test.txt")],
+                file_list: None,
+                recursive: false,
+                follow_symlinks: false,
+                format: Format::Text,
+                jobs: None,
+                decompress: true,
+                pattern: None,
+                watch: false,
+                interval: DEFAULT_INTERVAL,
+            })
+        );
+        let columns = parse_args(&args(&["-lw", "test.txt"])).unwrap().columns;
+        assert_eq!(
+            columns,
+            Columns {
+                lines: true,
+                words: true,
+                chars: false,
+                bytes: false,
+                max_line_len: false,
+                matching_lines: false,
+                matches: false
+            }
+        );
+        let columns = parse_args(&args(&["test.txt", "-m", "-c"]))
+            .unwrap()
+            .columns;
+        assert_eq!(
+            columns,
+            Columns {
+                lines: false,
+                words: false,
+                chars: true,
+                bytes: true,
+                max_line_len: false,
+                matching_lines: false,
+                matches: false
+            }
+        );
+        let options = parse_args(&args(&["a.txt", "-l", "b.txt"])).unwrap();
+        assert_eq!(options.filenames, vec!["a.txt", "b.txt"]);
+        let options = parse_args(&args(&["-rl", "--follow-symlinks", "dir"])).unwrap();
+        assert!(options.recursive && options.follow_symlinks);
+        assert!(options.columns.lines);
+        assert!(
+            parse_args(&args(&["--recursive", "dir"]))
+                .unwrap()
+                .recursive
+        );
+        assert_eq!(
+            parse_args(&args(&["--format", "json", "f"]))
+                .unwrap()
+                .format,
+            Format::Json
+        );
+        assert_eq!(
+            parse_args(&args(&["--format=csv", "f"])).unwrap().format,
+            Format::Csv
+        );
+        assert!(parse_args(&args(&["--format=xml", "f"])).is_err());
+        assert!(parse_args(&args(&["f", "--format"])).is_err());
+        assert_eq!(
+            parse_args(&args(&["--jobs", "8", "f"])).unwrap().jobs,
+            Some(8)
+        );
+        assert!(parse_args(&args(&["--jobs=0", "f"])).is_err());
+        assert!(parse_args(&args(&["--jobs=many", "f"])).is_err());
+        assert!(
+            !parse_args(&args(&["--no-decompress", "f"]))
+                .unwrap()
+                .decompress
+        );
+        assert!(parse_args(&args(&["--bogus", "test.txt"])).is_err());
+        assert!(parse_args(&args(&["--invert", "test.txt"])).is_err());
+        let err = parse_args(&args(&["--pattern", "(", "test.txt"])).unwrap_err();
+        assert!(err.starts_with("invalid pattern: "), "{}", err);
+        assert!(parse_args(&args(&["-x", "test.txt"])).is_err());
+        // With no filenames, standard input is counted
+        assert!(parse_args(&args(&["-l"])).unwrap().filenames.is_empty());
+    }
+
+    #[test]
+    fn test_parse_file_list() {
+        assert_eq!(
+            parse_args(&args(&["--files0-from", "-"]))
+                .unwrap()
+                .file_list,
+            Some(FileList {
+                path: String::from("-"),
+                terminator: b'\0'
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["-l", "--files-from=list.txt"]))
+                .unwrap()
+                .file_list,
+            Some(FileList {
+                path: String::from("list.txt"),
+                terminator: b'\n'
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["--files0-from", "-", "a.txt", "b.txt"])),
+            Err(String::from(
+                "extra operand 'a.txt'\nfile operands cannot be combined with --files0-from"
+            ))
+        );
+        assert!(parse_args(&args(&["--files0-from=a", "--files-from=b"])).is_err());
+        assert!(parse_args(&args(&["--files-from"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_watch() {
+        let options = parse_args(&args(&["--watch", "--interval=50", "-l", "log.txt"])).unwrap();
+        assert!(options.watch);
+        assert_eq!(options.interval, Duration::from_millis(50));
+        assert_eq!(options.filenames, vec!["log.txt"]);
+        assert!(parse_args(&args(&["--watch", "log.txt"])).is_ok());
+        // Exactly one named file, in the text format
+        assert!(parse_args(&args(&["--watch"])).is_err());
+        assert!(parse_args(&args(&["--watch", "-"])).is_err());
+        assert!(parse_args(&args(&["--watch", "a.txt", "b.txt"])).is_err());
+        assert!(parse_args(&args(&["--watch", "--files-from", "list"])).is_err());
+        assert!(parse_args(&args(&["--watch", "--format=json", "log.txt"])).is_err());
+        assert!(parse_args(&args(&["--watch", "--interval", "0", "log.txt"])).is_err());
+        assert!(parse_args(&args(&["--interval", "50", "log.txt"])).is_err());
+    }
+
+    #[test]
+    fn test_read_file_list() {
+        let path = std::env::temp_dir().join(format!("rwc-list-{}", std::process::id()));
+        let read = |contents: &[u8], terminator: u8| {
+            fs::write(&path, contents).unwrap();
+            read_file_list(&FileList {
+                path: path.display().to_string(),
+                terminator,
+            })
+        };
+        let names = |names: &[&str]| -> Vec<Result<String, String>> {
+            names.iter().map(|name| Ok(name.to_string())).collect()
+        };
+        // The last terminator is optional, and only a NUL list can hold a newline in a name
+        assert_eq!(read(b"a\0b\nc\0", b'\0'), Ok(names(&["a", "b\nc"])));
+        assert_eq!(read(b"a\0b\nc", b'\0'), Ok(names(&["a", "b\nc"])));
+        assert_eq!(read(b"a\nb c\n", b'\n'), Ok(names(&["a", "b c"])));
+        assert_eq!(read(b"", b'\0'), Ok(Vec::new()));
+        // Empty names are errors, but the names after them are still read
+        let entries = read(b"a\0\0b", b'\0').unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[1],
+            Err(format!(
+                "{}:2: invalid zero-length file name",
+                path.display()
+            ))
+        );
+        assert_eq!(entries[2], Ok(String::from("b")));
+        // A list that names standard input is fine when the list isn't read from it
+        assert_eq!(read(b"-\0", b'\0'), Ok(names(&["-"])));
+        let err = read_file_list(&FileList {
+            path: String::from("/nonexistent/list"),
+            terminator: b'\0',
+        })
+        .unwrap_err();
+        assert!(
+            err.starts_with("cannot open '/nonexistent/list' for reading: "),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_column_width() {
+        let utf8 = fixture("utf8.txt");
+        let whitespace = fixture("whitespace.txt");
+        let missing = String::from("/nonexistent/rwc.txt");
+        let stdin = String::from(STDIN);
+        let columns = parse_args(&args(&["f"])).unwrap().columns;
+        // 44 bytes, then 44 + 90 bytes
+        assert_eq!(
+            column_width(&[utf8.clone(), missing.clone()], &columns, true),
+            2
+        );
+        assert_eq!(
+            column_width(&[utf8.clone(), whitespace.clone()], &columns, true),
+            3
+        );
+        // A single column for a single file isn't padded, but one for several files is
+        let columns = parse_args(&args(&["-m", "f"])).unwrap().columns;
+        assert_eq!(column_width(&[missing], &columns, true), 1);
+        assert_eq!(
+            column_width(&[stdin.clone(), utf8.clone()], &columns, true),
+            7
+        );
+        assert_eq!(column_width(&[stdin], &columns, true), 1);
+        assert_eq!(column_width(&[utf8, whitespace], &columns, true), 3);
+    }
+
+    #[test]
+    fn test_counted_size() {
+        // Compressed files are measured by their decompressed size
+        let gzipped = fixture("whitespace.txt.gz");
+        assert_eq!(counted_size(&gzipped, true), Some(90));
+        assert_eq!(counted_size(&gzipped, false), Some(97));
+        assert_eq!(counted_size(&fixture("plain.txt.gz"), true), Some(24));
+        assert_eq!(counted_size(&fixture(""), true), None);
+    }
 }
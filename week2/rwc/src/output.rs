@@ -0,0 +1,260 @@
+// Turning counts into output rows, in wc's text format or in JSON or CSV for scripts.
+use crate::{Columns, STDIN};
+use rwc::Counts;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// wc's right-aligned columns
+    Text,
+    /// An array with one object per file, then the total
+    Json,
+    /// A header row, then one row per file
+    Csv,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// Escapes `s` as a JSON string, including the quotes.
+fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Quotes `s` as a CSV field if it needs it, doubling any quotes inside.
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Produces the output a piece at a time, so that each row can be printed as soon as its file has
+/// been counted. The text format only shows the selected columns; JSON and CSV always include
+/// every count (plus the match counts when there is a `--pattern`), so scripts can rely on their
+/// shape.
+pub struct Serializer<'a> {
+    format: Format,
+    columns: &'a Columns,
+    /// Width of each column in the text format
+    width: usize,
+}
+
+impl<'a> Serializer<'a> {
+    pub fn new(format: Format, columns: &'a Columns, width: usize) -> Serializer<'a> {
+        Serializer {
+            format,
+            columns,
+            width,
+        }
+    }
+
+    /// The counts that JSON and CSV include, with their names, in wc's column order.
+    fn fields(&self, counts: &Counts) -> Vec<(&'static str, usize)> {
+        let mut fields = vec![
+            ("lines", counts.lines),
+            ("words", counts.words),
+            ("chars", counts.chars),
+            ("bytes", counts.bytes),
+            ("max_line_length", counts.max_line_len),
+        ];
+        if self.columns.matches {
+            fields.push(("matching_lines", counts.matching_lines));
+            fields.push(("matches", counts.matches));
+        }
+        fields
+    }
+
+    /// The JSON members for every field.
+    fn json_fields(&self, counts: &Counts) -> String {
+        let members: Vec<String> = self
+            .fields(counts)
+            .iter()
+            .map(|(name, value)| format!("\"{}\": {}", name, value))
+            .collect();
+        members.join(", ")
+    }
+
+    /// Returns what comes before the first row.
+    pub fn begin(&self) -> String {
+        match self.format {
+            Format::Text => String::new(),
+            Format::Json => String::from("[\n"),
+            Format::Csv => {
+                let mut header = vec!["path"];
+                header.extend(self.fields(&Counts::default()).iter().map(|field| field.0));
+                format!("{}\n", header.join(","))
+            }
+        }
+    }
+
+    /// Returns the row for one file. A `path` of None is standard input when no files were named,
+    /// which (as in wc) gets no name in the text format.
+    pub fn row(&self, path: Option<&str>, counts: &Counts) -> String {
+        match self.format {
+            Format::Text => {
+                let mut values: Vec<String> = self
+                    .columns
+                    .select(counts)
+                    .iter()
+                    .map(|value| format!("{:>width$}", value, width = self.width))
+                    .collect();
+                values.extend(path.map(String::from));
+                format!("{}\n", values.join(" "))
+            }
+            // The total always follows, so every file needs a comma
+            Format::Json => format!(
+                "  {{\"path\": {}, {}}},\n",
+                json_string(path.unwrap_or(STDIN)),
+                self.json_fields(counts)
+            ),
+            Format::Csv => {
+                let mut values = vec![csv_field(path.unwrap_or(STDIN))];
+                values.extend(self.fields(counts).iter().map(|field| field.1.to_string()));
+                format!("{}\n", values.join(","))
+            }
+        }
+    }
+
+    /// Returns what comes after the last row. Like wc, the text format only shows the total if
+    /// there were `multiple` inputs; JSON always ends with it, and CSV never has one.
+    pub fn end(&self, total: &Counts, multiple: bool) -> String {
+        match self.format {
+            Format::Text if multiple => self.row(Some("total"), total),
+            Format::Text | Format::Csv => String::new(),
+            Format::Json => format!("  {{\"total\": {{{}}}}}\n]\n", self.json_fields(total)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const COUNTS: Counts = Counts {
+        lines: 3,
+        words: 7,
+        chars: 32,
+        bytes: 44,
+        max_line_len: 15,
+        matching_lines: 2,
+        matches: 5,
+    };
+
+    /// Serializes one file followed by the total, as a single string.
+    fn serialize(format: Format, columns: &Columns, path: &str) -> String {
+        let serializer = Serializer::new(format, columns, 2);
+        serializer.begin() + &serializer.row(Some(path), &COUNTS) + &serializer.end(&COUNTS, true)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Format::parse("json"), Some(Format::Json));
+        assert_eq!(Format::parse("csv"), Some(Format::Csv));
+        assert_eq!(Format::parse("text"), Some(Format::Text));
+        assert_eq!(Format::parse("xml"), None);
+    }
+
+    #[test]
+    fn test_text() {
+        let serializer = Serializer::new(Format::Text, &Columns::DEFAULT, 2);
+        assert_eq!(serializer.begin(), "");
+        assert_eq!(
+            serializer.row(Some("utf8.txt"), &COUNTS),
+            " 3  7 44 utf8.txt\n"
+        );
+        // Standard input has no name
+        assert_eq!(serializer.row(None, &COUNTS), " 3  7 44\n");
+        assert_eq!(serializer.end(&COUNTS, false), "");
+        assert_eq!(serializer.end(&COUNTS, true), " 3  7 44 total\n");
+        // Columns are always in wc's order, whatever order the flags are given in
+        let columns = Columns {
+            lines: true,
+            bytes: true,
+            max_line_len: true,
+            ..Columns::NONE
+        };
+        let serializer = Serializer::new(Format::Text, &columns, 3);
+        assert_eq!(serializer.row(Some("f"), &COUNTS), "  3  44  15 f\n");
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            serialize(Format::Json, &Columns::DEFAULT, "a.txt"),
+            "[\n\
+             \x20 {\"path\": \"a.txt\", \"lines\": 3, \"words\": 7, \"chars\": 32, \"bytes\": 44, \"max_line_length\": 15},\n\
+             \x20 {\"total\": {\"lines\": 3, \"words\": 7, \"chars\": 32, \"bytes\": 44, \"max_line_length\": 15}}\n\
+             ]\n"
+        );
+    }
+
+    #[test]
+    fn test_pattern_fields() {
+        let columns = Columns {
+            matching_lines: true,
+            matches: true,
+            ..Columns::NONE
+        };
+        let serializer = Serializer::new(Format::Json, &columns, 1);
+        assert_eq!(
+            serializer.row(None, &COUNTS),
+            "  {\"path\": \"-\", \"lines\": 3, \"words\": 7, \"chars\": 32, \"bytes\": 44, \"max_line_length\": 15, \"matching_lines\": 2, \"matches\": 5},\n"
+        );
+        assert_eq!(
+            serialize(Format::Csv, &columns, "a.txt"),
+            "path,lines,words,chars,bytes,max_line_length,matching_lines,matches\n\
+             a.txt,3,7,32,44,15,2,5\n"
+        );
+        let serializer = Serializer::new(Format::Text, &columns, 1);
+        assert_eq!(serializer.row(Some("a.txt"), &COUNTS), "2 5 a.txt\n");
+    }
+
+    #[test]
+    fn test_json_escaping() {
+        assert_eq!(json_string("plain.txt"), "\"plain.txt\"");
+        assert_eq!(
+            json_string("C:\\dir\\\"quoted\".txt"),
+            "\"C:\\\\dir\\\\\\\"quoted\\\".txt\""
+        );
+        assert_eq!(json_string("new\nline\u{1}"), "\"new\\nline\\u0001\"");
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(
+            serialize(Format::Csv, &Columns::DEFAULT, "a.txt"),
+            "path,lines,words,chars,bytes,max_line_length\n\
+             a.txt,3,7,32,44,15\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_escaping() {
+        assert_eq!(csv_field("plain.txt"), "plain.txt");
+        assert_eq!(csv_field("a,b.txt"), "\"a,b.txt\"");
+        assert_eq!(csv_field("say \"hi\".txt"), "\"say \"\"hi\"\".txt\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+}
@@ -0,0 +1,148 @@
+// Counting the lines that match a regular expression, for `rwc --pattern`.
+use regex::{Regex, RegexBuilder};
+
+/// A compiled `--pattern`, along with the modifiers that change how it matches.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    regex: Regex,
+    ignore_case: bool,
+    /// Count the lines that don't match, instead of the ones that do
+    invert: bool,
+}
+
+/// Two patterns are the same if they were compiled from the same source with the same options.
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.regex.as_str() == other.regex.as_str()
+            && self.ignore_case == other.ignore_case
+            && self.invert == other.invert
+    }
+}
+
+/// How one line matched.
+#[derive(Debug, PartialEq)]
+pub struct LineMatches {
+    /// Whether the line counts as a matching line (taking `--invert` into account)
+    pub selected: bool,
+    /// How many times the pattern matched in the line
+    pub matches: usize,
+}
+
+impl Pattern {
+    /// Compiles `pattern`, returning the regex engine's error message if it isn't valid.
+    pub fn new(pattern: &str, ignore_case: bool, invert: bool) -> Result<Pattern, String> {
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|err| err.to_string())?;
+        Ok(Pattern {
+            regex,
+            ignore_case,
+            invert,
+        })
+    }
+
+    /// Matches one line, without its line ending (so that `$` matches at the end of the line).
+    /// Matches are counted the way `grep -o` finds them: left to right without overlapping, so
+    /// `aa` matches `aaaa` twice, not three times. `--invert` only changes which lines are
+    /// selected; the match count is always the number of matches in the line.
+    pub fn match_line(&self, line: &str) -> LineMatches {
+        let matches = self.regex.find_iter(line).count();
+        LineMatches {
+            selected: (matches > 0) != self.invert,
+            matches,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_match_line() {
+        let pattern = Pattern::new("o+", false, false).unwrap();
+        assert_eq!(
+            pattern.match_line("foo boo bar"),
+            LineMatches {
+                selected: true,
+                matches: 2
+            }
+        );
+        assert_eq!(
+            pattern.match_line("bar"),
+            LineMatches {
+                selected: false,
+                matches: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_overlapping_matches() {
+        let pattern = Pattern::new("aa", false, false).unwrap();
+        assert_eq!(pattern.match_line("aaaa").matches, 2);
+        assert_eq!(pattern.match_line("aaa").matches, 1);
+        let pattern = Pattern::new("aba", false, false).unwrap();
+        assert_eq!(pattern.match_line("ababa").matches, 1);
+    }
+
+    #[test]
+    fn test_anchors() {
+        let pattern = Pattern::new("^a.*z$", false, false).unwrap();
+        assert!(pattern.match_line("abcz").selected);
+        assert!(!pattern.match_line("xabcz").selected);
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        assert_eq!(
+            Pattern::new("error", false, false)
+                .unwrap()
+                .match_line("Error ERROR error")
+                .matches,
+            1
+        );
+        assert_eq!(
+            Pattern::new("error", true, false)
+                .unwrap()
+                .match_line("Error ERROR error")
+                .matches,
+            3
+        );
+    }
+
+    #[test]
+    fn test_invert() {
+        let pattern = Pattern::new("error", false, true).unwrap();
+        assert_eq!(
+            pattern.match_line("all good"),
+            LineMatches {
+                selected: true,
+                matches: 0
+            }
+        );
+        assert_eq!(
+            pattern.match_line("an error, another error"),
+            LineMatches {
+                selected: false,
+                matches: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let err = Pattern::new("(unclosed", false, false).unwrap_err();
+        assert!(err.contains("unclosed group"), "{}", err);
+    }
+
+    #[test]
+    fn test_eq() {
+        let pattern = Pattern::new("a+", false, false).unwrap();
+        assert_eq!(pattern, Pattern::new("a+", false, false).unwrap());
+        assert_ne!(pattern, Pattern::new("a+", true, false).unwrap());
+        assert_ne!(pattern, Pattern::new("a+", false, true).unwrap());
+        assert_ne!(pattern, Pattern::new("b+", false, false).unwrap());
+    }
+}
@@ -0,0 +1,112 @@
+// Counting several files at once on a pool of worker threads.
+use std::collections::{HashMap, VecDeque};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Results from the worker threads, handed out in the order of the inputs they came from.
+pub struct OrderedResults<U> {
+    receiver: mpsc::Receiver<(usize, U)>,
+    /// Results that finished before some earlier input did, by input index
+    pending: HashMap<usize, U>,
+    /// Index of the next result to hand out
+    next: usize,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl<U> Iterator for OrderedResults<U> {
+    type Item = U;
+
+    fn next(&mut self) -> Option<U> {
+        loop {
+            if let Some(result) = self.pending.remove(&self.next) {
+                self.next += 1;
+                return Some(result);
+            }
+            match self.receiver.recv() {
+                Ok((idx, result)) => {
+                    self.pending.insert(idx, result);
+                }
+                // Every worker has finished and hung up
+                Err(_) => {
+                    for handle in self.threads.drain(..) {
+                        handle.join().expect("Error in joining thread");
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Applies `f` to every input on `num_threads` worker threads, which take inputs from a shared
+/// queue as they become free. The results come back in the same order as `inputs`, each as soon
+/// as it and every result before it are ready. With a single thread, the inputs are processed
+/// one at a time on the calling thread as the results are asked for.
+pub fn map_ordered<T, U, F>(inputs: Vec<T>, num_threads: usize, f: F) -> Box<dyn Iterator<Item = U>>
+where
+    T: Send + 'static,
+    U: Send + 'static,
+    F: Fn(T) -> U + Send + Sync + 'static,
+{
+    if num_threads <= 1 {
+        return Box::new(inputs.into_iter().map(f));
+    }
+    let queue: VecDeque<(usize, T)> = inputs.into_iter().enumerate().collect();
+    let queue = Arc::new(Mutex::new(queue));
+    let f = Arc::new(f);
+    let (sender, receiver) = mpsc::channel();
+    let mut threads = Vec::with_capacity(num_threads);
+    for _ in 0..num_threads {
+        let queue = queue.clone();
+        let f = f.clone();
+        let sender = sender.clone();
+        threads.push(thread::spawn(move || loop {
+            let next = queue.lock().unwrap().pop_front();
+            let (idx, input) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            // The receiver only goes away if the results are no longer wanted
+            if sender.send((idx, f(input))).is_err() {
+                break;
+            }
+        }));
+    }
+    Box::new(OrderedResults {
+        receiver,
+        pending: HashMap::new(),
+        next: 0,
+        threads,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_results_are_in_input_order() {
+        // Earlier inputs take longer, so they finish last
+        let inputs: Vec<u64> = (0..16).collect();
+        let results: Vec<u64> = map_ordered(inputs, 8, |n| {
+            thread::sleep(Duration::from_millis(2 * (16 - n)));
+            n * n
+        })
+        .collect();
+        assert_eq!(results, (0..16).map(|n| n * n).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_single_thread() {
+        let results: Vec<String> = map_ordered(vec![1, 2, 3], 1, |n| n.to_string()).collect();
+        assert_eq!(results, vec!["1", "2", "3"]);
+    }
+
+    #[test]
+    fn test_more_threads_than_inputs() {
+        let results: Vec<i32> = map_ordered(vec![1, 2], 8, |n| -n).collect();
+        assert_eq!(results, vec![-1, -2]);
+        assert_eq!(map_ordered(Vec::<i32>::new(), 8, |n| n).count(), 0);
+    }
+}
@@ -0,0 +1,208 @@
+// Finding the files to count under a directory, for `rwc -r`.
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// A directory or file that couldn't be read while walking.
+#[derive(Debug)]
+pub struct WalkError {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.error)
+    }
+}
+
+/// Walks a directory tree depth-first, yielding every regular file in it. The entries of each
+/// directory are visited in sorted order, so the output is the same on every run. Anything that
+/// isn't a regular file or a directory (sockets, device files, ...) is skipped.
+///
+/// Symlinks to files are yielded like files. Symlinks to directories are skipped unless
+/// `follow_symlinks` is set; when following them, a directory that has already been walked is not
+/// walked again, so symlink loops end instead of recursing forever. The root itself is always
+/// walked, even if it is a symlink.
+pub struct Walker {
+    /// Paths still to visit, with the next one at the end
+    pending: Vec<PathBuf>,
+    /// Whether the next path is the root
+    at_root: bool,
+    follow_symlinks: bool,
+    /// (device, inode) of every directory walked so far
+    visited: HashSet<(u64, u64)>,
+}
+
+impl Walker {
+    pub fn new(root: &Path, follow_symlinks: bool) -> Walker {
+        Walker {
+            pending: vec![root.to_path_buf()],
+            at_root: true,
+            follow_symlinks,
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Queues the entries of the directory at `path` to be visited next.
+    fn push_entries(&mut self, path: &Path) -> io::Result<()> {
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<PathBuf>>>()?;
+        entries.sort();
+        self.pending.extend(entries.into_iter().rev());
+        Ok(())
+    }
+}
+
+impl Iterator for Walker {
+    type Item = Result<PathBuf, WalkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(path) = self.pending.pop() {
+            let is_root = self.at_root;
+            self.at_root = false;
+            let result = fs::symlink_metadata(&path).and_then(|metadata| {
+                if metadata.file_type().is_symlink() {
+                    // Stat the target instead; a dangling link is an error
+                    fs::metadata(&path).map(|target| (target, true))
+                } else {
+                    Ok((metadata, false))
+                }
+            });
+            let (metadata, is_symlink) = match result {
+                Ok(result) => result,
+                Err(error) => return Some(Err(WalkError { path, error })),
+            };
+            if metadata.is_file() {
+                return Some(Ok(path));
+            }
+            if !metadata.is_dir() || (is_symlink && !self.follow_symlinks && !is_root) {
+                continue;
+            }
+            if !self.visited.insert((metadata.dev(), metadata.ino())) {
+                continue;
+            }
+            if let Err(error) = self.push_entries(&path) {
+                return Some(Err(WalkError { path, error }));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    /// Builds this tree in a fresh temp directory and returns its path:
+    ///
+    /// ```text
+    /// b.txt
+    /// a/z.txt
+    /// a/nested/y.txt
+    /// a/loop -> ..       (symlink loop)
+    /// a/b-link.txt -> ../b.txt
+    /// c/x.txt
+    /// ```
+    fn make_tree(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("rwc-walker-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("a/nested")).unwrap();
+        fs::create_dir_all(root.join("c")).unwrap();
+        fs::write(root.join("b.txt"), "b\n").unwrap();
+        fs::write(root.join("a/z.txt"), "z\n").unwrap();
+        fs::write(root.join("a/nested/y.txt"), "y\n").unwrap();
+        fs::write(root.join("c/x.txt"), "x\n").unwrap();
+        symlink("..", root.join("a/loop")).unwrap();
+        symlink("../b.txt", root.join("a/b-link.txt")).unwrap();
+        root
+    }
+
+    /// Walks `root`, returning the files found relative to it, and the errors.
+    fn walk(root: &Path, follow_symlinks: bool) -> (Vec<String>, Vec<PathBuf>) {
+        let mut files = Vec::new();
+        let mut errors = Vec::new();
+        for result in Walker::new(root, follow_symlinks) {
+            match result {
+                Ok(path) => files.push(path.strip_prefix(root).unwrap().display().to_string()),
+                Err(err) => errors.push(err.path),
+            }
+        }
+        (files, errors)
+    }
+
+    #[test]
+    fn test_sorted_depth_first() {
+        let root = make_tree("sorted");
+        let (files, errors) = walk(&root, false);
+        assert_eq!(
+            files,
+            vec![
+                "a/b-link.txt",
+                "a/nested/y.txt",
+                "a/z.txt",
+                "b.txt",
+                "c/x.txt"
+            ]
+        );
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_follow_symlink_loop() {
+        // Following a/loop leads back to the root, which has already been walked
+        let root = make_tree("loop");
+        let (files, errors) = walk(&root, true);
+        assert_eq!(files.len(), 5);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_follow_symlinked_directory() {
+        let root = make_tree("follow");
+        symlink("../c", root.join("a/c-link")).unwrap();
+        let (files, _) = walk(&root.join("a"), false);
+        assert!(!files.contains(&String::from("c-link/x.txt")));
+        let (files, _) = walk(&root.join("a"), true);
+        assert!(files.contains(&String::from("c-link/x.txt")));
+    }
+
+    #[test]
+    fn test_single_file_root() {
+        let root = make_tree("file-root");
+        let walked: Vec<PathBuf> = Walker::new(&root.join("b.txt"), false)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(walked, vec![root.join("b.txt")]);
+    }
+
+    #[test]
+    fn test_unreadable_directory_is_skipped() {
+        let root = make_tree("unreadable");
+        let locked = root.join("c");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // Root can read the directory anyway, in which case there's nothing to test
+        let readable = fs::read_dir(&locked).is_ok();
+        let (files, errors) = walk(&root, false);
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        if !readable {
+            assert_eq!(errors, vec![locked]);
+            assert_eq!(files.len(), 4);
+            assert_eq!(files.last().unwrap(), "b.txt");
+        }
+    }
+
+    #[test]
+    fn test_missing_root() {
+        let root = make_tree("missing");
+        let (files, errors) = walk(&root.join("missing"), false);
+        assert!(files.is_empty());
+        assert_eq!(errors, vec![root.join("missing")]);
+    }
+}
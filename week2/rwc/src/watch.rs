@@ -0,0 +1,194 @@
+// Watching a growing file, such as a log, and reprinting its counts as data is appended.
+use crate::output::Serializer;
+use rwc::{Counter, Counts, RunningCount};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to sleep at a time while waiting for the next check, so that a Ctrl-C is noticed
+/// promptly however long the interval is.
+const STOP_CHECK: Duration = Duration::from_millis(50);
+
+/// Set by the signal handler once the user has asked to stop.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGINT and SIGTERM ask the watch loop to stop, rather than killing the process before
+/// it can print the final counts.
+fn handle_stop_signals() {
+    let handler = request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Sleeps for `interval`, or until the user asks to stop. Returns whether they have.
+fn sleep_unless_stopped(interval: Duration) -> bool {
+    let deadline = Instant::now() + interval;
+    while !STOP.load(Ordering::SeqCst) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return false;
+        }
+        thread::sleep(left.min(STOP_CHECK));
+    }
+    true
+}
+
+/// The file being watched, kept open between checks, and how much of it has been counted.
+struct Watched {
+    file: File,
+    /// Device and inode number, to notice when another file is moved into its place
+    id: (u64, u64),
+    /// How many bytes have been read
+    offset: u64,
+    running: RunningCount,
+}
+
+impl Watched {
+    fn open(path: &str, counter: &Counter) -> io::Result<Watched> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        Ok(Watched {
+            file,
+            id: (metadata.dev(), metadata.ino()),
+            offset: 0,
+            running: counter.start(),
+        })
+    }
+
+    /// Goes back to the start of the file, forgetting everything counted so far.
+    fn restart(&mut self, counter: &Counter) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.offset = 0;
+        self.running = counter.start();
+        Ok(())
+    }
+
+    /// Counts whatever has been appended since the last read.
+    fn read_appended(&mut self, chunk: &mut [u8]) -> io::Result<()> {
+        loop {
+            let len = match self.file.read(chunk) {
+                Ok(0) => return Ok(()),
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            self.running.update(&chunk[..len]);
+            self.offset += len as u64;
+        }
+    }
+}
+
+/// Prints the counts for the watched file, either as a new row or, when `overwrite` is set (for a
+/// terminal), in place of the previous one.
+fn print_row<W: Write>(
+    output: &mut W,
+    serializer: &Serializer,
+    path: &str,
+    counts: &Counts,
+    overwrite: bool,
+) -> io::Result<()> {
+    let row = serializer.row(Some(path), counts);
+    if overwrite {
+        write!(output, "\r\x1b[K{}", row.trim_end_matches('\n'))?;
+    } else {
+        write!(output, "{}", row)?;
+    }
+    output.flush()
+}
+
+/// Counts `path`, then keeps checking it every `interval` and counts only what has been appended,
+/// printing the counts again whenever they change. If the file shrinks, it has been truncated,
+/// and if another file takes its place (as when a log is rotated), that is counted instead; either
+/// way counting starts again from zero, with a notice. (A file that is truncated and then grows
+/// past its old size between two checks can't be told apart from one that was only appended to.)
+/// The file is counted as it is, even if it's compressed. Watching stops on SIGINT or SIGTERM,
+/// after printing the final counts once more. Returns whether the file could be counted
+/// throughout, or an error if the output can't be written.
+pub fn watch<W: Write>(
+    path: &str,
+    counter: &Counter,
+    serializer: &Serializer,
+    interval: Duration,
+    overwrite: bool,
+    output: &mut W,
+) -> io::Result<bool> {
+    handle_stop_signals();
+    let mut chunk = vec![0; rwc::CHUNK_SIZE];
+    let mut watched = match Watched::open(path, counter) {
+        Ok(watched) => watched,
+        Err(err) => {
+            eprintln!("rwc: {}: {}", path, err);
+            return Ok(false);
+        }
+    };
+    let mut shown = None;
+    loop {
+        if let Err(err) = watched.read_appended(&mut chunk) {
+            eprintln!("rwc: {}: {}", path, err);
+            return Ok(false);
+        }
+        let counts = watched.running.counts();
+        if shown != Some(counts) {
+            print_row(output, serializer, path, &counts, overwrite)?;
+            shown = Some(counts);
+        }
+        if sleep_unless_stopped(interval) {
+            break;
+        }
+        // If the name is missing, the file is most likely being rotated, so the open file is
+        // counted until a new one appears
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let notice = if (metadata.dev(), metadata.ino()) != watched.id {
+            match Watched::open(path, counter) {
+                Ok(replacement) => watched = replacement,
+                Err(_) => continue,
+            }
+            "file replaced"
+        } else if metadata.len() < watched.offset {
+            if let Err(err) = watched.restart(counter) {
+                eprintln!("rwc: {}: {}", path, err);
+                return Ok(false);
+            }
+            "file truncated"
+        } else {
+            continue;
+        };
+        // Keep the last counts for the old contents on screen, above the notice
+        if overwrite {
+            writeln!(output)?;
+        }
+        eprintln!("rwc: {}: {}; counting from the start", path, notice);
+        shown = None;
+    }
+    // Pick up anything appended since the last check, and print the final counts on a line of
+    // their own, even if they haven't changed (on a terminal, the ^C may have overwritten them)
+    let result = watched.read_appended(&mut chunk);
+    print_row(
+        output,
+        serializer,
+        path,
+        &watched.running.counts(),
+        overwrite,
+    )?;
+    if overwrite {
+        writeln!(output)?;
+    }
+    output.flush()?;
+    if let Err(err) = result {
+        eprintln!("rwc: {}: {}", path, err);
+        return Ok(false);
+    }
+    Ok(true)
+}
@@ -0,0 +1,462 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Creates a fresh, empty directory for one test's files.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rwc-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run_rwc(dir: &PathBuf, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rwc"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_multiple_files_with_an_unreadable_one() {
+    let dir = temp_dir("multiple");
+    fs::write(dir.join("a.txt"), "one two\nthree\n").unwrap();
+    fs::write(dir.join("b.txt"), "four five six\n").unwrap();
+    // A directory can't be counted without -r, even when the tests run as root
+    fs::create_dir(dir.join("unreadable")).unwrap();
+
+    let output = run_rwc(&dir, &["a.txt", "unreadable", "b.txt"]);
+    assert!(!output.status.success());
+    // 14 + 14 bytes of regular files, so every column is two wide
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        " 2  3 14 a.txt\n 1  3 14 b.txt\n 3  6 28 total\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("rwc: unreadable: "), "{}", stderr);
+}
+
+#[test]
+fn test_missing_file() {
+    let dir = temp_dir("missing");
+    fs::write(dir.join("a.txt"), "one two\nthree\n").unwrap();
+    let output = run_rwc(&dir, &["-l", "missing.txt", "a.txt"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        " 2 a.txt\n 2 total\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("rwc: missing.txt: No such file or directory"));
+}
+
+#[test]
+fn test_single_file_has_no_total() {
+    let dir = temp_dir("single");
+    fs::write(dir.join("a.txt"), "one two\nthree\n").unwrap();
+    let output = run_rwc(&dir, &["a.txt"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        " 2  3 14 a.txt\n"
+    );
+}
+
+#[test]
+fn test_max_line_length_total_is_the_maximum() {
+    let dir = temp_dir("max-line-length");
+    fs::write(dir.join("a.txt"), "one two\nthree\n").unwrap();
+    fs::write(dir.join("b.txt"), "four\tfive six\n").unwrap();
+    let output = run_rwc(&dir, &["-lL", "a.txt", "b.txt"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        " 2  7 a.txt\n 1 16 b.txt\n 3 16 total\n"
+    );
+}
+
+#[test]
+fn test_directory_without_recursive() {
+    let dir = temp_dir("directory");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/a.txt"), "one two\nthree\n").unwrap();
+    let output = run_rwc(&dir, &["sub"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "rwc: sub: Is a directory\n"
+    );
+}
+
+#[test]
+fn test_recursive() {
+    let dir = temp_dir("recursive");
+    fs::create_dir_all(dir.join("sub/nested")).unwrap();
+    fs::write(dir.join("sub/b.txt"), "four five six\n").unwrap();
+    fs::write(dir.join("sub/nested/a.txt"), "one two\nthree\n").unwrap();
+    fs::write(dir.join("top.txt"), "seven\n").unwrap();
+    let output = run_rwc(&dir, &["-r", "sub", "top.txt"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        " 1  3 14 sub/b.txt\n 2  3 14 sub/nested/a.txt\n 1  1  6 top.txt\n 4  7 34 total\n"
+    );
+}
+
+#[test]
+fn test_invalid_utf8_and_missing_file() {
+    let dir = temp_dir("invalid-utf8");
+    fs::write(dir.join("a.txt"), "one two\nthree\n").unwrap();
+    fs::write(dir.join("invalid.txt"), b"a\xffb c\n\xfe\n").unwrap();
+    let output = run_rwc(&dir, &["a.txt", "missing.txt", "invalid.txt"]);
+    // Both good files are still counted, but the run as a whole failed
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        " 2  3 14 a.txt\n 2  3  8 invalid.txt\n 4  6 22 total\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "rwc: missing.txt: No such file or directory (os error 2)\n"
+    );
+
+    let output = run_rwc(&dir, &["a.txt", "invalid.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_usage_error() {
+    let dir = temp_dir("usage");
+    let output = run_rwc(&dir, &["-x", "a.txt"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("rwc: invalid option -- 'x'\nUsage: rwc"));
+}
+
+#[test]
+fn test_json_and_csv() {
+    let dir = temp_dir("formats");
+    fs::write(dir.join("a.txt"), "one two\nthree\n").unwrap();
+    fs::write(dir.join("say \"hi\".txt"), "four five six\n").unwrap();
+    let output = run_rwc(&dir, &["--format", "json", "a.txt", "say \"hi\".txt"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[\n\
+         \x20 {\"path\": \"a.txt\", \"lines\": 2, \"words\": 3, \"chars\": 14, \"bytes\": 14, \"max_line_length\": 7},\n\
+         \x20 {\"path\": \"say \\\"hi\\\".txt\", \"lines\": 1, \"words\": 3, \"chars\": 14, \"bytes\": 14, \"max_line_length\": 13},\n\
+         \x20 {\"total\": {\"lines\": 3, \"words\": 6, \"chars\": 28, \"bytes\": 28, \"max_line_length\": 13}}\n\
+         ]\n"
+    );
+    let output = run_rwc(&dir, &["--format=csv", "a.txt", "say \"hi\".txt"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "path,lines,words,chars,bytes,max_line_length\n\
+         a.txt,2,3,14,14,7\n\
+         \"say \"\"hi\"\".txt\",1,3,14,14,13\n"
+    );
+}
+
+#[test]
+fn test_parallel_output_matches_serial() {
+    let dir = temp_dir("parallel");
+    let mut args = vec!["-lwmcL"];
+    let names: Vec<String> = (0..5).map(|idx| format!("big{}.txt", idx)).collect();
+    for (idx, name) in names.iter().enumerate() {
+        // A few megabytes each, of different sizes so they finish in a different order
+        let line = format!(
+            "{} words on line number {}\tand a tab\n",
+            idx,
+            "x".repeat(idx)
+        );
+        fs::write(dir.join(name), line.repeat(30_000 * (3 - idx % 3))).unwrap();
+        args.push(name);
+    }
+    let serial = run_rwc(&dir, &[&["--jobs", "1"], &args[..]].concat());
+    let parallel = run_rwc(&dir, &[&["--jobs", "8"], &args[..]].concat());
+    assert!(serial.status.success());
+    assert_eq!(serial.stdout, parallel.stdout);
+    let serial = String::from_utf8(serial.stdout).unwrap();
+    assert_eq!(serial.lines().count(), 6);
+    assert!(serial.ends_with(" total\n"));
+}
+
+#[test]
+fn test_gzip() {
+    let dir = temp_dir("gzip");
+    let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    fs::copy(
+        format!("{}/whitespace.txt.gz", fixtures),
+        dir.join("whitespace.txt.gz"),
+    )
+    .unwrap();
+    fs::copy(
+        format!("{}/plain.txt.gz", fixtures),
+        dir.join("plain.txt.gz"),
+    )
+    .unwrap();
+    // Valid magic bytes, but not a valid stream
+    fs::write(dir.join("corrupt.gz"), b"\x1f\x8b\x08\x00garbage").unwrap();
+
+    let output = run_rwc(&dir, &["whitespace.txt.gz", "corrupt.gz", "plain.txt.gz"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "  7  14  90 whitespace.txt.gz\n  2   3  24 plain.txt.gz\n  9  17 114 total\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("rwc: corrupt.gz: "), "{}", stderr);
+    assert_eq!(stderr.lines().count(), 1);
+
+    let output = run_rwc(&dir, &["-c", "--no-decompress", "whitespace.txt.gz"]);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "97 whitespace.txt.gz\n"
+    );
+}
+
+fn run_rwc_with_stdin(dir: &PathBuf, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rwc"))
+        .current_dir(dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_stdin() {
+    let dir = temp_dir("stdin");
+    fs::write(dir.join("a.txt"), "one two\nthree\n").unwrap();
+    let output = run_rwc_with_stdin(&dir, &[], "four five six\n");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "      1       3      14\n"
+    );
+    let output = run_rwc_with_stdin(&dir, &["-l", "a.txt", "-"], "four five six\n");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "      2 a.txt\n      1 -\n      3 total\n"
+    );
+}
+
+#[test]
+fn test_pattern() {
+    let dir = temp_dir("pattern");
+    fs::create_dir(dir.join("logs")).unwrap();
+    fs::write(dir.join("logs/a.log"), "ok\nERROR disk\nerror: error\n").unwrap();
+    fs::write(dir.join("logs/b.log"), "ok\nok\n").unwrap();
+
+    // Matching lines, then matches; a line can match more than once
+    let output = run_rwc(&dir, &["-r", "--pattern", "error", "logs"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        " 1  2 logs/a.log\n 0  0 logs/b.log\n 1  2 total\n"
+    );
+    let output = run_rwc(
+        &dir,
+        &["-l", "-r", "--pattern=error", "--ignore-case", "logs"],
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        " 3  2  3 logs/a.log\n 2  0  0 logs/b.log\n 5  2  3 total\n"
+    );
+    // --invert counts the lines that don't match
+    let output = run_rwc(&dir, &["-r", "--pattern", "error", "--invert", "logs"]);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        " 2  2 logs/a.log\n 2  0 logs/b.log\n 4  2 total\n"
+    );
+    // Overlapping matches are only counted once
+    let output = run_rwc_with_stdin(&dir, &["--pattern", "aa"], "aaaa\n");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "      1       2\n"
+    );
+    let output = run_rwc(&dir, &["--format", "json", "--pattern", "ok", "logs/b.log"]);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[\n\
+         \x20 {\"path\": \"logs/b.log\", \"lines\": 2, \"words\": 2, \"chars\": 6, \"bytes\": 6, \"max_line_length\": 2, \"matching_lines\": 2, \"matches\": 2},\n\
+         \x20 {\"total\": {\"lines\": 2, \"words\": 2, \"chars\": 6, \"bytes\": 6, \"max_line_length\": 2, \"matching_lines\": 2, \"matches\": 2}}\n\
+         ]\n"
+    );
+}
+
+#[test]
+fn test_invalid_pattern() {
+    let dir = temp_dir("invalid-pattern");
+    let output = run_rwc(&dir, &["--pattern", "(unclosed", "a.txt"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("rwc: invalid pattern: "), "{}", stderr);
+    assert!(stderr.contains("unclosed group"), "{}", stderr);
+}
+
+#[test]
+fn test_files0_from() {
+    let dir = temp_dir("files0-from");
+    fs::write(dir.join("a.txt"), "one two\nthree\n").unwrap();
+    // Only a NUL-separated list can name this file
+    fs::write(dir.join("new\nline.txt"), "four five six\n").unwrap();
+    let output = run_rwc_with_stdin(&dir, &["--files0-from", "-"], "a.txt\0new\nline.txt\0");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        " 2  3 14 a.txt\n 1  3 14 new\nline.txt\n 3  6 28 total\n"
+    );
+    // The last name doesn't need a NUL, and one file has no total
+    let output = run_rwc_with_stdin(&dir, &["-l", "--files0-from=-"], "a.txt");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2 a.txt\n");
+    // The list can't name standard input when it is standard input
+    let output = run_rwc_with_stdin(&dir, &["-l", "--files0-from=-"], "a.txt\0-\0");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "rwc: when reading file names from stdin, no file name of '-' allowed\n"
+    );
+}
+
+#[test]
+fn test_files_from() {
+    let dir = temp_dir("files-from");
+    fs::write(dir.join("a.txt"), "one two\nthree\n").unwrap();
+    fs::write(dir.join("b.txt"), "four five six\n").unwrap();
+    fs::write(dir.join("list.txt"), "a.txt\nmissing.txt\nb.txt\n").unwrap();
+    // Names from a list are counted and reported just like arguments, including in parallel
+    let output = run_rwc(&dir, &["-l", "--files-from", "list.txt"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout.clone()).unwrap(),
+        " 2 a.txt\n 1 b.txt\n 3 total\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("rwc: missing.txt: "), "{}", stderr);
+    let parallel = run_rwc(&dir, &["-l", "--jobs=4", "--files-from", "list.txt"]);
+    assert_eq!(parallel.stdout, output.stdout);
+
+    let output = run_rwc(&dir, &["--files-from", "nonexistent.txt"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("rwc: cannot open 'nonexistent.txt' for reading: "),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_empty_file_list() {
+    let dir = temp_dir("empty-file-list");
+    let output = run_rwc_with_stdin(&dir, &["--files0-from", "-"], "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "0 0 0 total\n");
+}
+
+#[test]
+fn test_file_list_with_file_operands() {
+    let dir = temp_dir("file-list-operands");
+    fs::write(dir.join("a.txt"), "one two\nthree\n").unwrap();
+    // The arguments are rejected before standard input is read
+    let output = run_rwc(&dir, &["--files0-from", "-", "a.txt"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with(
+            "rwc: extra operand 'a.txt'\nfile operands cannot be combined with --files0-from\n"
+        ),
+        "{}",
+        stderr
+    );
+}
+
+/// Waits for `rwc --watch` to print `row` (ignoring how the columns are padded), skipping any
+/// rows before it, and panics if it doesn't within a few seconds.
+fn wait_for_row(rows: &mpsc::Receiver<String>, row: &str) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut seen = Vec::new();
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match rows.recv_timeout(left) {
+            Ok(line) if line.split_whitespace().collect::<Vec<_>>().join(" ") == row => return,
+            Ok(line) => seen.push(line),
+            Err(_) => break,
+        }
+    }
+    panic!("rwc never printed {:?}; it printed {:?}", row, seen);
+}
+
+#[test]
+fn test_watch() {
+    let dir = temp_dir("watch");
+    let log = dir.join("log.txt");
+    fs::write(&log, "one two\n").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rwc"))
+        .current_dir(&dir)
+        .args(["--watch", "--interval", "50", "log.txt"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let (sender, rows) = mpsc::channel();
+    thread::spawn(move || {
+        for line in stdout.lines() {
+            sender.send(line.unwrap()).unwrap();
+        }
+    });
+    let append = |text: &str| {
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    };
+
+    wait_for_row(&rows, "1 2 8 log.txt");
+    // A word split across two appends is only counted once
+    append("thr");
+    wait_for_row(&rows, "1 3 11 log.txt");
+    append("ee four\n");
+    wait_for_row(&rows, "2 4 19 log.txt");
+    // Truncating and rotating start the count again
+    fs::write(&log, "new\n").unwrap();
+    wait_for_row(&rows, "1 1 4 log.txt");
+    fs::rename(&log, dir.join("log.txt.1")).unwrap();
+    fs::write(&log, "a b c\n").unwrap();
+    wait_for_row(&rows, "1 3 6 log.txt");
+
+    // Interrupting prints the final counts and exits cleanly
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+    wait_for_row(&rows, "1 3 6 log.txt");
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        "rwc: log.txt: file truncated; counting from the start\n\
+         rwc: log.txt: file replaced; counting from the start\n"
+    );
+}
@@ -0,0 +1,159 @@
+use crate::factor;
+use crate::queue::{QueueKind, WorkQueue};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Order-independent fingerprint of the numbers the workers processed. If any number is skipped
+/// or factored twice, at least one of the fields will differ from the fingerprint of the input.
+#[derive(Debug, Default, PartialEq)]
+pub struct Checksum {
+    count: u64,
+    sum: u64,
+    xor: u64,
+}
+
+impl Checksum {
+    pub fn of(numbers: &[u64]) -> Checksum {
+        let mut checksum = Checksum::default();
+        for &num in numbers {
+            checksum.count += 1;
+            checksum.sum = checksum.sum.wrapping_add(mix(num));
+            checksum.xor ^= mix(num);
+        }
+        checksum
+    }
+}
+
+/// Thread-safe accumulator producing a Checksum.
+#[derive(Default)]
+struct AtomicChecksum {
+    count: AtomicU64,
+    sum: AtomicU64,
+    xor: AtomicU64,
+}
+
+impl AtomicChecksum {
+    fn add(&self, num: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(mix(num), Ordering::Relaxed);
+        self.xor.fetch_xor(mix(num), Ordering::Relaxed);
+    }
+
+    fn load(&self) -> Checksum {
+        Checksum {
+            count: self.count.load(Ordering::SeqCst),
+            sum: self.sum.load(Ordering::SeqCst),
+            xor: self.xor.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Spreads the bits of a number (splitmix64 finalizer) so that small inputs don't cancel each
+/// other out in the sum and xor.
+fn mix(num: u64) -> u64 {
+    let mut x = num;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Factors every number using `num_threads` workers pulling from a `kind` queue, discarding the
+/// output. Returns the wall time taken and the checksum of the numbers that were processed.
+pub fn run_once(kind: QueueKind, numbers: &[u64], num_threads: usize) -> (Duration, Checksum) {
+    let queue = Arc::new(WorkQueue::new(kind, numbers.to_vec()));
+    let checksum = Arc::new(AtomicChecksum::default());
+    let start = Instant::now();
+    let mut threads = Vec::with_capacity(num_threads);
+    for _ in 0..num_threads {
+        let queue = queue.clone();
+        let checksum = checksum.clone();
+        threads.push(thread::spawn(move || {
+            while let Some(num) = queue.next() {
+                factor(num);
+                checksum.add(num);
+            }
+        }));
+    }
+    for handler in threads {
+        handler.join().expect("Error in joining thread");
+    }
+    (start.elapsed(), checksum.load())
+}
+
+/// Returns the thread counts to benchmark: 1, 2, 4, and the number of CPUs.
+pub fn thread_counts(max_threads: usize) -> Vec<usize> {
+    let mut counts: Vec<usize> = vec![1, 2, 4]
+        .into_iter()
+        .filter(|&n| n < max_threads)
+        .collect();
+    counts.push(max_threads);
+    counts
+}
+
+/// Runs the same input under every queue implementation and thread count, printing a table of
+/// wall times and speedups relative to one thread. Exits with an error if any run skipped or
+/// repeated a number.
+pub fn run(numbers: &[u64], max_threads: usize) {
+    let expected = Checksum::of(numbers);
+    println!(
+        "{:>8} {:>8} {:>14} {:>8}",
+        "queue", "threads", "wall time", "speedup"
+    );
+    let mut all_correct = true;
+    for kind in QueueKind::ALL.iter() {
+        let mut baseline = None;
+        for num_threads in thread_counts(max_threads) {
+            let (elapsed, checksum) = run_once(*kind, numbers, num_threads);
+            let baseline = *baseline.get_or_insert(elapsed);
+            let correct = checksum == expected;
+            all_correct &= correct;
+            println!(
+                "{:>8} {:>8} {:>14} {:>7.2}x{}",
+                kind.name(),
+                num_threads,
+                format!("{:.2?}", elapsed),
+                baseline.as_secs_f64() / elapsed.as_secs_f64(),
+                if correct { "" } else { "  CHECKSUM MISMATCH" }
+            );
+        }
+    }
+    if !all_correct {
+        eprintln!("Some runs did not process every number exactly once!");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_thread_counts() {
+        assert_eq!(thread_counts(1), vec![1]);
+        assert_eq!(thread_counts(2), vec![1, 2]);
+        assert_eq!(thread_counts(4), vec![1, 2, 4]);
+        assert_eq!(thread_counts(16), vec![1, 2, 4, 16]);
+    }
+
+    #[test]
+    fn test_checksum_detects_skips_and_duplicates() {
+        let numbers = [12, 35, 35, 97, 1000];
+        let expected = Checksum::of(&numbers);
+        assert_eq!(Checksum::of(&[1000, 35, 97, 12, 35]), expected);
+        assert_ne!(Checksum::of(&[12, 35, 97, 1000]), expected);
+        assert_ne!(Checksum::of(&[12, 35, 35, 97, 97]), expected);
+        assert_ne!(Checksum::of(&[12, 12, 35, 35, 97, 1000]), expected);
+    }
+
+    #[test]
+    fn test_run_once_processes_everything() {
+        let numbers: Vec<u64> = (1..2000).collect();
+        let expected = Checksum::of(&numbers);
+        for kind in QueueKind::ALL.iter() {
+            let (_, checksum) = run_once(*kind, &numbers, 8);
+            assert_eq!(checksum, expected);
+        }
+    }
+}
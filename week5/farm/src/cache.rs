@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Remembers the factors of numbers that have already been factored, so that duplicate inputs
+/// don't need to be factored again. Shared between all worker threads.
+#[derive(Default)]
+pub struct FactorCache {
+    factors: Mutex<HashMap<u64, Vec<u64>>>,
+    hits: AtomicUsize,
+}
+
+impl FactorCache {
+    pub fn new() -> FactorCache {
+        FactorCache::default()
+    }
+
+    /// Returns the cached factors of `num`, or computes them with `factor` and caches the result.
+    ///
+    /// The lock is not held while factoring, so two workers that pick up the same uncached number
+    /// at the same time may both factor it; that only costs time, and keeps the cache from
+    /// serializing the workers.
+    pub fn get_or_factor<F>(&self, num: u64, factor: F) -> Vec<u64>
+    where
+        F: FnOnce(u64) -> Vec<u64>,
+    {
+        if let Some(factors) = self.factors.lock().unwrap().get(&num) {
+            self.hits.fetch_add(1, Ordering::SeqCst);
+            return factors.clone();
+        }
+        let factors = factor(num);
+        self.factors.lock().unwrap().insert(num, factors.clone());
+        factors
+    }
+
+    /// Returns how many lookups were served from the cache.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hit_skips_factoring() {
+        let cache = FactorCache::new();
+        assert_eq!(cache.get_or_factor(12, |_| vec![2, 2, 3]), vec![2, 2, 3]);
+        assert_eq!(cache.hits(), 0);
+        let factors = cache.get_or_factor(12, |_| panic!("12 should have been cached"));
+        assert_eq!(factors, vec![2, 2, 3]);
+        assert_eq!(cache.hits(), 1);
+    }
+
+    #[test]
+    fn test_distinct_numbers_miss() {
+        let cache = FactorCache::new();
+        cache.get_or_factor(12, |_| vec![2, 2, 3]);
+        assert_eq!(cache.get_or_factor(15, |_| vec![3, 5]), vec![3, 5]);
+        assert_eq!(cache.hits(), 0);
+    }
+}
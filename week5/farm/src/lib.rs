@@ -0,0 +1,254 @@
+pub mod bench;
+pub mod cache;
+pub mod progress;
+pub mod queue;
+pub mod stats;
+
+use cache::FactorCache;
+use progress::Progress;
+use queue::{QueueKind, WorkQueue};
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Determines whether a number is prime by trial division up to its square root.
+pub fn is_prime(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    let mut factor = 2;
+    // `factor <= n / factor` is `factor * factor <= n` without the risk of overflowing
+    while factor <= n / factor {
+        if n.is_multiple_of(factor) {
+            return false;
+        }
+        factor += 1;
+    }
+    true
+}
+
+/// Determines the prime factors of a number, in ascending order. The factors of 0 and 1 are
+/// defined to be the number itself, so that the product of the factors is always `n`.
+pub fn factor(n: u64) -> Vec<u64> {
+    if n < 2 {
+        return vec![n];
+    }
+    let mut factors = Vec::new();
+    let mut remaining = n;
+    let mut factor = 2;
+    while factor <= remaining / factor {
+        while remaining.is_multiple_of(factor) {
+            factors.push(factor);
+            remaining /= factor;
+        }
+        factor += 1;
+    }
+    // Whatever is left has no factors up to its square root, so it is prime
+    if remaining > 1 {
+        factors.push(remaining);
+    }
+    factors
+}
+
+/// Formats a list of factors as `2 * 2 * 3`.
+pub fn format_factors(factors: &[u64]) -> String {
+    factors
+        .iter()
+        .map(|f| f.to_string())
+        .collect::<Vec<String>>()
+        .join(" * ")
+}
+
+/// The outcome of factoring one input.
+#[derive(Debug)]
+pub struct FactorResult {
+    pub num: u64,
+    pub factors: Vec<u64>,
+    /// How long it took to factor (or look up) this number
+    pub elapsed: Duration,
+}
+
+/// Spawns `num_threads` workers that claim numbers from `queue` until it is empty. Each result is
+/// sent over the returned channel as soon as it is ready; the channel closes once every worker
+/// has finished. If a cache is supplied, numbers found in it are not factored again. If progress
+/// is being tracked, each worker counts a number as done as soon as it has its factors.
+pub fn spawn_workers(
+    queue: WorkQueue,
+    cache: Option<Arc<FactorCache>>,
+    progress: Option<Arc<Progress>>,
+    num_threads: usize,
+) -> (Vec<thread::JoinHandle<()>>, mpsc::Receiver<FactorResult>) {
+    let queue = Arc::new(queue);
+    let (sender, receiver) = mpsc::channel();
+    let mut threads = Vec::with_capacity(num_threads);
+    for _ in 0..num_threads {
+        let queue = queue.clone();
+        let cache = cache.clone();
+        let progress = progress.clone();
+        let sender = sender.clone();
+        threads.push(thread::spawn(move || {
+            while let Some(num) = queue.next() {
+                let start = Instant::now();
+                let factors = match &cache {
+                    Some(cache) => cache.get_or_factor(num, factor),
+                    None => factor(num),
+                };
+                let elapsed = start.elapsed();
+                if let Some(progress) = &progress {
+                    progress.complete_one();
+                }
+                // The receiver only goes away if the main thread has given up on the results
+                if sender
+                    .send(FactorResult {
+                        num,
+                        factors,
+                        elapsed,
+                    })
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }));
+    }
+    (threads, receiver)
+}
+
+/// Factors every input using `num_threads` threads. Returns `(input, factors)` pairs in the same
+/// order as `inputs`, regardless of the order in which the workers finished.
+pub fn factor_all(inputs: &[u64], num_threads: usize) -> Vec<(u64, Vec<u64>)> {
+    let queue = WorkQueue::new(QueueKind::Atomic, inputs.to_vec());
+    let (threads, results) = spawn_workers(queue, None, None, num_threads.max(1));
+    let factors_by_num: HashMap<u64, Vec<u64>> = results
+        .iter()
+        .map(|result| (result.num, result.factors))
+        .collect();
+    for handler in threads {
+        handler.join().expect("Error in joining thread");
+    }
+    inputs
+        .iter()
+        .map(|num| (*num, factors_by_num[num].clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// xorshift64: a tiny deterministic PRNG so the property tests are reproducible.
+    fn xorshift(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn test_is_prime_known_answers() {
+        let primes = [2, 3, 5, 7, 97, 1009, 65521, 1000000007, 4294967291];
+        let composites = [0, 1, 4, 9, 15, 25, 49, 1022117, 4294967297];
+        for n in primes.iter() {
+            assert!(is_prime(*n), "{} should be prime", n);
+        }
+        for n in composites.iter() {
+            assert!(!is_prime(*n), "{} should not be prime", n);
+        }
+    }
+
+    #[test]
+    fn test_factor_known_answers() {
+        assert_eq!(factor(0), vec![0]);
+        assert_eq!(factor(1), vec![1]);
+        assert_eq!(factor(2), vec![2]);
+        assert_eq!(factor(12), vec![2, 2, 3]);
+        assert_eq!(factor(25), vec![5, 5]);
+        assert_eq!(factor(97), vec![97]);
+        assert_eq!(factor(1022117), vec![1009, 1013]);
+        assert_eq!(factor(4294967297), vec![641, 6700417]);
+        assert_eq!(factor(1 << 40), vec![2; 40]);
+        assert_eq!(format_factors(&factor(360)), "2 * 2 * 2 * 3 * 3 * 5");
+    }
+
+    #[test]
+    fn test_factor_properties() {
+        let mut state = 0x2545f4914f6cdd1d;
+        for _ in 0..500 {
+            // Keep the inputs below 2^36 so that trial division stays fast in debug builds
+            let n = xorshift(&mut state) >> 28;
+            let factors = factor(n);
+            if n < 2 {
+                continue;
+            }
+            assert_eq!(factors.iter().product::<u64>(), n, "factors of {}", n);
+            assert!(factors.windows(2).all(|pair| pair[0] <= pair[1]));
+            for f in factors.iter() {
+                assert!(is_prime(*f), "{} is a non-prime factor of {}", f, n);
+            }
+        }
+    }
+
+    #[test]
+    fn test_factor_all_is_deterministic() {
+        let mut state = 88172645463325252;
+        let mut inputs: Vec<u64> = (0..300).map(|_| xorshift(&mut state) >> 34).collect();
+        // Duplicates must come back in their original positions too
+        inputs.extend_from_slice(&[12, 97, 12, 1022117, 97]);
+        let single = factor_all(&inputs, 1);
+        let multi = factor_all(&inputs, 8);
+        assert_eq!(single, multi);
+        assert_eq!(single.len(), inputs.len());
+        for ((num, factors), input) in single.iter().zip(inputs.iter()) {
+            assert_eq!(num, input);
+            assert_eq!(*factors, factor(*input));
+        }
+    }
+
+    #[test]
+    fn test_factor_all_empty() {
+        assert_eq!(factor_all(&[], 4), Vec::new());
+    }
+
+    #[test]
+    fn test_workers_count_progress() {
+        let numbers: Vec<u64> = (1..=50).collect();
+        let progress = Arc::new(Progress::new(numbers.len(), false));
+        let queue = WorkQueue::new(QueueKind::Atomic, numbers);
+        let (threads, results) = spawn_workers(queue, None, Some(progress.clone()), 4);
+        // Nothing is read from the channel: the workers count on their own
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(progress.completed(), 50);
+        assert_eq!(results.iter().count(), 50);
+    }
+
+    #[test]
+    fn test_cache_serves_duplicates() {
+        // 1000003 * 1000033 takes about a million trial divisions to factor
+        let semiprime = 1000036000099;
+        let numbers = vec![semiprime, 12, 12, semiprime, 97, 12, semiprime];
+        let cache = Arc::new(FactorCache::new());
+        // With a single worker, results arrive in input order
+        let queue = WorkQueue::new(QueueKind::Mutex, numbers.clone());
+        let (threads, results) = spawn_workers(queue, Some(cache.clone()), None, 1);
+        let results: Vec<FactorResult> = results.iter().collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+
+        let processed: Vec<u64> = results.iter().map(|result| result.num).collect();
+        assert_eq!(processed, numbers);
+        for result in results.iter() {
+            assert_eq!(result.factors, factor(result.num));
+        }
+        assert_eq!(cache.hits(), 4);
+
+        let first = results[0].elapsed;
+        for cached in [&results[3], &results[6]].iter() {
+            assert!(
+                cached.elapsed * 100 < first,
+                "cached lookup took {:?}, first factorization took {:?}",
+                cached.elapsed,
+                first
+            );
+        }
+    }
+}
@@ -1,84 +1,65 @@
-use std::collections::VecDeque;
-#[allow(unused_imports)]
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
-#[allow(unused_imports)]
-use std::{env, process, thread};
+use farm::cache::FactorCache;
+use farm::progress::{Progress, Ticker};
+use farm::queue::{QueueKind, WorkQueue};
+use farm::{bench, format_factors, spawn_workers, stats};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, process};
 
-/// Determines whether a number is prime. This function is taken from CS 110 factor.py.
-///
-/// You don't need to read or understand this code.
-#[allow(dead_code)]
-fn is_prime(num: u32) -> bool {
-    if num <= 1 {
-        return false;
-    }
-    for factor in 2..((num as f64).sqrt().floor() as u32) {
-        if num % factor == 0 {
-            return false;
-        }
-    }
-    true
+/// Flags that may be supplied on the command line alongside the numbers.
+struct Options {
+    /// Show a progress bar and ETA on stderr while factoring
+    progress: bool,
+    /// How workers claim the next number (`--queue mutex|atomic`)
+    queue: QueueKind,
+    /// Time every queue implementation at several thread counts instead of printing factors
+    bench: bool,
+    /// Don't print a line per number, only the final summary
+    quiet: bool,
+    /// Factor duplicate inputs again instead of reusing the earlier result
+    no_cache: bool,
 }
 
-/// Determines the prime factors of a number and prints them to stdout. This function is taken
-/// from CS 110 factor.py.
-///
-/// You don't need to read or understand this code.
-#[allow(dead_code)]
-fn factor_number(num: u32) {
-    let start = Instant::now();
-
-    if num == 1 || is_prime(num) {
-        println!("{} = {} [time: {:?}]", num, num, start.elapsed());
-        return;
-    }
-
-    let mut factors = Vec::new();
-    let mut curr_num = num;
-    for factor in 2..num {
-        while curr_num % factor == 0 {
-            factors.push(factor);
-            curr_num /= factor;
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            progress: false,
+            queue: QueueKind::Mutex,
+            bench: false,
+            quiet: false,
+            no_cache: false,
         }
     }
-    factors.sort();
-    let factors_str = factors
-        .into_iter()
-        .map(|f| f.to_string())
-        .collect::<Vec<String>>()
-        .join(" * ");
-    println!("{} = {} [time: {:?}]", num, factors_str, start.elapsed());
 }
 
-/// Returns a list of numbers supplied via argv.
-#[allow(dead_code)]
-fn get_input_numbers() -> VecDeque<u32> {
-    let mut numbers = VecDeque::new();
-    for arg in env::args().skip(1) {
-        if let Ok(val) = arg.parse::<u32>() {
-            numbers.push_back(val);
+/// Returns the flags and the list of numbers supplied via argv.
+fn get_input_numbers() -> (Options, Vec<u64>) {
+    let mut options = Options::default();
+    let mut numbers = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--progress" {
+            options.progress = true;
+        } else if arg == "--bench" {
+            options.bench = true;
+        } else if arg == "--quiet" {
+            options.quiet = true;
+        } else if arg == "--no-cache" {
+            options.no_cache = true;
+        } else if arg == "--queue" {
+            let name = args.next().unwrap_or_default();
+            options.queue = QueueKind::parse(&name).unwrap_or_else(|| {
+                println!("{} is not a valid queue (expected mutex or atomic)", name);
+                process::exit(1);
+            });
+        } else if let Ok(val) = arg.parse::<u64>() {
+            numbers.push(val);
         } else {
             println!("{} is not a valid number", arg);
             process::exit(1);
         }
     }
-    numbers
-}
-
-fn pop_front_from_queue(nums: &mut Arc<Mutex<VecDeque<u32>>>) -> Option<u32> {
-    let mut num_ref = nums.lock().unwrap();
-    return num_ref.pop_front();
-}
-
-fn worker_thread(mut nums: Arc<Mutex<VecDeque<u32>>>) {
-    loop {
-        if let Some(num) = pop_front_from_queue(&mut nums) {
-            factor_number(num)
-        } else {
-            break;
-        }
-    }
+    (options, numbers)
 }
 
 fn main() {
@@ -86,23 +67,50 @@ fn main() {
     println!("Farm starting on {} CPUs", num_threads);
     let start = Instant::now();
 
-    // TODO: call get_input_numbers() and store a queue of numbers to factor
-    let nums: Arc<Mutex<VecDeque<u32>>> = Arc::new(Mutex::new(get_input_numbers()));
-
-    // TODO: spawn `num_threads` threads, each of which pops numbers off the queue and calls
-    // factor_number() until the queue is empty
-    let mut threads = Vec::with_capacity(num_threads);
-    for _ in 0..num_threads {
-        let nums_clone = nums.clone();
-        threads.push(thread::spawn(move || {
-            worker_thread(nums_clone);
-        }));
+    let (options, numbers) = get_input_numbers();
+    if options.bench {
+        bench::run(&numbers, num_threads);
+        return;
     }
 
-    // TODO: join all the threads you created
+    let progress = Arc::new(Progress::new(numbers.len(), options.progress));
+    let ticker = if options.progress {
+        Some(Ticker::start(progress.clone(), start))
+    } else {
+        None
+    };
+    let cache = if options.no_cache {
+        None
+    } else {
+        Some(Arc::new(FactorCache::new()))
+    };
+
+    // Print each result as the workers report it
+    let queue = WorkQueue::new(options.queue, numbers);
+    let (threads, results) =
+        spawn_workers(queue, cache.clone(), Some(progress.clone()), num_threads);
+    let mut timings: Vec<(u64, Duration)> = Vec::new();
+    for result in results {
+        if !options.quiet {
+            progress.print_result(&format!(
+                "{} = {} [time: {:?}]",
+                result.num,
+                format_factors(&result.factors),
+                result.elapsed
+            ));
+        }
+        timings.push((result.num, result.elapsed));
+    }
     for handler in threads {
         handler.join().expect("Error in joining thread");
     }
 
+    // Stop the ticker (erasing the progress line) before printing anything else
+    if let Some(ticker) = ticker {
+        ticker.finish();
+    }
+
+    let cache_hits = cache.as_ref().map(|cache| cache.hits());
+    println!("{}", stats::summary(&timings, start.elapsed(), cache_hits));
     println!("Total execution time: {:?}", start.elapsed());
 }
@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the ticker thread redraws the progress line.
+const TICK_INTERVAL: Duration = Duration::from_millis(200);
+/// Number of ticks' worth of samples used to compute the moving-average completion rate (5s).
+const RATE_WINDOW_TICKS: usize = 25;
+/// Width of the `[#####.....]` bar, in characters.
+const BAR_WIDTH: usize = 10;
+/// Carriage return followed by the ANSI "erase to end of line" sequence.
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+/// Shared progress state. The workers call complete_one() as they finish each number; the ticker
+/// thread reads the counter and redraws the progress line on stderr.
+pub struct Progress {
+    total: usize,
+    completed: AtomicUsize,
+    enabled: bool,
+    /// Held while writing to the terminal, so that a result line is never printed in the middle
+    /// of a progress line redraw.
+    output_lock: Mutex<()>,
+}
+
+impl Progress {
+    pub fn new(total: usize, enabled: bool) -> Progress {
+        Progress {
+            total,
+            completed: AtomicUsize::new(0),
+            enabled,
+            output_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn complete_one(&self) {
+        self.completed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn completed(&self) -> usize {
+        self.completed.load(Ordering::SeqCst)
+    }
+
+    /// Prints a result line to stdout. If the progress line is being shown, it is erased first;
+    /// the ticker redraws it underneath the result on its next tick.
+    pub fn print_result(&self, line: &str) {
+        let _guard = self.output_lock.lock().unwrap();
+        if self.enabled {
+            eprint!("{}", CLEAR_LINE);
+        }
+        println!("{}", line);
+    }
+
+    fn draw(&self, line: &str) {
+        let _guard = self.output_lock.lock().unwrap();
+        eprint!("{}{}", CLEAR_LINE, line);
+        io::stderr().flush().expect("Error flushing stderr.");
+    }
+
+    fn clear(&self) {
+        let _guard = self.output_lock.lock().unwrap();
+        eprint!("{}", CLEAR_LINE);
+        io::stderr().flush().expect("Error flushing stderr.");
+    }
+}
+
+/// Keeps the most recent (elapsed, completed) samples and computes the completion rate over them,
+/// so that the ETA follows the recent pace rather than the average since startup.
+pub struct RateWindow {
+    samples: VecDeque<(Duration, usize)>,
+    capacity: usize,
+}
+
+impl RateWindow {
+    pub fn new(capacity: usize) -> RateWindow {
+        RateWindow {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, elapsed: Duration, completed: usize) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((elapsed, completed));
+    }
+
+    /// Returns the completion rate (numbers per second) over the window, or None if there isn't
+    /// enough information to estimate it yet.
+    pub fn rate(&self) -> Option<f64> {
+        let (first_time, first_count) = *self.samples.front()?;
+        let (last_time, last_count) = *self.samples.back()?;
+        let seconds = last_time.checked_sub(first_time)?.as_secs_f64();
+        if seconds <= 0.0 || last_count <= first_count {
+            return None;
+        }
+        Some((last_count - first_count) as f64 / seconds)
+    }
+}
+
+/// Estimates the time needed to finish `remaining` numbers at `rate` numbers per second.
+pub fn eta(remaining: usize, rate: Option<f64>) -> Option<Duration> {
+    if remaining == 0 {
+        return Some(Duration::from_secs(0));
+    }
+    let rate = rate?;
+    if rate <= 0.0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(remaining as f64 / rate))
+}
+
+/// Renders the bar portion of the progress line, e.g. `[#####.....]`.
+pub fn render_bar(completed: usize, total: usize, width: usize) -> String {
+    let filled = (completed.min(total) * width)
+        .checked_div(total)
+        .unwrap_or(width);
+    format!("[{}{}]", "#".repeat(filled), ".".repeat(width - filled))
+}
+
+/// Renders the full progress line, e.g. `[#####.....] 123/500 done, elapsed 12.3s, eta 38s`.
+pub fn render_line(
+    completed: usize,
+    total: usize,
+    elapsed: Duration,
+    eta: Option<Duration>,
+) -> String {
+    let eta_str = match eta {
+        Some(eta) => format!("{}s", eta.as_secs_f64().round() as u64),
+        None => String::from("?"),
+    };
+    format!(
+        "{} {}/{} done, elapsed {:.1}s, eta {}",
+        render_bar(completed, total, BAR_WIDTH),
+        completed,
+        total,
+        elapsed.as_secs_f64(),
+        eta_str
+    )
+}
+
+/// Handle to the thread that redraws the progress line.
+pub struct Ticker {
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Ticker {
+    /// Starts redrawing the progress line every TICK_INTERVAL until finish() is called.
+    pub fn start(progress: Arc<Progress>, start: Instant) -> Ticker {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut window = RateWindow::new(RATE_WINDOW_TICKS);
+            while !stop_clone.load(Ordering::SeqCst) {
+                let completed = progress.completed();
+                let elapsed = start.elapsed();
+                window.record(elapsed, completed);
+                let remaining = progress.total.saturating_sub(completed);
+                progress.draw(&render_line(
+                    completed,
+                    progress.total,
+                    elapsed,
+                    eta(remaining, window.rate()),
+                ));
+                thread::sleep(TICK_INTERVAL);
+            }
+            progress.clear();
+        });
+        Ticker { stop, handle }
+    }
+
+    /// Stops the ticker and waits for it to erase the progress line.
+    pub fn finish(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.handle
+            .join()
+            .expect("Error in joining progress thread");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_bar() {
+        assert_eq!(render_bar(0, 500, 10), "[..........]");
+        assert_eq!(render_bar(250, 500, 10), "[#####.....]");
+        assert_eq!(render_bar(499, 500, 10), "[#########.]");
+        assert_eq!(render_bar(500, 500, 10), "[##########]");
+        assert_eq!(render_bar(0, 0, 4), "[####]");
+    }
+
+    #[test]
+    fn test_render_line() {
+        let line = render_line(
+            123,
+            500,
+            Duration::from_millis(12300),
+            Some(Duration::from_secs(38)),
+        );
+        assert_eq!(line, "[##........] 123/500 done, elapsed 12.3s, eta 38s");
+        let line = render_line(0, 500, Duration::from_millis(200), None);
+        assert_eq!(line, "[..........] 0/500 done, elapsed 0.2s, eta ?");
+    }
+
+    #[test]
+    fn test_rate_uses_moving_window() {
+        let mut window = RateWindow::new(3);
+        assert_eq!(window.rate(), None);
+        window.record(Duration::from_secs(0), 0);
+        assert_eq!(window.rate(), None);
+        // A fast start: 100 numbers in the first second
+        window.record(Duration::from_secs(1), 100);
+        assert_eq!(window.rate(), Some(100.0));
+        // ...followed by a slowdown. Once the fast sample falls out of the window, only the
+        // recent pace counts.
+        window.record(Duration::from_secs(2), 110);
+        window.record(Duration::from_secs(3), 120);
+        assert_eq!(window.rate(), Some(10.0));
+    }
+
+    #[test]
+    fn test_rate_without_progress() {
+        let mut window = RateWindow::new(5);
+        window.record(Duration::from_secs(0), 7);
+        window.record(Duration::from_secs(1), 7);
+        assert_eq!(window.rate(), None);
+    }
+
+    #[test]
+    fn test_eta() {
+        assert_eq!(eta(0, None), Some(Duration::from_secs(0)));
+        assert_eq!(eta(10, None), None);
+        assert_eq!(eta(380, Some(10.0)), Some(Duration::from_secs(38)));
+        assert_eq!(eta(5, Some(2.0)), Some(Duration::from_millis(2500)));
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Which work distribution strategy the workers use to claim numbers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueueKind {
+    /// A VecDeque behind a Mutex; every claim takes the lock
+    Mutex,
+    /// A frozen Vec plus an atomic index; every claim is a single fetch_add
+    Atomic,
+}
+
+impl QueueKind {
+    pub const ALL: [QueueKind; 2] = [QueueKind::Mutex, QueueKind::Atomic];
+
+    pub fn parse(name: &str) -> Option<QueueKind> {
+        match name {
+            "mutex" => Some(QueueKind::Mutex),
+            "atomic" => Some(QueueKind::Atomic),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            QueueKind::Mutex => "mutex",
+            QueueKind::Atomic => "atomic",
+        }
+    }
+}
+
+/// A queue of numbers shared between worker threads. Each number is handed out exactly once.
+pub enum WorkQueue {
+    Mutex(Mutex<VecDeque<u64>>),
+    Atomic { items: Vec<u64>, next: AtomicUsize },
+}
+
+impl WorkQueue {
+    pub fn new(kind: QueueKind, numbers: Vec<u64>) -> WorkQueue {
+        match kind {
+            QueueKind::Mutex => WorkQueue::Mutex(Mutex::new(numbers.into())),
+            QueueKind::Atomic => WorkQueue::Atomic {
+                items: numbers,
+                next: AtomicUsize::new(0),
+            },
+        }
+    }
+
+    /// Claims the next number to factor, or returns None once the queue has been drained.
+    pub fn next(&self) -> Option<u64> {
+        match self {
+            WorkQueue::Mutex(nums) => nums.lock().unwrap().pop_front(),
+            WorkQueue::Atomic { items, next } => {
+                // fetch_add hands every caller a distinct index. Once the index runs past the end
+                // it keeps growing, but every caller past that point just sees None.
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                items.get(idx).copied()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn drain_concurrently(kind: QueueKind, numbers: Vec<u64>, num_threads: usize) -> Vec<u64> {
+        let queue = Arc::new(WorkQueue::new(kind, numbers));
+        let mut threads = Vec::new();
+        for _ in 0..num_threads {
+            let queue = queue.clone();
+            threads.push(thread::spawn(move || {
+                let mut claimed = Vec::new();
+                while let Some(num) = queue.next() {
+                    claimed.push(num);
+                }
+                claimed
+            }));
+        }
+        let mut claimed: Vec<u64> = threads
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        claimed.sort_unstable();
+        claimed
+    }
+
+    #[test]
+    fn test_every_number_claimed_exactly_once() {
+        let numbers: Vec<u64> = (0..100_000).collect();
+        for kind in QueueKind::ALL.iter() {
+            let claimed = drain_concurrently(*kind, numbers.clone(), 8);
+            assert_eq!(
+                claimed,
+                numbers,
+                "{} queue lost or duplicated work",
+                kind.name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_empty_queue() {
+        for kind in QueueKind::ALL.iter() {
+            let queue = WorkQueue::new(*kind, Vec::new());
+            assert_eq!(queue.next(), None);
+            assert_eq!(queue.next(), None);
+        }
+    }
+
+    #[test]
+    fn test_parse_kind() {
+        assert_eq!(QueueKind::parse("mutex"), Some(QueueKind::Mutex));
+        assert_eq!(QueueKind::parse("atomic"), Some(QueueKind::Atomic));
+        assert_eq!(QueueKind::parse("segqueue"), None);
+    }
+}
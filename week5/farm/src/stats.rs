@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+/// Aggregate timing statistics over the per-number factoring times.
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    pub count: usize,
+    /// Sum of every per-number time, i.e. the CPU time spent factoring across all workers
+    pub total: Duration,
+    pub mean: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+/// Computes statistics over a list of per-number durations. Returns None if the list is empty.
+pub fn compute(durations: &[Duration]) -> Option<Stats> {
+    if durations.is_empty() {
+        return None;
+    }
+    let mut sorted = durations.to_vec();
+    sorted.sort();
+    let count = sorted.len();
+    let total: Duration = sorted.iter().sum();
+    let median = if count % 2 == 1 {
+        sorted[count / 2]
+    } else {
+        (sorted[count / 2 - 1] + sorted[count / 2]) / 2
+    };
+    // Nearest-rank percentile: the smallest value that at least 95% of the samples don't exceed
+    let p95_rank = (count * 95).div_ceil(100);
+    Some(Stats {
+        count,
+        total,
+        mean: total / count as u32,
+        median,
+        p95: sorted[p95_rank - 1],
+        max: sorted[count - 1],
+    })
+}
+
+/// Returns the `n` inputs that took longest to factor, slowest first.
+pub fn slowest(results: &[(u64, Duration)], n: usize) -> Vec<(u64, Duration)> {
+    let mut sorted = results.to_vec();
+    sorted.sort_by_key(|&(_, elapsed)| std::cmp::Reverse(elapsed));
+    sorted.truncate(n);
+    sorted
+}
+
+/// Formats the end-of-run summary report. `cache_hits` is None if caching was disabled.
+pub fn summary(
+    results: &[(u64, Duration)],
+    wall_time: Duration,
+    cache_hits: Option<usize>,
+) -> String {
+    let durations: Vec<Duration> = results.iter().map(|(_, elapsed)| *elapsed).collect();
+    let stats = match compute(&durations) {
+        Some(stats) => stats,
+        None => return String::from("Summary: no numbers were factored"),
+    };
+    let slowest_str = slowest(results, 3)
+        .iter()
+        .map(|(num, elapsed)| format!("{} ({:.2?})", num, elapsed))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let cache_str = match cache_hits {
+        Some(hits) => format!("\n  {} of {} inputs served from cache", hits, stats.count),
+        None => String::new(),
+    };
+    format!(
+        "Summary: {} numbers factored\n\
+         \x20 wall time {:.2?}, total CPU time {:.2?} ({:.2}x parallel speedup)\n\
+         \x20 per number: mean {:.2?}, median {:.2?}, p95 {:.2?}, max {:.2?}\n\
+         \x20 slowest: {}{}",
+        stats.count,
+        wall_time,
+        stats.total,
+        stats.total.as_secs_f64() / wall_time.as_secs_f64(),
+        stats.mean,
+        stats.median,
+        stats.p95,
+        stats.max,
+        slowest_str,
+        cache_str
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_compute_empty() {
+        assert_eq!(compute(&[]), None);
+    }
+
+    #[test]
+    fn test_compute_single() {
+        let stats = compute(&[ms(7)]).unwrap();
+        assert_eq!(stats.count, 1);
+        assert_eq!(stats.total, ms(7));
+        assert_eq!(stats.mean, ms(7));
+        assert_eq!(stats.median, ms(7));
+        assert_eq!(stats.p95, ms(7));
+        assert_eq!(stats.max, ms(7));
+    }
+
+    #[test]
+    fn test_compute_unsorted_even() {
+        let stats = compute(&[ms(40), ms(10), ms(30), ms(20)]).unwrap();
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.total, ms(100));
+        assert_eq!(stats.mean, ms(25));
+        assert_eq!(stats.median, ms(25));
+        assert_eq!(stats.p95, ms(40));
+        assert_eq!(stats.max, ms(40));
+    }
+
+    #[test]
+    fn test_compute_p95() {
+        // 1ms, 2ms, ..., 100ms: the 95th value is 95ms
+        let durations: Vec<Duration> = (1..=100).map(ms).collect();
+        let stats = compute(&durations).unwrap();
+        assert_eq!(stats.median, Duration::from_micros(50500));
+        assert_eq!(stats.p95, ms(95));
+        assert_eq!(stats.max, ms(100));
+    }
+
+    #[test]
+    fn test_slowest() {
+        let results = [(4, ms(1)), (97, ms(9)), (12, ms(3)), (1000, ms(5))];
+        assert_eq!(
+            slowest(&results, 3),
+            vec![(97, ms(9)), (1000, ms(5)), (12, ms(3))]
+        );
+        assert_eq!(slowest(&results[..1], 3), vec![(4, ms(1))]);
+    }
+}